@@ -0,0 +1,142 @@
+use {
+    crate::{BlockPos, BlockTypeID, EMPTY_BLOCK},
+    glam::IVec3,
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
+};
+
+/// The length of a chunk along each axis, in blocks
+pub const CHUNK_SIZE: i32 = 16;
+pub(crate) const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// The position of a chunk, in chunks. Chunk (0, 0, 0) contains blocks (0, 0, 0) to (15, 15, 15)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkPos {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        Self { x, y, z }
+    }
+
+    /// Get the chunk containing the given block
+    pub fn containing(pos: BlockPos) -> Self {
+        Self {
+            x: pos.x.div_euclid(CHUNK_SIZE),
+            y: pos.y.div_euclid(CHUNK_SIZE),
+            z: pos.z.div_euclid(CHUNK_SIZE),
+        }
+    }
+
+    /// Get the position of the block in this chunk with the smallest coordinates
+    pub fn origin(&self) -> BlockPos {
+        BlockPos::new(
+            self.x * CHUNK_SIZE,
+            self.y * CHUNK_SIZE,
+            self.z * CHUNK_SIZE,
+        )
+    }
+
+    pub fn as_ivec3(&self) -> IVec3 {
+        IVec3::new(self.x, self.y, self.z)
+    }
+}
+
+impl From<IVec3> for ChunkPos {
+    fn from(vec: IVec3) -> Self {
+        Self::new(vec.x, vec.y, vec.z)
+    }
+}
+
+/// A 16³ cube of blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    // X, then Y, then Z
+    blocks: Box<[BlockTypeID]>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self {
+            blocks: vec![EMPTY_BLOCK; CHUNK_VOLUME].into_boxed_slice(),
+        }
+    }
+}
+
+impl Chunk {
+    /// Build a chunk from its raw blocks, returning `None` if there's the wrong number of them
+    pub fn from_blocks(blocks: Vec<BlockTypeID>) -> Option<Self> {
+        if blocks.len() != CHUNK_VOLUME {
+            return None;
+        }
+
+        Some(Self {
+            blocks: blocks.into_boxed_slice(),
+        })
+    }
+
+    /// The raw blocks in this chunk, X varying fastest, then Y, then Z
+    pub fn blocks(&self) -> &[BlockTypeID] {
+        &self.blocks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|&block| block == EMPTY_BLOCK)
+    }
+
+    pub(crate) fn get(&self, pos: BlockPos) -> &BlockTypeID {
+        &self.blocks[local_index(pos)]
+    }
+
+    pub(crate) fn get_mut(&mut self, pos: BlockPos) -> &mut BlockTypeID {
+        &mut self.blocks[local_index(pos)]
+    }
+
+    /// Iterate over the non-empty blocks in this chunk, given this chunk's position
+    pub fn iter_non_empty(
+        &self,
+        chunk_pos: ChunkPos,
+    ) -> impl Iterator<Item = (BlockPos, BlockTypeID)> + '_ {
+        let origin = chunk_pos.origin();
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, &block)| block != EMPTY_BLOCK)
+            .map(move |(index, &block)| {
+                let index = index as i32;
+                let offset = IVec3::new(
+                    index % CHUNK_SIZE,
+                    (index / CHUNK_SIZE) % CHUNK_SIZE,
+                    index / (CHUNK_SIZE * CHUNK_SIZE),
+                );
+                (origin + offset, block)
+            })
+    }
+}
+
+// Chunks are sent over the wire on their own when streaming, as their raw blocks
+impl Serialize for Chunk {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.blocks.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chunk {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let blocks = Vec::<BlockTypeID>::deserialize(deserializer)?;
+        Chunk::from_blocks(blocks)
+            .ok_or_else(|| serde::de::Error::custom("chunk has the wrong number of blocks"))
+    }
+}
+
+// The index of a block within its chunk
+fn local_index(pos: BlockPos) -> usize {
+    let (x, y, z) = (
+        pos.x.rem_euclid(CHUNK_SIZE),
+        pos.y.rem_euclid(CHUNK_SIZE),
+        pos.z.rem_euclid(CHUNK_SIZE),
+    );
+    (x + y * CHUNK_SIZE + z * CHUNK_SIZE * CHUNK_SIZE) as usize
+}
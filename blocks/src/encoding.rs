@@ -0,0 +1,211 @@
+use crate::{chunk::CHUNK_VOLUME, BlockGrid, BlockTypeID, Chunk, ChunkPos};
+
+// A compact binary encoding of block grids, for storing worlds. Each non-empty chunk is stored as
+// runs of identical blocks, which suits terrain where most of a chunk is one or two kinds of block.
+//
+// Everything is little endian:
+//   magic "HYBG", format version (u8), chunk count (u32)
+//   then for each chunk: position (3 × i32), run count (u16), runs of (length (u16), block (u8))
+
+const MAGIC: &[u8; 4] = b"HYBG";
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("not an encoded block grid")]
+    NotABlockGrid,
+    #[error("block grid format version {0} isn't one this build understands")]
+    UnknownVersion(u8),
+    #[error("the block grid ends part way through")]
+    Truncated,
+    #[error("chunk {0:?} has {1} blocks, rather than {CHUNK_VOLUME}")]
+    WrongChunkSize(ChunkPos, usize),
+}
+
+impl BlockGrid {
+    /// Encode the grid in a compact binary format. Empty chunks are left out.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut chunks = self
+            .chunks()
+            .filter(|(_, chunk)| !chunk.is_empty())
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|(position, _)| *position);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        for (position, chunk) in chunks {
+            for coordinate in [position.x, position.y, position.z] {
+                bytes.extend_from_slice(&coordinate.to_le_bytes());
+            }
+            let runs = runs(chunk.blocks());
+            bytes.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+            for (length, block) in runs {
+                bytes.extend_from_slice(&length.to_le_bytes());
+                bytes.push(block);
+            }
+        }
+        bytes
+    }
+
+    /// Decode a grid encoded with [`BlockGrid::encode`]
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::NotABlockGrid);
+        }
+        let version = reader.u8()?;
+        // Versions start at 1, so a 0 is corrupt rather than old
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(DecodeError::UnknownVersion(version));
+        }
+
+        let mut grid = BlockGrid::new();
+        let chunk_count = reader.u32()?;
+        for _ in 0..chunk_count {
+            let position = ChunkPos::new(reader.i32()?, reader.i32()?, reader.i32()?);
+            let run_count = reader.u16()?;
+            let mut blocks = Vec::with_capacity(CHUNK_VOLUME);
+            for _ in 0..run_count {
+                let length = reader.u16()? as usize;
+                let block = reader.u8()?;
+                // Don't let a corrupt file make us allocate more than a chunk's worth
+                if blocks.len() + length > CHUNK_VOLUME {
+                    return Err(DecodeError::WrongChunkSize(position, blocks.len() + length));
+                }
+                blocks.resize(blocks.len() + length, block);
+            }
+            let block_count = blocks.len();
+            let chunk = Chunk::from_blocks(blocks)
+                .ok_or(DecodeError::WrongChunkSize(position, block_count))?;
+            grid.insert_chunk(position, chunk);
+        }
+
+        Ok(grid)
+    }
+}
+
+// Runs of identical blocks, as (length, block). A run is at most a chunk long, so fits in a u16.
+fn runs(blocks: &[BlockTypeID]) -> Vec<(u16, BlockTypeID)> {
+    let mut runs: Vec<(u16, BlockTypeID)> = Vec::new();
+    for &block in blocks {
+        match runs.last_mut() {
+            Some((length, last)) if *last == block => *length += 1,
+            _ => runs.push((1, block)),
+        }
+    }
+    runs
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < count {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self
+            .take(N)?
+            .try_into()
+            .expect("took the wrong number of bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        self.array().map(i32::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::BlockPos};
+
+    #[test]
+    fn round_trip() {
+        let mut grid = BlockGrid::new();
+        grid[BlockPos::new(-20, 5, 100)] = 1;
+        grid[BlockPos::new(3, 3, 3)] = 7;
+        grid[BlockPos::new(4, 3, 3)] = 7;
+        grid[BlockPos::new(64, 64, 64)] = 1;
+        grid[BlockPos::new(64, 64, 64)] = 0;
+
+        let loaded = BlockGrid::decode(&grid.encode()).unwrap();
+
+        assert_eq!(loaded.chunks().count(), 2);
+        let mut blocks = loaded.iter_non_empty().collect::<Vec<_>>();
+        blocks.sort_by_key(|(pos, _)| (pos.x, pos.y, pos.z));
+        assert_eq!(
+            blocks,
+            vec![
+                (BlockPos::new(-20, 5, 100), 1),
+                (BlockPos::new(3, 3, 3), 7),
+                (BlockPos::new(4, 3, 3), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn solid_terrain_is_small() {
+        // A 128 × 16 × 128 slab of ground, which is 256 KiB of raw blocks
+        let mut grid = BlockGrid::new();
+        for x in 0..128 {
+            for y in 0..16 {
+                for z in 0..128 {
+                    grid[BlockPos::new(x, y, z)] = 1;
+                }
+            }
+        }
+
+        let encoded = grid.encode();
+        // 64 chunks, each of a single run
+        assert_eq!(encoded.len(), 9 + 64 * (12 + 2 + 3));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut grid = BlockGrid::new();
+        grid[BlockPos::new(1, 2, 3)] = 4;
+        let encoded = grid.encode();
+
+        assert_eq!(
+            BlockGrid::decode(b"[1, 2, 3]").unwrap_err(),
+            DecodeError::NotABlockGrid
+        );
+        assert_eq!(
+            BlockGrid::decode(&encoded[..encoded.len() - 1]).unwrap_err(),
+            DecodeError::Truncated
+        );
+
+        let mut newer = encoded.clone();
+        newer[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            BlockGrid::decode(&newer).unwrap_err(),
+            DecodeError::UnknownVersion(FORMAT_VERSION + 1)
+        );
+
+        let mut unversioned = encoded.clone();
+        unversioned[4] = 0;
+        assert_eq!(
+            BlockGrid::decode(&unversioned).unwrap_err(),
+            DecodeError::UnknownVersion(0)
+        );
+    }
+}
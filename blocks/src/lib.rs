@@ -4,60 +4,51 @@
 /// This should be used by both the server and the client, and not contain any game logic
 ///
 use {
-    serde::{Deserialize, Serialize},
-    std::ops::{Add, Index, IndexMut, Sub},
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
+    std::{
+        collections::HashMap,
+        ops::{Add, Index, IndexMut, Sub},
+    },
 };
 
-use glam::{IVec3, UVec3, Vec3};
+use glam::{IVec3, Vec3};
 use tsify::Tsify;
 use wasm_bindgen::prelude::*;
 
+mod chunk;
+mod encoding;
 mod raycast;
+mod region;
 
+pub use chunk::{Chunk, ChunkPos, CHUNK_SIZE};
+pub use encoding::DecodeError;
 pub use raycast::RayHit;
+pub use region::{region_positions, region_volume, BlockRegion};
 
 pub type BlockTypeID = u8;
 
 pub const EMPTY_BLOCK: BlockTypeID = 0;
-pub const MAX_BLOCK_HEIGHT: u32 = 64;
 
 #[wasm_bindgen]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BlockPos {
-    pub x: u32,
-    pub y: u32,
-    pub z: u32,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
 }
 
 impl BlockPos {
-    pub fn new(x: u32, y: u32, z: u32) -> Self {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
         Self { x, y, z }
     }
 
-    pub fn from_float(vec: Vec3) -> Option<Self> {
-        if vec.cmplt(Vec3::ZERO).any() {
-            return None;
-        }
-
-        Some(Self {
-            x: vec.x as u32,
-            y: vec.y as u32,
-            z: vec.z as u32,
-        })
+    /// Get the position of the block containing the given point
+    pub fn from_float(vec: Vec3) -> Self {
+        vec.floor().as_ivec3().into()
     }
 
-    pub fn add_signed(&self, vec: IVec3) -> Option<Self> {
-        let result = UVec3::new(self.x, self.y, self.z).as_ivec3() + vec;
-
-        if result.cmplt(IVec3::ZERO).any() {
-            return None;
-        }
-
-        Some(Self {
-            x: result.x as u32,
-            y: result.y as u32,
-            z: result.z as u32,
-        })
+    pub fn as_ivec3(&self) -> IVec3 {
+        IVec3::new(self.x, self.y, self.z)
     }
 }
 
@@ -91,14 +82,22 @@ impl Add<BlockPos> for BlockPos {
     }
 }
 
-impl From<[u32; 3]> for BlockPos {
-    fn from([x, y, z]: [u32; 3]) -> Self {
+impl Add<IVec3> for BlockPos {
+    type Output = BlockPos;
+
+    fn add(self, rhs: IVec3) -> Self::Output {
+        (self.as_ivec3() + rhs).into()
+    }
+}
+
+impl From<[i32; 3]> for BlockPos {
+    fn from([x, y, z]: [i32; 3]) -> Self {
         Self { x, y, z }
     }
 }
 
-impl From<UVec3> for BlockPos {
-    fn from(vec: UVec3) -> Self {
+impl From<IVec3> for BlockPos {
+    fn from(vec: IVec3) -> Self {
         Self {
             x: vec.x,
             y: vec.y,
@@ -107,53 +106,83 @@ impl From<UVec3> for BlockPos {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A sparse, unbounded grid of blocks, stored in 16³ chunks
+///
+/// Chunks are allocated the first time a block inside them is mutably accessed, so reading a
+/// block in a chunk that has never been written to returns `None`.
+#[derive(Clone, Debug, Default)]
 pub struct BlockGrid {
-    blocks: Vec<BlockTypeID>,
-    // X, Y, Z
-    size: (u32, u32, u32),
+    chunks: HashMap<ChunkPos, Chunk>,
 }
 
 impl BlockGrid {
-    /// Create a new block grid with the given dimensions
-    pub fn new(size_x: u32, size_y: u32, size_z: u32) -> Self {
-        Self {
-            blocks: vec![BlockTypeID::default(); (size_x * size_y * size_z) as usize],
-            size: (size_x, size_y, size_z),
-        }
+    /// Create a new, empty block grid
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Get the block at the given position
+    /// Get the block at the given position, or `None` if its chunk has never been written to
     pub fn get(&self, pos: BlockPos) -> Option<&BlockTypeID> {
-        self.blocks.get(block_pos_to_array_index(pos, self.size)?)
+        self.chunks
+            .get(&ChunkPos::containing(pos))
+            .map(|chunk| chunk.get(pos))
     }
 
     /// Get a mutable reference to the block at the given position
+    ///
+    /// This grows the grid to fit the position, so it always returns `Some`.
     pub fn get_mut(&mut self, pos: BlockPos) -> Option<&mut BlockTypeID> {
-        self.blocks
-            .get_mut(block_pos_to_array_index(pos, self.size)?)
+        let chunk = self.chunks.entry(ChunkPos::containing(pos)).or_default();
+        Some(chunk.get_mut(pos))
+    }
+
+    /// Set the block at the given position. Clearing a block never allocates a new chunk.
+    pub fn set(&mut self, pos: BlockPos, block: BlockTypeID) {
+        if block == EMPTY_BLOCK && self.get(pos).is_none() {
+            return;
+        }
+
+        self[pos] = block;
+    }
+
+    /// Get the chunk at the given chunk position, if it has been allocated
+    pub fn chunk(&self, pos: ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(&pos)
+    }
+
+    /// Replace the chunk at the given chunk position, eg. when it's streamed in from the server
+    pub fn insert_chunk(&mut self, pos: ChunkPos, chunk: Chunk) {
+        self.chunks.insert(pos, chunk);
     }
 
-    /// Get the size of the block grid
-    pub fn size(&self) -> (u32, u32, u32) {
-        self.size
+    /// Remove the chunk at the given chunk position, returning it if it was allocated
+    pub fn remove_chunk(&mut self, pos: ChunkPos) -> Option<Chunk> {
+        self.chunks.remove(&pos)
+    }
+
+    /// Get an iterator over all allocated chunks, in no particular order
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkPos, &Chunk)> + '_ {
+        self.chunks.iter().map(|(pos, chunk)| (*pos, chunk))
+    }
+
+    /// Get the bounds of all allocated chunks, as (inclusive minimum, exclusive maximum)
+    pub fn bounds(&self) -> Option<(BlockPos, BlockPos)> {
+        let mut positions = self.chunks.keys().map(ChunkPos::as_ivec3);
+        let first = positions.next()?;
+        let (min, max) = positions.fold((first, first), |(min, max), pos| {
+            (min.min(pos), max.max(pos))
+        });
+
+        let min = ChunkPos::from(min).origin();
+        let max = ChunkPos::from(max + IVec3::ONE).origin();
+        Some((min, max))
     }
 
     /// Get an iterator over all non-empty blocks in the grid
     pub fn iter_non_empty(&self) -> impl Iterator<Item = (BlockPos, BlockTypeID)> + '_ {
-        let indices = (0..self.size.0).flat_map(move |x| {
-            (0..self.size.1).flat_map(move |y| (0..self.size.2).map(move |z| [x, y, z]))
-        });
-        indices.filter_map(|pos| {
-            let block_pos: BlockPos = pos.into();
-            self.get(block_pos).and_then(|&block| {
-                if block != EMPTY_BLOCK {
-                    Some((block_pos, block))
-                } else {
-                    None
-                }
-            })
-        })
+        self.chunks
+            .iter()
+            .flat_map(|(pos, chunk)| chunk.iter_non_empty(*pos))
     }
 
     pub fn raycast(&self, start: Vec3, direction: glam::Vec3) -> Option<raycast::RayHit> {
@@ -164,39 +193,109 @@ impl BlockGrid {
 impl Index<BlockPos> for BlockGrid {
     type Output = BlockTypeID;
 
+    /// Blocks in chunks that have never been written to are empty
     fn index(&self, pos: BlockPos) -> &BlockTypeID {
-        self.get(pos)
-            .ok_or_else(|| {
-                format!(
-                    "Index ({}, {}, {}) out of bounds ({}, {}, {})",
-                    pos.x, pos.y, pos.z, self.size.0, self.size.1, self.size.2
-                )
-            })
-            .unwrap()
+        self.get(pos).unwrap_or(&EMPTY_BLOCK)
     }
 }
 
 impl IndexMut<BlockPos> for BlockGrid {
     fn index_mut(&mut self, pos: BlockPos) -> &mut BlockTypeID {
-        let size = self.size;
-        self.get_mut(pos)
-            .ok_or_else(|| {
-                format!(
-                    "Index ({}, {}, {}) out of bounds ({}, {}, {})",
-                    pos.x, pos.y, pos.z, size.0, size.1, size.2
-                )
+        self.get_mut(pos).expect("get_mut always allocates")
+    }
+}
+
+// Serialized chunks are sorted by position so saved worlds diff nicely, and empty chunks are
+// dropped entirely.
+#[derive(Serialize)]
+struct SerializeGrid<'a> {
+    chunks: Vec<SerializeChunk<'a>>,
+}
+
+#[derive(Serialize)]
+struct SerializeChunk<'a> {
+    position: ChunkPos,
+    blocks: &'a Chunk,
+}
+
+#[derive(Deserialize)]
+struct DeserializeGrid {
+    chunks: Vec<DeserializeChunk>,
+}
+
+#[derive(Deserialize)]
+struct DeserializeChunk {
+    position: ChunkPos,
+    blocks: Chunk,
+}
+
+// The flat layout used before the grid was chunked. Only ever read, from JSON.
+#[derive(Deserialize)]
+struct LegacyGrid {
+    blocks: Vec<BlockTypeID>,
+    // X, Y, Z
+    size: (u32, u32, u32),
+}
+
+impl Serialize for BlockGrid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut chunks = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| !chunk.is_empty())
+            .map(|(position, chunk)| SerializeChunk {
+                position: *position,
+                blocks: chunk,
             })
-            .unwrap()
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|chunk| chunk.position);
+
+        SerializeGrid { chunks }.serialize(serializer)
     }
 }
 
-fn block_pos_to_array_index(pos: BlockPos, size: (u32, u32, u32)) -> Option<usize> {
-    if pos.x >= size.0 || pos.y >= size.1 || pos.z >= size.2 {
-        return None;
+impl<'de> Deserialize<'de> for BlockGrid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AnyGrid {
+            Chunked(DeserializeGrid),
+            Legacy(LegacyGrid),
+        }
+
+        // Untagged enums need a self-describing format, which is fine since the legacy layout
+        // only ever existed in JSON files.
+        let grid = if deserializer.is_human_readable() {
+            match AnyGrid::deserialize(deserializer)? {
+                AnyGrid::Chunked(grid) => grid,
+                AnyGrid::Legacy(legacy) => return Ok(legacy.into()),
+            }
+        } else {
+            DeserializeGrid::deserialize(deserializer)?
+        };
+
+        let chunks = grid
+            .chunks
+            .into_iter()
+            .map(|DeserializeChunk { position, blocks }| (position, blocks))
+            .collect();
+
+        Ok(Self { chunks })
     }
+}
 
-    let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
-    Some(x + (y * size.0 as usize) + z * (size.0 as usize * size.1 as usize))
+impl From<LegacyGrid> for BlockGrid {
+    fn from(LegacyGrid { blocks, size }: LegacyGrid) -> Self {
+        let mut grid = BlockGrid::new();
+        for (index, &block) in blocks.iter().enumerate() {
+            let index = index as u32;
+            let x = index % size.0;
+            let y = (index / size.0) % size.1;
+            let z = index / (size.0 * size.1);
+            grid.set(BlockPos::new(x as i32, y as i32, z as i32), block);
+        }
+        grid
+    }
 }
 
 #[derive(Tsify, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -254,3 +353,60 @@ impl BlockRegistry {
         block_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_positions() {
+        let mut grid = BlockGrid::new();
+        assert_eq!(grid.bounds(), None);
+
+        grid[BlockPos::new(-1, -17, 3)] = 2;
+        grid.set(BlockPos::new(40, 0, 0), EMPTY_BLOCK);
+
+        assert_eq!(grid[BlockPos::new(-1, -17, 3)], 2);
+        assert_eq!(grid[BlockPos::new(15, -17, 3)], EMPTY_BLOCK);
+        assert_eq!(grid.get(BlockPos::new(40, 0, 0)), None);
+        assert_eq!(
+            grid.bounds(),
+            Some((BlockPos::new(-16, -32, 0), BlockPos::new(0, -16, 16)))
+        );
+        assert_eq!(
+            grid.iter_non_empty().collect::<Vec<_>>(),
+            vec![(BlockPos::new(-1, -17, 3), 2)]
+        );
+    }
+
+    #[test]
+    fn loads_legacy_grids() {
+        // 2x2x2, with blocks at (1, 0, 0) and (0, 1, 1)
+        let json = r#"{ "blocks": [0, 3, 0, 0, 0, 0, 4, 0], "size": [2, 2, 2] }"#;
+        let grid: BlockGrid = serde_json::from_str(json).unwrap();
+
+        let mut blocks = grid.iter_non_empty().collect::<Vec<_>>();
+        blocks.sort_by_key(|(pos, _)| (pos.x, pos.y, pos.z));
+        assert_eq!(
+            blocks,
+            vec![(BlockPos::new(0, 1, 1), 4), (BlockPos::new(1, 0, 0), 3)]
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut grid = BlockGrid::new();
+        grid[BlockPos::new(-20, 5, 100)] = 1;
+        grid[BlockPos::new(3, 3, 3)] = 7;
+        // Chunks that end up empty are not saved
+        grid[BlockPos::new(64, 64, 64)] = 1;
+        grid[BlockPos::new(64, 64, 64)] = EMPTY_BLOCK;
+
+        let json = serde_json::to_string(&grid).unwrap();
+        let loaded: BlockGrid = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.chunks().count(), 2);
+        assert_eq!(loaded[BlockPos::new(-20, 5, 100)], 1);
+        assert_eq!(loaded[BlockPos::new(3, 3, 3)], 7);
+    }
+}
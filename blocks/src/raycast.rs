@@ -47,33 +47,20 @@ pub(crate) fn raycast(
 
     for _ in 0..1000 {
         let blockpos = BlockPos::from_float(current_voxel);
-        let block = blockpos.and_then(|pos| blocks.get(pos)).copied();
-        let block_is_empty = block.map_or(true, |block| block == EMPTY_BLOCK);
-
-        if !block_is_empty {
-            let blockpos = blockpos.unwrap();
 
+        if blocks[blockpos] != EMPTY_BLOCK {
             return Some(RayHit {
                 position: blockpos,
                 entrance_face_normal,
             });
         }
 
-        // If we hit below the floor, we return the block above the floor with a zero face normal.
-        // CRIME(cw): This really should return the block below the floor and regular face normal,
-        //            but BlockPos doesn't support negative values.
+        // If we hit below the floor, we treat the block below the floor as solid.
         let below_floor = current_voxel.y < floor;
         if below_floor {
-            let blockpos = BlockPos::from_float(current_voxel + Vec3::Y)?;
-
-            // If we're out of bounds, we return None.
-            if blocks.get(blockpos).is_none() {
-                return None;
-            }
-
             return Some(RayHit {
                 position: blockpos,
-                entrance_face_normal: Vec3::ZERO,
+                entrance_face_normal,
             });
         }
 
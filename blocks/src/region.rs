@@ -0,0 +1,189 @@
+use {
+    crate::{BlockGrid, BlockPos, BlockTypeID, EMPTY_BLOCK},
+    glam::{IVec3, UVec3},
+    serde::{Deserialize, Serialize},
+};
+
+/// A box of blocks copied out of a grid, so it can be pasted somewhere else
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawRegion")]
+pub struct BlockRegion {
+    size: UVec3,
+    // X, then Y, then Z
+    blocks: Vec<BlockTypeID>,
+}
+
+#[derive(Deserialize)]
+struct RawRegion {
+    size: UVec3,
+    blocks: Vec<BlockTypeID>,
+}
+
+impl TryFrom<RawRegion> for BlockRegion {
+    type Error = &'static str;
+
+    fn try_from(RawRegion { size, blocks }: RawRegion) -> Result<Self, Self::Error> {
+        BlockRegion::new(size, blocks).ok_or("region has the wrong number of blocks")
+    }
+}
+
+impl BlockRegion {
+    /// Make a region from its raw blocks, returning `None` if there's the wrong number of them
+    pub fn new(size: UVec3, blocks: Vec<BlockTypeID>) -> Option<Self> {
+        (region_volume(size) == Some(blocks.len())).then_some(Self { size, blocks })
+    }
+
+    /// Copy the box of blocks of the given size, starting at `min`
+    pub fn copy(grid: &BlockGrid, min: BlockPos, size: UVec3) -> Self {
+        let blocks = region_positions(min, size)
+            .map(|pos| grid.get(pos).copied().unwrap_or(EMPTY_BLOCK))
+            .collect();
+        Self { size, blocks }
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// The number of blocks in the region
+    pub fn volume(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The same region with every block passed through `f`
+    pub fn map(&self, f: impl Fn(BlockTypeID) -> BlockTypeID) -> Self {
+        Self {
+            size: self.size,
+            blocks: self.blocks.iter().copied().map(f).collect(),
+        }
+    }
+
+    /// The blocks in this region, with their positions if it were pasted at `origin`. Empty blocks
+    /// are included, pasting a region replaces everything in its box.
+    pub fn blocks_at(
+        &self,
+        origin: BlockPos,
+    ) -> impl Iterator<Item = (BlockPos, BlockTypeID)> + '_ {
+        region_positions(origin, self.size).zip(self.blocks.iter().copied())
+    }
+
+    /// A quarter turn around the Y axis, taking +X to +Z and +Z to -X
+    pub fn rotated(&self) -> Self {
+        let size = UVec3::new(self.size.z, self.size.y, self.size.x);
+        let mut blocks = vec![EMPTY_BLOCK; self.blocks.len()];
+        for (offset, block) in self.offsets() {
+            let rotated = UVec3::new(self.size.z - 1 - offset.z, offset.y, offset.x);
+            blocks[index(size, rotated)] = block;
+        }
+        Self { size, blocks }
+    }
+
+    /// Flipped along the X axis
+    pub fn mirrored(&self) -> Self {
+        let mut blocks = vec![EMPTY_BLOCK; self.blocks.len()];
+        for (offset, block) in self.offsets() {
+            let mirrored = UVec3::new(self.size.x - 1 - offset.x, offset.y, offset.z);
+            blocks[index(self.size, mirrored)] = block;
+        }
+        Self {
+            size: self.size,
+            blocks,
+        }
+    }
+
+    // Each block with its offset from the region's corner
+    fn offsets(&self) -> impl Iterator<Item = (UVec3, BlockTypeID)> + '_ {
+        region_positions(BlockPos::new(0, 0, 0), self.size)
+            .map(|pos| pos.as_ivec3().as_uvec3())
+            .zip(self.blocks.iter().copied())
+    }
+}
+
+/// Every position in a box of the given size starting at `min`, X varying fastest, then Y, then Z
+pub fn region_positions(min: BlockPos, size: UVec3) -> impl Iterator<Item = BlockPos> {
+    let size = size.as_ivec3();
+    (0..size.z).flat_map(move |z| {
+        (0..size.y).flat_map(move |y| (0..size.x).map(move |x| min + IVec3::new(x, y, z)))
+    })
+}
+
+/// The number of blocks in a box of the given size, or `None` if it's too many to count
+pub fn region_volume(size: UVec3) -> Option<usize> {
+    (size.x as usize)
+        .checked_mul(size.y as usize)?
+        .checked_mul(size.z as usize)
+}
+
+fn index(size: UVec3, offset: UVec3) -> usize {
+    (offset.x + offset.y * size.x + offset.z * size.x * size.y) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An L shape in a 3 × 1 × 2 region: (0, 0, 0), (1, 0, 0), (2, 0, 0) and (0, 0, 1)
+    fn l_shape() -> BlockRegion {
+        BlockRegion::new(UVec3::new(3, 1, 2), vec![1, 2, 3, 4, 0, 0]).unwrap()
+    }
+
+    fn non_empty(region: &BlockRegion) -> Vec<(BlockPos, BlockTypeID)> {
+        region
+            .blocks_at(BlockPos::new(0, 0, 0))
+            .filter(|(_, block)| *block != EMPTY_BLOCK)
+            .collect()
+    }
+
+    #[test]
+    fn copy_and_paste() {
+        let mut grid = BlockGrid::new();
+        grid[BlockPos::new(-1, 0, 0)] = 1;
+        grid[BlockPos::new(0, 0, 0)] = 2;
+        grid[BlockPos::new(0, 1, 0)] = 3;
+
+        let region = BlockRegion::copy(&grid, BlockPos::new(-1, 0, 0), UVec3::new(2, 2, 1));
+        assert_eq!(region.blocks, vec![1, 2, 0, 3]);
+
+        for (pos, block) in region.blocks_at(BlockPos::new(10, 0, 0)) {
+            grid.set(pos, block);
+        }
+        assert_eq!(grid[BlockPos::new(10, 0, 0)], 1);
+        assert_eq!(grid[BlockPos::new(11, 0, 0)], 2);
+        assert_eq!(grid[BlockPos::new(11, 1, 0)], 3);
+    }
+
+    #[test]
+    fn rotate() {
+        let rotated = l_shape().rotated();
+        assert_eq!(rotated.size(), UVec3::new(2, 1, 3));
+        // The row along +X now runs along +Z, and the block that was at +Z is at -X
+        assert_eq!(
+            non_empty(&rotated),
+            vec![
+                (BlockPos::new(0, 0, 0), 4),
+                (BlockPos::new(1, 0, 0), 1),
+                (BlockPos::new(1, 0, 1), 2),
+                (BlockPos::new(1, 0, 2), 3),
+            ]
+        );
+
+        // Four turns and it's back where it started
+        let turned = l_shape().rotated().rotated().rotated().rotated();
+        assert_eq!(turned, l_shape());
+    }
+
+    #[test]
+    fn mirror() {
+        let mirrored = l_shape().mirrored();
+        assert_eq!(mirrored.size(), l_shape().size());
+        assert_eq!(mirrored.blocks, vec![3, 2, 1, 0, 0, 4]);
+        assert_eq!(mirrored.mirrored(), l_shape());
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_blocks() {
+        assert!(BlockRegion::new(UVec3::new(2, 2, 2), vec![0; 7]).is_none());
+        let json = r#"{ "size": [1, 1, 2], "blocks": [1] }"#;
+        assert!(serde_json::from_str::<BlockRegion>(json).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use blocks::BlockTypeID;
 use nanorand::Rng;
-use net_types::ClientPacket;
+use net_types::{ClientPacket, RemoveEntity, RenameEntity, SetEntityCustomState};
 use wasm_bindgen::prelude::*;
 use web_sys::js_sys;
 use {
@@ -21,6 +21,11 @@ pub enum EngineMode {
 pub struct Context {
     canvas: web_sys::HtmlCanvasElement,
     pub on_init_callback: Option<js_sys::Function>,
+    pub on_script_error_callback: Option<js_sys::Function>,
+    pub on_scripts_reloaded_callback: Option<js_sys::Function>,
+    pub on_selected_entity_callback: Option<js_sys::Function>,
+    // What `on_selected_entity_callback` was last called with
+    reported_selected_entity: Option<EntityData>,
 }
 
 impl Context {
@@ -28,6 +33,10 @@ impl Context {
         Self {
             canvas,
             on_init_callback: None,
+            on_script_error_callback: None,
+            on_scripts_reloaded_callback: None,
+            on_selected_entity_callback: None,
+            reported_selected_entity: None,
         }
     }
 }
@@ -61,11 +70,84 @@ impl Engine {
         self.context.on_init_callback = Some(cb);
     }
 
+    /// `cb` is called with each error the server's scripts throw, so the editor can show them
+    pub fn ctx_on_script_error(&mut self, cb: js_sys::Function) {
+        self.context.on_script_error_callback = Some(cb);
+    }
+
+    /// `cb` is called when the server has picked up changes to its scripts
+    pub fn ctx_on_scripts_reloaded(&mut self, cb: js_sys::Function) {
+        self.context.on_scripts_reloaded_callback = Some(cb);
+    }
+
+    /// `cb` is called with the entity selected in the editor whenever it, or anything about it,
+    /// changes. It's called with `null` when nothing is selected.
+    pub fn ctx_on_selected_entity(&mut self, cb: js_sys::Function) {
+        self.context.on_selected_entity_callback = Some(cb);
+    }
+
+    pub fn ctx_get_selected_entity(&self) -> JsValue {
+        match self.selected_entity() {
+            Some(entity) => {
+                serde_wasm_bindgen::to_value(entity).expect("Failed to serialize entity")
+            }
+            None => JsValue::null(),
+        }
+    }
+
+    pub fn ctx_rename_selected_entity(&mut self, name: String) {
+        let Some(entity) = self.selected_entity_mut() else {
+            return;
+        };
+        entity.name = name.clone();
+        let entity_id = entity.id.clone();
+
+        self.send_packet(ClientPacket::RenameEntity(RenameEntity { entity_id, name }));
+    }
+
+    /// Replace the selected entity's script state, which has to be an object
+    pub fn ctx_set_selected_entity_custom_state(
+        &mut self,
+        custom_state: JsValue,
+    ) -> Result<(), JsValue> {
+        let custom_state: HashMap<String, serde_json::Value> =
+            serde_wasm_bindgen::from_value(custom_state)?;
+        let Some(entity) = self.selected_entity_mut() else {
+            return Ok(());
+        };
+        entity.state.custom_state = custom_state.clone();
+        let entity_id = entity.id.clone();
+
+        self.send_packet(ClientPacket::SetEntityCustomState(SetEntityCustomState {
+            entity_id,
+            custom_state,
+        }));
+        Ok(())
+    }
+
+    pub fn ctx_remove_selected_entity(&mut self) {
+        let GameState::Editing {
+            entities,
+            selected_entity,
+            ..
+        } = &mut self.state
+        else {
+            return;
+        };
+        let Some(entity_id) = selected_entity.take() else {
+            return;
+        };
+        entities.remove(&entity_id);
+
+        self.send_packet(ClientPacket::RemoveEntity(RemoveEntity { entity_id }));
+    }
+
     pub fn ctx_set_editor_block_id(&mut self, block_id: BlockTypeID) {
         // Ensure we're in edit mode
         let GameState::Editing {
             selected_block_id,
             preview_entity,
+            selected_entity,
             ..
         } = &mut self.state
         else {
@@ -73,6 +155,7 @@ impl Engine {
         };
 
         // Set the block ID
+        *selected_entity = None;
         *selected_block_id = Some(block_id);
         *preview_entity = None;
     }
@@ -102,19 +185,23 @@ impl Engine {
         let entity_id = nanorand::tls_rng().generate::<u64>().to_string();
         *preview_entity = Some(EntityData {
             id: entity_id,
-            name: "We should let you set entity names in the editor".into(),
-            entity_type: entity_type_id,
+            name: entity_type.name(),
+            entity_type: Some(entity_type_id),
             model_path: entity_type.default_model_path().into(),
             state: EntityState::default(),
+            controller: None,
+            joints: Vec::new(),
         });
     }
 
     pub fn ctx_get_players(&self) -> JsValue {
         match &self.state {
-            GameState::Playing { players, .. } => serde_wasm_bindgen::to_value(
-                &players
-                    .iter()
-                    .map(|(id, player)| (id.clone(), player.script_state.clone()))
+            GameState::Playing { entities, .. } => serde_wasm_bindgen::to_value(
+                &entities
+                    .values()
+                    .filter_map(|entity| {
+                        Some((entity.player_id()?, entity.state.custom_state.clone()))
+                    })
                     .collect::<HashMap<_, _>>(),
             )
             .expect("Failed to serialize players"),
@@ -144,3 +231,50 @@ impl Engine {
         }
     }
 }
+
+impl Engine {
+    pub(crate) fn selected_entity(&self) -> Option<&EntityData> {
+        let GameState::Editing {
+            entities,
+            selected_entity: Some(entity_id),
+            ..
+        } = &self.state
+        else {
+            return None;
+        };
+        entities.get(entity_id)
+    }
+
+    fn selected_entity_mut(&mut self) -> Option<&mut EntityData> {
+        let GameState::Editing {
+            entities,
+            selected_entity: Some(entity_id),
+            ..
+        } = &mut self.state
+        else {
+            return None;
+        };
+        entities.get_mut(entity_id)
+    }
+
+    // Tell the React frontend if the selected entity has changed, so it can show its properties
+    pub(crate) fn report_selected_entity(&mut self) {
+        let selected_entity = self.selected_entity().cloned();
+        if selected_entity == self.context.reported_selected_entity {
+            return;
+        }
+
+        if let Some(callback) = &self.context.on_selected_entity_callback {
+            let entity = match &selected_entity {
+                Some(entity) => {
+                    serde_wasm_bindgen::to_value(entity).expect("Failed to serialize entity")
+                }
+                None => JsValue::null(),
+            };
+            if let Err(e) = callback.call1(&JsValue::NULL, &entity) {
+                tracing::error!("Selected entity callback failed: {e:?}");
+            }
+        }
+        self.context.reported_selected_entity = selected_entity;
+    }
+}
@@ -0,0 +1,340 @@
+// Selecting entities in the editor, the keyboard gizmo for moving, turning and scaling them, and
+// the region tools for filling, copying and pasting boxes of blocks
+
+use {
+    crate::{render::DebugLine, transform::Transform},
+    blocks::{BlockGrid, BlockPos, BlockRegion},
+    entities::{EntityData, EntityID, EntityState},
+    glam::{Quat, UVec3, Vec3, Vec4},
+    nanorand::Rng,
+    net_types::{RegionOperation, SetBlocks},
+    std::collections::HashMap,
+};
+
+// How far one key press moves, turns or scales the selected entity
+const MOVE_STEP: f32 = 0.25;
+const TURN_STEP: f32 = std::f32::consts::PI / 12.;
+const SCALE_STEP: f32 = 1.1;
+
+// How long the gizmo's axis lines are, relative to the entity's scale
+const GIZMO_LENGTH: f32 = 1.5;
+
+/// The entity nearest along a ray from the camera, if it's closer than `max_distance`. Models
+/// don't have bounds yet, so each entity is picked as a ball around its origin, as big as it is
+/// scaled.
+pub fn pick_entity<'a>(
+    entities: impl Iterator<Item = (&'a EntityID, Transform)>,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+) -> Option<EntityID> {
+    entities
+        .filter_map(|(entity_id, transform)| {
+            let to_entity = transform.position - origin;
+            let along = to_entity.dot(direction);
+            let radius = transform.scale.max_element() * 0.5;
+            let miss_squared = to_entity.length_squared() - along * along;
+            (along > 0. && along < max_distance && miss_squared <= radius * radius)
+                .then_some((entity_id, along))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(entity_id, _)| entity_id.clone())
+}
+
+/// The selected entity's new position, rotation and scale after this frame's key presses, if any
+/// of the gizmo keys were pressed
+///
+/// - Arrow keys move it across the ground, Page Up and Page Down move it up and down
+/// - Q and E turn it around the vertical axis
+/// - `=` and `-` scale it up and down
+pub fn gizmo_transform(
+    state: &EntityState,
+    pressed: impl Fn(&str) -> bool,
+) -> Option<(Vec3, Quat, Vec3)> {
+    let key = |code| if pressed(code) { 1. } else { 0. };
+
+    let movement = Vec3::new(
+        key("ArrowRight") - key("ArrowLeft"),
+        key("PageUp") - key("PageDown"),
+        key("ArrowDown") - key("ArrowUp"),
+    );
+    let turn = key("KeyQ") - key("KeyE");
+    let scale_steps = key("Equal") - key("Minus");
+    if movement == Vec3::ZERO && turn == 0. && scale_steps == 0. {
+        return None;
+    }
+
+    Some((
+        state.position + movement * MOVE_STEP,
+        Quat::from_rotation_y(turn * TURN_STEP) * state.rotation,
+        state.scale * SCALE_STEP.powf(scale_steps),
+    ))
+}
+
+/// Red, green and blue lines along the selected entity's X, Y and Z axes
+pub fn gizmo_lines(transform: &Transform) -> [DebugLine; 3] {
+    let length = transform.scale.max_element() * GIZMO_LENGTH;
+    [
+        (Vec3::X, Vec4::new(1., 0.2, 0.2, 1.)),
+        (Vec3::Y, Vec4::new(0.2, 1., 0.2, 1.)),
+        (Vec3::Z, Vec4::new(0.2, 0.4, 1., 1.)),
+    ]
+    .map(|(axis, color)| {
+        let end = transform.position + transform.rotation * axis * length;
+        DebugLine::new_with_color(transform.position, end, color)
+    })
+}
+
+/// The box of blocks the region tools work on, and what was last copied
+#[derive(Debug, Default)]
+pub struct RegionTools {
+    /// Opposite corners of the selected box, both inside it
+    pub corners: [Option<BlockPos>; 2],
+    pub clipboard: Option<Clipboard>,
+}
+
+impl RegionTools {
+    /// The selected box, as its smallest corner and its size, once both corners are set
+    pub fn selection(&self) -> Option<(BlockPos, UVec3)> {
+        let [Some(a), Some(b)] = self.corners else {
+            return None;
+        };
+        let min = a.as_ivec3().min(b.as_ivec3());
+        let max = a.as_ivec3().max(b.as_ivec3());
+        Some((min.into(), (max - min + 1).as_uvec3()))
+    }
+}
+
+/// Blocks and entities copied from a box, ready to paste somewhere else
+#[derive(Clone, Debug)]
+pub struct Clipboard {
+    pub blocks: BlockRegion,
+    /// Unanchored entities' positions are relative to the box's corner
+    pub entities: Vec<EntityData>,
+}
+
+impl Clipboard {
+    /// Copy the box of the given size starting at `min`, with the entities inside it and anything
+    /// anchored to them. Players aren't part of the world, so they're left behind.
+    pub fn copy(
+        blocks: &BlockGrid,
+        entities: &HashMap<EntityID, EntityData>,
+        min: BlockPos,
+        size: UVec3,
+    ) -> Self {
+        let corner = Vec3::from(min);
+        let inside = |position: Vec3| {
+            let offset = position - corner;
+            offset.cmpge(Vec3::ZERO).all() && offset.cmplt(size.as_vec3()).all()
+        };
+
+        let mut copied = entities
+            .values()
+            .filter(|entity| entity.state.anchor.is_none() && inside(entity.state.position))
+            .map(|entity| entity.id.clone())
+            .collect::<Vec<_>>();
+        // Children, then their children, and so on
+        let mut next = 0;
+        while next < copied.len() {
+            let parent_id = copied[next].clone();
+            copied.extend(
+                entities
+                    .values()
+                    .filter(|entity| {
+                        entity
+                            .state
+                            .anchor
+                            .as_ref()
+                            .is_some_and(|anchor| anchor.parent_id == parent_id)
+                            && !copied.contains(&entity.id)
+                    })
+                    .map(|entity| entity.id.clone())
+                    .collect::<Vec<_>>(),
+            );
+            next += 1;
+        }
+
+        let entities = copied
+            .iter()
+            .filter_map(|entity_id| entities.get(entity_id))
+            .filter(|entity| entity.controller.is_none())
+            .map(|entity| {
+                let mut entity = entity.clone();
+                if entity.state.anchor.is_none() {
+                    entity.state.position -= corner;
+                }
+                // Joints to things that weren't copied would pull the pasted copy back here
+                entity.joints.retain(|joint| {
+                    joint
+                        .other_entity
+                        .as_ref()
+                        .is_none_or(|other_id| copied.contains(other_id))
+                });
+                entity
+            })
+            .collect();
+
+        Self {
+            blocks: BlockRegion::copy(blocks, min, size),
+            entities,
+        }
+    }
+
+    /// A quarter turn around the Y axis, the same way as [`BlockRegion::rotated`]
+    pub fn rotated(&self) -> Self {
+        let turn = Quat::from_rotation_y(-std::f32::consts::FRAC_PI_2);
+        let depth = self.blocks.size().z as f32;
+        let entities = self
+            .entities
+            .iter()
+            .cloned()
+            .map(|mut entity| {
+                // Anchored entities turn with their parents
+                if entity.state.anchor.is_none() {
+                    let position = entity.state.position;
+                    entity.state.position = Vec3::new(depth - position.z, position.y, position.x);
+                    entity.state.rotation = turn * entity.state.rotation;
+                }
+                entity
+            })
+            .collect();
+        Self {
+            blocks: self.blocks.rotated(),
+            entities,
+        }
+    }
+
+    /// Flipped along the X axis, the same way as [`BlockRegion::mirrored`]. Models aren't mirrored,
+    /// only where they are and which way they face.
+    pub fn mirrored(&self) -> Self {
+        let width = self.blocks.size().x as f32;
+        let entities = self
+            .entities
+            .iter()
+            .cloned()
+            .map(|mut entity| {
+                // A reflection isn't a rotation, so anchored entities can't just follow their
+                // parents
+                let position = &mut entity.state.position;
+                position.x = match entity.state.anchor {
+                    Some(_) => -position.x,
+                    None => width - position.x,
+                };
+                let rotation = entity.state.rotation;
+                entity.state.rotation =
+                    Quat::from_xyzw(rotation.x, -rotation.y, -rotation.z, rotation.w);
+                entity
+            })
+            .collect();
+        Self {
+            blocks: self.blocks.mirrored(),
+            entities,
+        }
+    }
+
+    /// The packet that pastes this with its corner at `origin`. Every pasted entity gets a new id,
+    /// so the same clipboard can be pasted again.
+    pub fn paste(&self, origin: BlockPos) -> SetBlocks {
+        let mut rng = nanorand::tls_rng();
+        let new_ids = self
+            .entities
+            .iter()
+            .map(|entity| (entity.id.clone(), rng.generate::<u64>().to_string()))
+            .collect::<HashMap<_, _>>();
+        let new_id = |entity_id: &mut EntityID| {
+            if let Some(new_id) = new_ids.get(entity_id) {
+                *entity_id = new_id.clone();
+            }
+        };
+
+        let entities = self
+            .entities
+            .iter()
+            .cloned()
+            .map(|mut entity| {
+                new_id(&mut entity.id);
+                if let Some(anchor) = &mut entity.state.anchor {
+                    new_id(&mut anchor.parent_id);
+                }
+                for joint in &mut entity.joints {
+                    if let Some(other_id) = &mut joint.other_entity {
+                        new_id(other_id);
+                    }
+                }
+                entity
+            })
+            .collect();
+
+        SetBlocks {
+            origin,
+            operation: RegionOperation::Paste {
+                blocks: self.blocks.clone(),
+                entities,
+            },
+        }
+    }
+}
+
+/// The twelve edges of a box of blocks
+pub fn region_lines(min: BlockPos, size: UVec3, color: Vec4) -> Vec<DebugLine> {
+    let min = Vec3::from(min);
+    let size = size.as_vec3();
+    let corner = |x: f32, y: f32, z: f32| min + size * Vec3::new(x, y, z);
+    let mut lines = Vec::with_capacity(12);
+    for a in [0., 1.] {
+        for b in [0., 1.] {
+            lines.push(DebugLine::new_with_color(
+                corner(0., a, b),
+                corner(1., a, b),
+                color,
+            ));
+            lines.push(DebugLine::new_with_color(
+                corner(a, 0., b),
+                corner(a, 1., b),
+                color,
+            ));
+            lines.push(DebugLine::new_with_color(
+                corner(a, b, 0.),
+                corner(a, b, 1.),
+                color,
+            ));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(position: Vec3, scale: f32) -> Transform {
+        Transform {
+            position,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::splat(scale),
+        }
+    }
+
+    #[test]
+    fn picks_the_nearest_entity_along_the_ray() {
+        let ids = ["near", "far", "beside", "behind"].map(EntityID::from);
+        let transforms = [
+            at(Vec3::new(0., 0., -5.), 1.),
+            at(Vec3::new(0., 0., -10.), 1.),
+            at(Vec3::new(2., 0., -3.), 1.),
+            at(Vec3::new(0., 0., 2.), 1.),
+        ];
+        let entities = || ids.iter().zip(transforms);
+
+        let picked = pick_entity(entities(), Vec3::ZERO, Vec3::NEG_Z, 100.);
+        assert_eq!(picked.as_deref(), Some("near"));
+
+        // Just grazing the far one, which is scaled up
+        let big = [at(Vec3::new(1.9, 0., -10.), 4.)];
+        let picked = pick_entity(ids[1..].iter().zip(big), Vec3::ZERO, Vec3::NEG_Z, 100.);
+        assert_eq!(picked.as_deref(), Some("far"));
+
+        // Out of reach
+        assert_eq!(pick_entity(entities(), Vec3::ZERO, Vec3::NEG_Z, 4.), None);
+    }
+}
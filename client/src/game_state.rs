@@ -7,7 +7,13 @@ use blocks::{BlockGrid, BlockRegistry, BlockTypeID, RayHit};
 use entities::{EntityData, EntityTypeRegistry};
 use net_types::ClientShouldSwitchMode;
 
-use crate::{camera::FlyCamera, Player};
+use crate::{
+    camera::FlyCamera,
+    editor::RegionTools,
+    gltf::GLTFModel,
+    interpolation::{ServerClock, SnapshotBuffer},
+    prediction::Prediction,
+};
 
 #[derive(Debug, Default)]
 pub enum GameState {
@@ -17,10 +23,14 @@ pub enum GameState {
         blocks: BlockGrid,
         block_registry: BlockRegistry,
         entities: HashMap<EntityID, EntityData>,
-        _entity_type_registry: EntityTypeRegistry,
+        entity_snapshots: HashMap<EntityID, SnapshotBuffer>,
+        server_clock: ServerClock,
+        entity_type_registry: EntityTypeRegistry,
         client_player: PlayerId,
+        prediction: Prediction,
         camera: FlyCamera,
-        players: HashMap<PlayerId, Player>,
+        // Each player entity's own copy of its model, so that it can be animated
+        player_models: HashMap<EntityID, GLTFModel>,
         world_script_state: serde_json::Value,
     },
     Editing {
@@ -32,6 +42,9 @@ pub enum GameState {
         target_raycast: Option<RayHit>,
         selected_block_id: Option<BlockTypeID>,
         preview_entity: Option<EntityData>,
+        // The entity being moved or edited
+        selected_entity: Option<EntityID>,
+        region_tools: RegionTools,
         world_script_state: serde_json::Value,
     },
 }
@@ -59,16 +72,41 @@ impl GameState {
                     target_raycast: None,
                     selected_block_id: None,
                     preview_entity: None,
+                    selected_entity: None,
+                    region_tools: Default::default(),
+                    world_script_state: world.world_script_state,
+                }
+            }
+            // Editing -> Editing, when we started in edit mode
+            (
+                GameState::Editing {
+                    camera,
+                    selected_block_id,
+                    region_tools,
+                    ..
+                },
+                ClientShouldSwitchMode::Edit { world },
+            ) => {
+                tracing::debug!("Reloading the world for editing");
+                *self = GameState::Editing {
+                    blocks: world.blocks,
+                    block_registry: world.block_registry,
+                    entities: world.entities,
+                    entity_type_registry: world.entity_type_registry,
+                    camera,
+                    target_raycast: None,
+                    selected_block_id,
+                    preview_entity: None,
+                    selected_entity: None,
+                    region_tools,
                     world_script_state: world.world_script_state,
                 }
             }
             // Editing -> Playing
             (
                 GameState::Editing {
-                    blocks,
                     block_registry,
                     camera,
-                    entities,
                     entity_type_registry,
                     world_script_state,
                     ..
@@ -76,14 +114,18 @@ impl GameState {
                 ClientShouldSwitchMode::Play { new_player_id },
             ) => {
                 tracing::debug!("Transitioning from editing to playing");
+                // Blocks and entities will be streamed in by the server
                 *self = GameState::Playing {
-                    blocks,
+                    blocks: BlockGrid::new(),
                     block_registry,
-                    entities,
-                    _entity_type_registry: entity_type_registry,
+                    entities: Default::default(),
+                    entity_snapshots: Default::default(),
+                    server_clock: Default::default(),
+                    entity_type_registry,
                     camera,
                     client_player: new_player_id,
-                    players: Default::default(),
+                    prediction: Default::default(),
+                    player_models: Default::default(),
                     world_script_state,
                 }
             }
@@ -0,0 +1,227 @@
+// Snapshot interpolation for things the server moves.
+//
+// Updates from the server don't arrive at a steady rate, so instead of snapping remote players
+// and entities to the latest update we render them slightly in the past, interpolating between
+// the two updates either side of that time.
+
+use {
+    glam::{Quat, Vec3},
+    movement::TICK_DT,
+    std::{collections::VecDeque, time::Duration},
+};
+
+/// How far behind the server remote players and entities are rendered by default. Higher values
+/// hide more jitter, at the cost of seeing things later.
+pub const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_millis(100);
+
+// Old snapshots are thrown away as they're passed, but don't let a buffer grow without bound
+// if it's never sampled
+const MAX_SNAPSHOTS: usize = 64;
+// How quickly the clock estimate follows the server when updates start arriving later
+const CLOCK_DRIFT_RATE: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Snapshot {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            position: self.position.lerp(other.position, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+/// The snapshots received for a single player or entity, by server tick
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(u64, Snapshot)>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, tick: u64, snapshot: Snapshot) {
+        if let Some(&(last_tick, last)) = self.snapshots.back() {
+            if tick <= last_tick {
+                tracing::debug!("Ignoring out of order snapshot for tick {tick}");
+                return;
+            }
+
+            // The server only sends updates when something changes, so if we skipped some ticks it
+            // was sitting still until the tick before this one
+            if tick > last_tick + 1 {
+                self.snapshots.push_back((tick - 1, last));
+            }
+        }
+
+        self.snapshots.push_back((tick, snapshot));
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Forget all snapshots, eg. when the thing they're relative to has changed
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn latest(&self) -> Option<Snapshot> {
+        self.snapshots.back().map(|(_, snapshot)| *snapshot)
+    }
+
+    /// The state at `tick`, which can be fractional. Holds the oldest or newest snapshot if `tick`
+    /// is outside the buffer rather than extrapolating.
+    pub fn sample(&mut self, tick: f64) -> Option<Snapshot> {
+        // Drop snapshots we've moved past, keeping the one just before `tick`
+        while self
+            .snapshots
+            .get(1)
+            .is_some_and(|(next_tick, _)| (*next_tick as f64) <= tick)
+        {
+            self.snapshots.pop_front();
+        }
+
+        let (from_tick, from) = self.snapshots.front()?;
+        let Some((to_tick, to)) = self.snapshots.get(1) else {
+            return Some(*from);
+        };
+
+        let t = (tick - *from_tick as f64) / (*to_tick - *from_tick) as f64;
+        Some(from.lerp(to, t.clamp(0., 1.) as f32))
+    }
+}
+
+/// Estimates which server tick it is, from the ticks in the updates we've received
+#[derive(Clone, Debug, Default)]
+pub struct ServerClock {
+    // Server time minus local time, in seconds
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    /// Record an update for `tick` arriving at `local_time`
+    pub fn observe(&mut self, tick: u64, local_time: Duration) {
+        let sample = tick as f64 * TICK_DT as f64 - local_time.as_secs_f64();
+
+        // The earliest arriving updates had the least network delay, so jump forward to those
+        // straight away, and only drift back slowly when updates arrive late
+        self.offset = Some(match self.offset {
+            Some(offset) if sample < offset => offset + (sample - offset) * CLOCK_DRIFT_RATE,
+            _ => sample,
+        });
+    }
+
+    /// The (fractional) server tick to render at
+    pub fn render_tick(&self, local_time: Duration, delay: Duration) -> Option<f64> {
+        let offset = self.offset?;
+        let server_time = local_time.as_secs_f64() + offset - delay.as_secs_f64();
+        Some(server_time / TICK_DT as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Snapshot {
+        Snapshot {
+            position: Vec3::new(x, 0., 0.),
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        }
+    }
+
+    fn x(snapshot: Option<Snapshot>) -> f32 {
+        snapshot.expect("Expected a snapshot").position.x
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{a} isn't {b}");
+    }
+
+    #[test]
+    fn skipped_ticks_hold_the_last_snapshot() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(10, at(0.));
+        buffer.push(14, at(4.));
+
+        // Sitting still until the tick before the update, then moving
+        assert_eq!(x(buffer.sample(12.)), 0.);
+        assert_eq!(x(buffer.sample(13.5)), 2.);
+        assert_eq!(x(buffer.sample(14.)), 4.);
+    }
+
+    #[test]
+    fn out_of_order_snapshots_are_ignored() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(10, at(0.));
+        buffer.push(12, at(2.));
+        buffer.push(11, at(100.));
+        buffer.push(12, at(100.));
+
+        assert_eq!(x(buffer.latest()), 2.);
+        assert_eq!(x(buffer.sample(11.)), 0.);
+        assert_eq!(x(buffer.sample(11.5)), 1.);
+    }
+
+    #[test]
+    fn samples_are_clamped_to_the_buffer() {
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(10.), None);
+
+        buffer.push(10, at(0.));
+        buffer.push(11, at(1.));
+        // Held at the oldest snapshot rather than extrapolated backwards
+        assert_eq!(x(buffer.sample(5.)), 0.);
+        // Or the newest, and the ones before it are dropped as they're passed
+        assert_eq!(x(buffer.sample(20.)), 1.);
+        assert_eq!(x(buffer.sample(10.5)), 1.);
+    }
+
+    #[test]
+    fn clock_jumps_forward_and_drifts_back() {
+        let tick_time = TICK_DT as f64;
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.render_tick(Duration::ZERO, Duration::ZERO), None);
+
+        // 60 ticks in at one second
+        clock.observe(60, Duration::from_secs(1));
+        let offset = 60. * tick_time - 1.;
+        let render_tick = |clock: &ServerClock, local: f64, delay: Duration| {
+            clock
+                .render_tick(Duration::from_secs_f64(local), delay)
+                .unwrap()
+        };
+        assert_close(
+            render_tick(&clock, 2., Duration::ZERO),
+            (2. + offset) / tick_time,
+        );
+        // Rendered in the past by the delay
+        assert_close(
+            render_tick(&clock, 2., Duration::from_millis(100)),
+            (1.9 + offset) / tick_time,
+        );
+
+        // An update that arrives sooner than expected is believed straight away
+        clock.observe(180, Duration::from_secs(2));
+        let sooner = 180. * tick_time - 2.;
+        assert!(sooner > offset);
+        assert_close(
+            render_tick(&clock, 3., Duration::ZERO),
+            (3. + sooner) / tick_time,
+        );
+
+        // One that arrives later only moves the estimate a little
+        clock.observe(180, Duration::from_secs(3));
+        let later = 180. * tick_time - 3.;
+        let drifted = sooner + (later - sooner) * CLOCK_DRIFT_RATE;
+        assert_close(
+            render_tick(&clock, 4., Duration::ZERO),
+            (4. + drifted) / tick_time,
+        );
+    }
+}
@@ -4,12 +4,15 @@ use {
         transform::Transform,
     },
     anyhow::Result,
-    blocks::BlockTypeID,
+    blocks::{BlockTypeID, EMPTY_BLOCK},
     dolly::prelude::YawPitch,
-    entities::{Anchor, EntityState, PlayerId},
-    glam::{EulerRot, Quat, Vec2, Vec3},
+    entities::{Anchor, EntityData, EntityID, EntityState},
+    glam::{EulerRot, Vec2, Vec3, Vec4},
     image::GenericImageView,
-    net_types::{ServerPacket, SetWorldScriptState},
+    net_types::{
+        AddEntity, PatchWorldScriptState, RegionOperation, RemoveEntity, ServerPacket, SetBlocks,
+        MAX_REGION_VOLUME,
+    },
     std::{
         cell::RefCell,
         collections::{HashMap, HashSet},
@@ -25,7 +28,7 @@ pub use blocks::BlockPos;
 
 use blocks::BlockType;
 use game_state::GameState;
-use glam::{UVec2, UVec3};
+use glam::UVec2;
 use nanorand::Rng;
 use net_types::ClientPacket;
 use net_types::ClientShouldSwitchMode;
@@ -43,9 +46,12 @@ mod assets;
 mod audio;
 mod camera;
 mod context;
+mod editor;
 mod game_state;
 mod gltf;
+mod interpolation;
 mod packet_handlers;
+mod prediction;
 mod render;
 mod socket;
 mod transform;
@@ -72,6 +78,8 @@ pub struct Engine {
     last_seen_sequence_number: u64,
 
     controls: Controls,
+    // How far behind the server remote players and entities are rendered
+    interpolation_delay: Duration,
 
     cube_mesh_data: render::CubeVao,
     // Textures by block type ID
@@ -143,6 +151,7 @@ impl Engine {
             last_seen_sequence_number: 0,
 
             controls: Default::default(),
+            interpolation_delay: interpolation::DEFAULT_INTERPOLATION_DELAY,
 
             debug_lines: Vec::new(),
 
@@ -188,6 +197,11 @@ impl Engine {
         }
     }
 
+    /// Set how far behind the server remote players and entities are rendered, in milliseconds
+    pub fn set_interpolation_delay(&mut self, delay_ms: f64) {
+        self.interpolation_delay = Duration::from_secs_f64(delay_ms.max(0.) / 1000.0);
+    }
+
     pub fn resize(&mut self, width: u32, height: u32) {
         self.renderer.resize(UVec2::new(width, height));
     }
@@ -200,10 +214,8 @@ impl Engine {
         // Receive packets
         if *self.connection_state.borrow() == ConnectionState::Connected {
             loop {
-                let mut mode_switch: Option<ClientShouldSwitchMode> = None;
-
                 // Incoming messages are stored by their sequence number
-                let Some(packet) = self
+                let Some(packets) = self
                     .incoming_messages
                     .borrow_mut()
                     .remove(&self.last_seen_sequence_number)
@@ -214,166 +226,271 @@ impl Engine {
                 // Increment our sequence number
                 self.last_seen_sequence_number += 1;
 
-                match &mut self.state {
-                    GameState::Loading => match packet {
-                        ServerPacket::Init(net_types::Init {
-                            blocks,
-                            block_registry,
+                for packet in packets {
+                    // Script errors and reloads can arrive in any state
+                    if let ServerPacket::ScriptError(error) = packet {
+                        self.handle_script_error(error);
+                        continue;
+                    }
+                    if let ServerPacket::ScriptsReloaded = packet {
+                        tracing::info!("The server has reloaded its scripts");
+                        if let Some(on_scripts_reloaded) =
+                            &self.context.on_scripts_reloaded_callback
+                        {
+                            if let Err(e) = on_scripts_reloaded.call0(&JsValue::NULL) {
+                                tracing::error!("Error calling on_scripts_reloaded: {e:?}");
+                            }
+                        }
+                        continue;
+                    }
+
+                    let mut mode_switch: Option<ClientShouldSwitchMode> = None;
+
+                    match &mut self.state {
+                        GameState::Loading => match packet {
+                            ServerPacket::Init(net_types::Init {
+                                blocks,
+                                block_registry,
+                                entities,
+                                entity_type_registry,
+                                client_player,
+                                world_script_state,
+                            }) => {
+                                tracing::info!("Init received:");
+                                tracing::info!("Loaded level with bounds {:?}", blocks.bounds());
+                                tracing::info!("Block registry: {:#?}", block_registry);
+
+                                // Start fetching assets
+                                self.assets.load_block_textures(&block_registry);
+                                self.assets.load_entity_models(entities.values());
+
+                                // Tell the React frontend
+                                if let Some(on_init) = self.context.on_init_callback.take() {
+                                    let block_registry =
+                                        serde_wasm_bindgen::to_value(&block_registry).unwrap();
+                                    let entity_type_registry =
+                                        serde_wasm_bindgen::to_value(&entity_type_registry)
+                                            .unwrap();
+                                    on_init
+                                        .call2(
+                                            &JsValue::NULL,
+                                            &block_registry,
+                                            &entity_type_registry,
+                                        )
+                                        .expect("Unable to call on_init!");
+                                }
+                                let camera = FlyCamera::new([0.0, 10.0, 0.0].into(), -135.0, -45.0);
+
+                                if START_IN_EDIT_MODE {
+                                    self.state = GameState::Editing {
+                                        blocks,
+                                        block_registry,
+                                        entities,
+                                        entity_type_registry,
+                                        camera,
+                                        target_raycast: None,
+                                        selected_block_id: None,
+                                        preview_entity: None,
+                                        selected_entity: None,
+                                        region_tools: Default::default(),
+                                        world_script_state,
+                                    };
+
+                                    // When we've connected, tell the server we want to switch to edit mode.
+                                    self.send_packet(net_types::ClientPacket::Edit);
+                                } else {
+                                    self.state = GameState::Playing {
+                                        blocks,
+                                        block_registry,
+                                        entities,
+                                        entity_snapshots: Default::default(),
+                                        server_clock: Default::default(),
+                                        entity_type_registry,
+                                        camera,
+                                        client_player,
+                                        prediction: Default::default(),
+                                        player_models: Default::default(),
+                                        world_script_state,
+                                    };
+
+                                    // When we've connected, tell the server we want to switch to play mode
+                                    self.send_packet(net_types::ClientPacket::Start);
+                                }
+                            }
+                            ServerPacket::ClientShouldSwitchMode(new_mode) => {
+                                tracing::debug!("LOADING: Server wants us to switch modes");
+                                mode_switch = Some(new_mode)
+                            }
+                            p => {
+                                tracing::error!("Received unexpected packet: {:#?}", p);
+                            }
+                        },
+                        GameState::Playing {
+                            player_models,
                             entities,
                             entity_type_registry,
                             client_player,
+                            blocks,
                             world_script_state,
-                        }) => {
-                            tracing::info!("Init received:");
-                            tracing::info!("Loaded level of size {:?}", blocks.size());
-                            tracing::info!("Block registry: {:#?}", block_registry);
-
-                            // Start fetching assets
-                            self.assets.load_block_textures(&block_registry);
-                            self.assets.load_entity_models(entities.values());
-
-                            // Tell the React frontend
-                            if let Some(on_init) = self.context.on_init_callback.take() {
-                                let block_registry =
-                                    serde_wasm_bindgen::to_value(&block_registry).unwrap();
-                                let entity_type_registry =
-                                    serde_wasm_bindgen::to_value(&entity_type_registry).unwrap();
-                                on_init
-                                    .call2(&JsValue::NULL, &block_registry, &entity_type_registry)
-                                    .expect("Unable to call on_init!");
+                            prediction,
+                            entity_snapshots,
+                            server_clock,
+                            ..
+                        } => match packet {
+                            ServerPacket::PatchWorldScriptState(PatchWorldScriptState(patch)) => {
+                                if let Err(e) = patch.apply(world_script_state) {
+                                    tracing::error!("Failed to patch world script state: {e}");
+                                }
                             }
-                            let camera = FlyCamera::new([0.0, 10.0, 0.0].into(), -135.0, -45.0);
-
-                            if START_IN_EDIT_MODE {
-                                self.state = GameState::Editing {
-                                    blocks,
-                                    block_registry,
+                            ServerPacket::SetBlock(set_block) => {
+                                packet_handlers::handle_set_block(blocks, set_block)
+                                    .expect("Failed to set block");
+                            }
+                            ServerPacket::LoadChunk(load_chunk) => {
+                                packet_handlers::handle_load_chunk(blocks, load_chunk);
+                            }
+                            ServerPacket::UnloadChunk(unload_chunk) => {
+                                packet_handlers::handle_unload_chunk(blocks, unload_chunk);
+                            }
+                            ServerPacket::InputAck(ack) => {
+                                let obstacles = prediction.obstacles(
                                     entities,
                                     entity_type_registry,
-                                    camera,
-                                    target_raycast: None,
-                                    selected_block_id: None,
-                                    preview_entity: None,
-                                    world_script_state,
-                                };
-
-                                // When we've connected, tell the server we want to switch to edit mode.
-                                self.send_packet(net_types::ClientPacket::Edit);
-                            } else {
-                                self.state = GameState::Playing {
-                                    blocks,
-                                    block_registry,
+                                    *client_player,
+                                );
+                                prediction.reconcile(ack, blocks, &obstacles);
+                            }
+                            ServerPacket::AddEntity(add_entity) => {
+                                packet_handlers::handle_add_entity(
                                     entities,
-                                    _entity_type_registry: entity_type_registry,
-                                    camera,
-                                    client_player,
-                                    players: Default::default(),
-                                    world_script_state,
-                                };
-
-                                // When we've connected, tell the server we want to switch to play mode
-                                self.send_packet(net_types::ClientPacket::Start);
+                                    entity_snapshots,
+                                    add_entity,
+                                );
                             }
-                        }
-                        ServerPacket::ClientShouldSwitchMode(new_mode) => {
-                            tracing::debug!("LOADING: Server wants us to switch modes");
-                            mode_switch = Some(new_mode)
-                        }
-                        p => {
-                            tracing::error!("Received unexpected packet: {:#?}", p);
-                        }
-                    },
-                    GameState::Playing {
-                        players,
-                        entities,
-                        blocks,
-                        world_script_state,
-                        ..
-                    } => match packet {
-                        ServerPacket::SetWorldScriptState(SetWorldScriptState(new_state)) => {
-                            *world_script_state = new_state;
-                        }
-                        ServerPacket::SetBlock(set_block) => {
-                            packet_handlers::handle_set_block(blocks, set_block)
-                                .expect("Failed to set block");
-                        }
-                        ServerPacket::AddPlayer(add_player) => {
-                            packet_handlers::handle_add_player(
-                                players,
-                                &self.entity_models,
-                                add_player,
-                            )
-                            .expect("Failed to add player");
-                        }
-                        ServerPacket::UpdatePlayer(update_position) => {
-                            packet_handlers::handle_update_player(players, update_position);
-                        }
-                        ServerPacket::RemovePlayer(remove_player) => {
-                            packet_handlers::handle_remove_player(players, remove_player)
-                                .expect("Failed to remove player");
-                        }
-                        ServerPacket::AddEntity(add_entity) => {
-                            packet_handlers::handle_add_entity(entities, add_entity);
-                        }
-                        ServerPacket::UpdateEntity(update_entity) => {
-                            if let Err(e) =
-                                packet_handlers::handle_update_entity(entities, update_entity)
-                            {
-                                tracing::error!("Error when handling UpdateEntity: {e:#}");
+                            ServerPacket::UpdateEntity(update_entity) => {
+                                server_clock.observe(update_entity.tick, self.elapsed_time);
+                                if let Err(e) = packet_handlers::handle_update_entity(
+                                    entities,
+                                    entity_snapshots,
+                                    player_models,
+                                    update_entity,
+                                ) {
+                                    tracing::error!("Error when handling UpdateEntity: {e:#}");
+                                }
                             }
-                        }
-                        ServerPacket::RemoveEntity(remove_entity) => {
-                            packet_handlers::handle_remove_entity(entities, remove_entity);
-                        }
-                        ServerPacket::ClientShouldSwitchMode(new_mode) => {
-                            tracing::debug!("PLAYING: Server wants us to switch modes");
-                            mode_switch = Some(new_mode)
-                        }
-                        ServerPacket::SetDebugLines(server_debug_lines) => {
-                            // ally-oop
-                            self.debug_lines = server_debug_lines
-                                .into_iter()
-                                .map(DebugLine::from)
-                                .collect();
-                        }
-                        ServerPacket::PlaySound(PlaySound {
-                            sound_id,
-                            position,
-                            volume,
-                        }) => {
-                            if let Err(e) = self.play_sound_at_pos(&sound_id, position, volume) {
-                                tracing::error!("Error playing sound {sound_id}: {e:?}");
+                            ServerPacket::RemoveEntity(remove_entity) => {
+                                packet_handlers::handle_remove_entity(
+                                    entities,
+                                    entity_snapshots,
+                                    player_models,
+                                    remove_entity,
+                                );
                             }
-                        }
-                        p => {
-                            tracing::error!("Received unexpected packet: {:#?}", p);
-                        }
-                    },
-                    GameState::Editing { .. } => match packet {
-                        ServerPacket::ClientShouldSwitchMode(new_mode) => {
-                            tracing::debug!("EDITING: Server wants us to switch modes");
-                            mode_switch = Some(new_mode)
-                        }
-                        ServerPacket::SetDebugLines(server_debug_lines) => {
-                            // ally-oop
-                            self.debug_lines = server_debug_lines
-                                .into_iter()
-                                .map(DebugLine::from)
-                                .collect();
-                        }
-                        p => {
-                            tracing::error!("Received unexpected packet: {:#?}", p);
-                        }
-                    },
-                }
+                            ServerPacket::ClientShouldSwitchMode(new_mode) => {
+                                tracing::debug!("PLAYING: Server wants us to switch modes");
+                                mode_switch = Some(new_mode)
+                            }
+                            ServerPacket::SetDebugLines(server_debug_lines) => {
+                                // ally-oop
+                                self.debug_lines = server_debug_lines
+                                    .into_iter()
+                                    .map(DebugLine::from)
+                                    .collect();
+                            }
+                            ServerPacket::PlaySound(PlaySound {
+                                sound_id,
+                                position,
+                                volume,
+                            }) => {
+                                if let Err(e) = self.play_sound_at_pos(&sound_id, position, volume)
+                                {
+                                    tracing::error!("Error playing sound {sound_id}: {e:?}");
+                                }
+                            }
+                            p => {
+                                tracing::error!("Received unexpected packet: {:#?}", p);
+                            }
+                        },
+                        GameState::Editing {
+                            blocks, entities, ..
+                        } => match packet {
+                            ServerPacket::ClientShouldSwitchMode(new_mode) => {
+                                tracing::debug!("EDITING: Server wants us to switch modes");
+                                mode_switch = Some(new_mode)
+                            }
+                            // Edits being undone or redone
+                            ServerPacket::SetBlock(set_block) => {
+                                packet_handlers::handle_set_block(blocks, set_block)
+                                    .expect("Failed to set block");
+                            }
+                            ServerPacket::AddEntity(AddEntity {
+                                entity_id,
+                                entity_data,
+                            }) => {
+                                entities.insert(entity_id, entity_data);
+                            }
+                            ServerPacket::RemoveEntity(RemoveEntity { entity_id }) => {
+                                entities.remove(&entity_id);
+                            }
+                            // Regions we filled or pasted, once the server has made the change
+                            ServerPacket::SetBlocks(set_blocks) => {
+                                packet_handlers::handle_set_blocks(blocks, entities, set_blocks);
+                            }
+                            // Streamed before the server switched us to editing, the editor gets
+                            // the whole world with the mode switch
+                            ServerPacket::LoadChunk(_) | ServerPacket::UnloadChunk(_) => {}
+                            ServerPacket::SetDebugLines(server_debug_lines) => {
+                                // ally-oop
+                                self.debug_lines = server_debug_lines
+                                    .into_iter()
+                                    .map(DebugLine::from)
+                                    .collect();
+                            }
+                            p => {
+                                tracing::error!("Received unexpected packet: {:#?}", p);
+                            }
+                        },
+                    }
 
-                // If the server wanted us to switch modes, let's do that now.
-                if let Some(mode_switch) = mode_switch {
-                    self.state.switch_mode(mode_switch);
+                    // If the server wanted us to switch modes, let's do that now.
+                    if let Some(mode_switch) = mode_switch {
+                        self.state.switch_mode(mode_switch);
+                    }
                 }
             }
         }
 
+        // Move remote players and entities to where they were a little while ago on the server
+        if let GameState::Playing {
+            entities,
+            entity_snapshots,
+            server_clock,
+            client_player,
+            ..
+        } = &mut self.state
+        {
+            let render_tick = server_clock.render_tick(self.elapsed_time, self.interpolation_delay);
+
+            for (entity_id, snapshots) in entity_snapshots.iter_mut() {
+                let Some(entity) = entities.get_mut(entity_id) else {
+                    continue;
+                };
+                // Our own player's position is predicted, but it faces wherever the server last
+                // said
+                let snapshot = if entity.player_id() == Some(*client_player) {
+                    snapshots.latest()
+                } else {
+                    render_tick.and_then(|tick| snapshots.sample(tick))
+                };
+                let Some(snapshot) = snapshot else {
+                    continue;
+                };
+                entity.state.position = snapshot.position;
+                entity.state.rotation = snapshot.rotation;
+                entity.state.scale = snapshot.scale;
+            }
+        }
+
         self.load_block_textures();
 
         // Check for errors
@@ -389,8 +506,11 @@ impl Engine {
         // Send packets
         match &mut self.state {
             GameState::Playing {
-                players,
+                entities,
+                entity_type_registry,
                 client_player,
+                prediction,
+                blocks,
                 camera,
                 ..
             } => {
@@ -403,10 +523,54 @@ impl Engine {
                 self.controls.pitch = (self.controls.pitch + delta_pitch)
                     .clamp(-std::f32::consts::FRAC_PI_2, std::f32::consts::FRAC_PI_2);
 
-                let player_position = players
-                    .get(client_player)
-                    .map(|p| p.position)
-                    .unwrap_or_default();
+                // Player input
+                let mut move_dir = Vec2::ZERO;
+                if self.controls.keyboard_inputs.contains("KeyW") {
+                    move_dir.y += 1.0;
+                }
+                if self.controls.keyboard_inputs.contains("KeyS") {
+                    move_dir.y -= 1.0;
+                }
+                if self.controls.keyboard_inputs.contains("KeyA") {
+                    move_dir.x -= 1.0;
+                }
+                if self.controls.keyboard_inputs.contains("KeyD") {
+                    move_dir.x += 1.0;
+                }
+                move_dir = move_dir.normalize_or_zero();
+
+                // Presses are held on to until there's an input to send them with
+                self.controls.jump |= self.controls.keyboard_pressed.contains("Space");
+                self.controls.fire |= self.controls.mouse_left;
+
+                // Inputs are sent at the server's tick rate, and predicted locally as we go
+                let mut inputs = Vec::new();
+                let obstacles =
+                    prediction.obstacles(entities, entity_type_registry, *client_player);
+                for _ in 0..prediction.ticks(self.delta_time) {
+                    let controls = net_types::Controls {
+                        sequence: 0,
+                        move_direction: move_dir,
+                        jump: std::mem::take(&mut self.controls.jump),
+                        fire: std::mem::take(&mut self.controls.fire),
+                        camera_yaw: self.controls.yaw,
+                        camera_pitch: self.controls.pitch,
+                    };
+                    inputs.push(prediction.next_input(controls, blocks, &obstacles));
+                }
+
+                let player_position = match entities
+                    .values_mut()
+                    .find(|entity| entity.player_id() == Some(*client_player))
+                {
+                    Some(player) => {
+                        if let Some(position) = prediction.position() {
+                            player.state.position = position;
+                        }
+                        player.state.position
+                    }
+                    None => Vec3::ZERO,
+                };
                 let rotation = glam::Quat::from_euler(
                     EulerRot::YXZ,
                     self.controls.yaw,
@@ -425,36 +589,19 @@ impl Engine {
                 camera.set_position_and_rotation(position, YawPitch::new().rotation_quat(rotation));
                 camera.update(self.delta_time.as_secs_f32());
 
-                // Player input
-                let mut move_dir = Vec2::ZERO;
-                if self.controls.keyboard_inputs.contains("KeyW") {
-                    move_dir.y += 1.0;
-                }
-                if self.controls.keyboard_inputs.contains("KeyS") {
-                    move_dir.y -= 1.0;
-                }
-                if self.controls.keyboard_inputs.contains("KeyA") {
-                    move_dir.x -= 1.0;
-                }
-                if self.controls.keyboard_inputs.contains("KeyD") {
-                    move_dir.x += 1.0;
+                for controls in inputs {
+                    self.send_packet(net_types::ClientPacket::Controls(controls));
                 }
-                move_dir = move_dir.normalize_or_zero();
-                let controls = net_types::Controls {
-                    move_direction: move_dir.normalize_or_zero(),
-                    jump: self.controls.keyboard_pressed.contains("Space"),
-                    fire: self.controls.mouse_left,
-                    camera_yaw: self.controls.yaw,
-                    camera_pitch: self.controls.pitch,
-                };
-                self.send_packet(net_types::ClientPacket::Controls(controls));
             }
             GameState::Editing {
                 camera,
                 blocks,
+                entities,
                 target_raycast,
                 selected_block_id,
                 preview_entity,
+                selected_entity,
+                region_tools,
                 ..
             } => {
                 // Camera input
@@ -492,17 +639,139 @@ impl Engine {
                 *target_raycast = blocks.raycast(position, ray_dir);
 
                 if let Some(preview_entity) = preview_entity {
-                    let Some(position) = target_raycast.as_ref().and_then(|raycast| {
-                        raycast
-                            .position
-                            .add_signed(raycast.entrance_face_normal.as_ivec3())
-                    }) else {
+                    let Some(position) = target_raycast
+                        .as_ref()
+                        .map(|raycast| raycast.position + raycast.entrance_face_normal.as_ivec3())
+                    else {
                         return;
                     };
 
                     preview_entity.state.position = glam::Vec3::from(position).into();
                 }
 
+                // Undo with Ctrl+Z, redo with Ctrl+Shift+Z or Ctrl+Y
+                let ctrl = key_state("ControlLeft") + key_state("ControlRight") > 0.0;
+                let shift = key_state("ShiftLeft") + key_state("ShiftRight") > 0.0;
+                let pressed = |code: &str| self.controls.keyboard_pressed.contains(code);
+                let history_packet = if ctrl && pressed("KeyZ") && !shift {
+                    Some(ClientPacket::Undo)
+                } else if ctrl && (pressed("KeyY") || pressed("KeyZ")) {
+                    Some(ClientPacket::Redo)
+                } else {
+                    None
+                };
+
+                // Right click selects the entity under the crosshair, as long as there isn't a
+                // block in front of it, or clears the selection
+                let mut entity_packets = Vec::new();
+                if self.controls.mouse_right {
+                    let max_distance = target_raycast.as_ref().map_or(f32::INFINITY, |hit| {
+                        (Vec3::from(hit.position) + Vec3::splat(0.5) - position).length()
+                    });
+                    let transforms = entities.iter().map(|(entity_id, entity)| {
+                        let transform = get_entity_transform(
+                            entities,
+                            &HashMap::new(),
+                            &self.entity_models,
+                            &entity.state,
+                        );
+                        (entity_id, transform)
+                    });
+                    *selected_entity =
+                        editor::pick_entity(transforms, position, ray_dir, max_distance);
+                } else if pressed("Escape") {
+                    *selected_entity = None;
+                    region_tools.corners = [None, None];
+                } else if let Some(entity) = selected_entity
+                    .as_ref()
+                    .and_then(|entity_id| entities.get_mut(entity_id))
+                {
+                    if pressed("Delete") || pressed("Backspace") {
+                        let entity_id = entity.id.clone();
+                        entities.remove(&entity_id);
+                        *selected_entity = None;
+                        entity_packets.push(ClientPacket::RemoveEntity(RemoveEntity { entity_id }));
+                    } else if let Some((position, rotation, scale)) =
+                        editor::gizmo_transform(&entity.state, pressed)
+                    {
+                        entity.state.position = position;
+                        entity.state.rotation = rotation;
+                        entity.state.scale = scale;
+                        entity_packets.push(ClientPacket::SetEntityTransform(
+                            net_types::SetEntityTransform {
+                                entity_id: entity.id.clone(),
+                                position,
+                                rotation,
+                                scale,
+                            },
+                        ));
+                    }
+                }
+
+                // The region tools: [ and ] mark the corners of a box, then F fills it with the
+                // selected block, R replaces the targeted kind of block with it, and Delete clears
+                // it. Ctrl+C copies it, Ctrl+V pastes at the targeted face, and . and , turn and
+                // flip what was copied.
+                let target = target_raycast.as_ref().map(|hit| hit.position);
+                let paste_at = target_raycast
+                    .as_ref()
+                    .map(|hit| hit.position + hit.entrance_face_normal.as_ivec3());
+                for (code, corner) in [("BracketLeft", 0), ("BracketRight", 1)] {
+                    if pressed(code) {
+                        region_tools.corners[corner] = target;
+                    }
+                }
+                let selection = region_tools.selection();
+                let mut region_packet = None;
+                if ctrl && pressed("KeyC") {
+                    if let Some((min, size)) = selection {
+                        region_tools.clipboard =
+                            Some(editor::Clipboard::copy(blocks, entities, min, size));
+                    }
+                } else if ctrl && pressed("KeyV") {
+                    if let (Some(clipboard), Some(origin)) = (&region_tools.clipboard, paste_at) {
+                        region_packet = Some(clipboard.paste(origin));
+                    }
+                } else if pressed("Period") || pressed("Comma") {
+                    if let Some(clipboard) = &mut region_tools.clipboard {
+                        *clipboard = match pressed("Period") {
+                            true => clipboard.rotated(),
+                            false => clipboard.mirrored(),
+                        };
+                    }
+                } else if let Some((origin, size)) = selection {
+                    let operation = if pressed("KeyF") {
+                        selected_block_id.map(|block_id| RegionOperation::Fill { size, block_id })
+                    } else if pressed("KeyR") {
+                        target.zip(*selected_block_id).map(|(target, to)| {
+                            RegionOperation::Replace {
+                                size,
+                                from: blocks.get(target).copied().unwrap_or(EMPTY_BLOCK),
+                                to,
+                            }
+                        })
+                    } else if selected_entity.is_none()
+                        && (pressed("Delete") || pressed("Backspace"))
+                    {
+                        Some(RegionOperation::Fill {
+                            size,
+                            block_id: EMPTY_BLOCK,
+                        })
+                    } else {
+                        None
+                    };
+                    region_packet = operation.map(|operation| SetBlocks { origin, operation });
+                }
+                // The server sends regions back once it's changed them, so they aren't changed here
+                let region_packet = region_packet.filter(|set_blocks| {
+                    let volume = blocks::region_volume(set_blocks.size());
+                    let fits = volume.is_some_and(|volume| volume <= MAX_REGION_VOLUME);
+                    if !fits {
+                        tracing::warn!("Regions can be at most {MAX_REGION_VOLUME} blocks");
+                    }
+                    fits
+                });
+
                 if self.controls.mouse_left {
                     if selected_block_id.is_some() {
                         tracing::debug!("Placing block at {target_raycast:?}");
@@ -513,8 +782,19 @@ impl Engine {
                     }
                 }
 
+                if let Some(packet) = history_packet {
+                    self.send_packet(packet);
+                }
+                for packet in entity_packets {
+                    self.send_packet(packet);
+                }
+                if let Some(set_blocks) = region_packet {
+                    self.send_packet(ClientPacket::SetBlocks(set_blocks));
+                }
+
                 // Send empty player input
                 let controls = net_types::Controls {
+                    sequence: 0,
                     move_direction: Vec2::ZERO,
                     jump: false,
                     fire: false,
@@ -531,24 +811,20 @@ impl Engine {
         self.controls.mouse_left = false;
         self.controls.mouse_right = false;
 
-        if let GameState::Playing { players, .. } = &mut self.state {
-            for player in players.values_mut() {
-                if let Some(model) = &mut player.model {
-                    gltf::animate_model(model, self.delta_time);
-                }
+        if let GameState::Playing { player_models, .. } = &mut self.state {
+            for model in player_models.values_mut() {
+                gltf::animate_model(model, self.delta_time);
             }
         }
 
         self.update_audio_manager();
+        self.report_selected_entity();
 
         self.render();
     }
 
     fn send_packet(&mut self, packet: ClientPacket) {
-        // Bincode is currently broken, fall back to json for now.
-        // See: https://github.com/leetvr/hy/issues/189
-        // let message = bincode::serialize(&packet).unwrap();
-        let message = serde_json::ser::to_vec(&packet).unwrap();
+        let message = net_types::codec::encode(&packet).expect("Failed to serialize packet");
         self.ws
             .send_with_u8_array(&message)
             .expect("Failed to send controls");
@@ -573,14 +849,7 @@ impl Engine {
             false => {
                 // When we place a block, we place it at the position of the raycast, but offset by
                 // the entrance face normal, as we're placing it "on" the face the ray entered.
-                let Some(position) = target_raycast
-                    .position
-                    .add_signed(target_raycast.entrance_face_normal.as_ivec3())
-                else {
-                    return;
-                };
-
-                position
+                target_raycast.position + target_raycast.entrance_face_normal.as_ivec3()
             }
         };
 
@@ -654,9 +923,10 @@ impl Engine {
     fn render(&mut self) {
         let mut draw_calls = Vec::new();
         self.load_entity_models();
+        self.create_player_models();
 
-        let players = match &self.state {
-            GameState::Playing { players, .. } => players,
+        let player_models = match &self.state {
+            GameState::Playing { player_models, .. } => player_models,
             _ => &HashMap::new(),
         };
 
@@ -723,11 +993,22 @@ impl Engine {
                     let Some(model) = self.entity_models.get(&entity.model_path) else {
                         continue;
                     };
+                    // Players are animated, so they each have their own copy of their model
+                    let gltf = match player_models.get(&entity.id) {
+                        Some(player_model) => player_model,
+                        None if entity.controller.is_some() => continue,
+                        None => &model.gltf,
+                    };
 
                     draw_calls.extend(render::build_render_plan(
-                        slice::from_ref(&model.gltf),
+                        slice::from_ref(gltf),
                         slice::from_ref(&model.render_model),
-                        get_entity_transform(players, &entity.state),
+                        get_entity_transform(
+                            entities,
+                            player_models,
+                            &self.entity_models,
+                            &entity.state,
+                        ),
                         None,
                     ));
                 }
@@ -737,32 +1018,6 @@ impl Engine {
 
         // Gather state-specific extras
         match &mut self.state {
-            // Players
-            GameState::Playing { players, .. } => {
-                // HACK: The player model is rotated 90 degrees, also
-                // it rotates the wrong way? I'm just fixing it here but someone
-                // should figure out why it is like this.
-                for player in players.values_mut() {
-                    let Some(player_model) = self.entity_models.get(&player.model_path) else {
-                        continue;
-                    };
-                    if player.model.is_none() {
-                        let mut gltf = player_model.gltf.clone();
-                        gltf.play_animation(&player.animation_state, 0.);
-                        player.model = Some(gltf);
-
-                        player.model.as_mut().unwrap();
-                    }
-                    let player_gltf = player.model.as_ref().unwrap();
-
-                    draw_calls.extend(render::build_render_plan(
-                        slice::from_ref(player_gltf),
-                        slice::from_ref(&player_model.render_model),
-                        player_transform(player),
-                        None,
-                    ));
-                }
-            }
             // Ghost block
             GameState::Editing {
                 target_raycast: Some(raycast),
@@ -770,22 +1025,18 @@ impl Engine {
                 ..
             } if *block_id != 0 => {
                 if let Some(textures) = self.block_textures.get(block_id) {
-                    if let Some(block_position) = raycast
-                        .position
-                        .add_signed(raycast.entrance_face_normal.as_ivec3())
-                    {
-                        let blocks = [(block_position, textures)];
-
-                        draw_calls.extend(
-                            render::build_cube_draw_calls(
-                                &self.cube_mesh_data,
-                                blocks.into_iter(),
-                                gltf::TransparencyType::Blend,
-                                Some([0., 1.0, 0., 0.5].into()),
-                            )
-                            .into_iter(),
-                        );
-                    }
+                    let block_position = raycast.position + raycast.entrance_face_normal.as_ivec3();
+                    let blocks = [(block_position, textures)];
+
+                    draw_calls.extend(
+                        render::build_cube_draw_calls(
+                            &self.cube_mesh_data,
+                            blocks,
+                            gltf::TransparencyType::Blend,
+                            Some([0., 1.0, 0., 0.5].into()),
+                        )
+                        .into_iter(),
+                    );
                 }
             }
             // Ghost entity
@@ -797,7 +1048,12 @@ impl Engine {
                     draw_calls.extend(render::build_render_plan(
                         slice::from_ref(&model.gltf),
                         slice::from_ref(&model.render_model),
-                        get_entity_transform(&HashMap::new(), &preview_entity.state),
+                        get_entity_transform(
+                            &HashMap::new(),
+                            &HashMap::new(),
+                            &self.entity_models,
+                            &preview_entity.state,
+                        ),
                         Some([0.0, 0.5, 1.0, 0.5].into()),
                     ));
                 }
@@ -806,7 +1062,11 @@ impl Engine {
         }
 
         let block_grid = self.state.block_grid();
-        let block_grid_size = block_grid.map_or(UVec3::ZERO, |grid| grid.size().into());
+        let block_grid_bounds = block_grid
+            .and_then(blocks::BlockGrid::bounds)
+            .map_or((Vec3::ZERO, Vec3::ZERO), |(min, max)| {
+                (min.into(), max.into())
+            });
 
         let light = render::Light {
             position: Vec3::new(5.0, 2.0, 5.0),
@@ -820,8 +1080,51 @@ impl Engine {
             self.debug_lines.clear();
         }
 
+        // The selected entity's gizmo
+        if let Some(entity) = self.selected_entity() {
+            let GameState::Editing { entities, .. } = &self.state else {
+                unreachable!("entities are only selected in the editor");
+            };
+            let transform = get_entity_transform(
+                entities,
+                &HashMap::new(),
+                &self.entity_models,
+                &entity.state,
+            );
+            self.debug_lines.extend(editor::gizmo_lines(&transform));
+        }
+
+        // The region tools' selected box, and where the clipboard would be pasted while Ctrl is
+        // held
+        if let GameState::Editing {
+            region_tools,
+            target_raycast,
+            ..
+        } = &self.state
+        {
+            if let Some((min, size)) = region_tools.selection() {
+                let color = Vec4::new(1., 0.9, 0.2, 1.);
+                self.debug_lines
+                    .extend(editor::region_lines(min, size, color));
+            }
+            let ctrl = ["ControlLeft", "ControlRight"]
+                .iter()
+                .any(|code| self.controls.keyboard_inputs.contains(*code));
+            if let (true, Some(clipboard), Some(hit)) =
+                (ctrl, &region_tools.clipboard, target_raycast)
+            {
+                let origin = hit.position + hit.entrance_face_normal.as_ivec3();
+                let color = Vec4::new(0.2, 0.9, 1., 1.);
+                self.debug_lines.extend(editor::region_lines(
+                    origin,
+                    clipboard.blocks.size(),
+                    color,
+                ));
+            }
+        }
+
         self.renderer
-            .render(&draw_calls, &self.debug_lines, &[light], block_grid_size);
+            .render(&draw_calls, &self.debug_lines, &[light], block_grid_bounds);
 
         self.debug_lines.clear();
     }
@@ -831,14 +1134,9 @@ impl Engine {
             GameState::Editing { entities, .. } => {
                 Box::new(entities.values().map(|e| &e.model_path))
             }
-            GameState::Playing {
-                entities, players, ..
-            } => Box::new(
-                entities
-                    .values()
-                    .map(|e| &e.model_path)
-                    .chain(players.values().map(|p| &p.model_path)),
-            ),
+            GameState::Playing { entities, .. } => {
+                Box::new(entities.values().map(|e| &e.model_path))
+            }
             _ => return,
         };
 
@@ -866,6 +1164,34 @@ impl Engine {
         }
     }
 
+    // Give each player entity its own copy of its model once the model has loaded
+    fn create_player_models(&mut self) {
+        let GameState::Playing {
+            entities,
+            player_models,
+            ..
+        } = &mut self.state
+        else {
+            return;
+        };
+
+        for entity in entities.values() {
+            let Some(controller) = &entity.controller else {
+                continue;
+            };
+            if player_models.contains_key(&entity.id) {
+                continue;
+            }
+            let Some(model) = self.entity_models.get(&entity.model_path) else {
+                continue;
+            };
+
+            let mut gltf = model.gltf.clone();
+            gltf.play_animation(&controller.animation_state, 0.);
+            player_models.insert(entity.id.clone(), gltf);
+        }
+    }
+
     pub fn stop_sounds(&mut self) -> Result<(), JsValue> {
         self.audio_manager.stop_all_sounds()
     }
@@ -978,6 +1304,23 @@ impl Engine {
         )
     }
 
+    fn handle_script_error(&mut self, error: net_types::ScriptError) {
+        tracing::error!(
+            "Error in {} {}: {}",
+            error.script,
+            error.function,
+            error.stack.as_deref().unwrap_or(&error.message)
+        );
+
+        // Tell the React frontend, so it can show the error in the editor
+        if let Some(on_script_error) = &self.context.on_script_error_callback {
+            let error = serde_wasm_bindgen::to_value(&error).unwrap();
+            if let Err(e) = on_script_error.call1(&JsValue::NULL, &error) {
+                tracing::error!("Error calling on_script_error: {e:?}");
+            }
+        }
+    }
+
     // Update an already active SoundInstance with the specified handle
     pub fn update_sound_with_handle(
         &mut self,
@@ -1116,7 +1459,9 @@ fn load_texture_from_image(renderer: &mut Renderer, image_data: &[u8]) -> anyhow
 }
 
 fn get_entity_transform(
-    players: &HashMap<PlayerId, Player>,
+    entities: &HashMap<EntityID, EntityData>,
+    player_models: &HashMap<EntityID, GLTFModel>,
+    entity_models: &HashMap<String, LoadedGLTF>,
     EntityState {
         position,
         rotation,
@@ -1125,68 +1470,102 @@ fn get_entity_transform(
         ..
     }: &EntityState,
 ) -> Transform {
-    if let Some(Anchor {
-        player_id,
-        parent_anchor,
-    }) = anchor
-    {
-        if let Some(player) = players.get(player_id) {
-            // Recursively search for the anchor node and build its transform along the way
-            // Side note(ll): This is a cute function!
-            fn build_transform(
-                model: &GLTFModel,
-                node: usize,
-                find_node: &String,
-                parent_transform: Transform,
-            ) -> Option<Transform> {
-                let node = &model.nodes[node];
-                if node.name.as_ref() == Some(find_node) {
-                    return Some(parent_transform * node.current_transform);
-                } else {
-                    for child in &node.children {
-                        if let Some(transform) = build_transform(
-                            model,
-                            *child,
-                            find_node,
-                            parent_transform * node.current_transform,
-                        ) {
-                            return Some(transform);
-                        }
-                    }
-                }
-                None
-            }
+    let transform = Transform::new_with_scale(*position, *rotation, *scale);
+    let Some(anchor) = anchor else {
+        return transform;
+    };
 
-            let Some(player_model) = player.model.as_ref() else {
-                return Transform::new_with_scale(*position, *rotation, *scale);
-            };
-            if let Some(node_transform) = build_transform(
-                &player_model,
-                player_model.root_node_idx,
-                parent_anchor,
-                player_transform(player),
-            ) {
-                let transform =
-                    node_transform * Transform::new_with_scale(*position, *rotation, *scale);
-                return transform;
-            } else {
-                tracing::warn!("couldn't find anchor node {parent_anchor}");
-            }
-        } else {
-            tracing::warn!("Entity is anchored to non-existent player");
-        };
+    // If the anchor can't be found, fall back to treating the position as absolute
+    match get_anchor_transform(entities, player_models, entity_models, anchor, 0) {
+        Some(anchor_transform) => anchor_transform * transform,
+        None => transform,
     }
-
-    // No valid, return absolute position and rotation
-    Transform::new_with_scale(*position, *rotation, *scale)
 }
 
-fn player_transform(player: &Player) -> Transform {
-    Transform::new_with_scale(
-        player.position,
-        Quat::from_rotation_y(player.facing_angle),
-        glam::Vec3::splat(0.5),
-    )
+// The transform of the parent entity, or the node in its model, that an entity is anchored to.
+// Parents can be anchored too, so this works its way up to the top.
+fn get_anchor_transform(
+    entities: &HashMap<EntityID, EntityData>,
+    player_models: &HashMap<EntityID, GLTFModel>,
+    entity_models: &HashMap<String, LoadedGLTF>,
+    Anchor {
+        parent_id,
+        parent_node,
+    }: &Anchor,
+    depth: usize,
+) -> Option<Transform> {
+    // The server doesn't allow loops, but don't hang if one gets through
+    if depth > entities.len() {
+        tracing::warn!("Entity {parent_id} is anchored to itself");
+        return None;
+    }
+
+    let Some(parent) = entities.get(parent_id) else {
+        tracing::warn!("Entity is anchored to non-existent entity {parent_id}");
+        return None;
+    };
+
+    let state = &parent.state;
+    let mut parent_transform =
+        Transform::new_with_scale(state.position, state.rotation, state.scale);
+    if let Some(parent_anchor) = &state.anchor {
+        if let Some(anchor_transform) = get_anchor_transform(
+            entities,
+            player_models,
+            entity_models,
+            parent_anchor,
+            depth + 1,
+        ) {
+            parent_transform = anchor_transform * parent_transform;
+        }
+    }
+
+    let Some(parent_node) = parent_node else {
+        return Some(parent_transform);
+    };
+
+    // Recursively search for the anchor node and build its transform along the way
+    // Side note(ll): This is a cute function!
+    fn build_transform(
+        model: &GLTFModel,
+        node: usize,
+        find_node: &String,
+        parent_transform: Transform,
+    ) -> Option<Transform> {
+        let node = &model.nodes[node];
+        if node.name.as_ref() == Some(find_node) {
+            return Some(parent_transform * node.current_transform);
+        } else {
+            for child in &node.children {
+                if let Some(transform) = build_transform(
+                    model,
+                    *child,
+                    find_node,
+                    parent_transform * node.current_transform,
+                ) {
+                    return Some(transform);
+                }
+            }
+        }
+        None
+    }
+
+    // Players are animated, so their nodes move around in their own copy of their model
+    let parent_model = player_models.get(parent_id).or_else(|| {
+        entity_models
+            .get(&parent.model_path)
+            .map(|model| &model.gltf)
+    })?;
+    let node_transform = build_transform(
+        parent_model,
+        parent_model.root_node_idx,
+        parent_node,
+        parent_transform,
+    );
+    if node_transform.is_none() {
+        tracing::warn!("couldn't find anchor node {parent_node}");
+    }
+    node_transform
 }
 
 #[derive(Clone, Default)]
@@ -1197,21 +1576,15 @@ struct Controls {
     mouse_left: bool,
     mouse_right: bool,
 
+    // Jump and fire presses that haven't been sent to the server yet
+    jump: bool,
+    fire: bool,
+
     // Yaw and pitch, radians
     yaw: f32,
     pitch: f32,
 }
 
-#[derive(Clone, Debug, Default)]
-struct Player {
-    position: Vec3,
-    facing_angle: f32, // radians
-    model: Option<GLTFModel>,
-    script_state: HashMap<String, serde_json::Value>,
-    animation_state: String,
-    model_path: String,
-}
-
 const MOUSE_SENSITIVITY_X: f32 = 0.005;
 const MOUSE_SENSITIVITY_Y: f32 = 0.005;
 
@@ -1,13 +1,15 @@
 use {
-    crate::LoadedGLTF,
+    crate::{
+        gltf::GLTFModel,
+        interpolation::{Snapshot, SnapshotBuffer},
+    },
     anyhow::{bail, Result},
     blocks::BlockGrid,
-    entities::{EntityData, EntityID, PlayerId},
+    entities::{EntityData, EntityID},
     net_types::{AddEntity, RemoveEntity, UpdateEntity},
     std::collections::HashMap,
 };
 
-use crate::Player;
 // Handlers for incoming packets
 
 /// Handle a `SetBlock` packet
@@ -15,110 +17,122 @@ pub fn handle_set_block(
     blocks: &mut BlockGrid,
     net_types::SetBlock { position, block_id }: net_types::SetBlock,
 ) -> Result<()> {
-    blocks[position] = block_id;
+    blocks.set(position, block_id);
     Ok(())
 }
 
-/// Handle an `AddPlayer` packet
-pub fn handle_add_player(
-    players: &mut HashMap<PlayerId, Player>,
-    models: &HashMap<String, LoadedGLTF>,
-    net_types::AddPlayer {
-        id,
-        position,
-        animation_state,
-        script_state,
-        model_path,
-    }: net_types::AddPlayer,
-) -> Result<()> {
-    players.insert(
-        id,
-        Player {
-            position,
-            facing_angle: 0.,
-            model: models.get(&model_path).map(|model| model.gltf.clone()),
-            script_state,
-            model_path,
-            animation_state,
-        },
-    );
-    Ok(())
+/// Handle a `SetBlocks` packet, which the server only sends to the editor
+pub fn handle_set_blocks(
+    blocks: &mut BlockGrid,
+    entities: &mut HashMap<EntityID, EntityData>,
+    set_blocks: net_types::SetBlocks,
+) {
+    for (position, block_id) in set_blocks.blocks(blocks) {
+        blocks.set(position, block_id);
+    }
+    for entity in set_blocks.entities() {
+        entities.insert(entity.id.clone(), entity);
+    }
 }
 
-/// Handle a `RemovePlayer` packet
-pub fn handle_remove_player(
-    players: &mut HashMap<PlayerId, Player>,
-    net_types::RemovePlayer { id }: net_types::RemovePlayer,
-) -> Result<()> {
-    players.remove(&id);
-    Ok(())
+/// Handle a `LoadChunk` packet
+pub fn handle_load_chunk(
+    blocks: &mut BlockGrid,
+    net_types::LoadChunk { position, chunk }: net_types::LoadChunk,
+) {
+    tracing::debug!("Loaded chunk {position:?}");
+    blocks.insert_chunk(position, chunk);
 }
 
-/// Handle an `UpdatePlayer` packet
-pub fn handle_update_player(
-    players: &mut HashMap<PlayerId, Player>,
-    net_types::UpdatePlayer {
-        id,
-        position,
-        animation_state,
-        script_state,
-        facing_angle,
-    }: net_types::UpdatePlayer,
+/// Handle an `UnloadChunk` packet
+pub fn handle_unload_chunk(
+    blocks: &mut BlockGrid,
+    net_types::UnloadChunk { position }: net_types::UnloadChunk,
 ) {
-    let Some(player) = players.get_mut(&id) else {
-        tracing::warn!("Received update position for unknown player {id:?}");
-        return;
-    };
-
-    player.position = position;
-    player.facing_angle = facing_angle;
-    if let Some(animation_state) = animation_state {
-        if let Some(model) = player.model.as_mut() {
-            model.play_animation(&animation_state, 0.3);
-        }
-        player.animation_state = animation_state;
-    }
-    if let Some(script_state) = script_state {
-        player.script_state = script_state;
-    }
+    tracing::debug!("Unloaded chunk {position:?}");
+    blocks.remove_chunk(position);
 }
 
 pub(crate) fn handle_add_entity(
     entities: &mut HashMap<EntityID, EntityData>,
+    snapshots: &mut HashMap<EntityID, SnapshotBuffer>,
     AddEntity {
         entity_id,
         entity_data,
     }: AddEntity,
 ) {
     tracing::debug!("Added entity {entity_id}");
+    snapshots.remove(&entity_id);
     entities.insert(entity_id, entity_data);
 }
 
 pub(crate) fn handle_update_entity(
     entities: &mut HashMap<EntityID, EntityData>,
+    snapshots: &mut HashMap<EntityID, SnapshotBuffer>,
+    player_models: &mut HashMap<EntityID, GLTFModel>,
     UpdateEntity {
         entity_id,
+        tick,
         position,
         rotation,
         anchor,
         scale,
+        animation_state,
+        script_state,
     }: UpdateEntity,
 ) -> Result<()> {
     let Some(entity) = entities.get_mut(&entity_id) else {
         bail!("Received update entity for unknown entity {entity_id:?}");
     };
 
-    entity.state.position = position;
-    entity.state.rotation = rotation;
-    entity.state.scale = scale;
-    entity.state.anchor = anchor;
+    if let (Some(animation_state), Some(controller)) = (animation_state, &mut entity.controller) {
+        if let Some(model) = player_models.get_mut(&entity_id) {
+            model.play_animation(&animation_state, 0.3);
+        }
+        controller.animation_state = animation_state;
+    }
+    if let Some(patch) = script_state {
+        if let Err(e) = patch.apply_map(&mut entity.state.custom_state) {
+            tracing::error!("Failed to patch script state for entity {entity_id}: {e}");
+        }
+    }
+
+    let snapshots = snapshots.entry(entity_id).or_default();
+
+    // Only what changed is sent, so start from the last transform we were sent
+    let last = snapshots.latest().unwrap_or(Snapshot {
+        position: entity.state.position,
+        rotation: entity.state.rotation,
+        scale: entity.state.scale,
+    });
+    let snapshot = Snapshot {
+        position: position.map_or(last.position, Into::into),
+        rotation: rotation.map_or(last.rotation, Into::into),
+        scale: scale.map_or(last.scale, Into::into),
+    };
+
+    // Positions are relative to the anchor, so there's nothing to interpolate between if it changed
+    if let Some(anchor) = anchor {
+        snapshots.clear();
+        entity.state.position = snapshot.position;
+        entity.state.rotation = snapshot.rotation;
+        entity.state.scale = snapshot.scale;
+        entity.state.anchor = anchor;
+    }
+
+    // The transform is interpolated towards each frame
+    snapshots.push(tick, snapshot);
 
     Ok(())
 }
 
 pub(crate) fn handle_remove_entity(
     entities: &mut HashMap<EntityID, EntityData>,
+    snapshots: &mut HashMap<EntityID, SnapshotBuffer>,
+    player_models: &mut HashMap<EntityID, GLTFModel>,
     RemoveEntity { entity_id }: RemoveEntity,
 ) {
+    snapshots.remove(&entity_id);
+    player_models.remove(&entity_id);
     entities.remove(&entity_id);
 }
@@ -0,0 +1,209 @@
+// Client-side prediction for the local player's movement.
+//
+// Inputs are produced at the server's tick rate and applied locally straight away, using the same
+// movement code as the server. When the server acknowledges an input we snap back to where it says
+// the player was after that input, and replay the inputs it hasn't processed yet on top.
+
+use {
+    blocks::BlockGrid,
+    entities::{EntityData, EntityID, EntityTypeRegistry, PlayerId},
+    glam::Vec3,
+    movement::{MovementState, Obstacle, TICK_DT},
+    net_types::{Controls, InputAck},
+    std::{
+        collections::{HashMap, VecDeque},
+        time::Duration,
+    },
+};
+
+// Don't try to catch up on more than this many ticks in a single frame, eg. after the tab has been
+// in the background
+const MAX_INPUTS_PER_FRAME: usize = 5;
+// If the server stops acknowledging our inputs, stop remembering them after this many
+const MAX_PENDING_INPUTS: usize = 120;
+
+#[derive(Debug)]
+pub struct Prediction {
+    next_sequence: u32,
+    // Inputs that have been sent but not acknowledged, oldest first
+    pending: VecDeque<Controls>,
+    // Unknown until the server has acknowledged our first input
+    state: Option<MovementState>,
+    // Time that hasn't yet been turned into inputs
+    accumulator: Duration,
+}
+
+impl Default for Prediction {
+    fn default() -> Self {
+        Self {
+            // Zero means "no sequence number" to the server
+            next_sequence: 1,
+            pending: Default::default(),
+            state: None,
+            accumulator: Duration::ZERO,
+        }
+    }
+}
+
+impl Prediction {
+    /// How many inputs should be produced this frame
+    pub fn ticks(&mut self, delta_time: Duration) -> usize {
+        let tick = Duration::from_secs_f32(TICK_DT);
+        self.accumulator += delta_time;
+
+        let mut ticks = 0;
+        while self.accumulator >= tick {
+            self.accumulator -= tick;
+            ticks += 1;
+        }
+
+        if ticks > MAX_INPUTS_PER_FRAME {
+            tracing::debug!("Skipping {} ticks of input", ticks - MAX_INPUTS_PER_FRAME);
+            ticks = MAX_INPUTS_PER_FRAME;
+        }
+        ticks
+    }
+
+    /// Number the input and apply it locally. Returns the input to send to the server.
+    pub fn next_input(
+        &mut self,
+        mut controls: Controls,
+        blocks: &BlockGrid,
+        obstacles: &[Obstacle],
+    ) -> Controls {
+        controls.sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1).max(1);
+
+        if let Some(state) = &mut self.state {
+            *state = movement::step(state, &controls.movement_input(), blocks, obstacles);
+        }
+
+        self.pending.push_back(controls);
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+
+        controls
+    }
+
+    /// Correct the prediction with the server's state after the acknowledged input
+    pub fn reconcile(&mut self, ack: InputAck, blocks: &BlockGrid, obstacles: &[Obstacle]) {
+        // Forget everything the server has already processed
+        while self
+            .pending
+            .front()
+            .is_some_and(|controls| sequence_newer_or_equal(ack.sequence, controls.sequence))
+        {
+            self.pending.pop_front();
+        }
+
+        let mut state = ack.movement;
+        for controls in &self.pending {
+            state = movement::step(&state, &controls.movement_input(), blocks, obstacles);
+        }
+
+        if let Some(predicted) = self.state {
+            let error = predicted.position.distance(state.position);
+            if error > 0.01 {
+                tracing::trace!("Mispredicted player position by {error}");
+            }
+        }
+        self.state = Some(state);
+    }
+
+    /// Where we predict the local player is, if we know yet
+    pub fn position(&self) -> Option<Vec3> {
+        self.state.map(|state| state.position)
+    }
+
+    /// The other players and entities that are in the local player's way, see `movement::obstacles`
+    pub fn obstacles(
+        &self,
+        entities: &HashMap<EntityID, EntityData>,
+        entity_type_registry: &EntityTypeRegistry,
+        client_player: PlayerId,
+    ) -> Vec<Obstacle> {
+        let Some(player) = entities
+            .values()
+            .find(|entity| entity.player_id() == Some(client_player))
+        else {
+            return Vec::new();
+        };
+        let position = self.position().unwrap_or(player.state.position);
+        movement::obstacles(
+            entities.values(),
+            entity_type_registry,
+            &player.id,
+            position,
+        )
+    }
+}
+
+// Compares sequence numbers, allowing for them wrapping around
+fn sequence_newer_or_equal(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) < u32::MAX / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, glam::Vec2};
+
+    fn walk(turn: f32) -> Controls {
+        Controls {
+            move_direction: Vec2::new(turn, 1.),
+            ..Default::default()
+        }
+    }
+
+    fn ack(sequence: u32) -> InputAck {
+        InputAck {
+            sequence,
+            movement: MovementState {
+                position: Vec3::new(0., 50., 0.),
+                ..Default::default()
+            },
+        }
+    }
+
+    // Where the inputs take the player from the acked state, in an empty world
+    fn replay(ack: &InputAck, inputs: impl IntoIterator<Item = Controls>) -> Vec3 {
+        let blocks = BlockGrid::new();
+        let state = inputs.into_iter().fold(ack.movement, |state, controls| {
+            movement::step(&state, &controls.movement_input(), &blocks, &[])
+        });
+        state.position
+    }
+
+    #[test]
+    fn newer_inputs_are_replayed_on_the_ack() {
+        let blocks = BlockGrid::new();
+        let mut prediction = Prediction::default();
+        let inputs =
+            [0., 1., -1., 0.5, -0.5].map(|turn| prediction.next_input(walk(turn), &blocks, &[]));
+        assert_eq!(inputs.map(|controls| controls.sequence), [1, 2, 3, 4, 5]);
+
+        prediction.reconcile(ack(3), &blocks, &[]);
+        assert_eq!(
+            prediction.position(),
+            Some(replay(&ack(3), inputs[3..].to_vec()))
+        );
+
+        // Acked inputs are forgotten, so they aren't replayed again
+        prediction.reconcile(ack(5), &blocks, &[]);
+        assert_eq!(prediction.position(), Some(ack(5).movement.position));
+    }
+
+    #[test]
+    fn only_the_newest_inputs_are_kept() {
+        let blocks = BlockGrid::new();
+        let mut prediction = Prediction::default();
+        let inputs = (0..MAX_PENDING_INPUTS + 10)
+            .map(|i| prediction.next_input(walk(i as f32 / 100.), &blocks, &[]))
+            .collect::<Vec<_>>();
+
+        // An ack from before the oldest input that's still remembered
+        prediction.reconcile(ack(5), &blocks, &[]);
+        let kept = inputs[inputs.len() - MAX_PENDING_INPUTS..].to_vec();
+        assert_eq!(prediction.position(), Some(replay(&ack(5), kept)));
+    }
+}
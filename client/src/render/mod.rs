@@ -18,7 +18,7 @@ use {
 };
 
 use bytemuck::{offset_of, Pod, Zeroable};
-use glam::{Mat4, UVec2, UVec3, Vec3};
+use glam::{Mat4, UVec2, Vec3};
 use glow::HasContext;
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{js_sys, HtmlCanvasElement, WebGl2RenderingContext};
@@ -163,7 +163,8 @@ impl Renderer {
         draw_calls: &[DrawCall],
         debug_lines: &[DebugLine],
         lights: &[Light],
-        grid_size: UVec3,
+        // Min, max
+        grid_bounds: (Vec3, Vec3),
     ) {
         let aspect_ratio = self.canvas.client_width() as f32 / self.canvas.client_height() as f32;
         let light_direction = LIGHT_DIRECTION.normalize();
@@ -219,7 +220,7 @@ impl Renderer {
             self.gl.enable(glow::POLYGON_OFFSET_FILL);
             self.gl.polygon_offset(0.0, 0.0);
 
-            let shadow_from_world = compute_shadow_bounding_box(light_direction, grid_size);
+            let shadow_from_world = compute_shadow_bounding_box(light_direction, grid_bounds);
 
             blend_state.set(&self.gl, false);
 
@@ -1214,7 +1215,7 @@ pub fn build_cube_draw_calls<'a>(
     draw_calls
 }
 
-pub fn compute_shadow_bounding_box(direction: Vec3, grid_size: UVec3) -> Mat4 {
+pub fn compute_shadow_bounding_box(direction: Vec3, (grid_min, grid_max): (Vec3, Vec3)) -> Mat4 {
     // TODO(cw): Too Fancy
     // // All 8 corners of the grid
     // let corners = [
@@ -1244,8 +1245,8 @@ pub fn compute_shadow_bounding_box(direction: Vec3, grid_size: UVec3) -> Mat4 {
     // let center = (min + max) / 2.0;
     // let size = max - min;
 
-    let center = grid_size.as_vec3() * 0.5;
-    let size = Vec3::splat(grid_size.as_vec3().max_element() * 1.5);
+    let center = (grid_min + grid_max) * 0.5;
+    let size = Vec3::splat((grid_max - grid_min).max_element() * 1.5);
 
     let projection_matrix = Mat4::orthographic_rh_gl(
         -size.x / 2.0,
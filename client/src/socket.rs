@@ -1,6 +1,6 @@
 use {
     anyhow::Result,
-    net_types::ServerPacket,
+    net_types::{codec, ServerPacket},
     std::{
         cell::{Cell, RefCell},
         collections::BTreeMap,
@@ -19,7 +19,8 @@ thread_local! {
     static SEQUENCE_COUNTER: Cell<u64> = Cell::new(0);
 }
 
-pub type IncomingMessages = Rc<RefCell<BTreeMap<u64, ServerPacket>>>;
+// Each message from the server is a batch of packets
+pub type IncomingMessages = Rc<RefCell<BTreeMap<u64, Vec<ServerPacket>>>>;
 
 pub fn connect_to_server(
     addr: &str,
@@ -55,16 +56,16 @@ pub fn connect_to_server(
                 let array = Uint8Array::new(&array_buffer);
                 let data = array.to_vec();
 
-                // Bincode is currently broken, fall back to json for now.
-                // See: https://github.com/leetvr/hy/issues/189
-                // let packet: net_types::ServerPacket =
-                //     bincode::deserialize(&data).expect("Failed to deserialize server packet");
                 let packet: net_types::ServerPacket =
-                    serde_json::de::from_slice(&data).expect("Failed to deserialize server packet");
+                    codec::decode(&data).expect("Failed to deserialize server packet");
+                let packets = match packet {
+                    ServerPacket::Batch(packets) => packets,
+                    packet => vec![packet],
+                };
 
                 incoming_messages
                     .borrow_mut()
-                    .insert(sequence_number, packet);
+                    .insert(sequence_number, packets);
             });
         }
     }) as Box<dyn FnMut(MessageEvent)>);
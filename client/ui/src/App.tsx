@@ -2,7 +2,7 @@ import { useEffect, useState } from "react";
 import init, { BlockRegistry, Engine, EngineMode, EntityTypeRegistry } from "../../pkg/client.js";
 import "./App.css";
 import LeftBar from "./LeftBar.tsx";
-import RightBar from "./RightBar.tsx";
+import RightBar, { SelectedEntity } from "./RightBar.tsx";
 import CtfGameUi from "./CtfGameUi.tsx";
 import TopBar from "./TopBar.tsx";
 
@@ -11,6 +11,7 @@ function App({ engine }: { engine: Engine }) {
   const [currentMode, setModeState] = useState(initialEngineMode);
   const [blockRegistry, setBlockRegistry] = useState<BlockRegistry>();
   const [entityTypeRegistry, setEntityTypeRegistry] = useState<EntityTypeRegistry>();
+  const [selectedEntity, setSelectedEntity] = useState<SelectedEntity | null>(null);
 
   const setMode = (newMode: EngineMode) => {
 
@@ -30,6 +31,7 @@ function App({ engine }: { engine: Engine }) {
       setBlockRegistry(blockRegistry);
       setEntityTypeRegistry(entityTypeRegistry);
     });
+    engine.ctx_on_selected_entity(setSelectedEntity);
 
     setModeState(engine.ctx_get_engine_mode());
   }, [engine]);
@@ -47,7 +49,7 @@ function App({ engine }: { engine: Engine }) {
           entityTypeRegistry={entityTypeRegistry}
         />
       )}
-      <RightBar selectedEntity={false} />
+      <RightBar engine={engine} selectedEntity={selectedEntity} />
       {currentMode === EngineMode.Play && <CtfGameUi
           engine={engine}
       />}
@@ -1,14 +1,70 @@
 // The "right bar": invisible unless an entity is selected, and in that latter
 // case, the properties panel
+import { useEffect, useState } from "react";
+import { Engine } from "../../pkg/client.js";
 
-// TODO: implement entities and thus this
+// The parts of an entity the properties panel shows
+export interface SelectedEntity {
+    id: string;
+    name: string;
+    state: {
+        custom_state: Record<string, unknown>;
+    };
+}
+
+export default function RightBar({ engine, selectedEntity }: { engine: Engine, selectedEntity: SelectedEntity | null }) {
+    const [name, setName] = useState("");
+    const [customState, setCustomState] = useState("");
+    const [customStateError, setCustomStateError] = useState<string | null>(null);
+
+    // Start again whenever the engine tells us about a change, eg. an undo
+    useEffect(() => {
+        setName(selectedEntity?.name ?? "");
+        setCustomState(JSON.stringify(selectedEntity?.state.custom_state ?? {}, null, 2));
+        setCustomStateError(null);
+    }, [selectedEntity]);
 
-export default function RightBar({ selectedEntity }: { selectedEntity: boolean } ) {
-    if(selectedEntity) {
-        return <div className="editor-panel editor-only" id="propbox">
-            <p>Siege chopper, checking in 🚁</p>
-        </div>;
-    } else {
+    if (!selectedEntity) {
         return <></>;
     }
+
+    const applyCustomState = () => {
+        try {
+            const parsed = JSON.parse(customState);
+            if (typeof parsed !== "object" || parsed === null || Array.isArray(parsed)) {
+                setCustomStateError("Script state has to be an object");
+                return;
+            }
+            engine.ctx_set_selected_entity_custom_state(parsed);
+            setCustomStateError(null);
+        } catch (e) {
+            setCustomStateError(String(e));
+        }
+    };
+
+    return <div className="editor-panel editor-only" id="propbox">
+        <label>
+            Name
+            <input
+                value={name}
+                onChange={(event) => setName(event.target.value)}
+                onBlur={() => {
+                    if (name !== selectedEntity.name) {
+                        engine.ctx_rename_selected_entity(name);
+                    }
+                }}
+            />
+        </label>
+        <label>
+            Script state
+            <textarea
+                value={customState}
+                onChange={(event) => setCustomState(event.target.value)}
+                onBlur={applyCustomState}
+            />
+        </label>
+        {customStateError && <p className="error">{customStateError}</p>}
+        <button onClick={() => engine.ctx_remove_selected_entity()}>Remove</button>
+        <p>Right click selects. Arrows and Page Up/Down move, Q and E turn, = and - scale.</p>
+    </div>;
 }
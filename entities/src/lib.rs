@@ -5,9 +5,12 @@ use {
     wasm_bindgen::prelude::wasm_bindgen,
 };
 
-// THis is only in the entities crate instead of the net-types crate because I need the PlayerId
-// here and net-types depends on the entities crate. But it is my dream that players will one
-// day also be entities. 🙏
+pub mod script_value;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+// Identifies a connected player. Each player is an entity with a `PlayerController`, and this is
+// how the controller knows which client's inputs drive it.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PlayerId(u64);
 
@@ -28,9 +31,48 @@ pub type EntityID = String;
 pub struct EntityData {
     pub id: String,
     pub name: String,
-    pub entity_type: EntityTypeID,
+    /// `None` for players, which are driven by the player script rather than an entity type's
+    pub entity_type: Option<EntityTypeID>,
     pub model_path: String,
     pub state: EntityState,
+    /// Set if this entity is a player
+    #[serde(default)]
+    pub controller: Option<PlayerController>,
+    /// Physics joints from this entity's body to other entities' bodies, or to the world
+    #[serde(default)]
+    pub joints: Vec<Joint>,
+}
+
+impl EntityData {
+    pub fn player_id(&self) -> Option<PlayerId> {
+        self.controller
+            .as_ref()
+            .map(|controller| controller.player_id)
+    }
+}
+
+/// Makes an entity a player: it's moved by its client's inputs, through the player script
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerController {
+    pub player_id: PlayerId,
+    pub animation_state: String,
+    pub is_on_ground: bool,
+    // Jump leeway, see `movement::step`
+    pub coyote_time: f32,
+    pub jump_input_time: f32,
+}
+
+impl PlayerController {
+    pub fn new(player_id: PlayerId) -> Self {
+        Self {
+            player_id,
+            animation_state: Default::default(),
+            is_on_ground: false,
+            coyote_time: 0.,
+            jump_input_time: 0.,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
@@ -51,6 +93,18 @@ pub enum EntityColliderKind {
     Ball,
 }
 
+/// Controls which clients are told about entities of a given type
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Tsify)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EntityRelevance {
+    /// Send to every client regardless of distance, eg. for flags
+    pub always_relevant: bool,
+    /// Overrides the world's view radius for this entity type
+    pub view_radius: Option<f32>,
+    /// If not empty, only players on one of these teams are told about the entity
+    pub teams: Vec<String>,
+}
+
 #[wasm_bindgen]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, Tsify)]
 #[serde(rename_all = "camelCase")]
@@ -60,6 +114,8 @@ pub struct EntityType {
     script_path: String,
     default_model_path: String,
     physics_properties: Option<EntityPhysicsProperties>,
+    #[serde(default)]
+    relevance: EntityRelevance,
 }
 
 impl EntityType {
@@ -82,13 +138,69 @@ impl EntityType {
     pub fn physics_properties(&self) -> Option<&EntityPhysicsProperties> {
         self.physics_properties.as_ref()
     }
+
+    pub fn relevance(&self) -> &EntityRelevance {
+        &self.relevance
+    }
 }
 
+/// What an entity is attached to. An anchored entity's position, rotation and scale are relative
+/// to its anchor rather than to the world.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Anchor {
-    pub player_id: PlayerId,
-    pub parent_anchor: String,
+    /// The entity this one is attached to, which may be a player
+    pub parent_id: EntityID,
+    /// A named node in the parent's model. The entity is attached to the parent's origin if this
+    /// isn't set.
+    #[serde(default)]
+    pub parent_node: Option<String>,
+}
+
+/// A physics joint between an entity's body and another entity's, or a fixed point in the world.
+/// Only entities with physics properties, and players, have bodies to join.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Joint {
+    /// Names the joint among the entity's joints, eg. to remove it
+    pub name: String,
+    pub kind: JointKind,
+    /// The entity at the other end. The joint is pinned to the world if this isn't set.
+    #[serde(default)]
+    pub other_entity: Option<EntityID>,
+    /// Where the joint is on this entity, relative to its origin
+    #[serde(default)]
+    pub local_anchor: glam::Vec3,
+    /// Where the joint is on the other entity, relative to its origin, or in the world
+    #[serde(default)]
+    pub other_anchor: glam::Vec3,
+    /// The axis a revolute joint turns around or a prismatic joint slides along, in this entity's
+    /// space
+    #[serde(default = "default_joint_axis")]
+    pub axis: glam::Vec3,
+    /// How far a revolute joint can turn in radians, or a prismatic joint can slide, either way
+    #[serde(default)]
+    pub limits: Option<[f32; 2]>,
+    /// How far apart a rope lets the anchors get
+    #[serde(default)]
+    pub max_distance: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum JointKind {
+    /// Holds the bodies together, eg. to weld things into one object
+    Fixed,
+    /// Lets the bodies turn around the axis, eg. for doors and swinging hazards
+    Revolute,
+    /// Lets the bodies slide along the axis, eg. for lifts
+    Prismatic,
+    /// Keeps the anchors within `max_distance` of each other, eg. for chained objects
+    Rope,
+}
+
+fn default_joint_axis() -> glam::Vec3 {
+    glam::Vec3::Y
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -98,7 +210,7 @@ pub struct Interaction {
     pub position: glam::Vec3,
     pub yaw: f32,
     pub pitch: f32,
-    #[serde(default)]
+    #[serde(default, with = "script_value::map")]
     pub custom_state: HashMap<String, serde_json::Value>,
 }
 
@@ -112,11 +224,12 @@ pub struct EntityState {
     pub scale: glam::Vec3,
     pub anchor: Option<Anchor>,
     pub interactions: Vec<Interaction>,
-    #[serde(default)]
+    #[serde(default, with = "script_value::map")]
     pub custom_state: HashMap<String, serde_json::Value>,
     // A field to keep track of the entity's absolute position in the world, for use in server side
-    // scripts. If the entity is anchored, this should be the position of the anchor. Otherwise
-    // it's the same as `position`. This is pretty hacky.
+    // scripts. If the entity is anchored, this is its position relative to its parents, all the
+    // way up, including where the node it's anchored to is in the parent's model at rest.
+    // Otherwise it's the same as `position`. This is pretty hacky.
     #[serde(default)]
     pub absolute_position: glam::Vec3,
 }
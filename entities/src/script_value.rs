@@ -0,0 +1,148 @@
+//! Serde adapters for script state (`serde_json::Value`).
+//!
+//! `serde_json::Value` can only be deserialized by self-describing formats, which is what broke
+//! bincode for us (see https://github.com/leetvr/hy/issues/189). These adapters leave
+//! human-readable formats (JSON files, serde_v8, serde-wasm-bindgen) untouched, and encode values
+//! as an explicitly tagged enum for binary formats.
+//!
+//! Use with `#[serde(with = "entities::script_value")]`, or one of the submodules for containers.
+
+use {
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
+    serde_json::Value,
+    std::collections::HashMap,
+};
+
+#[derive(Serialize, Deserialize)]
+enum BinaryValue {
+    Null,
+    Bool(bool),
+    PosInt(u64),
+    NegInt(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<BinaryValue>),
+    Object(Vec<(String, BinaryValue)>),
+}
+
+impl From<&Value> for BinaryValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => BinaryValue::Null,
+            Value::Bool(b) => BinaryValue::Bool(*b),
+            Value::Number(n) => {
+                if let Some(n) = n.as_u64() {
+                    BinaryValue::PosInt(n)
+                } else if let Some(n) = n.as_i64() {
+                    BinaryValue::NegInt(n)
+                } else {
+                    BinaryValue::Float(n.as_f64().unwrap_or_default())
+                }
+            }
+            Value::String(s) => BinaryValue::String(s.clone()),
+            Value::Array(values) => BinaryValue::Array(values.iter().map(Into::into).collect()),
+            Value::Object(map) => {
+                BinaryValue::Object(map.iter().map(|(k, v)| (k.clone(), v.into())).collect())
+            }
+        }
+    }
+}
+
+impl From<BinaryValue> for Value {
+    fn from(value: BinaryValue) -> Self {
+        match value {
+            BinaryValue::Null => Value::Null,
+            BinaryValue::Bool(b) => Value::Bool(b),
+            BinaryValue::PosInt(n) => n.into(),
+            BinaryValue::NegInt(n) => n.into(),
+            // Non-finite floats become null, same as serde_json
+            BinaryValue::Float(n) => n.into(),
+            BinaryValue::String(s) => Value::String(s),
+            BinaryValue::Array(values) => {
+                Value::Array(values.into_iter().map(Into::into).collect())
+            }
+            BinaryValue::Object(entries) => {
+                Value::Object(entries.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+// Borrowed and owned wrappers so the container adapters can reuse the single value logic
+struct Wrapped<'a>(&'a Value);
+
+impl Serialize for Wrapped<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+struct Owned(Value);
+
+impl<'de> Deserialize<'de> for Owned {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(Owned)
+    }
+}
+
+pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        value.serialize(serializer)
+    } else {
+        BinaryValue::from(value).serialize(serializer)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+    if deserializer.is_human_readable() {
+        Value::deserialize(deserializer)
+    } else {
+        BinaryValue::deserialize(deserializer).map(Into::into)
+    }
+}
+
+/// Adapter for `HashMap<String, serde_json::Value>`, ie. custom script state
+pub mod map {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        map: &HashMap<String, Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(k, v)| (k, Wrapped(v))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Value>, D::Error> {
+        let map = HashMap::<String, Owned>::deserialize(deserializer)?;
+        Ok(map.into_iter().map(|(k, v)| (k, v.0)).collect())
+    }
+}
+
+/// Adapter for `Option<HashMap<String, serde_json::Value>>`
+pub mod option_map {
+    use super::*;
+
+    struct WrappedMap<'a>(&'a HashMap<String, Value>);
+
+    impl Serialize for WrappedMap<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            map::serialize(self.0, serializer)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        map: &Option<HashMap<String, Value>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        map.as_ref().map(WrappedMap).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<HashMap<String, Value>>, D::Error> {
+        let map = Option::<HashMap<String, Owned>>::deserialize(deserializer)?;
+        Ok(map.map(|map| map.into_iter().map(|(k, v)| (k, v.0)).collect()))
+    }
+}
@@ -0,0 +1,58 @@
+// Entity types and entities for other crates' tests, so each test only has to say what's different
+// about its entities
+
+use crate::{
+    EntityColliderKind, EntityData, EntityPhysicsProperties, EntityRelevance, EntityState,
+    EntityType, EntityTypeID,
+};
+
+/// An entity type without physics, whose script and model are named after it, eg. `part.js` and
+/// `part.gltf` for "Part"
+pub fn entity_type(id: EntityTypeID, name: &str) -> EntityType {
+    let file_name = name.to_lowercase();
+    EntityType {
+        id,
+        name: name.into(),
+        script_path: format!("{file_name}.js"),
+        default_model_path: format!("{file_name}.gltf"),
+        physics_properties: None,
+        relevance: EntityRelevance::default(),
+    }
+}
+
+/// A dynamic ball called "Ball", with `ball.js` as its script
+pub fn ball_type(id: EntityTypeID, diameter: f32) -> EntityType {
+    EntityType {
+        physics_properties: Some(EntityPhysicsProperties {
+            collider_kind: EntityColliderKind::Ball,
+            collider_width: diameter,
+            collider_height: diameter,
+            dynamic: true,
+        }),
+        ..entity_type(id, "Ball")
+    }
+}
+
+impl EntityType {
+    /// The same entity type, told to clients according to `relevance`
+    pub fn with_relevance(self, relevance: EntityRelevance) -> Self {
+        Self { relevance, ..self }
+    }
+}
+
+/// An unanchored entity of the given type, with nothing in its script state
+pub fn entity(id: &str, entity_type: EntityTypeID, position: glam::Vec3) -> EntityData {
+    EntityData {
+        id: id.into(),
+        name: format!("Entity {id}"),
+        entity_type: Some(entity_type),
+        model_path: "entity.gltf".into(),
+        state: EntityState {
+            position,
+            absolute_position: position,
+            ..Default::default()
+        },
+        controller: None,
+        joints: Vec::new(),
+    }
+}
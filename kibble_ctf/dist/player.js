@@ -1,7 +1,4 @@
-const GRAVITY = -20; // Gravity acceleration (m/s^2)
-const MOVE_SPEED = 7.0; // Movement speed (units per second)
-const JUMP_SPEED = 8.0; // Jump initial velocity (units per second)
-const MIN_FALL_SPEED = -20.;
+const JUMP_SPEED = 8.0; // Jump initial velocity (units per second), must match the engine's
 const DT = 1 / 60; // Fixed delta time (seconds per frame)
 export const onSpawn = (playerId, currentState) => {
     const { customState, position } = currentState;
@@ -19,8 +16,6 @@ export const onSpawn = (playerId, currentState) => {
     newCustomState.respawnTimer = RESPAWN_TIME;
     newCustomState.stunned = false;
     newCustomState.hasFlag = false;
-    newCustomState.coyoteTime = 0.;
-    newCustomState.jumpInputTime = 0.;
     newCustomState.itemPickupCooldowns = {};
     let gun = hy.spawnEntity(GUN_TYPE_ID, [0, 0, 0], [0, 0, 0], [0, 0, 0]);
     hy.anchorEntity(gun, playerId, "hand_right_anchor");
@@ -32,7 +27,7 @@ export const onSpawn = (playerId, currentState) => {
 export const update = (playerID, currentState, controls) => {
     // Note(ll): I just put attachedEntities in currentState but mutating it in the script will not have any effect.
     // It's just a quick way to pass data to the script.
-    const { position, velocity, animationState, facingAngle, isOnGround: wasOnGround, customState, attachedEntities, } = currentState;
+    const { position, velocity, animationState, facingAngle, isOnGround: wasOnGround, coyoteTime, jumpInputTime, customState, attachedEntities, } = currentState;
     let newPosition = [...position];
     let newVelocity = [...velocity];
     let newFacingAngle = facingAngle;
@@ -214,73 +209,37 @@ export const update = (playerID, currentState, controls) => {
             });
         }
     }
-    // Handle horizontal movement
+    // Handle movement. The client predicts its own player's movement using the same code as
+    // hy.stepMovement, so anything else we do to the player here will be corrected on the client.
     const inputX = newControls.move_direction[0];
     const inputZ = newControls.move_direction[1];
     if (inputX !== 0 || inputZ !== 0) {
         newFacingAngle = controls.camera_yaw;
-        // Normalize input direction
-        const inputLength = Math.hypot(inputX, inputZ);
-        const normalizedInput = [inputX / inputLength, inputZ / inputLength];
-        // Rotate input by camera yaw to get world space direction
-        const yaw = newControls.camera_yaw;
-        const sinYaw = Math.sin(yaw);
-        const cosYaw = Math.cos(yaw);
-        // Compute movement direction in world space
-        const moveDirX = normalizedInput[0] * cosYaw - normalizedInput[1] * sinYaw;
-        const moveDirZ = normalizedInput[0] * sinYaw + normalizedInput[1] * cosYaw;
-        // Update horizontal velocity
-        newVelocity[0] = moveDirX * MOVE_SPEED;
-        newVelocity[2] = -moveDirZ * MOVE_SPEED;
     }
-    else {
-        // TODO: Apply damping to horizontal velocity when no input
-        if (wasOnGround && !newCustomState.stunned) {
-            newVelocity[0] *= 0.7;
-            newVelocity[2] *= 0.7;
-        }
-    }
-    // Apply gravity
-    newVelocity[1] += GRAVITY * DT;
+    const movement = hy.stepMovement({
+        position: newPosition,
+        velocity: newVelocity,
+        isOnGround: wasOnGround,
+        coyoteTime,
+        jumpInputTime,
+    }, newControls);
+    const isOnGround = movement.isOnGround;
+    const didJump = movement.jumped;
+    newPosition = movement.position;
+    newVelocity = movement.velocity;
     if (knockback[0] != 0 || knockback[1] != 0 || knockback[2] != 0) {
         console.log("knockback", knockback);
         newVelocity[0] = knockback[0];
         newVelocity[1] = knockback[1];
         newVelocity[2] = knockback[2];
     }
-    // Update position based on velocity and delta time
-    const desiredMovement = [newVelocity[0], newVelocity[1], newVelocity[2]];
-    const { correctedMovement, isOnGround } = hy.checkMovementForCollisions(playerID, position, desiredMovement);
-    newVelocity[0] = correctedMovement[0];
-    newVelocity[1] = correctedMovement[1];
-    newVelocity[2] = correctedMovement[2];
-    newVelocity[1] = Math.max(newVelocity[1], MIN_FALL_SPEED);
-    if (isOnGround && newVelocity[1] < 0) {
+    if (isOnGround) {
         newCustomState.stunned = false;
-        newCustomState.coyoteTime = COYOTE_TIME;
-    }
-    else {
-        newCustomState.coyoteTime = Math.max(0., newCustomState.coyoteTime - DT);
-    }
-    if (controls.jump) {
-        newCustomState.jumpInputTime = JUMP_INPUT_TIME;
-    }
-    else {
-        newCustomState.jumpInputTime = Math.max(0., newCustomState.jumpInputTime - DT);
-    }
-    let didJump = false;
-    if (newCustomState.coyoteTime > 0. && newCustomState.jumpInputTime > 0.) {
-        didJump = true;
-        newVelocity[1] = JUMP_SPEED;
-        newCustomState.coyoteTime = 0.;
     }
     // Special jump pad logic
-    if (isOnGround && hy.getBlock([position[0], position[1] - 1.0, position[2]]) == 11) {
+    if (isOnGround && hy.getBlock([newPosition[0], newPosition[1] - 1.0, newPosition[2]]) == 11) {
         newVelocity[1] = JUMP_SPEED * 2;
     }
-    newPosition[0] += newVelocity[0] * DT;
-    newPosition[1] += newVelocity[1] * DT;
-    newPosition[2] += newVelocity[2] * DT;
     if (!isAlive) {
         newAnimationState = "sleep";
     }
@@ -300,7 +259,7 @@ export const update = (playerID, currentState, controls) => {
     if (newPosition[1] < -10) {
         newCustomState.health = 0;
     }
-    return Object.assign(Object.assign({}, currentState), { position: newPosition, velocity: newVelocity, facingAngle: newFacingAngle, animationState: newAnimationState, customState: newCustomState, isOnGround,
+    return Object.assign(Object.assign({}, currentState), { position: newPosition, velocity: newVelocity, facingAngle: newFacingAngle, animationState: newAnimationState, customState: newCustomState, isOnGround, coyoteTime: movement.coyoteTime, jumpInputTime: movement.jumpInputTime,
         attachedEntities });
 };
 const GUN_TYPE_ID = 1;
@@ -324,8 +283,3 @@ function max_ammo(entity_type) {
 }
 const MAX_HEALTH = 5;
 const RESPAWN_TIME = 3.0;
-// Coyote time is the time after the player has left the ground during which they can still jump
-const COYOTE_TIME = 0.1;
-// The time after the player has pressed the jump button during which they can still jump if they
-// hit the ground
-const JUMP_INPUT_TIME = 0.1;
@@ -28,12 +28,15 @@ export interface PlayerState {
   facingAngle: number;
   animationState: string;
   isOnGround: boolean;
+  coyoteTime: number;
+  jumpInputTime: number;
   customState: CustomState;
   attachedEntities: AttachedEntities;
   modelPath: string;
 }
 
 export interface PlayerControls {
+  readonly sequence: number;
   readonly move_direction: Vec2;
   readonly jump: boolean;
   readonly fire: boolean;
@@ -43,14 +46,54 @@ export interface PlayerControls {
 
 export interface EntityData {
   name: string;
-  entity_type: number;
+  /** Null for players */
+  entity_type: number | null;
   model_path: string;
   state: EntityState;
+  /** Set if this entity is a player */
+  controller: PlayerController | null;
+  joints: Joint[];
 }
 
-export interface Anchor {
+/**
+ * A physics joint between an entity's body and another entity's, or a fixed point in the world.
+ * Only entities with physics properties, and players, have bodies to join.
+ */
+export interface Joint {
+  /** Names the joint among the entity's joints */
+  name: string;
+  kind: "fixed" | "revolute" | "prismatic" | "rope";
+  /** The entity at the other end. The joint is pinned to the world if this is left out. */
+  otherEntity?: EntityId | null;
+  /** Where the joint is on this entity, relative to its origin */
+  localAnchor?: Vec3;
+  /** Where the joint is on the other entity, relative to its origin, or in the world */
+  otherAnchor?: Vec3;
+  /** The axis revolute joints turn around and prismatic joints slide along. Defaults to up. */
+  axis?: Vec3;
+  /** How far a revolute joint can turn in radians, or a prismatic joint can slide */
+  limits?: [number, number] | null;
+  /** How far apart a rope lets the anchors get */
+  maxDistance?: number;
+}
+
+export interface PlayerController {
   playerId: number;
-  parentAnchor: AnchorName;
+  animationState: string;
+  isOnGround: boolean;
+  coyoteTime: number;
+  jumpInputTime: number;
+}
+
+/**
+ * What an entity is attached to. While an entity is anchored, its position, rotation and scale are
+ * relative to its anchor.
+ */
+export interface Anchor {
+  /** The entity this one is attached to, which may be a player */
+  parentId: EntityId;
+  /** A named node in the parent's model. Null for the parent's origin. */
+  parentNode: AnchorName | null;
 }
 
 export interface EntityState {
@@ -59,6 +102,7 @@ export interface EntityState {
   rotation: Quat;
   scale: Vec3;
   anchor: Anchor | null;
+  /** Where the entity is in the world, following its anchors. Node offsets are from the model at rest. */
   absolutePosition: Vec3,
   interactions: Interaction[];
   customState: CustomState;
@@ -106,6 +150,33 @@ type OnEntitySpawn = (entityData: EntityData) => EntityData;
 
 type EntityUpdate = (id: string, currentState: EntityState) => EntityState;
 
+// Optional entity callbacks. They're called after the tick's updates and physics step, and (apart
+// from `OnEntityDespawn`) return the entity's next state like `EntityUpdate`.
+
+/** Called when the entity starts or stops touching a block, player or other entity. */
+type OnEntityCollision = (
+  id: string,
+  currentState: EntityState,
+  collision: Collision,
+) => EntityState;
+
+/**
+ * Called for each `hy.interactEntity` with this entity. The interactions are also still passed to
+ * `EntityUpdate` in `currentState.interactions`.
+ */
+type OnEntityInteract = (
+  id: string,
+  currentState: EntityState,
+  interaction: Interaction,
+) => EntityState;
+
+type OnEntityAnchor = (id: string, currentState: EntityState, anchor: Anchor) => EntityState;
+
+type OnEntityDetach = (id: string, currentState: EntityState) => EntityState;
+
+/** Called after the entity has been removed from the world, with its last state. */
+type OnEntityDespawn = (id: string, lastState: EntityState) => void;
+
 interface GlobalHy {
   getEntities: () => { [key: EntityId]: EntityData };
   getPlayerState: (playerID: number) => PlayerState | undefined;
@@ -123,7 +194,33 @@ interface GlobalHy {
     currentPosition: Vec3,
     movement: Vec3,
   ) => CollisionResult;
+  /**
+   * Moves a player by one tick. Clients predict their own player's movement with the same code,
+   * so movement that goes through here won't need correcting.
+   */
+  stepMovement: (movementState: MovementState, controls: PlayerControls) => MovementState;
+  /** Attaches an entity to a node in a player's model. */
   anchorEntity: (entityId: EntityId, anchorId: number, anchorName: AnchorName) => void;
+  /**
+   * Attaches an entity to another entity, or a named node in its model. Anchoring fails if the
+   * parent is already anchored to the entity. Anything anchored to an entity is despawned with it.
+   *
+   * @param offset - The entity's new position relative to the anchor. It keeps its current
+   * position if this is left out.
+   */
+  anchorToEntity: (
+    entityId: EntityId,
+    parentId: EntityId,
+    parentNode?: AnchorName | null,
+    offset?: Vec3,
+  ) => void;
+  /**
+   * Joins an entity to another entity, or to the world, at the end of the tick. Replaces any
+   * joint the entity already has with the same name. Joints are saved with the world, and removed
+   * when the entity at either end despawns.
+   */
+  addJoint: (entityId: EntityId, joint: Joint) => void;
+  removeJoint: (entityId: EntityId, name: string) => void;
   detachEntity: (entityId: EntityId, position: Vec3) => void;
   interactEntity: (
     entityId: EntityId,
@@ -135,8 +232,62 @@ interface GlobalHy {
   ) => void;
   getCollisionsForEntity: (entityId: EntityId) => Collision[];
   getCollisionsForPlayer: (playerID: number) => Collision[];
+  /**
+   * The first thing along a ray, as of the last physics step. The entity or player whose script
+   * is asking is never hit.
+   */
+  raycast: (
+    origin: Vec3,
+    direction: Vec3,
+    maxDistance: number,
+    filter?: HitFilter,
+  ) => QueryHit | null;
+  /** Like `raycast`, but sweeps a whole shape along the ray. */
+  shapeCast: (
+    shape: QueryShape,
+    origin: Vec3,
+    direction: Vec3,
+    maxDistance: number,
+    filter?: HitFilter,
+  ) => QueryHit | null;
+  /** Everything within `radius` of `center`. */
+  overlapSphere: (center: Vec3, radius: number, filter?: HitFilter) => Overlap[];
   playSound: (soundId: string, position: Vec3, volume: number) => void;
   getBlock: (position: Vec3) => number;
+  /** Changes a block at the end of the tick. Block ID 0 is empty. */
+  setBlock: (position: Vec3, blockId: number) => void;
+  /** Sets every block in the box between two corners, inclusive. */
+  fillRegion: (corner1: Vec3, corner2: Vec3, blockId: number) => void;
+  clearRegion: (corner1: Vec3, corner2: Vec3) => void;
+  /** The non-empty blocks in the box between two corners, inclusive. */
+  getBlocksInBox: (corner1: Vec3, corner2: Vec3) => BlockInBox[];
+  /**
+   * Calls `callback` once, after `ticks` ticks of the game clock. The clock doesn't run while the
+   * game is paused. Timers set by an entity's script are cleared when the entity despawns.
+   *
+   * @returns An ID for `clearTimer`.
+   */
+  setTimeout: (callback: () => void, ticks: number) => TimerId;
+  /** Like `setTimeout`, but calls `callback` every `ticks` ticks until it's cleared. */
+  setInterval: (callback: () => void, ticks: number) => TimerId;
+  clearTimer: (timerId: TimerId) => void;
+}
+
+type TimerId = number;
+
+interface BlockInBox {
+  readonly position: BlockPos;
+  readonly blockId: number;
+}
+
+export interface MovementState {
+  position: Vec3;
+  velocity: Vec3;
+  isOnGround: boolean;
+  coyoteTime: number;
+  jumpInputTime: number;
+  /** Whether the player jumped this tick. Only set on the way out of `stepMovement`. */
+  jumped?: boolean;
 }
 
 interface CollisionResult {
@@ -147,12 +298,45 @@ interface CollisionResult {
 
 interface Collision {
   readonly collisionKind: "contact" | "intersection";
-  readonly collisionTarget: "block" | "entity" | "player";
+  readonly collisionTarget: CollisionTarget;
   /**
   The ID of the thing this entity collided with  */
   readonly targetId: string;
 }
 
+type CollisionTarget = "block" | "entity" | "player";
+
+interface HitFilter {
+  /** Only hit these kinds of things. Everything can be hit if this is left out. */
+  targets?: CollisionTarget[];
+  excludeEntities?: EntityId[];
+  excludePlayers?: number[];
+  /** Whether to hit sensors, like trigger zones. Defaults to false. */
+  includeSensors?: boolean;
+}
+
+type QueryShape =
+  | { type: "sphere"; radius: number }
+  | { type: "box"; halfExtents: Vec3 }
+  | { type: "capsule"; halfHeight: number; radius: number };
+
+interface QueryHit {
+  readonly target: CollisionTarget;
+  /** Player ID if player, block type ID if block, entity ID if entity */
+  readonly targetId: string;
+  /** Only set when a block was hit */
+  readonly blockPosition?: BlockPos;
+  readonly point: Vec3;
+  /** Points out of the surface that was hit */
+  readonly normal: Vec3;
+  readonly distance: number;
+}
+
+interface Overlap {
+  readonly target: CollisionTarget;
+  readonly targetId: string;
+}
+
 declare global {
   const hy: GlobalHy;
 }
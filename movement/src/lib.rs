@@ -0,0 +1,433 @@
+//! Player movement, shared by the server and the client
+//!
+//! The server runs this (via `hy.stepMovement`) to move players, and the client runs the exact
+//! same code to predict its own player's movement ahead of the server's acknowledgements.
+//! Anything in here must be deterministic given the same state, input, blocks and obstacles.
+
+use {
+    blocks::{BlockGrid, BlockPos, EMPTY_BLOCK},
+    entities::{EntityColliderKind, EntityData, EntityTypeRegistry},
+    glam::{BVec3, Vec2, Vec3},
+    serde::{Deserialize, Serialize},
+};
+
+/// Movement is always stepped at the server's tick rate
+pub const TICK_DT: f32 = 1. / 60.;
+
+pub const GRAVITY: f32 = -20.;
+pub const MOVE_SPEED: f32 = 7.;
+pub const JUMP_SPEED: f32 = 8.;
+pub const MIN_FALL_SPEED: f32 = -20.;
+/// The time after the player has left the ground during which they can still jump
+pub const COYOTE_TIME: f32 = 0.1;
+/// The time after the player has pressed jump during which they will still jump if they hit the
+/// ground
+pub const JUMP_INPUT_TIME: f32 = 0.1;
+// Horizontal velocity is multiplied by this every tick on the ground without any input
+const GROUND_DAMPING: f32 = 0.7;
+
+// obtained by creating rulers in Blender and comparing them against the Player model, and halved
+// because we scale the model down in the client
+pub const PLAYER_WIDTH: f32 = 1.6 / 2.0;
+pub const PLAYER_HEIGHT: f32 = 3.04 / 2.0;
+
+// Gap kept between the player and the blocks they run into, so they're not touching or inside them
+const SKIN: f32 = 0.001;
+// Moves are split into steps no longer than this, so fast players can't tunnel through blocks
+const MAX_STEP: f32 = 0.5;
+// Only obstacles this close to the player are collided with. Far more than a player moves in a
+// tick, so the server and the client agree on the ones that matter even if they look from slightly
+// different places.
+const OBSTACLE_REACH: f32 = 8.;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MovementState {
+    /// The position of the player's feet
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub is_on_ground: bool,
+    #[serde(default)]
+    pub coyote_time: f32,
+    #[serde(default)]
+    pub jump_input_time: f32,
+    /// Whether the player jumped this tick
+    #[serde(default)]
+    pub jumped: bool,
+}
+
+/// A box that players can't move into, eg. another player or a solid entity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Obstacle {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Obstacle {
+    fn overlaps(&self, (min, max): (Vec3, Vec3)) -> bool {
+        min.cmplt(self.max).all() && max.cmpgt(self.min).all()
+    }
+}
+
+/// The obstacles near `position` for the player whose entity is `player_id`: the other players,
+/// and every entity with a collider. Each is the box around its collider, the same shape the
+/// physics engine gives it. The server and the client both use this, so that prediction agrees
+/// with the server about what's in the way.
+pub fn obstacles<'a>(
+    entities: impl IntoIterator<Item = &'a EntityData>,
+    entity_type_registry: &EntityTypeRegistry,
+    player_id: &str,
+    position: Vec3,
+) -> Vec<Obstacle> {
+    let reach = Vec3::splat(OBSTACLE_REACH);
+    let near = Obstacle {
+        min: position - reach,
+        max: position + reach,
+    };
+    entities
+        .into_iter()
+        .filter(|entity| entity.id != player_id)
+        .filter_map(|entity| obstacle(entity, entity_type_registry))
+        .filter(|obstacle| near.overlaps((obstacle.min, obstacle.max)))
+        .collect()
+}
+
+fn obstacle(entity: &EntityData, entity_type_registry: &EntityTypeRegistry) -> Option<Obstacle> {
+    if entity.controller.is_some() {
+        let (min, max) = player_aabb(entity.state.position);
+        return Some(Obstacle { min, max });
+    }
+
+    let physics_properties = entity_type_registry
+        .get(entity.entity_type?)?
+        .physics_properties()?;
+    let half_height = physics_properties.collider_height / 2.;
+    let half_width = physics_properties.collider_width / 2.;
+    // Anchored entities' positions are relative to their parents
+    let position = match entity.state.anchor {
+        Some(_) => entity.state.absolute_position,
+        None => entity.state.position,
+    };
+    let rotation = glam::Mat3::from_quat(entity.state.rotation);
+    // Colliders sit on the entity's origin rather than around it
+    let center = position + rotation * Vec3::new(0., half_height, 0.);
+    // The half extents of the box around the turned collider
+    let turned = |half_extents: Vec3| {
+        glam::Mat3::from_cols(
+            rotation.x_axis.abs(),
+            rotation.y_axis.abs(),
+            rotation.z_axis.abs(),
+        ) * half_extents
+    };
+    let half_extents = match physics_properties.collider_kind {
+        EntityColliderKind::Capsule => turned(Vec3::Y * half_height) + half_width,
+        EntityColliderKind::Cube => turned(Vec3::splat(half_width)),
+        EntityColliderKind::Ball => Vec3::splat(half_height),
+    };
+    Some(Obstacle {
+        min: center - half_extents,
+        max: center + half_extents,
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MovementInput {
+    pub move_direction: Vec2,
+    pub camera_yaw: f32, // radians
+    pub jump: bool,
+}
+
+/// Advance the player's movement by a single tick
+pub fn step(
+    state: &MovementState,
+    input: &MovementInput,
+    blocks: &BlockGrid,
+    obstacles: &[Obstacle],
+) -> MovementState {
+    let mut velocity = state.velocity;
+
+    // Horizontal movement, relative to the camera
+    if input.move_direction != Vec2::ZERO {
+        let direction = input.move_direction.normalize();
+        let (sin_yaw, cos_yaw) = input.camera_yaw.sin_cos();
+        velocity.x = (direction.x * cos_yaw - direction.y * sin_yaw) * MOVE_SPEED;
+        velocity.z = -(direction.x * sin_yaw + direction.y * cos_yaw) * MOVE_SPEED;
+    } else if state.is_on_ground {
+        velocity.x *= GROUND_DAMPING;
+        velocity.z *= GROUND_DAMPING;
+    }
+
+    velocity.y = (velocity.y + GRAVITY * TICK_DT).max(MIN_FALL_SPEED);
+
+    // Jumping, with a little leeway either side of touching the ground
+    let mut coyote_time = state.coyote_time;
+    let mut jump_input_time = if input.jump {
+        JUMP_INPUT_TIME
+    } else {
+        (state.jump_input_time - TICK_DT).max(0.)
+    };
+    let jumped = coyote_time > 0. && jump_input_time > 0.;
+    if jumped {
+        velocity.y = JUMP_SPEED;
+        coyote_time = 0.;
+        jump_input_time = 0.;
+    }
+
+    let (position, blocked) =
+        move_and_collide(state.position, velocity * TICK_DT, blocks, obstacles);
+    let is_on_ground = blocked.y && velocity.y <= 0.;
+
+    // Stop moving along any axis we bumped into
+    let velocity = Vec3::select(blocked, Vec3::ZERO, velocity);
+
+    let coyote_time = if is_on_ground {
+        COYOTE_TIME
+    } else {
+        (coyote_time - TICK_DT).max(0.)
+    };
+
+    MovementState {
+        position,
+        velocity,
+        is_on_ground,
+        coyote_time,
+        jump_input_time,
+        jumped,
+    }
+}
+
+// Move the player, one axis at a time, stopping at the first block or obstacle in the way along
+// each axis. Returns the new position and the axes that were blocked.
+fn move_and_collide(
+    mut position: Vec3,
+    movement: Vec3,
+    blocks: &BlockGrid,
+    obstacles: &[Obstacle],
+) -> (Vec3, BVec3) {
+    let steps = (movement.abs().max_element() / MAX_STEP).ceil().max(1.);
+    let step = movement / steps;
+    let mut blocked = [false; 3];
+
+    for _ in 0..steps as usize {
+        // Y first, so that walking into a wall doesn't stop you from landing
+        for axis in [1, 0, 2] {
+            if blocked[axis] || step[axis] == 0. {
+                continue;
+            }
+
+            let mut moved = position;
+            moved[axis] += step[axis];
+            let hit_block = collides_with_blocks(moved, blocks);
+            let hit_obstacles = obstacles
+                .iter()
+                .filter(|obstacle| obstacle.overlaps(player_aabb(moved)))
+                .collect::<Vec<_>>();
+            if !hit_block && hit_obstacles.is_empty() {
+                position = moved;
+                continue;
+            }
+
+            // Snap up against the nearest thing we hit
+            blocked[axis] = true;
+            let (min, max) = player_aabb(moved);
+            let mut snapped = position;
+            snapped[axis] = if step[axis] > 0. {
+                let block = hit_block.then(|| moved[axis] - (max[axis] - max[axis].floor()));
+                let obstacles = hit_obstacles
+                    .iter()
+                    .map(|obstacle| moved[axis] - (max[axis] - obstacle.min[axis]));
+                block.into_iter().chain(obstacles).fold(f32::MAX, f32::min) - SKIN
+            } else {
+                let block = hit_block.then(|| moved[axis] + (min[axis].floor() + 1. - min[axis]));
+                let obstacles = hit_obstacles
+                    .iter()
+                    .map(|obstacle| moved[axis] + (obstacle.max[axis] - min[axis]));
+                block.into_iter().chain(obstacles).fold(f32::MIN, f32::max) + SKIN
+            };
+
+            // Only ever snap forwards, never back out through something
+            let is_forwards = (snapped[axis] - position[axis]) * step[axis] > 0.;
+            if is_forwards && !collides(snapped, blocks, obstacles) {
+                position = snapped;
+            }
+        }
+    }
+
+    (position, BVec3::new(blocked[0], blocked[1], blocked[2]))
+}
+
+fn player_aabb(position: Vec3) -> (Vec3, Vec3) {
+    let half_width = PLAYER_WIDTH / 2.;
+    let min = position - Vec3::new(half_width, 0., half_width);
+    let max = position + Vec3::new(half_width, PLAYER_HEIGHT, half_width);
+    (min, max)
+}
+
+fn collides(position: Vec3, blocks: &BlockGrid, obstacles: &[Obstacle]) -> bool {
+    collides_with_blocks(position, blocks)
+        || obstacles
+            .iter()
+            .any(|obstacle| obstacle.overlaps(player_aabb(position)))
+}
+
+fn collides_with_blocks(position: Vec3, blocks: &BlockGrid) -> bool {
+    let (min, max) = player_aabb(position);
+    let (min, max) = (BlockPos::from_float(min), BlockPos::from_float(max));
+
+    (min.x..=max.x).any(|x| {
+        (min.y..=max.y)
+            .any(|y| (min.z..=max.z).any(|z| blocks[BlockPos::new(x, y, z)] != EMPTY_BLOCK))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 16x16 floor with its top at y = 0, and a wall along x = 4
+    fn blocks() -> BlockGrid {
+        let mut blocks = BlockGrid::new();
+        for x in -8..8 {
+            for z in -8..8 {
+                blocks[BlockPos::new(x, -1, z)] = 1;
+                blocks[BlockPos::new(4, 0, z)] = 1;
+                blocks[BlockPos::new(4, 1, z)] = 1;
+            }
+        }
+        blocks
+    }
+
+    fn run(state: MovementState, input: MovementInput, ticks: usize) -> MovementState {
+        run_among(state, input, ticks, &[])
+    }
+
+    fn run_among(
+        mut state: MovementState,
+        input: MovementInput,
+        ticks: usize,
+        obstacles: &[Obstacle],
+    ) -> MovementState {
+        let blocks = blocks();
+        for _ in 0..ticks {
+            state = step(&state, &input, &blocks, obstacles);
+        }
+        state
+    }
+
+    // Yaw of -90 degrees faces +X
+    fn walk_towards_x() -> MovementInput {
+        MovementInput {
+            move_direction: Vec2::Y,
+            camera_yaw: -std::f32::consts::FRAC_PI_2,
+            jump: false,
+        }
+    }
+
+    #[test]
+    fn lands_on_the_ground() {
+        let state = MovementState {
+            position: Vec3::new(0., 3., 0.),
+            ..Default::default()
+        };
+        let state = run(state, MovementInput::default(), 120);
+
+        assert!(state.is_on_ground);
+        assert!(state.position.y >= 0. && state.position.y < 0.01);
+        assert_eq!(state.velocity, Vec3::ZERO);
+    }
+
+    #[test]
+    fn stops_at_walls() {
+        let state = run(MovementState::default(), walk_towards_x(), 120);
+
+        let max_x = state.position.x + PLAYER_WIDTH / 2.;
+        assert!(max_x < 4. && max_x > 3.99, "{state:?}");
+        assert_eq!(state.velocity.x, 0.);
+    }
+
+    #[test]
+    fn jumps() {
+        let state = run(MovementState::default(), MovementInput::default(), 10);
+        let input = MovementInput {
+            jump: true,
+            ..Default::default()
+        };
+        let state = run(state, input, 1);
+
+        assert!(!state.is_on_ground);
+        assert!(state.jumped);
+        assert!(!run(state, MovementInput::default(), 1).jumped);
+    }
+
+    #[test]
+    fn stops_at_obstacles() {
+        let crate_box = Obstacle {
+            min: Vec3::new(2., 0., -1.),
+            max: Vec3::new(3., 1., 1.),
+        };
+        let state = run_among(
+            MovementState::default(),
+            walk_towards_x(),
+            120,
+            &[crate_box],
+        );
+
+        let max_x = state.position.x + PLAYER_WIDTH / 2.;
+        assert!(max_x < 2. && max_x > 1.99, "{state:?}");
+
+        // And can stand on top of them
+        let state = MovementState {
+            position: Vec3::new(2.5, 3., 0.),
+            ..Default::default()
+        };
+        let state = run_among(state, MovementInput::default(), 120, &[crate_box]);
+        assert!(state.is_on_ground);
+        assert!(
+            state.position.y >= 1. && state.position.y < 1.01,
+            "{state:?}"
+        );
+    }
+
+    #[test]
+    fn entities_with_colliders_are_obstacles() {
+        let mut registry = EntityTypeRegistry::default();
+        registry.insert(entities::test_util::ball_type(0, 1.));
+        registry.insert(entities::test_util::entity_type(1, "Scenery"));
+        let player = EntityData {
+            controller: Some(entities::PlayerController::new(entities::PlayerId::new(0))),
+            entity_type: None,
+            ..entities::test_util::entity("player", 0, Vec3::ZERO)
+        };
+        let ball = entities::test_util::entity("ball", 0, Vec3::new(2., 0., 0.));
+        let scenery = entities::test_util::entity("scenery", 1, Vec3::new(1., 0., 0.));
+        let far = entities::test_util::entity("far", 0, Vec3::new(100., 0., 0.));
+
+        let obstacles = obstacles(
+            [&player, &ball, &scenery, &far],
+            &registry,
+            "player",
+            Vec3::ZERO,
+        );
+        assert_eq!(
+            obstacles,
+            vec![Obstacle {
+                min: Vec3::new(1.5, 0., -0.5),
+                max: Vec3::new(2.5, 1., 0.5),
+            }]
+        );
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let input = MovementInput {
+            move_direction: Vec2::new(0.3, 1.),
+            camera_yaw: 1.2,
+            jump: true,
+        };
+        let state = MovementState {
+            position: Vec3::new(0.5, 2., -3.),
+            ..Default::default()
+        };
+        assert_eq!(run(state, input, 200), run(state, input, 200));
+    }
+}
@@ -0,0 +1,429 @@
+//! Binary wire format for `ServerPacket`s and `ClientPacket`s
+//!
+//! Every message is a single version byte followed by a bincode payload (varint integers, little
+//! endian). Script state is carried using the adapters in `entities::script_value`.
+
+use {
+    bincode::Options,
+    serde::{de::DeserializeOwned, Serialize},
+};
+
+/// Bump this whenever a packet changes shape, so mismatched clients fail loudly
+pub const PROTOCOL_VERSION: u8 = 15;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("received an empty message")]
+    Empty,
+    #[error("protocol version mismatch: expected {expected}, received {received}")]
+    VersionMismatch { expected: u8, received: u8 },
+    #[error("malformed packet: {0}")]
+    Malformed(#[from] bincode::Error),
+}
+
+fn options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+}
+
+/// Encode a packet, prefixed with the protocol version
+pub fn encode<P: Serialize>(packet: &P) -> Result<Vec<u8>, CodecError> {
+    let options = options();
+    let mut message = Vec::with_capacity(1 + options.serialized_size(packet)? as usize);
+    message.push(PROTOCOL_VERSION);
+    options.serialize_into(&mut message, packet)?;
+    Ok(message)
+}
+
+/// Decode a packet produced by `encode`
+pub fn decode<P: DeserializeOwned>(message: &[u8]) -> Result<P, CodecError> {
+    let Some((&version, payload)) = message.split_first() else {
+        return Err(CodecError::Empty);
+    };
+
+    if version != PROTOCOL_VERSION {
+        return Err(CodecError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            received: version,
+        });
+    }
+
+    Ok(options().deserialize(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{patch::ScriptStatePatch, *},
+        blocks::{BlockGrid, BlockPos, BlockRegion, BlockRegistry, ChunkPos},
+        entities::{
+            Anchor, EntityData, EntityState, EntityTypeRegistry, Interaction, Joint, JointKind,
+            PlayerController, PlayerId,
+        },
+        serde_json::json,
+        std::collections::HashMap,
+    };
+
+    fn script_state() -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            ("health".into(), json!(100)),
+            ("velocity".into(), json!(-3)),
+            ("cooldown".into(), json!(0.25)),
+            ("team".into(), json!("red")),
+            ("spawnPosition".into(), json!([26, 1.5, 5])),
+            (
+                "pickups".into(),
+                json!({ "ammo": { "count": 3 }, "empty": null }),
+            ),
+            ("hasFlag".into(), json!(false)),
+        ])
+    }
+
+    fn entity_data() -> EntityData {
+        EntityData {
+            id: "14781514255198195871".into(),
+            name: "Red Flag".into(),
+            entity_type: Some(4),
+            model_path: "kibble_ctf/red_flag.gltf".into(),
+            state: EntityState {
+                position: glam::Vec3::new(26., 1., 5.),
+                rotation: glam::Quat::from_rotation_y(1.0),
+                velocity: glam::Vec3::new(0., -9.8, 0.),
+                anchor: Some(Anchor {
+                    parent_id: "2394817263548172635".into(),
+                    parent_node: Some("hand_right_anchor".into()),
+                }),
+                interactions: vec![Interaction {
+                    player_id: PlayerId::new(3),
+                    position: glam::Vec3::ONE,
+                    yaw: 0.5,
+                    pitch: -0.5,
+                    custom_state: script_state(),
+                }],
+                custom_state: script_state(),
+                ..Default::default()
+            },
+            controller: None,
+            joints: vec![Joint {
+                name: "hinge".into(),
+                kind: JointKind::Revolute,
+                other_entity: None,
+                local_anchor: glam::Vec3::new(0., 0., -0.5),
+                other_anchor: glam::Vec3::new(26., 1., 4.5),
+                axis: glam::Vec3::Y,
+                limits: Some([-1.5, 1.5]),
+                max_distance: 0.,
+            }],
+        }
+    }
+
+    fn player_data() -> EntityData {
+        EntityData {
+            id: "9076521358137610000".into(),
+            name: "Player 2".into(),
+            entity_type: None,
+            model_path: "kibble_ctf/player_red.gltf".into(),
+            state: EntityState {
+                position: glam::Vec3::new(1., 2., 3.),
+                custom_state: script_state(),
+                ..Default::default()
+            },
+            controller: Some(PlayerController {
+                animation_state: "idle".into(),
+                ..PlayerController::new(PlayerId::new(2))
+            }),
+            joints: vec![],
+        }
+    }
+
+    fn init() -> Init {
+        let mut blocks = BlockGrid::new();
+        blocks[BlockPos::new(1, 2, 3)] = 2;
+        blocks[BlockPos::new(-40, -1, 17)] = 3;
+
+        Init {
+            blocks,
+            block_registry: BlockRegistry::default(),
+            entities: HashMap::from([(entity_data().id, entity_data())]),
+            entity_type_registry: EntityTypeRegistry::default(),
+            client_player: PlayerId::new(7),
+            world_script_state: json!({ "redScore": 2, "redTeam": [0, 2], "blueSpawn": null }),
+        }
+    }
+
+    // Packets don't implement PartialEq, so compare their JSON representations instead
+    fn assert_round_trip<P: Serialize + DeserializeOwned + std::fmt::Debug>(packet: P) {
+        let encoded = encode(&packet).unwrap();
+        let decoded: P = decode(&encoded).unwrap();
+        assert_eq!(
+            serde_json::to_value(&packet).unwrap(),
+            serde_json::to_value(&decoded).unwrap(),
+            "{packet:?} did not survive a round trip"
+        );
+    }
+
+    #[test]
+    fn server_packets_round_trip() {
+        let packets: Vec<ServerPacket> = vec![
+            init().into(),
+            PatchWorldScriptState(ScriptStatePatch::diff(
+                &json!({ "scoreCooldown": 60, "redScore": 1 }),
+                &json!({ "scoreCooldown": 59 }),
+            ))
+            .into(),
+            ClientShouldSwitchMode::Play {
+                new_player_id: PlayerId::new(1),
+            }
+            .into(),
+            ClientShouldSwitchMode::Pause {
+                new_player_id: PlayerId::new(1),
+            }
+            .into(),
+            ClientShouldSwitchMode::Edit { world: init() }.into(),
+            SetBlock {
+                position: BlockPos::new(1, 2, 3),
+                block_id: 5,
+            }
+            .into(),
+            LoadChunk {
+                position: ChunkPos::new(-2, 0, 3),
+                chunk: init().blocks.chunk(ChunkPos::new(0, 0, 0)).unwrap().clone(),
+            }
+            .into(),
+            UnloadChunk {
+                position: ChunkPos::new(-2, 0, 3),
+            }
+            .into(),
+            AddEntity {
+                entity_id: entity_data().id,
+                entity_data: entity_data(),
+            }
+            .into(),
+            UpdateEntity {
+                entity_id: entity_data().id,
+                tick: 600,
+                position: Some(glam::Vec3::new(1., 2., 3.).into()),
+                rotation: Some(glam::Quat::from_rotation_x(0.3).into()),
+                scale: Some(glam::Vec3::splat(2.).into()),
+                anchor: Some(entity_data().state.anchor),
+                animation_state: None,
+                script_state: None,
+            }
+            .into(),
+            UpdateEntity {
+                entity_id: entity_data().id,
+                tick: 601,
+                position: None,
+                rotation: Some(glam::Quat::from_rotation_x(0.4).into()),
+                scale: None,
+                anchor: Some(None),
+                animation_state: None,
+                script_state: None,
+            }
+            .into(),
+            RemoveEntity {
+                entity_id: entity_data().id,
+            }
+            .into(),
+            AddEntity {
+                entity_id: player_data().id,
+                entity_data: player_data(),
+            }
+            .into(),
+            UpdateEntity {
+                entity_id: player_data().id,
+                tick: 600,
+                position: Some(glam::Vec3::new(1., 2., 3.).into()),
+                rotation: Some(glam::Quat::from_rotation_y(1.5).into()),
+                scale: None,
+                anchor: None,
+                animation_state: Some("run".into()),
+                script_state: Some(ScriptStatePatch::diff_map(&HashMap::new(), &script_state())),
+            }
+            .into(),
+            InputAck {
+                sequence: 1234,
+                movement: movement::MovementState {
+                    position: glam::Vec3::new(1., 2., 3.),
+                    velocity: glam::Vec3::new(0., -9.8, 0.),
+                    is_on_ground: false,
+                    coyote_time: 0.05,
+                    jump_input_time: 0.,
+                    jumped: true,
+                },
+            }
+            .into(),
+            ServerPacket::SetDebugLines(vec![DebugLine::new(glam::Vec3::ZERO, glam::Vec3::ONE)]),
+            PlaySound {
+                sound_id: "shoot".into(),
+                position: glam::Vec3::new(1., 2., 3.),
+                volume: 0.5,
+            }
+            .into(),
+            ServerPacket::Batch(vec![
+                UnloadChunk {
+                    position: ChunkPos::new(0, 0, 0),
+                }
+                .into(),
+                RemoveEntity {
+                    entity_id: player_data().id,
+                }
+                .into(),
+            ]),
+        ];
+
+        for packet in packets {
+            assert_round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn client_packets_round_trip() {
+        let packets = vec![
+            ClientPacket::Controls(Controls {
+                sequence: 1234,
+                move_direction: glam::Vec2::new(0.5, -1.),
+                jump: true,
+                fire: false,
+                camera_yaw: 3.1,
+                camera_pitch: -0.2,
+            }),
+            ClientPacket::Start,
+            ClientPacket::Pause,
+            ClientPacket::Edit,
+            ClientPacket::SetBlock(SetBlock {
+                position: BlockPos::new(1, 2, 3),
+                block_id: 0,
+            }),
+            ClientPacket::AddEntity(AddEntity {
+                entity_id: entity_data().id,
+                entity_data: entity_data(),
+            }),
+            ClientPacket::Undo,
+            ClientPacket::Redo,
+            ClientPacket::RemoveEntity(RemoveEntity {
+                entity_id: entity_data().id,
+            }),
+            ClientPacket::SetEntityTransform(SetEntityTransform {
+                entity_id: entity_data().id,
+                position: glam::Vec3::new(1., 2., 3.),
+                rotation: glam::Quat::from_rotation_y(1.),
+                scale: glam::Vec3::splat(2.),
+            }),
+            ClientPacket::RenameEntity(RenameEntity {
+                entity_id: entity_data().id,
+                name: "Red Flag".into(),
+            }),
+            ClientPacket::SetEntityCustomState(SetEntityCustomState {
+                entity_id: entity_data().id,
+                custom_state: HashMap::from([(
+                    "team".to_string(),
+                    json!({ "name": "red", "score": [1, 2.5, null] }),
+                )]),
+            }),
+            ClientPacket::SetBlocks(SetBlocks {
+                origin: BlockPos::new(-4, 0, 7),
+                operation: RegionOperation::Fill {
+                    size: glam::UVec3::new(2, 3, 4),
+                    block_id: 2,
+                },
+            }),
+            ClientPacket::SetBlocks(SetBlocks {
+                origin: BlockPos::new(-4, 0, 7),
+                operation: RegionOperation::Replace {
+                    size: glam::UVec3::new(2, 3, 4),
+                    from: 1,
+                    to: 0,
+                },
+            }),
+            ClientPacket::SetBlocks(SetBlocks {
+                origin: BlockPos::new(1, 2, 3),
+                operation: RegionOperation::Paste {
+                    blocks: BlockRegion::new(glam::UVec3::new(2, 1, 1), vec![1, 0]).unwrap(),
+                    entities: vec![entity_data()],
+                },
+            }),
+        ];
+
+        for packet in packets {
+            assert_round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        assert!(matches!(
+            decode::<ClientPacket>(&[]),
+            Err(CodecError::Empty)
+        ));
+
+        let mut message = encode(&ClientPacket::Start).unwrap();
+        message[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            decode::<ClientPacket>(&message),
+            Err(CodecError::VersionMismatch { .. })
+        ));
+
+        assert!(matches!(
+            decode::<ServerPacket>(&[PROTOCOL_VERSION, 200]),
+            Err(CodecError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn smaller_than_json() {
+        let position = glam::Vec3::new(1.1, 2.2, 3.3);
+        let rotation = glam::Quat::from_rotation_y(0.5);
+        let scale = glam::Vec3::ONE;
+        let packet: ServerPacket = UpdateEntity {
+            entity_id: entity_data().id,
+            tick: 600,
+            position: Some(position.into()),
+            rotation: Some(rotation.into()),
+            scale: Some(scale.into()),
+            anchor: Some(None),
+            animation_state: None,
+            script_state: None,
+        }
+        .into();
+
+        // Compared against the full precision transform, as the old JSON packets sent it
+        let binary = encode(&packet).unwrap();
+        let json = serde_json::to_vec(&json!({
+            "UpdateEntity": {
+                "entity_id": entity_data().id,
+                "position": position,
+                "rotation": rotation,
+                "scale": scale,
+                "anchor": null,
+            }
+        }))
+        .unwrap();
+        assert!(binary.len() * 2 < json.len());
+    }
+
+    #[test]
+    fn only_sends_dirty_fields() {
+        let update = |position: Option<glam::Vec3>| -> ServerPacket {
+            UpdateEntity {
+                entity_id: "1".into(),
+                tick: 600,
+                position: position.map(Into::into),
+                rotation: None,
+                scale: None,
+                anchor: None,
+                animation_state: None,
+                script_state: None,
+            }
+            .into()
+        };
+
+        let empty = encode(&update(None)).unwrap();
+        let moved = encode(&update(Some(glam::Vec3::new(1., 2., 3.)))).unwrap();
+
+        // Version, packet tag, entity ID, tick and the mask
+        assert_eq!(empty.len(), 1 + 1 + 2 + 3 + 1);
+        // Three varint coordinates, each small enough to fit in three bytes
+        assert_eq!(moved.len(), empty.len() + 3 * 3);
+    }
+}
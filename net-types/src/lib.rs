@@ -1,7 +1,16 @@
+pub mod codec;
+mod mask;
+pub mod patch;
+pub mod quantize;
+
 use {
-    blocks::{BlockGrid, BlockPos, BlockRegistry},
+    blocks::{BlockGrid, BlockPos, BlockRegion, BlockRegistry, BlockTypeID, Chunk, ChunkPos},
     derive_more::From,
     entities::{Anchor, EntityData, EntityID, EntityTypeRegistry, PlayerId},
+    mask::masked_serde,
+    movement::{MovementInput, MovementState},
+    patch::ScriptStatePatch,
+    quantize::{QuantizedQuat, QuantizedVec3},
     serde::{Deserialize, Serialize},
     std::collections::HashMap,
 };
@@ -9,6 +18,9 @@ use {
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 // Client's input state, sent greedily to the server
 pub struct Controls {
+    // Increases by one for every tick's worth of input, so the server can acknowledge them
+    #[serde(default)]
+    pub sequence: u32,
     pub move_direction: glam::Vec2,
     pub jump: bool,
     pub fire: bool,
@@ -16,53 +28,57 @@ pub struct Controls {
     pub camera_pitch: f32, // radians
 }
 
+impl Controls {
+    pub fn movement_input(&self) -> MovementInput {
+        MovementInput {
+            move_direction: self.move_direction,
+            camera_yaw: self.camera_yaw,
+            jump: self.jump,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ClientPacket {
     Controls(Controls),
     Start,
     Pause,
     Edit,
-    SetBlock(SetBlock),   // used by editor
-    AddEntity(AddEntity), // used by editor
+    SetBlock(SetBlock),                         // used by editor
+    AddEntity(AddEntity),                       // used by editor
+    Undo,                                       // used by editor
+    Redo,                                       // used by editor
+    RemoveEntity(RemoveEntity),                 // used by editor
+    SetEntityTransform(SetEntityTransform),     // used by editor
+    RenameEntity(RenameEntity),                 // used by editor
+    SetEntityCustomState(SetEntityCustomState), // used by editor
+    SetBlocks(SetBlocks),                       // used by editor
 }
 
 // Packets from the server to the client
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-/// Update a player's state
-pub struct UpdatePlayer {
-    pub id: PlayerId,
-    pub position: glam::Vec3,
-    pub facing_angle: f32,
-    // Included if the animation state has changed
-    pub animation_state: Option<String>,
-    // Included if the script state has changed
-    pub script_state: Option<HashMap<String, serde_json::Value>>,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-/// Send a new player to the game
-pub struct AddPlayer {
-    pub id: PlayerId,
-    pub position: glam::Vec3,
-    pub animation_state: String,
-    pub model_path: String,
-    pub script_state: HashMap<String, serde_json::Value>,
-}
-
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-/// Remove an existing player from the game
-pub struct RemovePlayer {
-    pub id: PlayerId,
+/// The last input the server processed for a client's player, and where it left the player. The
+/// client replays any newer inputs on top of this. Only sent to the player's own client, when the
+/// server has processed more of its input, or has moved the player again while its next input is
+/// late.
+pub struct InputAck {
+    pub sequence: u32,
+    pub movement: MovementState,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+/// Sent on join, and with `ClientShouldSwitchMode::Edit`
+///
+/// When playing, `blocks` and `entities` start out empty and are streamed in around the player
+/// with `LoadChunk` and `AddEntity`. The editor gets the whole world.
 pub struct Init {
     pub blocks: BlockGrid,
     pub block_registry: BlockRegistry,
     pub entities: HashMap<String, EntityData>,
     pub entity_type_registry: EntityTypeRegistry,
     pub client_player: PlayerId,
+    #[serde(with = "entities::script_value")]
     pub world_script_state: serde_json::Value,
 }
 
@@ -79,6 +95,107 @@ pub struct SetBlock {
     pub block_id: blocks::BlockTypeID,
 }
 
+/// The most blocks one `SetBlocks` can change, a 128 block cube
+pub const MAX_REGION_VOLUME: usize = 128 * 128 * 128;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Change a box of blocks at once in the editor. The server makes all of the change or none of it,
+/// then sends it back to the editor, which only changes its copy of the world then.
+pub struct SetBlocks {
+    /// The corner of the box with the smallest coordinates
+    pub origin: BlockPos,
+    pub operation: RegionOperation,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RegionOperation {
+    /// Set every block in the box, to `EMPTY_BLOCK` to clear it
+    Fill {
+        size: glam::UVec3,
+        block_id: BlockTypeID,
+    },
+    /// Change every block of one type in the box to another
+    Replace {
+        size: glam::UVec3,
+        from: BlockTypeID,
+        to: BlockTypeID,
+    },
+    /// Blocks and entities copied from somewhere else. The entities' positions are relative to the
+    /// origin, unless they're anchored.
+    Paste {
+        blocks: BlockRegion,
+        entities: Vec<EntityData>,
+    },
+}
+
+impl SetBlocks {
+    pub fn size(&self) -> glam::UVec3 {
+        match &self.operation {
+            RegionOperation::Fill { size, .. } | RegionOperation::Replace { size, .. } => *size,
+            RegionOperation::Paste { blocks, .. } => blocks.size(),
+        }
+    }
+
+    /// The blocks this sets, given the grid it's being applied to
+    pub fn blocks(&self, grid: &BlockGrid) -> Vec<(BlockPos, BlockTypeID)> {
+        match &self.operation {
+            RegionOperation::Fill { size, block_id } => {
+                blocks::region_positions(self.origin, *size)
+                    .map(|pos| (pos, *block_id))
+                    .collect()
+            }
+            RegionOperation::Replace { size, from, to } => {
+                blocks::region_positions(self.origin, *size)
+                    .filter(|&pos| grid.get(pos).copied().unwrap_or(blocks::EMPTY_BLOCK) == *from)
+                    .map(|pos| (pos, *to))
+                    .collect()
+            }
+            RegionOperation::Paste { blocks, .. } => blocks.blocks_at(self.origin).collect(),
+        }
+    }
+
+    /// The whole box once this has been applied, given what was in it before
+    pub fn region_after(&self, before: &BlockRegion) -> BlockRegion {
+        match &self.operation {
+            RegionOperation::Fill { block_id, .. } => before.map(|_| *block_id),
+            RegionOperation::Replace { from, to, .. } => {
+                before.map(|block| if block == *from { *to } else { block })
+            }
+            RegionOperation::Paste { blocks, .. } => blocks.clone(),
+        }
+    }
+
+    /// The entities this adds, where they end up in the world
+    pub fn entities(&self) -> Vec<EntityData> {
+        let RegionOperation::Paste { entities, .. } = &self.operation else {
+            return Vec::new();
+        };
+        entities
+            .iter()
+            .cloned()
+            .map(|mut entity| {
+                if entity.state.anchor.is_none() {
+                    entity.state.position += glam::Vec3::from(self.origin);
+                }
+                entity
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Send a chunk that has come into range of the client's player, replacing any existing copy
+pub struct LoadChunk {
+    pub position: ChunkPos,
+    pub chunk: Chunk,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// Tell the client to forget a chunk that has gone out of range
+pub struct UnloadChunk {
+    pub position: ChunkPos,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AddEntity {
     pub entity_id: EntityID,
@@ -91,14 +208,48 @@ pub struct RemoveEntity {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct UpdateEntity {
+/// Move, rotate or scale an entity in the editor. Relative to its anchor, if it has one.
+pub struct SetEntityTransform {
     pub entity_id: EntityID,
     pub position: glam::Vec3,
     pub rotation: glam::Quat,
     pub scale: glam::Vec3,
-    pub anchor: Option<Anchor>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Rename an entity in the editor
+pub struct RenameEntity {
+    pub entity_id: EntityID,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Replace an entity's script state in the editor
+pub struct SetEntityCustomState {
+    pub entity_id: EntityID,
+    #[serde(with = "entities::script_value::map")]
+    pub custom_state: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug)]
+/// Update an entity's state. Only the fields that have changed since the last update are sent.
+pub struct UpdateEntity {
+    pub entity_id: EntityID,
+    // The server tick this update is from, used by the client to interpolate
+    pub tick: u64,
+    pub position: Option<QuantizedVec3>,
+    pub rotation: Option<QuantizedQuat>,
+    pub scale: Option<QuantizedVec3>,
+    // `Some(None)` when the entity has been detached
+    pub anchor: Option<Option<Anchor>>,
+    // Players only, the animation their player script wants played
+    pub animation_state: Option<String>,
+    // Changes since the script state the client was last sent
+    pub script_state: Option<ScriptStatePatch>,
+}
+
+masked_serde!(UpdateEntity { entity_id, tick; position, rotation, scale, anchor, animation_state, script_state });
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DebugLine {
     pub start: glam::Vec3,
@@ -118,21 +269,119 @@ impl DebugLine {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// A script threw an exception, or returned something the server couldn't use. Shown in the editor.
+pub struct ScriptError {
+    /// The script that failed, relative to the world's scripts
+    pub script: String,
+    /// The exported function that was called, eg. `update`
+    pub function: String,
+    /// The entity the script was running for, if it's an entity script
+    pub entity_id: Option<EntityID>,
+    pub message: String,
+    /// Where the error was thrown, mapped back to the TypeScript if there's a source map
+    pub stack: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct SetWorldScriptState(pub serde_json::Value);
+/// Changes to the world script state since the client was last sent it
+pub struct PatchWorldScriptState(pub ScriptStatePatch);
 
 #[derive(Clone, Debug, Serialize, Deserialize, From)]
 pub enum ServerPacket {
     Init(Init),
-    SetWorldScriptState(SetWorldScriptState),
+    PatchWorldScriptState(PatchWorldScriptState),
     ClientShouldSwitchMode(ClientShouldSwitchMode),
     SetBlock(SetBlock),
-    AddPlayer(AddPlayer),
-    UpdatePlayer(UpdatePlayer),
-    RemovePlayer(RemovePlayer),
+    SetBlocks(SetBlocks),
+    LoadChunk(LoadChunk),
+    UnloadChunk(UnloadChunk),
     AddEntity(AddEntity),
     UpdateEntity(UpdateEntity),
     RemoveEntity(RemoveEntity),
+    InputAck(InputAck),
     SetDebugLines(Vec<DebugLine>),
     PlaySound(PlaySound),
+    ScriptError(ScriptError),
+    /// The server has reloaded the world's scripts after they changed on disk
+    ScriptsReloaded,
+    /// Everything sent to a client in a tick, in order. Batches are never nested.
+    Batch(Vec<ServerPacket>),
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, glam::UVec3};
+
+    fn entity(id: &str, position: glam::Vec3, anchor: Option<entities::Anchor>) -> EntityData {
+        EntityData {
+            id: id.into(),
+            name: id.into(),
+            entity_type: Some(1),
+            model_path: String::new(),
+            state: entities::EntityState {
+                position,
+                anchor,
+                ..Default::default()
+            },
+            controller: None,
+            joints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn replace_only_changes_matching_blocks() {
+        let mut grid = BlockGrid::new();
+        grid[BlockPos::new(0, 0, 0)] = 1;
+        grid[BlockPos::new(1, 0, 0)] = 2;
+        grid[BlockPos::new(5, 0, 0)] = 1;
+
+        let set_blocks = SetBlocks {
+            origin: BlockPos::new(0, 0, 0),
+            operation: RegionOperation::Replace {
+                size: UVec3::new(3, 1, 1),
+                from: 1,
+                to: 3,
+            },
+        };
+        assert_eq!(set_blocks.blocks(&grid), vec![(BlockPos::new(0, 0, 0), 3)]);
+
+        let before = BlockRegion::copy(&grid, BlockPos::new(0, 0, 0), UVec3::new(3, 1, 1));
+        assert_eq!(
+            set_blocks.region_after(&before),
+            BlockRegion::new(UVec3::new(3, 1, 1), vec![3, 2, 0]).unwrap()
+        );
+    }
+
+    #[test]
+    fn pasted_entities_move_with_the_origin() {
+        let loose = entity("loose", glam::Vec3::new(0.5, 0., 1.5), None);
+        let anchor = entities::Anchor {
+            parent_id: "loose".into(),
+            parent_node: None,
+        };
+        let anchored = entity("anchored", glam::Vec3::new(0., 1., 0.), Some(anchor));
+
+        let set_blocks = SetBlocks {
+            origin: BlockPos::new(10, 0, -4),
+            operation: RegionOperation::Paste {
+                blocks: BlockRegion::new(UVec3::new(1, 1, 2), vec![1, 0]).unwrap(),
+                entities: vec![loose, anchored],
+            },
+        };
+        assert_eq!(
+            set_blocks.blocks(&BlockGrid::new()),
+            vec![(BlockPos::new(10, 0, -4), 1), (BlockPos::new(10, 0, -3), 0)]
+        );
+        let positions = set_blocks
+            .entities()
+            .into_iter()
+            .map(|entity| entity.state.position)
+            .collect::<Vec<_>>();
+        // Anchored entities are relative to their parents, wherever they're pasted
+        assert_eq!(
+            positions,
+            vec![glam::Vec3::new(10.5, 0., -2.5), glam::Vec3::new(0., 1., 0.)]
+        );
+    }
 }
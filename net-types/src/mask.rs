@@ -0,0 +1,96 @@
+//! Dirty mask serialization for update packets
+//!
+//! Update packets mostly consist of optional fields, only a few of which are set at a time. Rather
+//! than a tag byte per field, `masked_serde!` writes a single bitmask of which fields are present
+//! followed by just those fields.
+
+use serde::de::{Error, SeqAccess};
+
+// Used by the generated code
+pub(crate) fn next_element<'de, T: serde::Deserialize<'de>, A: SeqAccess<'de>>(
+    seq: &mut A,
+    field: &'static str,
+) -> Result<T, A::Error> {
+    seq.next_element()?
+        .ok_or_else(|| A::Error::missing_field(field))
+}
+
+/// Implement `Serialize` and `Deserialize` for a struct whose optional fields are sent behind a
+/// dirty mask. The fields before the `;` are always sent, those after it must be `Option`s (at most
+/// eight of them).
+macro_rules! masked_serde {
+    ($name:ident { $($field:ident),* ; $($optional:ident),* $(,)? }) => {
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use serde::ser::SerializeTuple;
+
+                let mut mask: u8 = 0;
+                let mut bit: u8 = 1;
+                let mut len = [$(stringify!($field)),*].len() + 1;
+                $(
+                    if self.$optional.is_some() {
+                        mask |= bit;
+                        len += 1;
+                    }
+                    bit = bit.wrapping_shl(1);
+                )*
+                let _ = bit;
+
+                let mut tuple = serializer.serialize_tuple(len)?;
+                $(tuple.serialize_element(&self.$field)?;)*
+                tuple.serialize_element(&mask)?;
+                $(
+                    if let Some(value) = &self.$optional {
+                        tuple.serialize_element(value)?;
+                    }
+                )*
+                tuple.end()
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct Visitor;
+
+                impl<'de> serde::de::Visitor<'de> for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        formatter.write_str(concat!("a masked ", stringify!($name)))
+                    }
+
+                    fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                        self,
+                        mut seq: A,
+                    ) -> Result<$name, A::Error> {
+                        use $crate::mask::next_element;
+
+                        $(let $field = next_element(&mut seq, stringify!($field))?;)*
+                        let mask: u8 = next_element(&mut seq, "mask")?;
+                        let mut bit: u8 = 1;
+                        $(
+                            let $optional = if mask & bit != 0 {
+                                Some(next_element(&mut seq, stringify!($optional))?)
+                            } else {
+                                None
+                            };
+                            bit = bit.wrapping_shl(1);
+                        )*
+                        let _ = bit;
+
+                        Ok($name {
+                            $($field,)*
+                            $($optional,)*
+                        })
+                    }
+                }
+
+                // The length is the most elements there could be, only those in the mask are read
+                let len = [$(stringify!($field),)* "mask", $(stringify!($optional)),*].len();
+                deserializer.deserialize_tuple(len, Visitor)
+            }
+        }
+    };
+}
+
+pub(crate) use masked_serde;
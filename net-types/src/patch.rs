@@ -0,0 +1,285 @@
+//! JSON-patch style diffs for script state
+//!
+//! Scripts tend to change one or two keys in their state at a time, so rather than resending the
+//! whole state we send the changes against what the client already has. Paths are JSON pointers
+//! (RFC 6901). Arrays aren't diffed, they're replaced whole if anything in them changes.
+
+use {
+    serde::{Deserialize, Serialize},
+    serde_json::{Map, Value},
+    std::collections::HashMap,
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PatchOp {
+    /// Set the value at `path`, adding it if it doesn't exist
+    Replace {
+        path: String,
+        #[serde(with = "entities::script_value")]
+        value: Value,
+    },
+    /// Remove the key at `path`
+    Remove { path: String },
+}
+
+impl PatchOp {
+    fn path(&self) -> &str {
+        match self {
+            PatchOp::Replace { path, .. } | PatchOp::Remove { path } => path,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("invalid patch path {0:?}")]
+    InvalidPath(String),
+    #[error("patch path {0:?} does not exist")]
+    MissingPath(String),
+    #[error("patch path {0:?} is not inside an object")]
+    NotAnObject(String),
+}
+
+/// The changes from one script state to another
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScriptStatePatch(pub Vec<PatchOp>);
+
+impl ScriptStatePatch {
+    /// The patch that turns `old` into `new`
+    pub fn diff(old: &Value, new: &Value) -> Self {
+        let mut ops = Vec::new();
+        diff_value(&mut ops, "", old, new);
+        Self(ops)
+    }
+
+    /// Like `diff`, for script state that's kept as a map of keys to values
+    pub fn diff_map(old: &HashMap<String, Value>, new: &HashMap<String, Value>) -> Self {
+        let mut ops = Vec::new();
+        diff_object(&mut ops, "", old, new);
+        Self(ops)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Apply the patch to a copy of the state it was made against
+    pub fn apply(&self, target: &mut Value) -> Result<(), PatchError> {
+        for op in &self.0 {
+            let tokens = parse_pointer(op.path())?;
+            apply_op(target, &tokens, op)?;
+        }
+        Ok(())
+    }
+
+    /// Like `apply`, for script state that's kept as a map of keys to values
+    pub fn apply_map(&self, target: &mut HashMap<String, Value>) -> Result<(), PatchError> {
+        for op in &self.0 {
+            let tokens = parse_pointer(op.path())?;
+            let Some((key, rest)) = tokens.split_first() else {
+                // Replacing the whole state
+                let PatchOp::Replace {
+                    value: Value::Object(map),
+                    ..
+                } = op
+                else {
+                    return Err(PatchError::NotAnObject(op.path().into()));
+                };
+                *target = map.clone().into_iter().collect();
+                continue;
+            };
+
+            if rest.is_empty() {
+                match op {
+                    PatchOp::Replace { value, .. } => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                    PatchOp::Remove { .. } => {
+                        target.remove(key);
+                    }
+                }
+            } else {
+                let child = target
+                    .get_mut(key)
+                    .ok_or_else(|| PatchError::MissingPath(op.path().into()))?;
+                apply_op(child, rest, op)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lets maps and JSON objects be diffed the same way
+trait Object {
+    fn get_value(&self, key: &str) -> Option<&Value>;
+    fn entries(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_>;
+}
+
+impl Object for Map<String, Value> {
+    fn get_value(&self, key: &str) -> Option<&Value> {
+        self.get(key)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+impl Object for HashMap<String, Value> {
+    fn get_value(&self, key: &str) -> Option<&Value> {
+        self.get(key)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+fn diff_value(ops: &mut Vec<PatchOp>, path: &str, old: &Value, new: &Value) {
+    if old == new {
+        return;
+    }
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => diff_object(ops, path, old, new),
+        _ => ops.push(PatchOp::Replace {
+            path: path.into(),
+            value: new.clone(),
+        }),
+    }
+}
+
+fn diff_object(ops: &mut Vec<PatchOp>, path: &str, old: &impl Object, new: &impl Object) {
+    for (key, _) in old.entries() {
+        if new.get_value(key).is_none() {
+            ops.push(PatchOp::Remove {
+                path: child_path(path, key),
+            });
+        }
+    }
+
+    for (key, new_value) in new.entries() {
+        let path = child_path(path, key);
+        match old.get_value(key) {
+            Some(old_value) => diff_value(ops, &path, old_value, new_value),
+            None => ops.push(PatchOp::Replace {
+                path,
+                value: new_value.clone(),
+            }),
+        }
+    }
+}
+
+fn apply_op(target: &mut Value, tokens: &[String], op: &PatchOp) -> Result<(), PatchError> {
+    let Some((last, parents)) = tokens.split_last() else {
+        *target = match op {
+            PatchOp::Replace { value, .. } => value.clone(),
+            PatchOp::Remove { .. } => Value::Null,
+        };
+        return Ok(());
+    };
+
+    let mut current = target;
+    for token in parents {
+        current = current
+            .get_mut(token.as_str())
+            .ok_or_else(|| PatchError::MissingPath(op.path().into()))?;
+    }
+
+    let Value::Object(map) = current else {
+        return Err(PatchError::NotAnObject(op.path().into()));
+    };
+    match op {
+        PatchOp::Replace { value, .. } => {
+            map.insert(last.clone(), value.clone());
+        }
+        PatchOp::Remove { .. } => {
+            map.remove(last);
+        }
+    }
+    Ok(())
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn parse_pointer(path: &str) -> Result<Vec<String>, PatchError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(path) = path.strip_prefix('/') else {
+        return Err(PatchError::InvalidPath(path.into()));
+    };
+
+    Ok(path
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[test]
+    fn patches_values() {
+        let old = json!({
+            "score": 1,
+            "teams": { "red": [1, 2], "blue": [3] },
+            "flag/holder": "red",
+            "gone": true,
+        });
+        let new = json!({
+            "score": 2,
+            "teams": { "red": [1, 2], "blue": [3, 4] },
+            "flag/holder": null,
+            "new": { "nested": "value" },
+        });
+
+        let patch = ScriptStatePatch::diff(&old, &new);
+        // Only the changed keys are sent
+        assert_eq!(patch.0.len(), 5, "{patch:?}");
+
+        let mut patched = old.clone();
+        patch.apply(&mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        assert!(ScriptStatePatch::diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn patches_maps() {
+        let old = HashMap::from([
+            ("health".to_string(), json!(5)),
+            ("ammo".to_string(), json!({ "gun": 10, "shotgun": 2 })),
+        ]);
+        let new = HashMap::from([
+            ("health".to_string(), json!(5)),
+            ("ammo".to_string(), json!({ "gun": 9, "shotgun": 2 })),
+        ]);
+
+        let patch = ScriptStatePatch::diff_map(&old, &new);
+        assert_eq!(
+            patch.0,
+            vec![PatchOp::Replace {
+                path: "/ammo/gun".into(),
+                value: json!(9)
+            }]
+        );
+
+        let mut patched = old.clone();
+        patch.apply_map(&mut patched).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn replaces_the_root() {
+        let new = json!({ "redScore": 0 });
+        let patch = ScriptStatePatch::diff(&Value::Null, &new);
+
+        let mut patched = Value::Null;
+        patch.apply(&mut patched).unwrap();
+        assert_eq!(patched, new);
+    }
+}
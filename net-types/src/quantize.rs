@@ -0,0 +1,181 @@
+//! Lossy, compact encodings for positions, rotations and angles in update packets
+//!
+//! These are only used for things the client displays. Anything the client simulates with (like
+//! `InputAck`) is sent at full precision, otherwise prediction would drift.
+
+use {
+    glam::{IVec3, Quat, Vec3},
+    serde::{Deserialize, Serialize},
+    std::f32::consts::{FRAC_1_SQRT_2, TAU},
+};
+
+/// Positions are rounded to the nearest 1/512th of a block
+const POSITION_SCALE: f32 = 512.;
+
+// Bits per component of a smallest-three quaternion
+const QUAT_COMPONENT_BITS: u32 = 10;
+const QUAT_COMPONENT_MAX: u32 = (1 << QUAT_COMPONENT_BITS) - 1;
+
+/// A `Vec3` in fixed point. Small values encode to fewer bytes, since the codec uses varints.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedVec3(IVec3);
+
+impl From<Vec3> for QuantizedVec3 {
+    fn from(value: Vec3) -> Self {
+        Self((value * POSITION_SCALE).round().as_ivec3())
+    }
+}
+
+impl From<QuantizedVec3> for Vec3 {
+    fn from(value: QuantizedVec3) -> Self {
+        value.0.as_vec3() / POSITION_SCALE
+    }
+}
+
+/// A unit quaternion packed into 32 bits with the "smallest three" method
+///
+/// The largest component is left out, and recovered from the other three since the quaternion is
+/// unit length. The top two bits hold which component was dropped, and the remaining three are
+/// stored in 10 bits each.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "[u8; 4]", into = "[u8; 4]")]
+pub struct QuantizedQuat(u32);
+
+impl From<Quat> for QuantizedQuat {
+    fn from(value: Quat) -> Self {
+        let components = value.normalize().to_array();
+        let largest = (0..4)
+            .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+            .unwrap();
+
+        // q and -q are the same rotation, so flip it to make the dropped component positive
+        let sign = components[largest].signum();
+
+        let mut packed = largest as u32;
+        for (index, component) in components.into_iter().enumerate() {
+            if index == largest {
+                continue;
+            }
+            // The other components are all within ±1/√2
+            let normalized = (component * sign / FRAC_1_SQRT_2 + 1.) / 2.;
+            let quantized = (normalized * QUAT_COMPONENT_MAX as f32).round() as u32;
+            packed = (packed << QUAT_COMPONENT_BITS) | quantized.min(QUAT_COMPONENT_MAX);
+        }
+
+        Self(packed)
+    }
+}
+
+impl From<QuantizedQuat> for Quat {
+    fn from(value: QuantizedQuat) -> Self {
+        let largest = (value.0 >> (QUAT_COMPONENT_BITS * 3)) as usize;
+
+        let mut components = [0.; 4];
+        let mut sum_of_squares = 0.;
+        let mut shift = QUAT_COMPONENT_BITS * 3;
+        for (index, component) in components.iter_mut().enumerate() {
+            if index == largest {
+                continue;
+            }
+            shift -= QUAT_COMPONENT_BITS;
+            let quantized = (value.0 >> shift) & QUAT_COMPONENT_MAX;
+            let normalized = quantized as f32 / QUAT_COMPONENT_MAX as f32;
+            *component = (normalized * 2. - 1.) * FRAC_1_SQRT_2;
+            sum_of_squares += *component * *component;
+        }
+        components[largest] = (1. - sum_of_squares).max(0.).sqrt();
+
+        Quat::from_array(components).normalize()
+    }
+}
+
+impl From<[u8; 4]> for QuantizedQuat {
+    fn from(value: [u8; 4]) -> Self {
+        Self(u32::from_le_bytes(value))
+    }
+}
+
+impl From<QuantizedQuat> for [u8; 4] {
+    fn from(value: QuantizedQuat) -> Self {
+        value.0.to_le_bytes()
+    }
+}
+
+/// An angle in radians, stored as a fraction of a full turn in 16 bits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "[u8; 2]", into = "[u8; 2]")]
+pub struct QuantizedAngle(u16);
+
+impl From<f32> for QuantizedAngle {
+    fn from(value: f32) -> Self {
+        let turns = value.rem_euclid(TAU) / TAU;
+        Self(((turns * 65536.).round() as u32 % 65536) as u16)
+    }
+}
+
+impl From<QuantizedAngle> for f32 {
+    fn from(value: QuantizedAngle) -> Self {
+        value.0 as f32 / 65536. * TAU
+    }
+}
+
+impl From<[u8; 2]> for QuantizedAngle {
+    fn from(value: [u8; 2]) -> Self {
+        Self(u16::from_le_bytes(value))
+    }
+}
+
+impl From<QuantizedAngle> for [u8; 2] {
+    fn from(value: QuantizedAngle) -> Self {
+        value.0.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_close() {
+        for position in [
+            Vec3::ZERO,
+            Vec3::new(1.25, -3.5, 1000.),
+            Vec3::new(-0.0013, 12.3456, -789.01),
+        ] {
+            let quantized = Vec3::from(QuantizedVec3::from(position));
+            assert!(quantized.abs_diff_eq(position, 0.5 / POSITION_SCALE));
+        }
+    }
+
+    #[test]
+    fn rotations_are_close() {
+        let rotations = [
+            Quat::IDENTITY,
+            Quat::from_rotation_y(1.0),
+            Quat::from_rotation_x(-2.5) * Quat::from_rotation_z(0.7),
+            -Quat::from_rotation_y(3.0),
+            Quat::from_xyzw(0.5, 0.5, -0.5, 0.5),
+        ];
+
+        for rotation in rotations {
+            let quantized = Quat::from(QuantizedQuat::from(rotation));
+            // Less than half a degree out
+            assert!(
+                quantized.angle_between(rotation) < 0.5_f32.to_radians(),
+                "{rotation:?} became {quantized:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn angles_are_close() {
+        for angle in [0., 1., -1., 3.14, TAU - 0.0001, 10.] {
+            let quantized = f32::from(QuantizedAngle::from(angle));
+            let difference = (quantized - angle).rem_euclid(TAU);
+            assert!(
+                difference.min(TAU - difference) < 0.001,
+                "{angle} became {quantized}"
+            );
+        }
+    }
+}
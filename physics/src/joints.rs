@@ -0,0 +1,224 @@
+use {
+    crate::{glam_to_na, PhysicsWorld},
+    entities::{EntityData, EntityID, Joint, JointKind},
+    nalgebra::{Point3, Unit},
+    rapier3d::dynamics::{
+        FixedJointBuilder, GenericJoint, PrismaticJointBuilder, RevoluteJointBuilder,
+        RigidBodyHandle, RopeJointBuilder,
+    },
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum JointError {
+    #[error("entity {0} has no body")]
+    NoBody(EntityID),
+    #[error("the joint's axis is zero")]
+    ZeroAxis,
+}
+
+// Joints are removed by rapier along with either of their bodies, so despawning an entity takes
+// care of the joints at both ends. We only have to forget their handles.
+impl PhysicsWorld {
+    /// Join an entity's body to another entity's, or to the world. Replaces any joint the entity
+    /// already has with the same name.
+    pub fn add_joint(&mut self, entity_id: &str, joint: &Joint) -> Result<(), JointError> {
+        let body1 = self.entity_body_handle(entity_id)?;
+        let body2 = match &joint.other_entity {
+            Some(other_entity) => self.entity_body_handle(other_entity)?,
+            None => self.world_body,
+        };
+        let data = joint_data(joint)?;
+
+        self.remove_joint(entity_id, &joint.name);
+        let handle = self.impulse_joints.insert(body1, body2, data, true);
+        self.entity_joints
+            .entry(entity_id.to_string())
+            .or_default()
+            .insert(joint.name.clone(), handle);
+        Ok(())
+    }
+
+    /// Remove one of an entity's joints, if it has it
+    pub fn remove_joint(&mut self, entity_id: &str, name: &str) {
+        let Some(handle) = self
+            .entity_joints
+            .get_mut(entity_id)
+            .and_then(|joints| joints.remove(name))
+        else {
+            return;
+        };
+        self.impulse_joints.remove(handle, true);
+    }
+
+    /// Add all of an entity's joints. The entities at the other ends need to have been spawned
+    /// already, so when spawning a whole world, do this once everything else is spawned.
+    pub fn add_entity_joints(&mut self, entity_data: &EntityData) {
+        for joint in &entity_data.joints {
+            if let Err(e) = self.add_joint(&entity_data.id, joint) {
+                tracing::warn!(
+                    "Couldn't add joint {} to entity {}: {e}",
+                    joint.name,
+                    entity_data.id
+                );
+            }
+        }
+    }
+
+    // Forget the joints that went with an entity's body
+    pub(crate) fn forget_removed_joints(&mut self, entity_id: &str) {
+        self.entity_joints.remove(entity_id);
+        for joints in self.entity_joints.values_mut() {
+            joints.retain(|_, handle| self.impulse_joints.contains(*handle));
+        }
+    }
+
+    fn entity_body_handle(&self, entity_id: &str) -> Result<RigidBodyHandle, JointError> {
+        self.entity_bodies
+            .get(entity_id)
+            .map(|body| body.handle)
+            .ok_or_else(|| JointError::NoBody(entity_id.to_string()))
+    }
+}
+
+// The bodies at either end of a joint never collide with each other, otherwise a chain of boxes
+// would push itself apart
+fn joint_data(joint: &Joint) -> Result<GenericJoint, JointError> {
+    let anchor1 = Point3::from(glam_to_na(joint.local_anchor));
+    let anchor2 = Point3::from(glam_to_na(joint.other_anchor));
+    let axis = || Unit::try_new(glam_to_na(joint.axis), f32::EPSILON).ok_or(JointError::ZeroAxis);
+
+    let data = match joint.kind {
+        JointKind::Fixed => FixedJointBuilder::new()
+            .local_anchor1(anchor1)
+            .local_anchor2(anchor2)
+            .contacts_enabled(false)
+            .into(),
+        JointKind::Revolute => {
+            let mut builder = RevoluteJointBuilder::new(axis()?)
+                .local_anchor1(anchor1)
+                .local_anchor2(anchor2)
+                .contacts_enabled(false);
+            if let Some(limits) = joint.limits {
+                builder = builder.limits(limits);
+            }
+            builder.into()
+        }
+        JointKind::Prismatic => {
+            let mut builder = PrismaticJointBuilder::new(axis()?)
+                .local_anchor1(anchor1)
+                .local_anchor2(anchor2)
+                .contacts_enabled(false);
+            if let Some(limits) = joint.limits {
+                builder = builder.limits(limits);
+            }
+            builder.into()
+        }
+        JointKind::Rope => RopeJointBuilder::new(joint.max_distance)
+            .local_anchor1(anchor1)
+            .local_anchor2(anchor2)
+            .contacts_enabled(false)
+            .into(),
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        entities::{test_util, EntityTypeRegistry},
+        std::collections::HashMap,
+    };
+
+    fn ball_registry() -> EntityTypeRegistry {
+        let mut registry = EntityTypeRegistry::default();
+        registry.insert(test_util::ball_type(0, 0.5));
+        registry
+    }
+
+    fn ball(id: &str, position: glam::Vec3, joints: Vec<Joint>) -> EntityData {
+        EntityData {
+            joints,
+            ..test_util::entity(id, 0, position)
+        }
+    }
+
+    fn rope(name: &str, other_entity: Option<&str>, other_anchor: glam::Vec3) -> Joint {
+        Joint {
+            name: name.into(),
+            kind: JointKind::Rope,
+            other_entity: other_entity.map(Into::into),
+            local_anchor: glam::Vec3::ZERO,
+            other_anchor,
+            axis: glam::Vec3::Y,
+            limits: None,
+            max_distance: 2.,
+        }
+    }
+
+    #[test]
+    fn ropes_hold_entities_up() {
+        let registry = ball_registry();
+        let mut physics_world = PhysicsWorld::new();
+        let anchor = glam::Vec3::new(0., 10., 0.);
+        let mut entities = HashMap::from([
+            (
+                "1".to_string(),
+                ball("1", anchor, vec![rope("rope", None, anchor)]),
+            ),
+            (
+                "2".to_string(),
+                ball("2", anchor + glam::Vec3::X * 5., vec![]),
+            ),
+        ]);
+        for entity in entities.values() {
+            physics_world.spawn_entity(entity, &registry);
+        }
+        for entity in entities.values() {
+            physics_world.add_entity_joints(entity);
+        }
+
+        for _ in 0..120 {
+            physics_world.step(&mut entities, &registry);
+        }
+
+        // The ball on the rope hangs at the end of it, the other one keeps falling
+        let hanging = entities["1"].state.position;
+        assert!(
+            (hanging - (anchor - glam::Vec3::Y * 2.)).length() < 0.1,
+            "{hanging}"
+        );
+        let falling = entities["2"].state.position;
+        assert!(falling.y < 0., "{falling}");
+    }
+
+    #[test]
+    fn joints_are_removed_with_either_entity() {
+        let registry = ball_registry();
+        let mut physics_world = PhysicsWorld::new();
+        let chain = ball(
+            "1",
+            glam::Vec3::ZERO,
+            vec![rope("link", Some("2"), glam::Vec3::ZERO)],
+        );
+        physics_world.spawn_entity(&chain, &registry);
+        physics_world.spawn_entity(&ball("2", glam::Vec3::X, vec![]), &registry);
+        physics_world.add_entity_joints(&chain);
+        assert_eq!(physics_world.impulse_joints.len(), 1);
+
+        // Adding a joint with the same name replaces it
+        physics_world
+            .add_joint("1", &rope("link", Some("2"), glam::Vec3::Y))
+            .unwrap();
+        assert_eq!(physics_world.impulse_joints.len(), 1);
+
+        physics_world.despawn_entity("2");
+        assert_eq!(physics_world.impulse_joints.len(), 0);
+        assert!(physics_world.entity_joints["1"].is_empty());
+
+        assert!(matches!(
+            physics_world.add_joint("1", &rope("link", Some("2"), glam::Vec3::ZERO)),
+            Err(JointError::NoBody(_))
+        ));
+    }
+}
@@ -1,23 +1,32 @@
-use blocks::BlockTypeID;
-use entities::{EntityData, EntityID, EntityPhysicsProperties, EntityState, EntityTypeRegistry};
+use blocks::{Chunk, ChunkPos};
+use entities::{
+    EntityData, EntityID, EntityPhysicsProperties, EntityState, EntityTypeRegistry, PlayerId,
+};
 use glam::Vec3Swizzles;
-use nalgebra::{point, vector, Vector3};
+use nalgebra::{vector, Vector3};
 use rapier3d::{
-    dynamics::RigidBodyHandle,
+    dynamics::{ImpulseJointHandle, RigidBodyHandle},
     geometry::{Group, InteractionGroups},
     math::{Point, Vector},
     parry::query::ShapeCastOptions,
     pipeline::QueryFilter,
     prelude::{
-        ActiveCollisionTypes, CCDSolver, Collider, ColliderBuilder, ColliderHandle, ColliderSet,
-        DebugRenderBackend, DebugRenderMode, DebugRenderObject, DebugRenderPipeline,
-        DefaultBroadPhase, ImpulseJointSet, IntegrationParameters, IslandManager,
-        MultibodyJointSet, NarrowPhase, PhysicsPipeline, QueryPipeline, Real, RigidBody,
-        RigidBodyBuilder, RigidBodySet, RigidBodyType,
+        ActiveCollisionTypes, ActiveEvents, CCDSolver, Collider, ColliderBuilder, ColliderHandle,
+        ColliderSet, ContactPair, DebugRenderBackend, DebugRenderMode, DebugRenderObject,
+        DebugRenderPipeline, DefaultBroadPhase, EventHandler, ImpulseJointSet,
+        IntegrationParameters, IslandManager, MultibodyJointSet, NarrowPhase, PhysicsPipeline,
+        QueryPipeline, Real, RigidBody, RigidBodyBuilder, RigidBodySet, RigidBodyType,
     },
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Mutex};
+
+mod joints;
+mod query;
+mod terrain;
+
+pub use joints::JointError;
+pub use query::{HitFilter, Overlap, QueryHit, QueryShape};
 
 const BLOCK_GROUP: Group = Group::GROUP_1;
 const PLAYER_GROUP: Group = Group::GROUP_2;
@@ -38,7 +47,7 @@ pub struct PhysicsWorld {
     ccd_solver: CCDSolver,
     query_pipeline: QueryPipeline,
     physics_hooks: (),
-    event_handler: (),
+    event_handler: CollisionEventCollector,
 
     // Physics objects
     islands: IslandManager,
@@ -46,10 +55,18 @@ pub struct PhysicsWorld {
     colliders: ColliderSet,
     impulse_joints: ImpulseJointSet,
     multibody_joints: MultibodyJointSet,
-    pub player_handles: HashMap<u64, RigidBodyHandle>,
     debug: DebugRenderPipeline,
     debug_lines: Vec<net_types::DebugLine>,
     pub entity_bodies: HashMap<EntityID, PhysicsBody>,
+    // Each entity's joints, by name
+    entity_joints: HashMap<EntityID, HashMap<String, ImpulseJointHandle>>,
+    // A fixed body at the origin, for joints that pin entities to the world
+    world_body: RigidBodyHandle,
+    // The colliders for each chunk's blocks
+    terrain_colliders: HashMap<ChunkPos, Vec<ColliderHandle>>,
+    // What each collider belongs to. Removed colliders are kept until their collision events have
+    // been taken, as a collision that ended because one side was removed is still reported.
+    collider_owners: HashMap<ColliderHandle, (CollisionTarget, String)>,
 }
 
 impl Drop for PhysicsWorld {
@@ -74,14 +91,15 @@ impl PhysicsWorld {
         let ccd_solver = CCDSolver::new();
         let query_pipeline = QueryPipeline::new();
         let physics_hooks = ();
-        let event_handler = ();
+        let event_handler = CollisionEventCollector::default();
 
         // Physics object sets
         let islands = IslandManager::new();
-        let bodies = RigidBodySet::new();
+        let mut bodies = RigidBodySet::new();
         let colliders = ColliderSet::new();
         let impulse_joints = ImpulseJointSet::new();
         let multibody_joints = MultibodyJointSet::new();
+        let world_body = bodies.insert(RigidBodyBuilder::fixed().build());
 
         PhysicsWorld {
             gravity,
@@ -98,10 +116,13 @@ impl PhysicsWorld {
             query_pipeline,
             physics_hooks,
             event_handler,
-            player_handles: Default::default(),
             debug: DebugRenderPipeline::new(Default::default(), DebugRenderMode::COLLIDER_SHAPES),
             debug_lines: Default::default(),
             entity_bodies: Default::default(),
+            entity_joints: Default::default(),
+            world_body,
+            terrain_colliders: Default::default(),
+            collider_owners: Default::default(),
         }
     }
 
@@ -112,13 +133,15 @@ impl PhysicsWorld {
     ) {
         self.debug_lines.clear();
 
-        // 1) Update non-dynamic bodies
+        // 1) Update non-dynamic bodies, which includes players
         for (_, entity_data) in entities.iter_mut() {
-            let entity_type = entity_type_registry
-                .get(entity_data.entity_type)
-                .expect("Entity type ID doesn't exist?");
+            if entity_data.controller.is_some() {
+                self.sync_entity(entity_data);
+                continue;
+            }
 
-            let Some(physics_properties) = entity_type.physics_properties() else {
+            let Some(physics_properties) = physics_properties(entity_data, entity_type_registry)
+            else {
                 continue;
             };
 
@@ -146,11 +169,8 @@ impl PhysicsWorld {
 
         // 3) Update dynamic bodies
         for (_, entity_data) in entities.iter_mut() {
-            let entity_type = entity_type_registry
-                .get(entity_data.entity_type)
-                .expect("Entity type ID doesn't exist?");
-
-            let Some(physics_properties) = entity_type.physics_properties() else {
+            let Some(physics_properties) = physics_properties(entity_data, entity_type_registry)
+            else {
                 continue;
             };
 
@@ -160,6 +180,80 @@ impl PhysicsWorld {
         }
     }
 
+    /// Entities that started or stopped touching something since this was last called
+    pub fn take_collision_events(&mut self) -> Vec<CollisionEvent> {
+        let events = std::mem::take(&mut *self.event_handler.events.lock().expect("Deadlock!"));
+
+        let mut collision_events = Vec::new();
+        for event in events {
+            let (collider1, collider2) = (event.collider1(), event.collider2());
+            for (collider, other_collider) in [(collider1, collider2), (collider2, collider1)] {
+                // Entities that have been removed aren't told about it
+                if !self.colliders.contains(collider) {
+                    continue;
+                }
+                let Some((CollisionTarget::Entity, entity_id)) =
+                    self.collider_owners.get(&collider)
+                else {
+                    continue;
+                };
+
+                // The other collider may have been removed, which is what ended the collision
+                let Some((collision_target, target_id)) = self.collider_owners.get(&other_collider)
+                else {
+                    continue;
+                };
+                let collision = Collision {
+                    collision_kind: if event.sensor() {
+                        CollisionKind::Intersection
+                    } else {
+                        CollisionKind::Contact
+                    },
+                    collision_target: *collision_target,
+                    target_id: target_id.clone(),
+                };
+                let entity_id = entity_id.clone();
+
+                collision_events.push(match event {
+                    rapier3d::geometry::CollisionEvent::Started(..) => CollisionEvent::Started {
+                        entity_id,
+                        collision,
+                    },
+                    rapier3d::geometry::CollisionEvent::Stopped(..) => CollisionEvent::Stopped {
+                        entity_id,
+                        collision,
+                    },
+                });
+            }
+        }
+
+        let colliders = &self.colliders;
+        self.collider_owners
+            .retain(|handle, _| colliders.contains(*handle));
+
+        collision_events
+    }
+
+    // Insert a collider, attached to a body if it has one, remembering what it belongs to
+    fn insert_collider(
+        &mut self,
+        collider: Collider,
+        parent: Option<RigidBodyHandle>,
+    ) -> ColliderHandle {
+        let owner = get_entity_collision_target(&collider)
+            .map(|target| (target, collider.user_data.to_string()));
+        let handle = match parent {
+            Some(parent) => self
+                .colliders
+                .insert_with_parent(collider, parent, &mut self.bodies),
+            None => self.colliders.insert(collider),
+        };
+        if let Some(owner) = owner {
+            self.collider_owners.insert(handle, owner);
+        }
+        handle
+    }
+
     /// Adds a ball rigidbody
     pub fn add_ball_body(&mut self, position: glam::Vec3, size: f32) -> PhysicsBody {
         let rigid_body = RigidBodyBuilder::dynamic()
@@ -169,44 +263,7 @@ impl PhysicsWorld {
             .build();
         let collider = ColliderBuilder::ball(size).build();
         let handle = self.bodies.insert(rigid_body);
-        self.colliders
-            .insert_with_parent(collider, handle, &mut self.bodies);
-
-        PhysicsBody {
-            handle,
-            removed: false,
-        }
-    }
-
-    /// Add player body
-    /// Creates a kinematic body with a cuboid collider
-    pub fn add_player_body(
-        &mut self,
-        player_id: u64, // evil
-        position: glam::Vec3,
-        player_width: f32,
-        player_height: f32,
-    ) -> PhysicsBody {
-        let rigid_body = RigidBodyBuilder::kinematic_position_based()
-            .translation(glam_to_na(position))
-            .enabled_rotations(false, false, false)
-            .enabled_translations(false, false, false)
-            .user_data(player_id as _)
-            .ccd_enabled(true)
-            .build();
-        let radius = player_width / 2.0;
-        let half_height = player_height / 2.0;
-        tracing::debug!("Creating player with half height of {half_height} and radius {radius}");
-        let collider = ColliderBuilder::capsule_y(half_height - radius, radius)
-            .collision_groups(InteractionGroups::new(PLAYER_GROUP, Group::all()))
-            .position(vector![0.0, half_height, 0.0].into())
-            .active_collision_types(ActiveCollisionTypes::all())
-            .build();
-        let handle = self.bodies.insert(rigid_body);
-        self.colliders
-            .insert_with_parent(collider, handle, &mut self.bodies);
-
-        self.player_handles.insert(player_id, handle);
+        self.insert_collider(collider, Some(handle));
 
         PhysicsBody {
             handle,
@@ -282,34 +339,48 @@ impl PhysicsWorld {
         )
     }
 
-    /// Adds a trimesh collider
-    pub fn add_trimesh_collider(
-        &mut self,
-        vertices: impl Iterator<Item = glam::Vec3>,
-        indices: impl Iterator<Item = [u32; 3]>,
-    ) -> PhysicsCollider {
-        let vertices: Vec<_> = vertices.map(|v| point![v.x, v.y, v.z]).collect();
-        let indices: Vec<_> = indices.collect();
-        let collider = ColliderBuilder::trimesh(vertices, indices)
-            .collision_groups(InteractionGroups::new(BLOCK_GROUP, Group::all()))
-            .position(vector![0.0, 0.0, 0.0].into())
-            .build();
-        let handle = self.colliders.insert(collider);
-        PhysicsCollider {
-            handle,
-            removed: false,
+    /// Rebuilds the colliders for the chunk at `position` from its blocks, replacing whatever it
+    /// had before. Call with `None` once the chunk has been removed.
+    pub fn set_terrain_chunk(&mut self, position: ChunkPos, chunk: Option<&Chunk>) {
+        for handle in self.terrain_colliders.remove(&position).unwrap_or_default() {
+            // Anything resting on the old terrain needs to notice if it's gone
+            let touching = self
+                .narrow_phase
+                .contact_pairs_with(handle)
+                .flat_map(|pair| [pair.collider1, pair.collider2])
+                .filter_map(|collider| self.colliders.get(collider)?.parent())
+                .collect::<Vec<_>>();
+            for body in touching {
+                self.islands.wake_up(&mut self.bodies, body, true);
+            }
+
+            self.colliders
+                .remove(handle, &mut self.islands, &mut self.bodies, false);
         }
-    }
 
-    /// Adds a block collider
-    pub fn add_block_collider(&mut self, position: glam::Vec3, block_type_id: BlockTypeID) {
-        let position = position + glam::Vec3::new(0.5, 0.5, 0.5);
-        let collider = ColliderBuilder::cuboid(0.5, 0.5, 0.5)
-            .translation(vector![position.x, position.y, position.z])
-            .collision_groups(InteractionGroups::new(BLOCK_GROUP, Group::all()))
-            .user_data(block_type_id.into())
-            .build();
-        self.colliders.insert(collider);
+        let Some(chunk) = chunk else {
+            return;
+        };
+
+        let origin = position.origin().as_ivec3();
+        let handles = terrain::greedy_boxes(chunk)
+            .into_iter()
+            .map(|terrain_box| {
+                let half_extents = terrain_box.size.as_vec3() / 2.;
+                let center = (origin + terrain_box.min).as_vec3() + half_extents;
+                let collider =
+                    ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z)
+                        .translation(vector![center.x, center.y, center.z])
+                        .collision_groups(InteractionGroups::new(BLOCK_GROUP, Group::all()))
+                        .user_data(terrain_box.block_type_id.into())
+                        .build();
+                self.insert_collider(collider, None)
+            })
+            .collect::<Vec<_>>();
+
+        if !handles.is_empty() {
+            self.terrain_colliders.insert(position, handles);
+        }
     }
 
     /// Adds a cuboid static collider
@@ -321,7 +392,7 @@ impl PhysicsWorld {
         let collider = ColliderBuilder::cuboid(size.x, size.y, size.z)
             .translation(vector![position.x, position.y, position.z])
             .build();
-        let handle = self.colliders.insert(collider);
+        let handle = self.insert_collider(collider, None);
         PhysicsCollider {
             handle,
             removed: false,
@@ -339,13 +410,16 @@ impl PhysicsWorld {
         );
     }
 
+    /// Slide a player's collider along `desired_velocity` for a tick, stopping at anything in the
+    /// way
     pub fn check_movement_for_collisions(
         &mut self,
-        player_id: u64,
+        entity_id: &str,
         current_position: glam::Vec3,
         desired_velocity: glam::Vec3,
     ) -> CollisionResult {
-        let Some((_, player_collider_handle)) = self.get_player_handles(player_id) else {
+        let Some(player_collider_handle) = self.get_entity_collider(entity_id) else {
+            tracing::warn!("Couldn't find a collider for {entity_id}");
             return CollisionResult {
                 corrected_movement: desired_velocity,
                 is_on_ground: false,
@@ -373,21 +447,11 @@ impl PhysicsWorld {
         result
     }
 
-    fn get_player_handles(&mut self, player_id: u64) -> Option<(RigidBodyHandle, ColliderHandle)> {
-        // Extract the player's shape
-        let Some(player_body_handle) = self.player_handles.get(&player_id) else {
-            tracing::warn!("Couldn't find a player handle for {player_id}");
-            return None;
-        };
-
-        let player_body_handle = *player_body_handle;
-        let player_body = &self.bodies[player_body_handle];
-        let Some(player_collider_handle) = player_body.colliders().first() else {
-            tracing::warn!("Couldn't find a collider for {player_id}");
-            return None;
-        };
-
-        Some((player_body_handle.clone(), player_collider_handle.clone()))
+    fn get_entity_collider(&self, entity_id: &str) -> Option<ColliderHandle> {
+        self.entity_bodies
+            .get(entity_id)
+            .and_then(|body| self.bodies.get(body.handle))
+            .and_then(|body| body.colliders().first().copied())
     }
 
     pub fn get_debug_lines(&mut self) -> Vec<net_types::DebugLine> {
@@ -422,13 +486,15 @@ impl PhysicsWorld {
             self.remove_body(body);
         };
 
-        let physics_properties = entity_type_registry
-            .get(entity_data.entity_type)
-            .unwrap()
-            .physics_properties();
+        // Players always have the same body, whatever the player script does with it
+        if let Some(controller) = &entity_data.controller {
+            let body = self.add_player_body(controller.player_id, entity_data.state.position);
+            self.entity_bodies.insert(entity_data.id.clone(), body);
+            return;
+        }
 
         // If this entity has no physics properties, then there's nothing to do
-        let Some(physics_properties) = physics_properties else {
+        let Some(physics_properties) = physics_properties(entity_data, entity_type_registry) else {
             tracing::debug!(
                 "Entity {} has no physics properties, doing nothing",
                 &entity_data.id
@@ -440,8 +506,7 @@ impl PhysicsWorld {
             build_rigid_body_for_entity(&entity_data.id, physics_properties, &entity_data.state);
         let handle = self.bodies.insert(rigid_body);
         let collider = build_collider_for_entity(&entity_data.id, physics_properties);
-        self.colliders
-            .insert_with_parent(collider, handle, &mut self.bodies);
+        self.insert_collider(collider, Some(handle));
 
         self.entity_bodies
             .insert(entity_data.id.clone(), PhysicsBody::new(handle));
@@ -473,12 +538,17 @@ impl PhysicsWorld {
             return;
         }
 
-        // If the entity is *not* dynamic, we set the body's position and velocity from the entity
+        // If the entity is *not* dynamic, we set the body's position and velocity from the entity.
+        // Anchored entities' positions are relative to their anchor, so they go where it is.
+        let position = match entity_data.state.anchor {
+            Some(_) => entity_data.state.absolute_position,
+            None => entity_data.state.position,
+        };
         physics_body.set_linvel(glam_to_na(entity_data.state.velocity), true);
         physics_body.set_next_kinematic_position(glam_to_na(entity_data.state.velocity).into());
 
         // I don't know why `set_next_kinematic_position` is not enough, but this fixes #209
-        physics_body.set_position(glam_to_na(entity_data.state.position).into(), true);
+        physics_body.set_position(glam_to_na(position).into(), true);
     }
 
     pub fn despawn_entity(&mut self, entity_id: &str) {
@@ -488,15 +558,11 @@ impl PhysicsWorld {
         };
 
         self.remove_body(body);
+        self.forget_removed_joints(entity_id);
     }
 
-    pub fn get_collisions_for_entity(&self, entity_id: &EntityID) -> Vec<Collision> {
-        let Some(collider) = self
-            .entity_bodies
-            .get(entity_id)
-            .and_then(|body| self.bodies.get(body.handle))
-            .and_then(|body| body.colliders().first().cloned())
-        else {
+    pub fn get_collisions_for_entity(&self, entity_id: &str) -> Vec<Collision> {
+        let Some(collider) = self.get_entity_collider(entity_id) else {
             tracing::warn!("Tried to get collisions for entity {entity_id} but it has no body!");
             return Vec::new();
         };
@@ -504,18 +570,28 @@ impl PhysicsWorld {
         self.get_collisions_for_collider(collider)
     }
 
-    pub fn get_collisions_for_player(&self, player_id: u64) -> Vec<Collision> {
-        let Some(collider) = self
-            .player_handles
-            .get(&player_id)
-            .and_then(|body| self.bodies.get(*body))
-            .and_then(|body| body.colliders().first().cloned())
-        else {
-            tracing::warn!("Tried to get collisions for entity {player_id} but it has no body!");
-            return Vec::new();
-        };
+    // A kinematic capsule, moved by the player script. Its collider is in the player group, with
+    // the player's ID, so that scripts can tell players apart from other entities.
+    fn add_player_body(&mut self, player_id: PlayerId, position: glam::Vec3) -> PhysicsBody {
+        let rigid_body = RigidBodyBuilder::kinematic_position_based()
+            .translation(glam_to_na(position))
+            .enabled_rotations(false, false, false)
+            .enabled_translations(false, false, false)
+            .user_data(player_id.inner() as _)
+            .ccd_enabled(true)
+            .build();
+        let radius = movement::PLAYER_WIDTH / 2.0;
+        let half_height = movement::PLAYER_HEIGHT / 2.0;
+        let collider = ColliderBuilder::capsule_y(half_height - radius, radius)
+            .collision_groups(InteractionGroups::new(PLAYER_GROUP, Group::all()))
+            .user_data(player_id.inner() as _)
+            .position(vector![0.0, half_height, 0.0].into())
+            .active_collision_types(ActiveCollisionTypes::all())
+            .build();
+        let handle = self.bodies.insert(rigid_body);
+        self.insert_collider(collider, Some(handle));
 
-        self.get_collisions_for_collider(collider)
+        PhysicsBody::new(handle)
     }
 
     fn get_collisions_for_collider(&self, collider: ColliderHandle) -> Vec<Collision> {
@@ -611,6 +687,16 @@ fn get_entity_collision_target(other_collider: &Collider) -> Option<CollisionTar
     None
 }
 
+// Players and entities without an entity type don't have any
+fn physics_properties<'a>(
+    entity_data: &EntityData,
+    entity_type_registry: &'a EntityTypeRegistry,
+) -> Option<&'a EntityPhysicsProperties> {
+    entity_type_registry
+        .get(entity_data.entity_type?)?
+        .physics_properties()
+}
+
 fn na_quat_to_glam(rotation: nalgebra::Unit<nalgebra::Quaternion<f32>>) -> glam::Quat {
     // jesus, nalgebra
     glam::Quat::from_array(rotation.into_inner().coords.data.0[0])
@@ -672,6 +758,7 @@ fn build_collider_for_entity(
             ENTITY_GROUP,
             BLOCK_GROUP | PLAYER_GROUP | ENTITY_GROUP,
         ))
+        .active_events(ActiveEvents::COLLISION_EVENTS)
         .user_data(entity_id)
         .position(vector![0., half_height, 0.].into())
         .build()
@@ -855,6 +942,35 @@ impl Drop for PhysicsCollider {
 fn glam_to_na(input: glam::Vec3) -> nalgebra::Vector3<f32> {
     vector![input.x, input.y, input.z]
 }
+// Keeps the collision events from each step, for `take_collision_events`. Rapier calls this from
+// inside the step, possibly from several threads, so the events go behind a mutex.
+#[derive(Default)]
+struct CollisionEventCollector {
+    events: Mutex<Vec<rapier3d::geometry::CollisionEvent>>,
+}
+
+impl EventHandler for CollisionEventCollector {
+    fn handle_collision_event(
+        &self,
+        _bodies: &RigidBodySet,
+        _colliders: &ColliderSet,
+        event: rapier3d::geometry::CollisionEvent,
+        _contact_pair: Option<&ContactPair>,
+    ) {
+        self.events.lock().expect("Deadlock!").push(event);
+    }
+
+    fn handle_contact_force_event(
+        &self,
+        _dt: Real,
+        _bodies: &RigidBodySet,
+        _colliders: &ColliderSet,
+        _contact_pair: &ContactPair,
+        _total_force_magnitude: Real,
+    ) {
+    }
+}
+
 struct PhysicsRenderer<'a> {
     lines: &'a mut Vec<net_types::DebugLine>,
 }
@@ -897,10 +1013,78 @@ pub enum CollisionKind {
     Intersection,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum CollisionTarget {
     Block,
     Entity,
     Player,
 }
+
+/// An entity started or stopped touching something
+#[derive(Debug, Clone)]
+pub enum CollisionEvent {
+    Started {
+        entity_id: EntityID,
+        collision: Collision,
+    },
+    Stopped {
+        entity_id: EntityID,
+        collision: Collision,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, entities::test_util};
+
+    #[test]
+    fn collisions_end_when_the_other_entity_is_despawned() {
+        let mut registry = EntityTypeRegistry::default();
+        registry.insert(test_util::ball_type(0, 0.5));
+        let mut physics_world = PhysicsWorld::new();
+        // Two balls side by side, just touching
+        let mut entities = HashMap::from([
+            (
+                "1".to_string(),
+                test_util::entity("1", 0, glam::Vec3::new(0., 10., 0.)),
+            ),
+            (
+                "2".to_string(),
+                test_util::entity("2", 0, glam::Vec3::new(0.45, 10., 0.)),
+            ),
+        ]);
+        for entity in entities.values() {
+            physics_world.spawn_entity(entity, &registry);
+        }
+
+        physics_world.step(&mut entities, &registry);
+        let started = physics_world.take_collision_events();
+        assert!(
+            started.iter().any(|event| matches!(
+                event,
+                CollisionEvent::Started { entity_id, collision }
+                    if entity_id == "1" && collision.target_id == "2"
+            )),
+            "{started:?}"
+        );
+
+        physics_world.despawn_entity("2");
+        entities.remove("2");
+        physics_world.step(&mut entities, &registry);
+        let stopped = physics_world.take_collision_events();
+        // Only the ball that's left is told
+        assert!(
+            matches!(
+                stopped.as_slice(),
+                [CollisionEvent::Stopped { entity_id, collision }]
+                    if entity_id == "1"
+                        && collision.target_id == "2"
+                        && collision.collision_target == CollisionTarget::Entity
+            ),
+            "{stopped:?}"
+        );
+        // The despawned ball's collider is forgotten once its events have been taken
+        assert_eq!(physics_world.collider_owners.len(), 1);
+    }
+}
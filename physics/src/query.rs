@@ -0,0 +1,355 @@
+use {
+    crate::{get_entity_collision_target, glam_to_na, na_to_glam, CollisionTarget, PhysicsWorld},
+    blocks::BlockPos,
+    entities::EntityID,
+    nalgebra::{point, Isometry3},
+    rapier3d::{
+        geometry::{Ball, Capsule, Cuboid, Ray},
+        parry::query::ShapeCastOptions,
+        pipeline::QueryFilter,
+        prelude::{Collider, ColliderHandle, Shape},
+    },
+    serde::{Deserialize, Serialize},
+};
+
+/// Which colliders a raycast, shape cast or overlap can hit
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HitFilter {
+    /// Only hit these kinds of things. Everything is hit if this is empty.
+    pub targets: Vec<CollisionTarget>,
+    pub exclude_entities: Vec<EntityID>,
+    pub exclude_players: Vec<u64>,
+    /// Whether to hit sensor colliders, like trigger zones
+    pub include_sensors: bool,
+}
+
+impl HitFilter {
+    fn accepts(&self, collider: &Collider) -> bool {
+        let Some(target) = get_entity_collision_target(collider) else {
+            return false;
+        };
+
+        if !self.targets.is_empty() && !self.targets.contains(&target) {
+            return false;
+        }
+
+        match target {
+            CollisionTarget::Block => true,
+            CollisionTarget::Entity => !self
+                .exclude_entities
+                .contains(&collider.user_data.to_string()),
+            CollisionTarget::Player => !self.exclude_players.contains(&(collider.user_data as u64)),
+        }
+    }
+}
+
+/// A shape to cast, or to check for overlaps with
+#[derive(Debug, Clone, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum QueryShape {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: glam::Vec3,
+    },
+    /// Upright, like the player's
+    Capsule {
+        half_height: f32,
+        radius: f32,
+    },
+}
+
+impl QueryShape {
+    fn to_shape(&self) -> Box<dyn Shape> {
+        match *self {
+            QueryShape::Sphere { radius } => Box::new(Ball::new(radius)),
+            QueryShape::Box { half_extents } => Box::new(Cuboid::new(glam_to_na(half_extents))),
+            QueryShape::Capsule {
+                half_height,
+                radius,
+            } => Box::new(Capsule::new_y(half_height, radius)),
+        }
+    }
+}
+
+/// The first thing a raycast or shape cast hit
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryHit {
+    pub target: CollisionTarget,
+    /// Player ID if player, block type ID if block, entity ID if entity
+    pub target_id: String,
+    /// The block that was hit, if a block was hit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_position: Option<BlockPos>,
+    pub point: glam::Vec3,
+    /// Points out of the surface that was hit
+    pub normal: glam::Vec3,
+    pub distance: f32,
+}
+
+/// Something a shape overlaps
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overlap {
+    pub target: CollisionTarget,
+    pub target_id: String,
+}
+
+// Queries see colliders as they were after the last physics step
+impl PhysicsWorld {
+    /// The first thing along a ray from `origin`, within `max_distance`
+    pub fn raycast(
+        &self,
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        max_distance: f32,
+        filter: &HitFilter,
+    ) -> Option<QueryHit> {
+        let direction = direction.try_normalize()?;
+        let ray = Ray::new(point![origin.x, origin.y, origin.z], glam_to_na(direction));
+
+        let predicate = |_, collider: &Collider| filter.accepts(collider);
+        let (handle, intersection) = self.query_pipeline.cast_ray_and_get_normal(
+            &self.bodies,
+            &self.colliders,
+            &ray,
+            max_distance,
+            true,
+            rapier_filter(filter, &predicate),
+        )?;
+
+        let distance = intersection.time_of_impact;
+        self.query_hit(
+            handle,
+            origin + direction * distance,
+            na_to_glam(intersection.normal),
+            distance,
+        )
+    }
+
+    /// The first thing `shape` would hit if it moved from `origin` along `direction`, up to
+    /// `max_distance`. The hit's point is on the thing that was hit.
+    pub fn shape_cast(
+        &self,
+        shape: &QueryShape,
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        max_distance: f32,
+        filter: &HitFilter,
+    ) -> Option<QueryHit> {
+        let direction = direction.try_normalize()?;
+        let options = ShapeCastOptions {
+            max_time_of_impact: max_distance,
+            stop_at_penetration: true,
+            ..Default::default()
+        };
+
+        let predicate = |_, collider: &Collider| filter.accepts(collider);
+        let (handle, hit) = self.query_pipeline.cast_shape(
+            &self.bodies,
+            &self.colliders,
+            &Isometry3::translation(origin.x, origin.y, origin.z),
+            &glam_to_na(direction),
+            &*shape.to_shape(),
+            options,
+            rapier_filter(filter, &predicate),
+        )?;
+
+        // Witness and normal 1 are on the collider that was hit, in world space
+        self.query_hit(
+            handle,
+            na_to_glam(hit.witness1.coords),
+            na_to_glam(hit.normal1.into_inner()),
+            hit.time_of_impact,
+        )
+    }
+
+    /// Everything within `radius` of `center`
+    pub fn overlap_sphere(
+        &self,
+        center: glam::Vec3,
+        radius: f32,
+        filter: &HitFilter,
+    ) -> Vec<Overlap> {
+        let mut overlaps = Vec::new();
+        let predicate = |_, collider: &Collider| filter.accepts(collider);
+        self.query_pipeline.intersections_with_shape(
+            &self.bodies,
+            &self.colliders,
+            &Isometry3::translation(center.x, center.y, center.z),
+            &Ball::new(radius),
+            rapier_filter(filter, &predicate),
+            |handle| {
+                if let Some(collider) = self.colliders.get(handle) {
+                    overlaps.extend(get_entity_collision_target(collider).map(|target| Overlap {
+                        target,
+                        target_id: collider.user_data.to_string(),
+                    }));
+                }
+                true
+            },
+        );
+        overlaps
+    }
+
+    fn query_hit(
+        &self,
+        handle: ColliderHandle,
+        point: glam::Vec3,
+        normal: glam::Vec3,
+        distance: f32,
+    ) -> Option<QueryHit> {
+        let collider = self.colliders.get(handle)?;
+        let target = get_entity_collision_target(collider)?;
+
+        // Terrain colliders cover many blocks, so find the block from where it was hit. The point
+        // is on the block's surface, so step back into it.
+        let block_position = matches!(target, CollisionTarget::Block)
+            .then(|| BlockPos::from_float(point - normal * 0.01));
+
+        Some(QueryHit {
+            target,
+            target_id: collider.user_data.to_string(),
+            block_position,
+            point,
+            normal,
+            distance,
+        })
+    }
+}
+
+fn rapier_filter<'a>(
+    filter: &HitFilter,
+    predicate: &'a impl Fn(ColliderHandle, &Collider) -> bool,
+) -> QueryFilter<'a> {
+    let rapier_filter = QueryFilter::default().predicate(predicate);
+    if filter.include_sensors {
+        rapier_filter
+    } else {
+        rapier_filter.exclude_sensors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        blocks::{Chunk, ChunkPos},
+        entities::{EntityData, EntityState, EntityTypeRegistry, PlayerController, PlayerId},
+    };
+
+    // A physics world with a floor of blocks at y = 0, stepped once so that queries can see it
+    fn world_with_floor() -> PhysicsWorld {
+        let mut physics_world = PhysicsWorld::new();
+        // X fastest, then Y, then Z
+        let chunk = (0..16 * 16 * 16)
+            .map(|index| if (index / 16) % 16 == 0 { 1 } else { 0 })
+            .collect();
+        physics_world.set_terrain_chunk(ChunkPos::new(0, 0, 0), Chunk::from_blocks(chunk).as_ref());
+        physics_world
+            .query_pipeline
+            .update(&physics_world.colliders);
+        physics_world
+    }
+
+    #[test]
+    fn raycasts_hit_blocks() {
+        let physics_world = world_with_floor();
+        let hit = physics_world
+            .raycast(
+                glam::Vec3::new(3.5, 5., 4.5),
+                glam::Vec3::NEG_Y,
+                10.,
+                &HitFilter::default(),
+            )
+            .expect("Raycast missed the floor");
+
+        assert!(matches!(hit.target, CollisionTarget::Block));
+        assert_eq!(hit.target_id, "1");
+        assert_eq!(hit.block_position, Some(BlockPos::new(3, 0, 4)));
+        assert!((hit.point - glam::Vec3::new(3.5, 1., 4.5)).length() < 0.001);
+        assert!((hit.normal - glam::Vec3::Y).length() < 0.001);
+        assert!((hit.distance - 4.).abs() < 0.001);
+    }
+
+    #[test]
+    fn queries_respect_distance_and_filters() {
+        let physics_world = world_with_floor();
+        let origin = glam::Vec3::new(3.5, 5., 4.5);
+        assert!(physics_world
+            .raycast(origin, glam::Vec3::NEG_Y, 3., &HitFilter::default())
+            .is_none());
+
+        let only_entities = HitFilter {
+            targets: vec![CollisionTarget::Entity],
+            ..Default::default()
+        };
+        assert!(physics_world
+            .raycast(origin, glam::Vec3::NEG_Y, 10., &only_entities)
+            .is_none());
+        assert!(physics_world
+            .overlap_sphere(glam::Vec3::new(3.5, 1., 4.5), 0.5, &only_entities)
+            .is_empty());
+    }
+
+    #[test]
+    fn raycasts_hit_players() {
+        let mut physics_world = world_with_floor();
+        let player = EntityData {
+            id: "1".into(),
+            name: "Player 7".into(),
+            entity_type: None,
+            model_path: "player.gltf".into(),
+            state: EntityState {
+                position: glam::Vec3::new(3.5, 1., 4.5),
+                ..Default::default()
+            },
+            controller: Some(PlayerController::new(PlayerId::new(7))),
+            joints: vec![],
+        };
+        physics_world.spawn_entity(&player, &EntityTypeRegistry::default());
+        physics_world
+            .query_pipeline
+            .update(&physics_world.colliders);
+
+        let origin = glam::Vec3::new(-5., 1.5, 4.5);
+        let hit = physics_world
+            .raycast(origin, glam::Vec3::X, 20., &HitFilter::default())
+            .expect("Raycast missed the player");
+        assert!(matches!(hit.target, CollisionTarget::Player));
+        assert_eq!(hit.target_id, "7");
+
+        let not_player_7 = HitFilter {
+            exclude_players: vec![7],
+            ..Default::default()
+        };
+        assert!(physics_world
+            .raycast(origin, glam::Vec3::X, 20., &not_player_7)
+            .is_none());
+    }
+
+    #[test]
+    fn shape_casts_hit_blocks() {
+        let physics_world = world_with_floor();
+        let hit = physics_world
+            .shape_cast(
+                &QueryShape::Sphere { radius: 0.5 },
+                glam::Vec3::new(3.5, 5., 4.5),
+                glam::Vec3::NEG_Y,
+                10.,
+                &HitFilter::default(),
+            )
+            .expect("Shape cast missed the floor");
+
+        assert!((hit.distance - 3.5).abs() < 0.001);
+        assert!((hit.point.y - 1.).abs() < 0.001);
+        assert_eq!(hit.block_position, Some(BlockPos::new(3, 0, 4)));
+    }
+}
@@ -0,0 +1,176 @@
+use {
+    blocks::{BlockTypeID, Chunk, CHUNK_SIZE, EMPTY_BLOCK},
+    glam::IVec3,
+};
+
+const CHUNK_SIZE_USIZE: usize = CHUNK_SIZE as usize;
+
+/// A box of blocks of the same type, in block coordinates relative to its chunk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TerrainBox {
+    pub min: IVec3,
+    pub size: IVec3,
+    pub block_type_id: BlockTypeID,
+}
+
+/// Cover the non-empty blocks of a chunk with as few boxes as we reasonably can, so that the
+/// chunk only needs a handful of colliders rather than one per block.
+///
+/// Greedy: starting from each block that isn't covered yet, grow a box along X, then Y, then Z
+/// for as long as every block it would take in is uncovered and of the same type. Only blocks of
+/// the same type are merged so that collisions can still tell what was hit.
+pub(crate) fn greedy_boxes(chunk: &Chunk) -> Vec<TerrainBox> {
+    let blocks = chunk.blocks();
+    let mut covered = vec![false; blocks.len()];
+    let mut boxes = Vec::new();
+
+    // Whether every block from `min` to `max` (exclusive) can join a box of this type
+    let can_cover = |covered: &[bool], min: IVec3, max: IVec3, block_type_id| {
+        (min.z..max.z).all(|z| {
+            (min.y..max.y).all(|y| {
+                (min.x..max.x).all(|x| {
+                    let index = index(IVec3::new(x, y, z));
+                    !covered[index] && blocks[index] == block_type_id
+                })
+            })
+        })
+    };
+
+    for z in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                let min = IVec3::new(x, y, z);
+                let block_type_id = blocks[index(min)];
+                if block_type_id == EMPTY_BLOCK || covered[index(min)] {
+                    continue;
+                }
+
+                let mut max = min + IVec3::ONE;
+                for axis in [IVec3::X, IVec3::Y, IVec3::Z] {
+                    while (max + axis).cmple(IVec3::splat(CHUNK_SIZE)).all() {
+                        // The slice of blocks just beyond the box along this axis
+                        let slice_min = min * (IVec3::ONE - axis) + (max * axis);
+                        if !can_cover(&covered, slice_min, max + axis, block_type_id) {
+                            break;
+                        }
+                        max += axis;
+                    }
+                }
+
+                for z in min.z..max.z {
+                    for y in min.y..max.y {
+                        for x in min.x..max.x {
+                            covered[index(IVec3::new(x, y, z))] = true;
+                        }
+                    }
+                }
+
+                boxes.push(TerrainBox {
+                    min,
+                    size: max - min,
+                    block_type_id,
+                });
+            }
+        }
+    }
+
+    boxes
+}
+
+// The index of a block in `Chunk::blocks`
+fn index(position: IVec3) -> usize {
+    let position = position.as_uvec3();
+    position.x as usize
+        + position.y as usize * CHUNK_SIZE_USIZE
+        + position.z as usize * CHUNK_SIZE_USIZE * CHUNK_SIZE_USIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_with(blocks: impl IntoIterator<Item = (IVec3, BlockTypeID)>) -> Chunk {
+        let mut raw = vec![EMPTY_BLOCK; (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize];
+        for (position, block_type_id) in blocks {
+            raw[index(position)] = block_type_id;
+        }
+        Chunk::from_blocks(raw).unwrap()
+    }
+
+    // Every non-empty block is covered by exactly one box of its own type
+    fn assert_covers(chunk: &Chunk, boxes: &[TerrainBox]) {
+        let mut coverage = vec![0; chunk.blocks().len()];
+        for terrain_box in boxes {
+            let max = terrain_box.min + terrain_box.size;
+            for z in terrain_box.min.z..max.z {
+                for y in terrain_box.min.y..max.y {
+                    for x in terrain_box.min.x..max.x {
+                        let index = index(IVec3::new(x, y, z));
+                        assert_eq!(chunk.blocks()[index], terrain_box.block_type_id);
+                        coverage[index] += 1;
+                    }
+                }
+            }
+        }
+
+        for (index, &block_type_id) in chunk.blocks().iter().enumerate() {
+            let expected = if block_type_id == EMPTY_BLOCK { 0 } else { 1 };
+            assert_eq!(coverage[index], expected, "block {index}");
+        }
+    }
+
+    #[test]
+    fn empty_chunks_have_no_boxes() {
+        assert!(greedy_boxes(&Chunk::default()).is_empty());
+    }
+
+    #[test]
+    fn full_chunks_are_one_box() {
+        let chunk =
+            Chunk::from_blocks(vec![1; (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize]).unwrap();
+        let boxes = greedy_boxes(&chunk);
+        assert_eq!(
+            boxes,
+            [TerrainBox {
+                min: IVec3::ZERO,
+                size: IVec3::splat(CHUNK_SIZE),
+                block_type_id: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn floors_are_one_box() {
+        let chunk = chunk_with(
+            (0..CHUNK_SIZE).flat_map(|x| (0..CHUNK_SIZE).map(move |z| (IVec3::new(x, 0, z), 1))),
+        );
+        let boxes = greedy_boxes(&chunk);
+        assert_eq!(boxes.len(), 1);
+        assert_covers(&chunk, &boxes);
+    }
+
+    #[test]
+    fn block_types_are_not_merged() {
+        let chunk = chunk_with([
+            (IVec3::new(0, 0, 0), 1),
+            (IVec3::new(1, 0, 0), 1),
+            (IVec3::new(2, 0, 0), 2),
+            (IVec3::new(3, 0, 0), 2),
+        ]);
+        let boxes = greedy_boxes(&chunk);
+        assert_eq!(boxes.len(), 2);
+        assert_covers(&chunk, &boxes);
+    }
+
+    #[test]
+    fn irregular_shapes_are_covered() {
+        // A staircase with a hole in it
+        let chunk = chunk_with(
+            (0..CHUNK_SIZE)
+                .flat_map(|x| (0..=x).map(move |y| (IVec3::new(x, y, 3), 1 + (x % 2) as u8)))
+                .filter(|(position, _)| *position != IVec3::new(5, 2, 3)),
+        );
+        let boxes = greedy_boxes(&chunk);
+        assert_covers(&chunk, &boxes);
+    }
+}
@@ -0,0 +1,241 @@
+// The editor's undo and redo history.
+//
+// Every edit is recorded as the changes it made, each with what was there before, so undoing one
+// is a matter of applying the changes backwards. Boxes of blocks are kept as a copy of the whole
+// box, which is a byte a block rather than a change each. The history belongs to the server rather
+// than the editor instance, so it's still there after playing the world and coming back to edit
+// it.
+
+use {
+    blocks::{BlockPos, BlockRegion, BlockTypeID},
+    entities::{EntityData, EntityID},
+    std::collections::VecDeque,
+};
+
+// The oldest edits are forgotten after this many, or once the history holds this many blocks. A
+// few big fills can reach the block limit long before there are that many edits.
+const MAX_EDITS: usize = 1000;
+const MAX_BLOCKS: usize = 32 * 1024 * 1024;
+
+/// A change to one block or entity
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Block {
+        position: BlockPos,
+        before: BlockTypeID,
+        after: BlockTypeID,
+    },
+    /// `None` when the entity doesn't exist, so adding an entity has no `before`
+    Entity {
+        entity_id: EntityID,
+        before: Option<Box<EntityData>>,
+        after: Option<Box<EntityData>>,
+    },
+    /// A box of blocks, starting at `origin`
+    Region {
+        origin: BlockPos,
+        before: BlockRegion,
+        after: BlockRegion,
+    },
+}
+
+impl Change {
+    /// The change that puts things back the way they were
+    pub fn inverse(&self) -> Change {
+        match self.clone() {
+            Change::Block {
+                position,
+                before,
+                after,
+            } => Change::Block {
+                position,
+                before: after,
+                after: before,
+            },
+            Change::Entity {
+                entity_id,
+                before,
+                after,
+            } => Change::Entity {
+                entity_id,
+                before: after,
+                after: before,
+            },
+            Change::Region {
+                origin,
+                before,
+                after,
+            } => Change::Region {
+                origin,
+                before: after,
+                after: before,
+            },
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        match self {
+            Change::Block { before, after, .. } => before == after,
+            Change::Entity { before, after, .. } => before == after,
+            Change::Region { before, after, .. } => before == after,
+        }
+    }
+
+    // How many blocks the change holds on to, counting anything else as one
+    fn blocks(&self) -> usize {
+        match self {
+            Change::Region { before, after, .. } => before.volume() + after.volume(),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct EditHistory {
+    // Each edit is the changes it made, in the order they were made
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    // Across the edits that can be undone and redone
+    blocks: usize,
+}
+
+impl EditHistory {
+    /// Remember an edit that has been applied. Anything that was undone can't be redone after this.
+    pub fn record(&mut self, changes: Vec<Change>) {
+        let changes = changes
+            .into_iter()
+            .filter(|change| !change.is_noop())
+            .collect::<Vec<_>>();
+        if changes.is_empty() {
+            return;
+        }
+
+        for forgotten in self.redo.drain(..) {
+            self.blocks -= edit_blocks(&forgotten);
+        }
+        self.blocks += edit_blocks(&changes);
+        self.undo.push_back(changes);
+        // The latest edit is always kept, however big it is
+        while self.undo.len() > MAX_EDITS || (self.blocks > MAX_BLOCKS && self.undo.len() > 1) {
+            if let Some(forgotten) = self.undo.pop_front() {
+                self.blocks -= edit_blocks(&forgotten);
+            }
+        }
+    }
+
+    /// Take back the last edit, returning the changes that undo it
+    pub fn undo(&mut self) -> Option<Vec<Change>> {
+        let changes = self.undo.pop_back()?;
+        let undone = changes.iter().rev().map(Change::inverse).collect();
+        self.redo.push(changes);
+        Some(undone)
+    }
+
+    /// Make the last edit that was undone again, returning its changes
+    pub fn redo(&mut self) -> Option<Vec<Change>> {
+        let changes = self.redo.pop()?;
+        self.undo.push_back(changes.clone());
+        Some(changes)
+    }
+}
+
+fn edit_blocks(changes: &[Change]) -> usize {
+    changes.iter().map(Change::blocks).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, glam::UVec3};
+
+    fn set_block(x: i32, before: BlockTypeID, after: BlockTypeID) -> Change {
+        Change::Block {
+            position: BlockPos::new(x, 0, 0),
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = EditHistory::default();
+        history.record(vec![set_block(0, 0, 1), set_block(1, 0, 1)]);
+        history.record(vec![set_block(0, 1, 2)]);
+
+        assert_eq!(history.undo(), Some(vec![set_block(0, 2, 1)]));
+        // Changes are undone last first
+        assert_eq!(
+            history.undo(),
+            Some(vec![set_block(1, 1, 0), set_block(0, 1, 0)])
+        );
+        assert_eq!(history.undo(), None);
+
+        assert_eq!(
+            history.redo(),
+            Some(vec![set_block(0, 0, 1), set_block(1, 0, 1)])
+        );
+        assert_eq!(
+            history.undo(),
+            Some(vec![set_block(1, 1, 0), set_block(0, 1, 0)])
+        );
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut history = EditHistory::default();
+        history.record(vec![set_block(0, 0, 1)]);
+        history.undo();
+        history.record(vec![set_block(5, 0, 3)]);
+
+        assert_eq!(history.redo(), None);
+    }
+
+    #[test]
+    fn edits_that_change_nothing_are_not_recorded() {
+        let mut history = EditHistory::default();
+        history.record(vec![set_block(0, 0, 1)]);
+        history.record(vec![set_block(0, 1, 1)]);
+
+        assert_eq!(history.undo(), Some(vec![set_block(0, 1, 0)]));
+        assert_eq!(history.undo(), None);
+    }
+
+    #[test]
+    fn old_edits_are_forgotten() {
+        let mut history = EditHistory::default();
+        for x in 0..MAX_EDITS as i32 + 10 {
+            history.record(vec![set_block(x, 0, 1)]);
+        }
+
+        let mut undone = 0;
+        while history.undo().is_some() {
+            undone += 1;
+        }
+        assert_eq!(undone, MAX_EDITS);
+    }
+
+    #[test]
+    fn big_edits_are_forgotten_sooner() {
+        // Each edit holds a quarter of the blocks the history can
+        let size = UVec3::new(256, 256, 64);
+        let volume = blocks::region_volume(size).unwrap();
+        assert_eq!(volume * 2 * 4, MAX_BLOCKS);
+        let fill = |x: i32| Change::Region {
+            origin: BlockPos::new(x, 0, 0),
+            before: BlockRegion::new(size, vec![0; volume]).unwrap(),
+            after: BlockRegion::new(size, vec![1; volume]).unwrap(),
+        };
+
+        let mut history = EditHistory::default();
+        for x in 0..6 {
+            history.record(vec![fill(x)]);
+        }
+        history.record(vec![set_block(0, 0, 1)]);
+
+        let mut undone = 0;
+        while history.undo().is_some() {
+            undone += 1;
+        }
+        // The single block, and the three fills that fit alongside it
+        assert_eq!(undone, 4);
+    }
+}
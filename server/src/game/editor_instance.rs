@@ -1,15 +1,23 @@
 use {
     crate::game::{
+        edit_history::{Change, EditHistory},
         network::Client,
         world::{self, World},
         NextServerState,
     },
-    entities::PlayerId,
-    net_types::{ClientShouldSwitchMode, ServerPacket, SetBlock},
+    blocks::{BlockPos, BlockRegion, EMPTY_BLOCK},
+    entities::{EntityData, PlayerId},
+    net_types::{
+        ClientShouldSwitchMode, RegionOperation, RemoveEntity, RenameEntity, ServerPacket,
+        SetBlock, SetBlocks, SetEntityCustomState, SetEntityTransform, MAX_REGION_VOLUME,
+    },
     physics::PhysicsWorld,
     std::{
+        collections::HashSet,
+        fmt::Display,
         path::PathBuf,
         sync::{Arc, Mutex},
+        time::{Duration, Instant},
     },
     tokio::sync::mpsc,
 };
@@ -18,16 +26,29 @@ use crate::js::JSContext;
 
 use super::game_instance::GameInstance;
 
+// Saves wait until the editor has stopped changing things for a moment, so dragging out a wall
+// doesn't rewrite the world once per block. A long run of edits still gets saved every so often.
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(1);
+const MAX_SAVE_DELAY: Duration = Duration::from_secs(10);
+
+const MAX_ENTITY_NAME_LENGTH: usize = 64;
+
 pub struct EditorInstance {
     pub world: Arc<Mutex<World>>,
     pub editor_client: Client,
     pub physics_world: Arc<Mutex<PhysicsWorld>>,
+    unsaved_edits: Option<UnsavedEdits>,
+}
+
+struct UnsavedEdits {
+    first: Instant,
+    last: Instant,
 }
 
 impl EditorInstance {
     pub async fn from_transition(
         game_instance: GameInstance,
-        editor_client: Client,
+        mut editor_client: Client,
         storage_dir: &PathBuf,
         js_context: &mut JSContext,
     ) -> Self {
@@ -35,11 +56,20 @@ impl EditorInstance {
         let GameInstance {
             world,
             physics_world,
-            mut colliders,
-            mut players,
             ..
         } = game_instance;
 
+        // Clean up the old players. They aren't saved, so they'd be gone from the reloaded world
+        // but their bodies would be left behind.
+        {
+            let mut world = world.lock().expect("Deadlock!");
+            let mut physics_world = physics_world.lock().expect("Deadlock!");
+            let player_ids = world.players.keys().copied().collect::<Vec<_>>();
+            for player_id in player_ids {
+                world.remove_player(player_id, &mut physics_world);
+            }
+        }
+
         // Reload the world from storage
         *world.lock().expect("Deadlock!") = World::load(storage_dir).expect("couldn't load world");
 
@@ -47,50 +77,37 @@ impl EditorInstance {
         {
             let mut world = world.lock().expect("Deadlock!");
             let entity_type_registry = world.entity_type_registry.clone();
-            for entity_data in world.entities.values_mut() {
-                world::spawn_entity(
-                    entity_data,
-                    js_context,
-                    physics_world.clone(),
-                    &entity_type_registry,
-                );
-            }
-        }
-
-        // Clean up the old game instance
-        {
-            let mut physics_world = physics_world.lock().expect("Deadlock!");
-
-            // Clean up the old player handles
-            for (_, player) in players.drain() {
-                physics_world.remove_body(player.body);
-            }
-
-            // Clean up old colliders
-            for collider in colliders.drain(..) {
-                physics_world.remove_collider(collider);
-            }
+            world::spawn_entities(
+                &mut world.entities,
+                js_context,
+                physics_world.clone(),
+                &entity_type_registry,
+            );
         }
 
         // IMPORTANT: Send the client a packet to confirm the mode switch
         {
             let world = world.lock().expect("Deadlock!");
-            editor_client
-                .outgoing_tx
-                .send(ServerPacket::ClientShouldSwitchMode(
-                    ClientShouldSwitchMode::Edit {
-                        world: net_types::Init {
-                            blocks: world.blocks.clone(),
-                            block_registry: world.block_registry.clone(),
-                            entities: world.entities.clone(),
-                            entity_type_registry: world.entity_type_registry.clone(),
-                            client_player: PlayerId::new(0), // ignored by the editor
-                            world_script_state: game_instance.custom_world_state.clone(),
-                        },
+            editor_client.send(ServerPacket::ClientShouldSwitchMode(
+                ClientShouldSwitchMode::Edit {
+                    world: net_types::Init {
+                        blocks: world.blocks.clone(),
+                        block_registry: world.block_registry.clone(),
+                        entities: world.entities.clone(),
+                        entity_type_registry: world.entity_type_registry.clone(),
+                        client_player: PlayerId::new(0), // ignored by the editor
+                        world_script_state: game_instance.custom_world_state.clone(),
                     },
-                ))
-                .await
-                .expect("Failed to send packet");
+                },
+            ));
+            // Anything that failed while respawning the entities
+            for error in js_context.take_errors() {
+                editor_client.send(ServerPacket::ScriptError(error));
+            }
+
+            if !editor_client.flush() {
+                tracing::warn!("Failed to send the world to the editor");
+            }
         }
 
         tracing::debug!("We're now in edit mode");
@@ -99,10 +116,15 @@ impl EditorInstance {
             world,
             editor_client,
             physics_world,
+            unsaved_edits: None,
         }
     }
 
-    pub(crate) fn tick(&mut self, storage_dir: &PathBuf) -> Option<super::NextServerState> {
+    pub(crate) fn tick(
+        &mut self,
+        storage_dir: &PathBuf,
+        edit_history: &mut EditHistory,
+    ) -> Option<super::NextServerState> {
         let mut maybe_next_state = None;
 
         while let Some(packet) = match self.editor_client.incoming_rx.try_recv() {
@@ -112,6 +134,7 @@ impl EditorInstance {
                 mpsc::error::TryRecvError::Disconnected => {
                     // If the editor client disconnected, we must leave the editing state and wait
                     // for new clients.
+                    self.save(storage_dir);
                     return Some(NextServerState::Paused);
                 }
             },
@@ -120,37 +143,825 @@ impl EditorInstance {
                 net_types::ClientPacket::Start => maybe_next_state = Some(NextServerState::Playing),
                 net_types::ClientPacket::Pause => maybe_next_state = Some(NextServerState::Paused),
                 net_types::ClientPacket::SetBlock(set_block) => {
-                    self.set_block(set_block, storage_dir);
+                    self.set_block(set_block, edit_history);
                 }
                 net_types::ClientPacket::AddEntity(entity) => {
-                    self.add_entity(entity, storage_dir);
+                    self.add_entity(entity, edit_history);
+                }
+                net_types::ClientPacket::Undo => {
+                    if let Some(changes) = edit_history.undo() {
+                        self.apply(&changes, true);
+                    }
+                }
+                net_types::ClientPacket::Redo => {
+                    if let Some(changes) = edit_history.redo() {
+                        self.apply(&changes, true);
+                    }
+                }
+                net_types::ClientPacket::RemoveEntity(remove_entity) => {
+                    self.remove_entity(remove_entity, edit_history);
+                }
+                net_types::ClientPacket::SetEntityTransform(set_transform) => {
+                    self.set_entity_transform(set_transform, edit_history);
+                }
+                net_types::ClientPacket::RenameEntity(rename_entity) => {
+                    self.rename_entity(rename_entity, edit_history);
+                }
+                net_types::ClientPacket::SetEntityCustomState(set_custom_state) => {
+                    self.set_entity_custom_state(set_custom_state, edit_history);
+                }
+                net_types::ClientPacket::SetBlocks(set_blocks) => {
+                    self.set_blocks(set_blocks, edit_history);
                 }
                 _ => {}
             }
         }
 
+        // Keep trying to send anything that didn't fit last tick. The editor is the only client, so
+        // there's no one else to hold up.
+        self.editor_client.flush();
+
+        // Coming back to the editor reloads the world from storage, so everything has to be saved
+        // before leaving
+        let save_due = self.unsaved_edits.as_ref().is_some_and(|edits| {
+            edits.last.elapsed() >= SAVE_DEBOUNCE || edits.first.elapsed() >= MAX_SAVE_DELAY
+        });
+        if save_due || maybe_next_state.is_some() {
+            self.save(storage_dir);
+        }
+
         maybe_next_state
     }
 
-    fn set_block(&mut self, set_block: SetBlock, storage_dir: &PathBuf) {
+    fn mark_unsaved(&mut self) {
+        let now = Instant::now();
+        match &mut self.unsaved_edits {
+            Some(edits) => edits.last = now,
+            None => {
+                self.unsaved_edits = Some(UnsavedEdits {
+                    first: now,
+                    last: now,
+                })
+            }
+        }
+    }
+
+    fn save(&mut self, storage_dir: &PathBuf) {
+        if self.unsaved_edits.is_none() {
+            return;
+        }
+
+        let saved = self.world.lock().expect("Deadlock!").save(storage_dir);
+        match saved {
+            Ok(()) => self.unsaved_edits = None,
+            // Keep the edits, and try again once the debounce is up
+            Err(e) => {
+                tracing::error!("Couldn't save the world: {e:#}");
+                self.mark_unsaved();
+            }
+        }
+    }
+
+    fn set_block(&mut self, set_block: SetBlock, edit_history: &mut EditHistory) {
         let SetBlock { position, block_id } = set_block;
         tracing::debug!("Setting block at {position:?} to {block_id}");
 
-        let mut world = self.world.lock().expect("Deadlock!!");
+        let before = self
+            .world
+            .lock()
+            .expect("Deadlock!!")
+            .blocks
+            .get(position)
+            .copied()
+            .unwrap_or(EMPTY_BLOCK);
+        self.edit(
+            vec![Change::Block {
+                position,
+                before,
+                after: block_id,
+            }],
+            false,
+            edit_history,
+        );
+    }
 
-        world.blocks[position] = block_id;
-        world.save(storage_dir).expect("save world");
+    // Fill, replace or paste a box of blocks. Unlike single blocks, the editor waits for the server
+    // to send the change back before making it, so a region that's refused is just dropped.
+    fn set_blocks(&mut self, set_blocks: SetBlocks, edit_history: &mut EditHistory) {
+        let size = set_blocks.size();
+        tracing::debug!(
+            "Setting the {size} box of blocks at {:?}",
+            set_blocks.origin
+        );
+
+        if blocks::region_volume(size).is_none_or(|volume| volume > MAX_REGION_VOLUME) {
+            tracing::warn!("Not setting blocks, {size} is more than {MAX_REGION_VOLUME} blocks");
+            return;
+        }
+        let far_corner = set_blocks.origin.as_ivec3().as_i64vec3() + size.as_i64vec3();
+        if far_corner.max_element() > i32::MAX as i64 {
+            tracing::warn!("Not setting blocks, the region goes off the edge of the world");
+            return;
+        }
+
+        let changes = {
+            let world = self.world.lock().expect("Deadlock!!");
+            let entities = set_blocks.entities();
+            if let Err(reason) = check_pasted_entities(&world, &entities) {
+                tracing::warn!("Not pasting blocks, {reason}");
+                return;
+            }
+
+            let before = BlockRegion::copy(&world.blocks, set_blocks.origin, size);
+            let mut changes = vec![Change::Region {
+                origin: set_blocks.origin,
+                after: set_blocks.region_after(&before),
+                before,
+            }];
+            changes.extend(entities.into_iter().map(|entity| Change::Entity {
+                entity_id: entity.id.clone(),
+                before: None,
+                after: Some(Box::new(entity)),
+            }));
+            changes
+        };
+
+        // All of it is one edit, so it's undone in one go
+        self.edit(changes, false, edit_history);
+        self.editor_client.send(ServerPacket::SetBlocks(set_blocks));
     }
 
-    fn add_entity(&mut self, entity: net_types::AddEntity, storage_dir: &PathBuf) {
+    fn add_entity(&mut self, entity: net_types::AddEntity, edit_history: &mut EditHistory) {
         let id = entity.entity_id;
         let position = entity.entity_data.state.position.clone();
-        let entity_type_id = entity.entity_data.entity_type;
+        let Some(entity_type_id) = entity.entity_data.entity_type else {
+            tracing::warn!("Not adding entity {id:?}, only entities with a type can be placed");
+            return;
+        };
         tracing::info!("Adding entity {id:?} at {position:?} of type {entity_type_id}");
 
+        let before = self
+            .world
+            .lock()
+            .expect("Deadlock!!")
+            .entities
+            .get(&id)
+            .cloned()
+            .map(Box::new);
+        self.edit(
+            vec![Change::Entity {
+                entity_id: id,
+                before,
+                after: Some(Box::new(entity.entity_data)),
+            }],
+            false,
+            edit_history,
+        );
+    }
+
+    fn remove_entity(&mut self, remove_entity: RemoveEntity, edit_history: &mut EditHistory) {
+        let RemoveEntity { entity_id } = remove_entity;
+        if self.editable_entity(&entity_id).is_none() {
+            return;
+        }
+        tracing::info!("Removing entity {entity_id:?}");
+
+        // Anything anchored to the entity goes with it, as do joints to any of them. The editor
+        // only removed the one entity, so it's sent all of these.
+        let changes = {
+            let world = self.world.lock().expect("Deadlock!!");
+            let mut removed = world.descendants(&entity_id);
+            removed.push(entity_id);
+
+            let mut changes = removed
+                .iter()
+                .map(|entity_id| Change::Entity {
+                    entity_id: entity_id.clone(),
+                    before: world.entities.get(entity_id).cloned().map(Box::new),
+                    after: None,
+                })
+                .collect::<Vec<_>>();
+            for (entity_id, entity) in &world.entities {
+                let mut after = entity.clone();
+                after.joints.retain(|joint| {
+                    !joint
+                        .other_entity
+                        .as_ref()
+                        .is_some_and(|other_entity| removed.contains(other_entity))
+                });
+                if !removed.contains(entity_id) && after.joints.len() != entity.joints.len() {
+                    changes.push(Change::Entity {
+                        entity_id: entity_id.clone(),
+                        before: Some(Box::new(entity.clone())),
+                        after: Some(Box::new(after)),
+                    });
+                }
+            }
+            changes
+        };
+        self.edit(changes, true, edit_history);
+    }
+
+    fn set_entity_transform(
+        &mut self,
+        set_transform: SetEntityTransform,
+        edit_history: &mut EditHistory,
+    ) {
+        let SetEntityTransform {
+            entity_id,
+            position,
+            rotation,
+            scale,
+        } = set_transform;
+        let Some(before) = self.editable_entity(&entity_id) else {
+            return;
+        };
+        if !position.is_finite() || !rotation.is_finite() || !scale.is_finite() {
+            return self.reject(&entity_id, "its transform isn't finite");
+        }
+        if scale.min_element() <= 0. {
+            return self.reject(&entity_id, "its scale must be positive");
+        }
+        if rotation.length_squared() < f32::EPSILON {
+            return self.reject(&entity_id, "its rotation is zero");
+        }
+
+        let mut after = before.clone();
+        after.state.position = position;
+        after.state.rotation = rotation.normalize();
+        after.state.scale = scale;
+        self.edit_entity(before, after, false, edit_history);
+    }
+
+    fn rename_entity(&mut self, rename_entity: RenameEntity, edit_history: &mut EditHistory) {
+        let RenameEntity { entity_id, name } = rename_entity;
+        let Some(before) = self.editable_entity(&entity_id) else {
+            return;
+        };
+        let trimmed = name.trim();
+        if trimmed.is_empty() {
+            return self.reject(&entity_id, "its name is empty");
+        }
+        if trimmed.chars().count() > MAX_ENTITY_NAME_LENGTH {
+            return self.reject(
+                &entity_id,
+                format!("its name is longer than {MAX_ENTITY_NAME_LENGTH} characters"),
+            );
+        }
+
+        let mut after = before.clone();
+        after.name = trimmed.to_string();
+        // The editor has the untrimmed name
+        let send_to_editor = trimmed != name;
+        self.edit_entity(before, after, send_to_editor, edit_history);
+    }
+
+    fn set_entity_custom_state(
+        &mut self,
+        set_custom_state: SetEntityCustomState,
+        edit_history: &mut EditHistory,
+    ) {
+        let SetEntityCustomState {
+            entity_id,
+            custom_state,
+        } = set_custom_state;
+        let Some(before) = self.editable_entity(&entity_id) else {
+            return;
+        };
+
+        let mut after = before.clone();
+        after.state.custom_state = custom_state;
+        self.edit_entity(before, after, false, edit_history);
+    }
+
+    // The entity the editor wants to change, if it's one the editor can change. Players can't be
+    // edited, they aren't part of the world.
+    fn editable_entity(&mut self, entity_id: &str) -> Option<EntityData> {
+        let entity = self
+            .world
+            .lock()
+            .expect("Deadlock!!")
+            .entities
+            .get(entity_id)
+            .cloned();
+        match entity {
+            Some(entity) if entity.controller.is_none() => Some(entity),
+            Some(_) => {
+                self.reject(entity_id, "it's a player");
+                None
+            }
+            None => {
+                self.reject(entity_id, "it doesn't exist");
+                None
+            }
+        }
+    }
+
+    // Refuse a change to an entity. The editor has already made the change on its side, so it's
+    // sent the world's copy of the entity to put it back.
+    fn reject(&mut self, entity_id: &str, reason: impl Display) {
+        tracing::warn!("Not changing entity {entity_id:?}, {reason}");
+
+        let entity = self
+            .world
+            .lock()
+            .expect("Deadlock!!")
+            .entities
+            .get(entity_id)
+            .cloned();
+        let packet = match entity {
+            Some(entity_data) => ServerPacket::AddEntity(net_types::AddEntity {
+                entity_id: entity_id.to_string(),
+                entity_data,
+            }),
+            None => ServerPacket::RemoveEntity(RemoveEntity {
+                entity_id: entity_id.to_string(),
+            }),
+        };
+        self.editor_client.send(packet);
+    }
+
+    fn edit_entity(
+        &mut self,
+        before: EntityData,
+        after: EntityData,
+        send_to_editor: bool,
+        edit_history: &mut EditHistory,
+    ) {
+        let change = Change::Entity {
+            entity_id: before.id.clone(),
+            before: Some(Box::new(before)),
+            after: Some(Box::new(after)),
+        };
+        self.edit(vec![change], send_to_editor, edit_history);
+    }
+
+    // Make an edit the editor asked for. Unless `send_to_editor` is set, the editor has already
+    // made it on its side.
+    fn edit(&mut self, changes: Vec<Change>, send_to_editor: bool, edit_history: &mut EditHistory) {
+        self.apply(&changes, send_to_editor);
+        edit_history.record(changes);
+    }
+
+    // Change the world, telling the editor about it if it didn't make the changes itself
+    fn apply(&mut self, changes: &[Change], send_to_editor: bool) {
         let mut world = self.world.lock().expect("Deadlock!!");
+        let mut changed_blocks = Vec::new();
+        for change in changes {
+            let packet = match change.clone() {
+                Change::Block {
+                    position, after, ..
+                } => {
+                    world.blocks.set(position, after);
+                    changed_blocks.push(position);
+                    continue;
+                }
+                Change::Region { origin, after, .. } => {
+                    for (position, block) in after.blocks_at(origin) {
+                        world.blocks.set(position, block);
+                        changed_blocks.push(position);
+                    }
+                    continue;
+                }
+                Change::Entity {
+                    entity_id,
+                    after: Some(entity_data),
+                    ..
+                } => {
+                    world
+                        .entities
+                        .insert(entity_id.clone(), *entity_data.clone());
+                    ServerPacket::AddEntity(net_types::AddEntity {
+                        entity_id,
+                        entity_data: *entity_data,
+                    })
+                }
+                Change::Entity {
+                    entity_id,
+                    after: None,
+                    ..
+                } => {
+                    world.entities.remove(&entity_id);
+                    ServerPacket::RemoveEntity(RemoveEntity { entity_id })
+                }
+            };
+            if send_to_editor {
+                self.editor_client.send(packet);
+            }
+        }
+        if send_to_editor {
+            for packet in block_packets(&world.blocks, &changed_blocks) {
+                self.editor_client.send(packet);
+            }
+        }
+        drop(world);
+
+        self.mark_unsaved();
+    }
+}
+
+// Why pasted entities can't be added to the world, if they can't. They need fresh numeric ids and a
+// type that exists, and anything they're anchored or jointed to has to be in the world already or
+// pasted along with them.
+fn check_pasted_entities(world: &World, entities: &[EntityData]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for entity in entities {
+        // Physics bodies are labelled with the id as a number
+        if entity.id.parse::<u128>().is_err() || world.entities.contains_key(&entity.id) {
+            return Err(format!("entity {:?} needs a new numeric id", entity.id));
+        }
+        if !ids.insert(entity.id.as_str()) {
+            return Err(format!("entity {:?} is pasted twice", entity.id));
+        }
+        if entity.entity_type.is_none() || entity.controller.is_some() {
+            return Err(format!(
+                "only entities with a type can be pasted, not {:?}",
+                entity.id
+            ));
+        }
+        if entity
+            .entity_type
+            .is_some_and(|entity_type| world.entity_type_registry.get(entity_type).is_none())
+        {
+            return Err(format!(
+                "entity {:?} is of a type that doesn't exist",
+                entity.id
+            ));
+        }
+    }
+
+    for entity in entities {
+        let anchored_to = entity.state.anchor.as_ref().map(|anchor| &anchor.parent_id);
+        let jointed_to = entity
+            .joints
+            .iter()
+            .filter_map(|joint| joint.other_entity.as_ref());
+        for other_id in anchored_to.into_iter().chain(jointed_to) {
+            if !ids.contains(other_id.as_str()) && !world.entities.contains_key(other_id) {
+                return Err(format!(
+                    "entity {:?} is attached to {other_id:?}, which doesn't exist",
+                    entity.id
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The packets that tell the editor about changed blocks. A lot of them, eg. from undoing a fill,
+// are sent as a single region covering all of them, unless they're too spread out for that.
+fn block_packets(blocks: &blocks::BlockGrid, changed: &[BlockPos]) -> Vec<ServerPacket> {
+    let set_block = |&position: &BlockPos| {
+        ServerPacket::SetBlock(SetBlock {
+            position,
+            block_id: blocks.get(position).copied().unwrap_or(EMPTY_BLOCK),
+        })
+    };
+    let [first, rest @ ..] = changed else {
+        return Vec::new();
+    };
+    if rest.is_empty() {
+        return vec![set_block(first)];
+    }
+
+    let (min, max) = rest.iter().fold(
+        (first.as_ivec3(), first.as_ivec3()),
+        |(min, max), position| (min.min(position.as_ivec3()), max.max(position.as_ivec3())),
+    );
+    let (min, size) = (BlockPos::from(min), (max - min + 1).as_uvec3());
+    if blocks::region_volume(size).is_none_or(|volume| volume > MAX_REGION_VOLUME) {
+        return changed.iter().map(set_block).collect();
+    }
+    vec![ServerPacket::SetBlocks(SetBlocks {
+        origin: min,
+        operation: RegionOperation::Paste {
+            blocks: BlockRegion::copy(blocks, min, size),
+            entities: Vec::new(),
+        },
+    })]
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::MAX_ENTITY_NAME_LENGTH,
+        crate::game::test_harness::{TestServer, TestWorld},
+        blocks::{BlockPos, BlockTypeID, EMPTY_BLOCK},
+        entities::{
+            test_util, Anchor, EntityData, EntityID, Joint, JointKind, PlayerController, PlayerId,
+        },
+        net_types::{
+            ClientPacket, RegionOperation, RemoveEntity, RenameEntity, ServerPacket, SetBlock,
+            SetBlocks, SetEntityCustomState, SetEntityTransform,
+        },
+    };
+
+    // A cart with a turret on it and a flag on the turret, a ball tied to the flag and to a post,
+    // and a player left behind in the world
+    fn entity_world() -> TestWorld {
+        let part = |id: &str, parent: Option<&str>| {
+            let mut entity = test_util::entity(id, 0, glam::Vec3::new(4., 1., 4.));
+            entity.state.anchor = parent.map(|parent_id| Anchor {
+                parent_id: parent_id.into(),
+                parent_node: None,
+            });
+            entity
+        };
+        let rope = |name: &str, other_entity: Option<&str>| Joint {
+            name: name.into(),
+            kind: JointKind::Rope,
+            other_entity: other_entity.map(Into::into),
+            local_anchor: glam::Vec3::ZERO,
+            other_anchor: glam::Vec3::new(8., 5., 8.),
+            axis: glam::Vec3::Y,
+            limits: None,
+            max_distance: 2.,
+        };
+
+        let mut ball = part("4", None);
+        ball.joints = vec![rope("flag", Some("3")), rope("post", None)];
+        let mut player = part("5", None);
+        player.controller = Some(PlayerController::new(PlayerId::new(7)));
+        let world = TestWorld::with_entities(
+            vec![test_util::entity_type(0, "Part")],
+            vec![
+                part("1", None),
+                part("2", Some("1")),
+                part("3", Some("2")),
+                ball,
+                player,
+            ],
+        );
+        world.write_script(
+            "part.js",
+            "export const update = (entityId, state) => state;",
+        );
+        world
+    }
+
+    // The entities the editor was sent, and the ids of the ones it was told to remove
+    fn entity_packets(packets: Vec<ServerPacket>) -> (Vec<EntityData>, Vec<EntityID>) {
+        let (mut added, mut removed) = (Vec::new(), Vec::new());
+        for packet in packets {
+            match packet {
+                ServerPacket::AddEntity(add) => added.push(add.entity_data),
+                ServerPacket::RemoveEntity(remove) => removed.push(remove.entity_id),
+                _ => {}
+            }
+        }
+        removed.sort();
+        (added, removed)
+    }
+
+    fn set_transform(entity_id: &str, position: glam::Vec3, scale: glam::Vec3) -> ClientPacket {
+        ClientPacket::SetEntityTransform(SetEntityTransform {
+            entity_id: entity_id.into(),
+            position,
+            rotation: glam::Quat::IDENTITY,
+            scale,
+        })
+    }
+
+    // The single blocks the editor was told to set
+    fn set_blocks(packets: Vec<ServerPacket>) -> Vec<(BlockPos, BlockTypeID)> {
+        packets
+            .into_iter()
+            .filter_map(|packet| match packet {
+                ServerPacket::SetBlock(SetBlock { position, block_id }) => {
+                    Some((position, block_id))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn edits_can_be_undone_and_redone() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut editor = server.connect_editing().await;
+        let position = BlockPos::new(3, 1, 3);
+
+        editor.send(ClientPacket::SetBlock(SetBlock {
+            position,
+            block_id: 1,
+        }));
+        server.tick().await;
+        assert_eq!(server.block(position), 1);
+        // The editor has already placed it
+        assert_eq!(set_blocks(editor.received()), vec![]);
+
+        editor.send(ClientPacket::Undo);
+        server.tick().await;
+        assert_eq!(server.block(position), EMPTY_BLOCK);
+        assert_eq!(set_blocks(editor.received()), vec![(position, EMPTY_BLOCK)]);
+
+        editor.send(ClientPacket::Redo);
+        server.tick().await;
+        assert_eq!(server.block(position), 1);
+        assert_eq!(set_blocks(editor.received()), vec![(position, 1)]);
+    }
+
+    #[tokio::test]
+    async fn history_lasts_between_editing_sessions() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut editor = server.connect_editing().await;
+        let position = BlockPos::new(3, 1, 3);
+
+        editor.send(ClientPacket::SetBlock(SetBlock {
+            position,
+            block_id: 1,
+        }));
+        server.tick().await;
+
+        editor.send(ClientPacket::Start);
+        server.tick_n(2).await;
+        assert_eq!(server.state(), "Playing");
+        editor.send(ClientPacket::Edit);
+        server.tick_n(2).await;
+        assert_eq!(server.state(), "Editing");
+        // The block was saved when the game started, and loaded again for the editor
+        assert_eq!(server.block(position), 1);
+        editor.received();
+
+        editor.send(ClientPacket::Undo);
+        server.tick().await;
+        assert_eq!(server.block(position), EMPTY_BLOCK);
+        assert_eq!(set_blocks(editor.received()), vec![(position, EMPTY_BLOCK)]);
+    }
+
+    #[tokio::test]
+    async fn removing_entities_takes_what_is_attached_along() {
+        let mut server = TestServer::start(entity_world()).await;
+        let mut editor = server.connect_editing().await;
+
+        editor.send(ClientPacket::RemoveEntity(RemoveEntity {
+            entity_id: "1".into(),
+        }));
+        server.tick().await;
+        for entity_id in ["1", "2", "3"] {
+            assert!(server.entity(entity_id).is_none(), "{entity_id}");
+        }
+        let ball = server.entity("4").unwrap();
+        assert_eq!(ball.joints.len(), 1, "{:?}", ball.joints);
+        assert_eq!(ball.joints[0].name, "post");
+
+        // The editor only removed the cart itself, so it's told about the rest
+        let (added, removed) = entity_packets(editor.received());
+        assert_eq!(removed, vec!["1", "2", "3"]);
+        assert_eq!(added, vec![ball]);
+
+        // All of it comes back in one go
+        editor.send(ClientPacket::Undo);
+        server.tick().await;
+        for entity_id in ["1", "2", "3"] {
+            assert!(server.entity(entity_id).is_some(), "{entity_id}");
+        }
+        assert_eq!(server.entity("4").unwrap().joints.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn bad_transforms_are_put_back() {
+        let mut server = TestServer::start(entity_world()).await;
+        let mut editor = server.connect_editing().await;
+        let cart = server.entity("1").unwrap();
+
+        for packet in [
+            set_transform("1", glam::Vec3::new(f32::NAN, 1., 4.), glam::Vec3::ONE),
+            set_transform("1", glam::Vec3::new(f32::INFINITY, 1., 4.), glam::Vec3::ONE),
+            set_transform(
+                "1",
+                glam::Vec3::new(5., 1., 4.),
+                glam::Vec3::new(1., 0., 1.),
+            ),
+        ] {
+            editor.send(packet);
+            server.tick().await;
+            assert_eq!(server.entity("1").unwrap(), cart);
+            // The editor has already moved it, so it's sent the entity as it was
+            let (added, _) = entity_packets(editor.received());
+            assert_eq!(added, vec![cart.clone()]);
+        }
+
+        editor.send(ClientPacket::SetEntityTransform(SetEntityTransform {
+            entity_id: "1".into(),
+            position: glam::Vec3::new(5., 1., 4.),
+            rotation: glam::Quat::from_xyzw(0., 0., 0., 2.),
+            scale: glam::Vec3::splat(2.),
+        }));
+        server.tick().await;
+        let state = server.entity("1").unwrap().state;
+        assert_eq!(state.position, glam::Vec3::new(5., 1., 4.));
+        assert_eq!(state.rotation, glam::Quat::IDENTITY);
+        assert_eq!(state.scale, glam::Vec3::splat(2.));
+        assert_eq!(entity_packets(editor.received()), (vec![], vec![]));
+    }
+
+    #[tokio::test]
+    async fn entities_can_be_renamed() {
+        let mut server = TestServer::start(entity_world()).await;
+        let mut editor = server.connect_editing().await;
+        let rename = |name: String| {
+            ClientPacket::RenameEntity(RenameEntity {
+                entity_id: "1".into(),
+                name,
+            })
+        };
+
+        editor.send(rename("a".repeat(MAX_ENTITY_NAME_LENGTH + 1)));
+        server.tick().await;
+        assert_eq!(server.entity("1").unwrap().name, "Entity 1");
+        let (added, _) = entity_packets(editor.received());
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].name, "Entity 1");
+
+        // The name is trimmed, so the editor is sent what it ended up as
+        editor.send(rename("  Cart ".into()));
+        server.tick().await;
+        assert_eq!(server.entity("1").unwrap().name, "Cart");
+        let (added, _) = entity_packets(editor.received());
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].name, "Cart");
+    }
+
+    #[tokio::test]
+    async fn custom_state_can_be_edited() {
+        let mut server = TestServer::start(entity_world()).await;
+        let editor = server.connect_editing().await;
+        let custom_state = [("speed".to_string(), serde_json::json!(2))]
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+
+        editor.send(ClientPacket::SetEntityCustomState(SetEntityCustomState {
+            entity_id: "1".into(),
+            custom_state: custom_state.clone(),
+        }));
+        server.tick().await;
+        assert_eq!(server.entity("1").unwrap().state.custom_state, custom_state);
+
+        editor.send(ClientPacket::Undo);
+        server.tick().await;
+        assert!(server.entity("1").unwrap().state.custom_state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn players_and_missing_entities_cant_be_edited() {
+        let mut server = TestServer::start(entity_world()).await;
+        let mut editor = server.connect_editing().await;
+        let player = server.entity("5").unwrap();
+
+        editor.send(ClientPacket::RemoveEntity(RemoveEntity {
+            entity_id: "5".into(),
+        }));
+        server.tick().await;
+        assert_eq!(server.entity("5"), Some(player.clone()));
+        assert_eq!(entity_packets(editor.received()), (vec![player], vec![]));
+
+        // The editor is told to get rid of an entity the server doesn't have
+        editor.send(set_transform("6", glam::Vec3::ZERO, glam::Vec3::ONE));
+        server.tick().await;
+        assert_eq!(
+            entity_packets(editor.received()),
+            (vec![], vec!["6".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn pasted_entities_are_checked() {
+        let mut server = TestServer::start(entity_world()).await;
+        let mut editor = server.connect_editing().await;
+        let paste = |id: &str, entity_type| {
+            let entity = EntityData {
+                entity_type: Some(entity_type),
+                ..test_util::entity(id, 0, glam::Vec3::new(0.5, 1., 0.5))
+            };
+            ClientPacket::SetBlocks(SetBlocks {
+                origin: BlockPos::new(2, 1, 2),
+                operation: RegionOperation::Paste {
+                    blocks: blocks::BlockRegion::new(glam::UVec3::ONE, vec![1]).unwrap(),
+                    entities: vec![entity],
+                },
+            })
+        };
+
+        // Nothing is pasted, and the editor isn't sent anything back
+        for refused in [
+            paste("crate", 0),
+            paste("", 0),
+            paste("1", 0),
+            paste("6", 3),
+        ] {
+            editor.send(refused);
+            server.tick().await;
+            assert_eq!(server.block(BlockPos::new(2, 1, 2)), EMPTY_BLOCK);
+            assert!(editor.received().is_empty());
+        }
+        assert!(server.entity("6").is_none());
 
-        world.entities.insert(id, entity.entity_data);
-        world.save(storage_dir).expect("save world");
+        editor.send(paste("6", 0));
+        server.tick().await;
+        assert_eq!(server.block(BlockPos::new(2, 1, 2)), 1);
+        let pasted = server.entity("6").unwrap();
+        assert_eq!(pasted.state.position, glam::Vec3::new(2.5, 2., 2.5));
     }
 }
@@ -1,38 +1,58 @@
 use {
-    crate::game::{network::KnownEntityState, world::spawn_entity},
+    crate::game::{network::KnownEntityState, world::spawn_entities},
     anyhow::Result,
-    entities::{Anchor, PlayerId},
+    entities::PlayerId,
     serde_json::Map,
-    std::{
-        mem,
-        sync::{Arc, Mutex},
-    },
+    std::sync::{Arc, Mutex},
 };
 
 use {
-    crate::game::{network::ClientPlayerState, PlayerState},
     entities::{EntityData, EntityID},
     std::collections::{HashMap, HashSet},
 };
 
-use blocks::{BlockGrid, BlockPos, EMPTY_BLOCK};
+use blocks::{BlockGrid, BlockPos, ChunkPos};
 use entities::EntityTypeID;
-use glam::Vec3;
-use net_types::ClientShouldSwitchMode;
-use physics::{PhysicsCollider, PhysicsWorld};
+use net_types::{patch::ScriptStatePatch, ClientShouldSwitchMode};
+use physics::PhysicsWorld;
 use tokio::sync::mpsc;
 
-use crate::js::JSContext;
+use crate::js::{JSContext, OutOfTime};
 
 use super::{
     editor_instance::EditorInstance,
+    movement_state,
     network::{Client, ClientId, ClientMessageReceiver, ServerMessageSender},
+    new_player,
     world::{self, World},
-    GameState, NextServerState, Player, WORLD_SIZE,
+    EntityEvent, GameState, NextServerState, PlayerState, TICK_RATE,
 };
 
 const DEBUG_LINES: bool = false;
 
+// Chunks within this many chunks of a client's player are streamed to that client
+const CHUNK_LOAD_RADIUS: i32 = 4;
+// Chunks are only unloaded a little further out, so that walking back and forth
+// over a chunk border doesn't resend the same chunks over and over
+const CHUNK_UNLOAD_RADIUS: i32 = CHUNK_LOAD_RADIUS + 1;
+// If a client's inputs arrive faster than we process them, only this many are kept
+const MAX_QUEUED_INPUTS: usize = 8;
+// A player whose next input is late waits this many ticks for it, in case it's only held up a
+// little. After that it carries on with its last controls, so it can't hang in the air by not
+// sending any.
+const LATE_INPUT_TICKS: u32 = 2;
+// Limits how many chunks are sent to each client per tick, so joining a large world doesn't stall
+const MAX_CHUNK_LOADS_PER_TICK: usize = 8;
+// If more blocks than this change in a chunk in one tick, the whole chunk is sent again instead
+const MAX_BLOCK_CHANGES_PER_CHUNK: usize = 64;
+// Entities whose scripts fail this many times are disabled, rather than failing every tick forever
+const ENTITY_ERROR_BUDGET: u32 = 10;
+// Players whose scripts fail this many times aren't updated any more, for the same reason
+const PLAYER_ERROR_BUDGET: u32 = 10;
+// The same script error is only sent once in this many ticks, so one thrown every tick doesn't
+// flood the editor
+const SCRIPT_ERROR_REPEAT_TICKS: u64 = TICK_RATE as u64 * 5;
+
 pub struct GameInstance {
     pub world: Arc<Mutex<World>>,
     // world script state
@@ -42,25 +62,41 @@ pub struct GameInstance {
     pub clients: HashMap<ClientId, Client>,
 
     pub physics_world: Arc<Mutex<PhysicsWorld>>,
-    pub colliders: Vec<PhysicsCollider>,
     next_player_id: u64,
-    pub players: HashMap<PlayerId, Player>,
     player_spawn_point: glam::Vec3,
+    // Counts up once per tick. Sent with updates so clients can interpolate between them.
+    current_tick: u64,
+    // How many times each entity's script has failed
+    entity_errors: HashMap<EntityID, u32>,
+    // Entities that have used up their error budget. They stay in the world, but their scripts
+    // aren't run any more.
+    disabled_entities: HashSet<EntityID>,
+    // Same again for players, which stay where they are once their script is no longer run
+    player_errors: HashMap<PlayerId, u32>,
+    disabled_players: HashSet<PlayerId>,
+    // The client that came from the editor. Script errors are for whoever is working on the world,
+    // so they're only sent to this client.
+    editor_client: Option<ClientId>,
+    // The tick each script error was last sent on, by script, function and message
+    reported_errors: HashMap<(String, String, String), u64>,
 }
 
 impl GameInstance {
     pub fn new(world: Arc<Mutex<World>>) -> Self {
-        // Roughly in the center of the map
-        let player_spawn_point =
-            glam::Vec3::new(WORLD_SIZE as f32 / 2., 4., WORLD_SIZE as f32 / 2.);
-
         let mut physics_world = PhysicsWorld::new();
-        let mut colliders = Vec::new();
 
-        {
+        // Roughly in the center of the map
+        let player_spawn_point = {
             let world = world.lock().expect("DEADLOCK!!");
-            bake_terrain_colliders(&mut physics_world, &world.blocks, &mut colliders);
-        }
+            bake_terrain_colliders(&mut physics_world, &world.blocks);
+
+            let (min, max) = world
+                .blocks
+                .bounds()
+                .unwrap_or((BlockPos::new(0, 0, 0), BlockPos::new(0, 0, 0)));
+            let center = (glam::Vec3::from(min) + glam::Vec3::from(max)) / 2.;
+            glam::Vec3::new(center.x, 4., center.z)
+        };
 
         let physics_world = Arc::new(Mutex::new(physics_world));
 
@@ -69,12 +105,17 @@ impl GameInstance {
             custom_world_state: serde_json::Value::Object(Map::new()),
             player_spawn_point,
             physics_world,
-            colliders,
             _game_state: Default::default(),
             next_client_id: Default::default(),
             clients: Default::default(),
             next_player_id: 0,
-            players: Default::default(),
+            current_tick: 0,
+            entity_errors: Default::default(),
+            disabled_entities: Default::default(),
+            player_errors: Default::default(),
+            disabled_players: Default::default(),
+            editor_client: None,
+            reported_errors: Default::default(),
         }
     }
 
@@ -92,6 +133,7 @@ impl GameInstance {
             world,
             mut editor_client,
             physics_world,
+            ..
         } = editor_instance;
         let mut game_instance = GameInstance::new(world.clone());
 
@@ -109,24 +151,24 @@ impl GameInstance {
         // The new old physics world is dropped here
         game_instance.physics_world = physics_world;
 
+        // Timers from the last time the game was played would go off in the middle of this one
+        js_context.clear_timers(None);
+
         // Entities need to be spawned into the new physics world
         {
             let mut world = game_instance.world.lock().unwrap();
             let entity_type_registry = world.entity_type_registry.clone();
-            for entity_data in world.entities.values_mut() {
-                spawn_entity(
-                    entity_data,
-                    js_context,
-                    game_instance.physics_world.clone(),
-                    &entity_type_registry,
-                );
-            }
+            spawn_entities(
+                &mut world.entities,
+                js_context,
+                game_instance.physics_world.clone(),
+                &entity_type_registry,
+            );
         }
 
-        // Init the world after entities are spawned but before players are added
-        game_instance
-            .init(js_context)
-            .expect("Error during world init");
+        // Init the world after entities are spawned but before players are added. If it fails the
+        // error has been reported, and we carry on with whatever state the world had.
+        let _ = game_instance.init(js_context);
 
         // Create a player for the editor client and also spawn that into the new physics world
         let new_player_id = PlayerId::new(game_instance.next_player_id);
@@ -136,21 +178,16 @@ impl GameInstance {
             &mut game_instance.custom_world_state,
             new_player_id,
             game_instance.player_spawn_point,
-            &game_instance.physics_world,
         ) {
             Ok(p) => p,
             Err(e) => {
                 tracing::error!("Error spawning player: {:?}\n", e);
                 tracing::warn!("Spawning default player");
                 // make a new player without calling any spawn script
-                Player::new(
-                    new_player_id,
-                    &mut game_instance.physics_world.lock().expect("Deadlock"),
-                    game_instance.player_spawn_point,
-                )
+                new_player(new_player_id, game_instance.player_spawn_point)
             }
         };
-        game_instance.players.insert(new_player_id, player);
+        game_instance.add_player(player);
 
         // Set the player ID on the editor client
         editor_client.player_id = new_player_id;
@@ -162,121 +199,117 @@ impl GameInstance {
         game_instance.next_client_id = game_instance.next_client_id + 1;
 
         // IMPORTANT: Send switch mode packet
-        let _ = editor_client
-            .outgoing_tx
-            .send(
-                net_types::ServerPacket::ClientShouldSwitchMode(ClientShouldSwitchMode::Play {
-                    new_player_id,
-                })
-                .into(),
-            )
-            .await;
+        editor_client.send(ClientShouldSwitchMode::Play { new_player_id });
         game_instance.clients.insert(client_id, editor_client);
+        game_instance.editor_client = Some(client_id);
         game_instance
     }
 
-    pub async fn tick(&mut self, js_context: &mut JSContext) -> Option<NextServerState> {
-        // World script update
-        if let Err(err) = js_context.run_world_update(&mut self.custom_world_state) {
-            tracing::error!("Error running scripted world update: {err:#}");
-        }
+    /// While paused the world carries on as usual, but the game clock that script timers run on
+    /// stands still.
+    pub async fn tick(
+        &mut self,
+        js_context: &mut JSContext,
+        paused: bool,
+    ) -> Option<NextServerState> {
+        self.current_tick += 1;
+        js_context.begin_tick();
 
-        // Handle client messages
-        let maybe_next_state = self.client_net_updates().await;
+        // World script update. Errors are reported by the JS context.
+        let _ = js_context.run_world_update(&mut self.custom_world_state);
 
-        // Remove any entities attached to removed players
-        {
-            let mut world = self.world.lock().expect("Deadlock!");
-            world.entities.retain(|_, entity| {
-                if let Some(Anchor { player_id, .. }) = entity.state.anchor {
-                    self.players.contains_key(&player_id)
-                } else {
-                    true
-                }
-            });
+        if !paused {
+            let _ = js_context.run_timers();
         }
 
-        // Copy player data into world
-        {
-            let mut world = self.world.lock().expect("Deadlock");
-            world.player_data = self
-                .players
-                .iter()
-                .map(|(player_id, player)| (player_id.clone(), player.state.clone()))
-                .collect();
-        }
+        // Handle client messages
+        let maybe_next_state = self.client_net_updates().await;
 
-        // Update players
+        // Update players. Their bodies are moved along with the other entities' in the physics
+        // step.
+        let mut failed_players = Vec::new();
         for client in self.clients.values_mut() {
-            let player = self.players.get_mut(&client.player_id).unwrap();
-
-            // Update the list of attached entities
-            {
-                let world = self.world.lock().expect("Deadlock!");
+            let Some(player_state) = self
+                .world
+                .lock()
+                .expect("Deadlock!")
+                .player_state(client.player_id)
+            else {
+                continue;
+            };
 
-                player.state.attached_entities.clear();
-                for (entity_id, entity) in &world.entities {
-                    let Some(anchor) = &entity.state.anchor else {
-                        continue;
-                    };
-                    if anchor.player_id != client.player_id {
+            // Process one input per tick, the same rate the client produces them at. If the next
+            // input hasn't arrived yet the player waits a moment for it, then moves with the last
+            // controls again. Those moves are acked under the last input's sequence, and the client
+            // corrects for them when it reconciles.
+            match client.input_queue.pop_front() {
+                Some(controls) => {
+                    client.last_controls = controls;
+                    client.late_ticks = 0;
+                }
+                None if client.last_controls.sequence != 0 => {
+                    client.late_ticks += 1;
+                    if client.late_ticks <= LATE_INPUT_TICKS {
                         continue;
                     }
-
-                    let entity_list = player
-                        .state
-                        .attached_entities
-                        .entry(anchor.parent_anchor.clone())
-                        .or_insert_with(Default::default);
-                    entity_list.push(entity_id.clone());
                 }
+                None => {}
             }
 
-            player.state = js_context
-                .get_player_next_state(client.player_id, &player.state, &client.last_controls)
-                .await
-                .unwrap();
+            if self.disabled_players.contains(&client.player_id) {
+                continue;
+            }
 
-            // Update Rapier
+            // If the script fails the player stays where it is
+            match js_context
+                .get_player_next_state(client.player_id, &player_state, &client.last_controls)
+                .await
             {
-                let mut physics_world = self.physics_world.lock().expect("Deadlock!");
-                physics_world.set_velocity_and_position(
-                    &player.body,
-                    player.state.velocity,
-                    player.state.position,
-                );
+                Ok(next_state) => {
+                    let mut world = self.world.lock().expect("Deadlock!");
+                    if let Some(player) = world.player_entity_mut(client.player_id) {
+                        next_state.apply(player);
+                    }
+                }
+                Err(error) if !error.is::<OutOfTime>() => failed_players.push(client.player_id),
+                Err(_) => {}
             }
 
             // Reset edge trigger controls once per tick
             client.last_controls.fire = false;
             client.last_controls.jump = false;
         }
+        for player_id in failed_players {
+            self.player_script_failed(player_id);
+        }
 
         // Update entities' absolute positions immediately after updating players
-        {
-            let mut world = self.world.lock().expect("Deadlock!");
-            for entity in world.entities.values_mut() {
-                if let Some(anchor) = &entity.state.anchor {
-                    if let Some(player) = self.players.get(&anchor.player_id) {
-                        entity.state.absolute_position = player.state.position;
-                    }
-                } else {
-                    entity.state.absolute_position = entity.state.position;
-                }
-            }
-        }
+        self.world
+            .lock()
+            .expect("Deadlock!")
+            .update_absolute_positions();
 
         // Update entities
         let entity_data = self.get_entities_in_world();
 
         for (entity_id, entity_type_id) in entity_data {
-            js_context
+            if self.disabled_entities.contains(&entity_id) {
+                continue;
+            }
+
+            // Running out of time isn't the entity's fault, so it doesn't count as a failure
+            if let Err(error) = js_context
                 .run_script_for_entity(&entity_id, entity_type_id)
                 .await
-                .unwrap();
+            {
+                if !error.is::<OutOfTime>() {
+                    self.entity_script_failed(entity_id);
+                }
+            }
         }
 
         // Step physics
+        let mut entity_events = Vec::new();
         {
             let mut physics_world = self.physics_world.lock().expect("Deadlock!");
             let mut world = self.world.lock().expect("Deadlock!");
@@ -284,25 +317,58 @@ impl GameInstance {
             // borrowing is hard
             let entity_type_registry = world.entity_type_registry.clone();
             physics_world.step(&mut world.entities, &entity_type_registry);
+            entity_events.extend(
+                physics_world
+                    .take_collision_events()
+                    .into_iter()
+                    .map(EntityEvent::from),
+            );
 
             if DEBUG_LINES {
                 let debug_lines = physics_world.get_debug_lines();
-                for (_, client) in self.clients.iter() {
-                    let _ = client
-                        .outgoing_tx
-                        .send(net_types::ServerPacket::SetDebugLines(debug_lines.clone()))
-                        .await;
+                for client in self.clients.values_mut() {
+                    client.send(net_types::ServerPacket::SetDebugLines(debug_lines.clone()));
                 }
             }
         }
 
+        // onSpawn and the event callbacks only happen once, so they can't be skipped for lack of
+        // time. They're only limited by the call budget.
+        js_context.end_tick();
+
         // Run world commands queued from the scripts
-        let mut world = self.world.lock().expect("Deadlock!");
         let mut queued_sounds = Vec::new();
-        world.apply_queued_updates(js_context, self.physics_world.clone(), &mut queued_sounds);
+        let mut changed_blocks = Vec::new();
+        self.world.lock().expect("Deadlock!").apply_queued_updates(
+            js_context,
+            self.physics_world.clone(),
+            &mut queued_sounds,
+            &mut entity_events,
+            &mut changed_blocks,
+        );
+        self.send_changed_blocks_to_clients(&changed_blocks);
+
+        // Let entities' scripts know what happened to them this tick
+        for event in entity_events {
+            let entity_id = event.entity_id();
+            if !self.disabled_entities.contains(entity_id)
+                && js_context.run_entity_event(&event).is_err()
+            {
+                self.entity_script_failed(entity_id.to_string());
+            }
+
+            // Timers set by the entity's script go with it
+            if let EntityEvent::Despawn(_) = event {
+                js_context.clear_timers(Some(entity_id));
+            }
+        }
 
         // NASTY(kmrw)
-        self.send_queued_sounds_to_clients(queued_sounds).await;
+        self.send_queued_sounds_to_clients(queued_sounds);
+        self.send_script_errors_to_clients(js_context.take_errors());
+
+        // Send everything queued for each client this tick
+        self.flush_clients();
 
         maybe_next_state
     }
@@ -314,54 +380,38 @@ impl GameInstance {
     ) {
         let player_id = PlayerId::new(self.next_player_id);
         self.next_player_id += 1;
-        {
-            let player = match spawn_player(
-                js_context,
-                &mut self.custom_world_state,
-                player_id,
-                self.player_spawn_point,
-                &self.physics_world,
-            ) {
-                Ok(p) => p,
-                Err(e) => {
-                    tracing::error!("Error spawning player: {:?}", e);
-                    // Drop player connection
-                    return;
-                }
-            };
-            self.players.insert(player_id, player);
-        }
+        let player = match spawn_player(
+            js_context,
+            &mut self.custom_world_state,
+            player_id,
+            self.player_spawn_point,
+        ) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::error!("Error spawning player: {:?}", e);
+                // Drop player connection
+                return;
+            }
+        };
+        self.add_player(player);
 
         let client_id = self.next_client_id;
         self.next_client_id = self.next_client_id + 1;
 
         let world = &self.world.lock().expect("Deadlock!");
 
-        // Send world init packet
-        let _ = outgoing_tx
-            .send(
-                net_types::Init {
-                    blocks: world.blocks.clone(),
-                    block_registry: world.block_registry.clone(),
-                    entities: world.entities.clone(),
-                    entity_type_registry: world.entity_type_registry.clone(),
-                    client_player: player_id,
-                    world_script_state: self.custom_world_state.clone(),
-                }
-                .into(),
-            )
-            .await;
-
-        self.clients.insert(
-            client_id,
-            Client {
-                last_controls: net_types::Controls::default(),
-                player_id,
-                awareness: Default::default(),
-                incoming_rx,
-                outgoing_tx,
-            },
-        );
+        // Send world init packet. Blocks and entities are streamed in once the client is added.
+        let mut client = Client::new(player_id, incoming_rx, outgoing_tx);
+        client.send(net_types::Init {
+            blocks: BlockGrid::new(),
+            block_registry: world.block_registry.clone(),
+            entities: HashMap::new(),
+            entity_type_registry: world.entity_type_registry.clone(),
+            client_player: player_id,
+            world_script_state: self.custom_world_state.clone(),
+        });
+
+        self.clients.insert(client_id, client);
 
         tracing::info!("New client connected: {:?}", client_id);
     }
@@ -369,11 +419,9 @@ impl GameInstance {
     async fn client_net_updates(&mut self) -> Option<NextServerState> {
         let mut disconnected = Vec::new();
         let mut maybe_next_state = None;
-        let live_players = self.players.keys().copied().collect::<HashSet<_>>();
-        let world = self.world.lock().expect("Deadlock!");
+        let mut world = self.world.lock().expect("Deadlock!");
         let mut physics_world = self.physics_world.lock().expect("Deadlock!");
 
-        let live_entities = world.entities.keys().cloned().collect::<HashSet<_>>();
         'client_loop: for (client_id, client) in self.clients.iter_mut() {
             while let Some(packet) = match client.incoming_rx.try_recv() {
                 Ok(v) => Some(v),
@@ -387,23 +435,16 @@ impl GameInstance {
                 },
             } {
                 match packet {
-                    net_types::ClientPacket::Controls(net_types::Controls {
-                        move_direction,
-                        jump,
-                        fire,
-                        camera_yaw,
-                        camera_pitch,
-                    }) => {
-                        client.last_controls.move_direction = move_direction;
-                        client.last_controls.camera_yaw = camera_yaw;
-                        client.last_controls.camera_pitch = camera_pitch;
-
-                        // Only allow the client to trigger jump and fire once per tick
-                        if jump {
-                            client.last_controls.jump = true;
-                        }
-                        if fire {
-                            client.last_controls.fire = true;
+                    net_types::ClientPacket::Controls(controls) => {
+                        client.input_queue.push_back(controls);
+
+                        // Drop the oldest inputs if there's a backlog, but don't lose any presses
+                        while client.input_queue.len() > MAX_QUEUED_INPUTS {
+                            let dropped = client.input_queue.pop_front().unwrap();
+                            if let Some(next) = client.input_queue.front_mut() {
+                                next.jump |= dropped.jump;
+                                next.fire |= dropped.fire;
+                            }
                         }
                     }
                     net_types::ClientPacket::Start => {
@@ -419,18 +460,42 @@ impl GameInstance {
                 }
             }
 
-            sync_players_to_client(&self.players, &live_players, client).await;
-            sync_entities_to_client(&world.entities, &live_entities, client).await;
-            sync_world_script_state_to_client(&self.custom_world_state, client).await;
+            let Some(player) = world.player_entity(client.player_id) else {
+                continue;
+            };
+            let player_chunk = ChunkPos::containing(BlockPos::from_float(player.state.position));
+
+            // Work out what's relevant to this client
+            let viewer = world.relevance.viewer(client.player_id, player);
+            let relevant_players = world.relevance.relevant_players(
+                &viewer,
+                &world.players,
+                &world.entities,
+                &client.awareness.entities,
+            );
+            let relevant_entities = world.relevance.relevant_entities(
+                &viewer,
+                &world.entities,
+                &world.entity_type_registry,
+                &relevant_players,
+                &client.awareness.entities,
+            );
+
+            sync_chunks_to_client(&world.blocks, player_chunk, client);
+            sync_entities_to_client(
+                &world.entities,
+                &relevant_entities,
+                self.current_tick,
+                client,
+            );
+            sync_input_ack_to_client(player, client);
+            sync_world_script_state_to_client(&self.custom_world_state, client);
         }
 
         // Remove disconnected clients, and their associated players
         self.clients.retain(|client_id, client| {
             if disconnected.contains(client_id) {
-                if let Some(player) = self.players.remove(&client.player_id) {
-                    // Make sure to remove the physics body
-                    physics_world.remove_body(player.body);
-                }
+                world.remove_player(client.player_id, &mut physics_world);
                 false
             } else {
                 true
@@ -447,149 +512,284 @@ impl GameInstance {
         // work around borrowing issues? no time, baby
         let entity_type_registry = world.entity_type_registry.clone();
 
-        let entities = &mut world.entities;
-        for entity_data in entities.values_mut() {
-            world::spawn_entity(
-                entity_data,
-                js_context,
-                self.physics_world.clone(),
-                &entity_type_registry,
-            );
-        }
+        world::spawn_entities(
+            &mut world.entities,
+            js_context,
+            self.physics_world.clone(),
+            &entity_type_registry,
+        );
     }
 
+    // The entities with scripts to run, which is everything but the players
     fn get_entities_in_world(&self) -> Vec<(EntityID, EntityTypeID)> {
         let world = self.world.lock().expect("Deadlock!");
         world
             .entities
             .iter()
-            .map(|(entity_id, entity)| (entity_id.clone(), entity.entity_type))
+            .filter_map(|(entity_id, entity)| Some((entity_id.clone(), entity.entity_type?)))
             .collect::<Vec<_>>()
     }
 
-    async fn send_queued_sounds_to_clients(&self, queued_sounds: Vec<net_types::PlaySound>) {
+    fn add_player(&mut self, player: EntityData) {
+        let mut physics_world = self.physics_world.lock().expect("Deadlock!");
+        self.world
+            .lock()
+            .expect("Deadlock!")
+            .add_player(player, &mut physics_world);
+    }
+
+    fn send_queued_sounds_to_clients(&mut self, queued_sounds: Vec<net_types::PlaySound>) {
         if queued_sounds.is_empty() {
             return;
         };
 
-        for client in self.clients.values() {
+        for client in self.clients.values_mut() {
             for sound in &queued_sounds {
-                if let Err(_) = client
-                    .outgoing_tx
-                    .send(net_types::ServerPacket::PlaySound(sound.clone()))
-                    .await
-                {
-                    tracing::error!("Error sending play sound packet");
-                }
+                client.send(net_types::ServerPacket::PlaySound(sound.clone()));
             }
         }
     }
-}
 
-async fn sync_players_to_client(
-    players: &HashMap<PlayerId, Player>,
-    live_players: &HashSet<PlayerId>,
-    client: &mut Client,
-) {
-    let known_players = client
-        .awareness
-        .players
-        .keys()
-        .copied()
-        .collect::<HashSet<_>>();
+    fn send_changed_blocks_to_clients(&mut self, changed_blocks: &[BlockPos]) {
+        if changed_blocks.is_empty() {
+            return;
+        }
 
-    let new_players = live_players.difference(&known_players);
-    let removed_players = known_players.difference(live_players);
-
-    // Add new players to this client
-    for player_id in new_players {
-        let player = players.get(player_id).unwrap();
-        let _ = client
-            .outgoing_tx
-            .send(
-                net_types::AddPlayer {
-                    id: *player_id,
-                    position: player.state.position,
-                    animation_state: player.state.animation_state.clone(),
-                    script_state: player.state.custom_state.clone(),
-                    model_path: player.state.model_path.clone(),
-                }
-                .into(),
-            )
-            .await;
-        client
-            .awareness
+        let mut changes_by_chunk: HashMap<ChunkPos, Vec<BlockPos>> = HashMap::new();
+        for &position in changed_blocks {
+            changes_by_chunk
+                .entry(ChunkPos::containing(position))
+                .or_default()
+                .push(position);
+        }
+
+        let world = self.world.lock().expect("Deadlock!");
+        for client in self.clients.values_mut() {
+            sync_changed_blocks_to_client(&world.blocks, &changes_by_chunk, client);
+        }
+    }
+
+    pub fn send_script_errors_to_clients(&mut self, errors: Vec<net_types::ScriptError>) {
+        let Some(client) = self
+            .editor_client
+            .and_then(|client_id| self.clients.get_mut(&client_id))
+        else {
+            return;
+        };
+
+        let current_tick = self.current_tick;
+        self.reported_errors
+            .retain(|_, &mut tick| current_tick < tick + SCRIPT_ERROR_REPEAT_TICKS);
+        for error in errors {
+            let key = (
+                error.script.clone(),
+                error.function.clone(),
+                error.message.clone(),
+            );
+            if self.reported_errors.contains_key(&key) {
+                continue;
+            }
+            self.reported_errors.insert(key, current_tick);
+            client.send(net_types::ServerPacket::ScriptError(error));
+        }
+    }
+
+    /// The scripts have changed, so anything that was failing gets another chance
+    pub fn scripts_reloaded(&mut self) {
+        self.entity_errors.clear();
+        self.disabled_entities.clear();
+        self.player_errors.clear();
+        self.disabled_players.clear();
+        self.reported_errors.clear();
+    }
+
+    fn entity_script_failed(&mut self, entity_id: EntityID) {
+        let errors = self.entity_errors.entry(entity_id.clone()).or_default();
+        *errors += 1;
+        let errors = *errors;
+        if errors < ENTITY_ERROR_BUDGET {
+            return;
+        }
+
+        tracing::warn!("Disabling entity {entity_id}, its script has failed {errors} times");
+        let error = net_types::ScriptError {
+            script: self.entity_script_path(&entity_id).unwrap_or_default(),
+            function: "update".into(),
+            entity_id: Some(entity_id.clone()),
+            message: format!("Entity {entity_id} disabled after its script failed {errors} times"),
+            stack: None,
+        };
+        self.send_script_errors_to_clients(vec![error]);
+        self.disabled_entities.insert(entity_id);
+    }
+
+    fn player_script_failed(&mut self, player_id: PlayerId) {
+        let errors = self.player_errors.entry(player_id).or_default();
+        *errors += 1;
+        let errors = *errors;
+        if errors < PLAYER_ERROR_BUDGET {
+            return;
+        }
+
+        let player_id_number = player_id.inner();
+        tracing::warn!(
+            "No longer updating player {player_id_number}, its script has failed {errors} times"
+        );
+        let entity_id = self
+            .world
+            .lock()
+            .expect("Deadlock!")
             .players
-            .insert(*player_id, ClientPlayerState::new(&player.state));
+            .get(&player_id)
+            .cloned();
+        let error = net_types::ScriptError {
+            script: "player.js".into(),
+            function: "update".into(),
+            entity_id,
+            message: format!(
+                "Player {player_id_number} stopped after its script failed {errors} times"
+            ),
+            stack: None,
+        };
+        self.send_script_errors_to_clients(vec![error]);
+        self.disabled_players.insert(player_id);
     }
 
-    // Remove old players from this client
-    for player_id in removed_players {
-        let _ = client
-            .outgoing_tx
-            .send(net_types::RemovePlayer { id: *player_id }.into())
-            .await;
-        client.awareness.players.remove(player_id);
+    fn entity_script_path(&self, entity_id: &str) -> Option<String> {
+        let world = self.world.lock().expect("Deadlock!");
+        let entity = world.entities.get(entity_id)?;
+        let entity_type = world.entity_type_registry.get(entity.entity_type?)?;
+        Some(entity_type.script_path().to_string())
     }
 
-    // Update player positions for all known players
-    // TODO: Update sates instead?
-    for (player_id, known_state) in client.awareness.players.iter_mut() {
-        let player = players.get(player_id).unwrap();
-        if let Some(update) = player_update(*player_id, known_state, &player.state) {
-            let _ = client.outgoing_tx.send(update.into()).await;
-            *known_state = ClientPlayerState::new(&player.state);
+    // Send each client its packets for this tick. Clients that have gone away or can't keep up
+    // are dropped, rather than holding up the tick for everyone else.
+    fn flush_clients(&mut self) {
+        let dropped = self
+            .clients
+            .iter_mut()
+            .filter_map(|(client_id, client)| (!client.flush()).then_some(*client_id))
+            .collect::<Vec<_>>();
+        if dropped.is_empty() {
+            return;
+        }
+
+        let mut world = self.world.lock().expect("Deadlock!");
+        let mut physics_world = self.physics_world.lock().expect("Deadlock!");
+        for client_id in dropped {
+            tracing::warn!("Dropping client {client_id:?}, it has gone away or isn't keeping up");
+            let Some(client) = self.clients.remove(&client_id) else {
+                continue;
+            };
+            world.remove_player(client.player_id, &mut physics_world);
         }
     }
 }
 
-async fn sync_world_script_state_to_client(
-    world_script_state: &serde_json::Value,
+// The distance between two chunks, in chunks, along the axis they're furthest apart on
+fn chunk_distance(a: ChunkPos, b: ChunkPos) -> i32 {
+    (a.as_ivec3() - b.as_ivec3()).abs().max_element()
+}
+
+// Tell a client about blocks that have changed in the chunks it has. Chunks it doesn't have yet
+// are sent whole when it gets close enough, changes and all.
+fn sync_changed_blocks_to_client(
+    blocks: &BlockGrid,
+    changes_by_chunk: &HashMap<ChunkPos, Vec<BlockPos>>,
     client: &mut Client,
 ) {
+    for (&chunk_position, changes) in changes_by_chunk {
+        if !client.awareness.chunks.contains(&chunk_position) {
+            continue;
+        }
+
+        // It's cheaper to resend the whole chunk than to send lots of it a block at a time
+        if changes.len() > MAX_BLOCK_CHANGES_PER_CHUNK {
+            if let Some(chunk) = blocks.chunk(chunk_position) {
+                client.send(net_types::LoadChunk {
+                    position: chunk_position,
+                    chunk: chunk.clone(),
+                });
+                continue;
+            }
+        }
+
+        for &position in changes {
+            client.send(net_types::SetBlock {
+                position,
+                block_id: blocks[position],
+            });
+        }
+    }
+}
+
+fn sync_chunks_to_client(blocks: &BlockGrid, player_chunk: ChunkPos, client: &mut Client) {
+    // Unload chunks that are out of range, or that no longer exist
+    let out_of_range = client
+        .awareness
+        .chunks
+        .iter()
+        .copied()
+        .filter(|position| {
+            blocks.chunk(*position).is_none()
+                || chunk_distance(player_chunk, *position) > CHUNK_UNLOAD_RADIUS
+        })
+        .collect::<Vec<_>>();
+
+    for position in out_of_range {
+        client.send(net_types::UnloadChunk { position });
+        client.awareness.chunks.remove(&position);
+    }
+
+    // Load the closest chunks in range that the client doesn't have yet
+    let mut in_range = blocks
+        .chunks()
+        .filter(|(position, _)| {
+            !client.awareness.chunks.contains(position)
+                && chunk_distance(player_chunk, *position) <= CHUNK_LOAD_RADIUS
+        })
+        .collect::<Vec<_>>();
+    in_range.sort_by_key(|(position, _)| {
+        (position.as_ivec3() - player_chunk.as_ivec3()).length_squared()
+    });
+
+    for (position, chunk) in in_range.into_iter().take(MAX_CHUNK_LOADS_PER_TICK) {
+        client.send(net_types::LoadChunk {
+            position,
+            chunk: chunk.clone(),
+        });
+        client.awareness.chunks.insert(position);
+    }
+}
+
+fn sync_world_script_state_to_client(world_script_state: &serde_json::Value, client: &mut Client) {
     if world_script_state != &client.awareness.world_state {
-        let _ = client
-            .outgoing_tx
-            .send(net_types::SetWorldScriptState(world_script_state.clone()).into())
-            .await;
+        let patch = ScriptStatePatch::diff(&client.awareness.world_state, world_script_state);
+        client.send(net_types::PatchWorldScriptState(patch));
         client.awareness.world_state = world_script_state.clone();
     }
 }
 
-fn player_update(
-    id: PlayerId,
-    last_state: &ClientPlayerState,
-    current_state: &PlayerState,
-) -> Option<net_types::UpdatePlayer> {
-    let animation_change = if last_state.animation_state != current_state.animation_state {
-        Some(current_state.animation_state.clone())
-    } else {
-        None
-    };
-    let script_state_change = if last_state.script_state != current_state.custom_state {
-        Some(current_state.custom_state.clone())
-    } else {
-        None
-    };
-    if animation_change.is_some()
-        || script_state_change.is_some()
-        || last_state.position != current_state.position
-    {
-        Some(net_types::UpdatePlayer {
-            id,
-            position: current_state.position,
-            animation_state: animation_change,
-            script_state: script_state_change,
-            facing_angle: current_state.facing_angle,
-        })
-    } else {
-        None
+// Let the client know how far through its inputs we are, so it can reconcile
+fn sync_input_ack_to_client(player: &EntityData, client: &mut Client) {
+    let sequence = client.last_controls.sequence;
+    // Moves made while the next input is late are acked again under the same sequence
+    let repeating = client.late_ticks > LATE_INPUT_TICKS;
+    if sequence == 0 || (client.awareness.acked_input == Some(sequence) && !repeating) {
+        return;
     }
+    let Some(movement) = movement_state(player) else {
+        return;
+    };
+
+    client.send(net_types::InputAck { sequence, movement });
+    client.awareness.acked_input = Some(sequence);
 }
 
-async fn sync_entities_to_client(
+fn sync_entities_to_client(
     entities: &HashMap<EntityID, EntityData>,
-    live_entities: &HashSet<String>,
+    relevant_entities: &HashSet<EntityID>,
+    tick: u64,
     client: &mut Client,
 ) {
     let known_entities = client
@@ -599,273 +799,123 @@ async fn sync_entities_to_client(
         .cloned()
         .collect::<HashSet<_>>();
 
-    let new_entities = live_entities.difference(&known_entities);
-    let removed_entities = known_entities.difference(live_entities);
+    let new_entities = relevant_entities.difference(&known_entities);
+    let removed_entities = known_entities.difference(relevant_entities);
 
     // Add new entities to this client
     for entity_id in new_entities {
         let entity = entities.get(entity_id).unwrap();
-        let _ = client
-            .outgoing_tx
-            .send(
-                net_types::AddEntity {
-                    entity_id: entity_id.clone(),
-                    entity_data: entity.clone(),
-                }
-                .into(),
-            )
-            .await;
-        client.awareness.entities.insert(
-            entity_id.clone(),
-            KnownEntityState {
-                position: entity.state.position,
-                rotation: entity.state.rotation,
-                scale: entity.state.scale,
-                anchor: entity.state.anchor.clone(),
-            },
-        );
+        client.send(net_types::AddEntity {
+            entity_id: entity_id.clone(),
+            entity_data: entity.clone(),
+        });
+        client
+            .awareness
+            .entities
+            .insert(entity_id.clone(), KnownEntityState::new(entity));
     }
 
     // Remove old entities from this client
     for entity_id in removed_entities {
-        let _ = client
-            .outgoing_tx
-            .send(
-                net_types::RemoveEntity {
-                    entity_id: entity_id.clone(),
-                }
-                .into(),
-            )
-            .await;
+        client.send(net_types::RemoveEntity {
+            entity_id: entity_id.clone(),
+        });
         client.awareness.entities.remove(entity_id);
     }
 
-    // Update client's entity positions for all known entities
-    for (
-        entity_id,
-        KnownEntityState {
-            position: known_position,
-            rotation: known_rotation,
-            scale: known_scale,
-            anchor: known_anchor,
-        },
-    ) in &mut client.awareness.entities
-    {
+    // Update all known entities on the client, sending only what's changed
+    let mut updates = Vec::new();
+    for (entity_id, known_state) in &mut client.awareness.entities {
         let entity = entities.get(entity_id).unwrap();
-        if entity.state.position != *known_position
-            || entity.state.rotation != *known_rotation
-            || entity.state.scale != *known_scale
-            || entity.state.anchor != *known_anchor
-        {
-            let _ = client
-                .outgoing_tx
-                .send(
-                    net_types::UpdateEntity {
-                        entity_id: entity_id.clone(),
-                        position: entity.state.position,
-                        rotation: entity.state.rotation,
-                        scale: entity.state.scale,
-                        anchor: entity.state.anchor.clone(),
-                    }
-                    .into(),
-                )
-                .await;
-            *known_position = entity.state.position.clone();
-            *known_rotation = entity.state.rotation.clone();
-            *known_scale = entity.state.scale.clone();
-            *known_anchor = entity.state.anchor.clone();
+        if let Some(update) = entity_update(entity, tick, known_state) {
+            updates.push(update);
         }
     }
+    for update in updates {
+        client.send(update);
+    }
 }
 
+// What's changed about an entity since the client was last told, if anything has
+fn entity_update(
+    entity: &EntityData,
+    tick: u64,
+    known_state: &mut KnownEntityState,
+) -> Option<net_types::UpdateEntity> {
+    let position = entity.state.position.into();
+    let rotation = entity.state.rotation.into();
+    let scale = entity.state.scale.into();
+    let animation_state = entity
+        .controller
+        .as_ref()
+        .map(|controller| &controller.animation_state);
+
+    let update = net_types::UpdateEntity {
+        entity_id: entity.id.clone(),
+        tick,
+        position: (known_state.position != position).then_some(position),
+        rotation: (known_state.rotation != rotation).then_some(rotation),
+        scale: (known_state.scale != scale).then_some(scale),
+        anchor: (known_state.anchor != entity.state.anchor).then(|| entity.state.anchor.clone()),
+        animation_state: animation_state
+            .filter(|animation_state| known_state.animation_state.as_ref() != Some(animation_state))
+            .cloned(),
+        script_state: (known_state.script_state != entity.state.custom_state).then(|| {
+            ScriptStatePatch::diff_map(&known_state.script_state, &entity.state.custom_state)
+        }),
+    };
+
+    if update.position.is_none()
+        && update.rotation.is_none()
+        && update.scale.is_none()
+        && update.anchor.is_none()
+        && update.animation_state.is_none()
+        && update.script_state.is_none()
+    {
+        return None;
+    }
+
+    // Script state can be big, so it's only copied when it's changed
+    if update.script_state.is_some() {
+        known_state.script_state = entity.state.custom_state.clone();
+    }
+    known_state.position = position;
+    known_state.rotation = rotation;
+    known_state.scale = scale;
+    known_state.anchor = entity.state.anchor.clone();
+    known_state.animation_state = animation_state.cloned();
+
+    Some(update)
+}
+
+/// Runs the world's `onAddPlayer` and the player's `onSpawn` for a new player. The player isn't in
+/// the world yet, that's up to the caller.
 pub fn spawn_player(
     js_context: &mut JSContext,
     custom_world_state: &mut serde_json::Value,
     id: PlayerId,
     position: glam::Vec3,
-    physics_world: &Arc<Mutex<PhysicsWorld>>,
-) -> Result<Player> {
-    let mut physics_world = physics_world.lock().expect("Deadlock!");
-    let mut player = Player::new(id, &mut physics_world, position);
+) -> Result<EntityData> {
+    let mut player = new_player(id, position);
+    let state = PlayerState::new(&player, HashMap::new()).expect("New players are players");
 
     // First let the world mutate the spawned player
-    player.state =
-        js_context.run_world_spawn_player_script(custom_world_state, id, &player.state)?;
+    let state = js_context.run_world_spawn_player_script(custom_world_state, id, &state)?;
 
     // Note(ll): Consider that this lets the world script push any data from itself to the player
     // script. We should think about whether we want to allow this.
 
     // Then let the player script mutate itself
-    player.state = js_context.get_player_spawn_state(id, &player.state)?;
+    let state = js_context.get_player_spawn_state(id, &state)?;
+    state.apply(&mut player);
 
     Ok(player)
 }
 
-/// Rebuilds the terrain colliders from the block grid
-///
-/// This builds trimesh colliders, two for each layer along each axis: X+, X-, Y+, Y-, Z+, Z-
-pub fn bake_terrain_colliders(
-    physics_world: &mut PhysicsWorld,
-    blocks: &BlockGrid,
-    colliders: &mut Vec<PhysicsCollider>,
-) {
-    for (position, block_type_id) in blocks.iter_non_empty() {
-        physics_world.add_block_collider(position.into(), block_type_id);
-    }
-
-    if true {
-        return;
-    }
-
-    // Vertices can be shared between many faces, store indices for each unique vertex
-    let mut vert_indices = HashMap::new();
-    let mut last_vert_index: u32 = 0;
-
-    let mut layer_meshes = Vec::new();
-    let size = blocks.size();
-
-    for axis in [Axis::X, Axis::Y, Axis::Z] {
-        let forward_offset = match axis {
-            Axis::X => BlockPos::new(1, 0, 0),
-            Axis::Y => BlockPos::new(0, 1, 0),
-            Axis::Z => BlockPos::new(0, 0, 1),
-        };
-        let (layers, rows, cols) = match axis {
-            Axis::X => (size.0, size.1, size.2),
-            Axis::Y => (size.1, size.0, size.2),
-            Axis::Z => (size.2, size.0, size.1),
-        };
-
-        for layer_pos in 0..layers {
-            // We generate 2 meshes for each axis, one for the front face and one for the back face
-            let mut front_mesh = Vec::new();
-            let mut back_mesh = Vec::new();
-
-            for row in 0..rows {
-                for col in 0..cols {
-                    let mut pos = BlockPos {
-                        x: layer_pos,
-                        y: row,
-                        z: col,
-                    };
-                    match axis {
-                        Axis::X => {}
-                        Axis::Y => mem::swap(&mut pos.x, &mut pos.y),
-                        Axis::Z => mem::swap(&mut pos.x, &mut pos.z),
-                    }
-
-                    if blocks.get(pos).copied().unwrap_or(EMPTY_BLOCK) == EMPTY_BLOCK {
-                        // Empty blocks have no collider
-                        continue;
-                    }
-
-                    // Block has a collider in the front if there is no block in front of it
-                    let front_block = if layer_pos + 1 < layers {
-                        blocks
-                            .get(pos + forward_offset)
-                            .copied()
-                            .unwrap_or(EMPTY_BLOCK)
-                    } else {
-                        EMPTY_BLOCK
-                    };
-                    if front_block == EMPTY_BLOCK {
-                        let vert_indices = axis_face_vertices(axis).map(|vertex| {
-                            let vertex_pos = pos + vertex + forward_offset;
-
-                            // Get the unique index for this vertex
-                            *vert_indices.entry(vertex_pos).or_insert_with(|| {
-                                let i = last_vert_index;
-                                last_vert_index += 1;
-                                i
-                            })
-                        });
-
-                        // Add face to mesh, 2 triangles
-                        front_mesh.push([vert_indices[0], vert_indices[1], vert_indices[2]]);
-                        front_mesh.push([vert_indices[0], vert_indices[2], vert_indices[3]]);
-                    }
-
-                    // Block has a collider in the back if there is no block behind it
-                    let back_block = if layer_pos > 0 {
-                        blocks
-                            .get(pos - forward_offset)
-                            .copied()
-                            .unwrap_or(EMPTY_BLOCK)
-                    } else {
-                        EMPTY_BLOCK
-                    };
-                    if back_block == EMPTY_BLOCK {
-                        let vert_indices = axis_face_vertices(axis).map(|vertex| {
-                            let vertex_pos = pos + vertex;
-
-                            *vert_indices.entry(vertex_pos).or_insert_with(|| {
-                                let i = last_vert_index;
-                                last_vert_index += 1;
-                                i
-                            })
-                        });
-
-                        // Add face to mesh, 2 triangles
-                        back_mesh.push([vert_indices[0], vert_indices[1], vert_indices[2]]);
-                        back_mesh.push([vert_indices[0], vert_indices[2], vert_indices[3]]);
-                    }
-                }
-            }
-
-            if front_mesh.len() > 0 {
-                layer_meshes.push(front_mesh);
-            }
-            if back_mesh.len() > 0 {
-                layer_meshes.push(back_mesh);
-            }
-        }
-    }
-
-    // Invert the vertices map, putting the vertices in a vec where the indices correspond to the
-    // indices generated for each layer mesh
-    let mut vertices = vec![Vec3::ZERO; vert_indices.len() as usize];
-    for (vertex, index) in vert_indices {
-        vertices[index as usize] = Vec3::new(vertex.x as f32, vertex.y as f32, vertex.z as f32);
-    }
-    tracing::info!(
-        "Generating trimesh colliders from {} unique vertices",
-        vertices.len()
-    );
-
-    for layer_mesh in layer_meshes {
-        let collider =
-            physics_world.add_trimesh_collider(vertices.iter().copied(), layer_mesh.into_iter());
-        colliders.push(collider);
-    }
-}
-
-/// Get 4 vertices for a block's face aligned along the specified axis
-fn axis_face_vertices(axis: Axis) -> [BlockPos; 4] {
-    match axis {
-        Axis::X => [
-            BlockPos::new(0, 0, 0),
-            BlockPos::new(0, 0, 1),
-            BlockPos::new(0, 1, 1),
-            BlockPos::new(0, 1, 0),
-        ],
-        Axis::Y => [
-            BlockPos::new(0, 0, 0),
-            BlockPos::new(0, 0, 1),
-            BlockPos::new(1, 0, 1),
-            BlockPos::new(1, 0, 0),
-        ],
-        Axis::Z => [
-            BlockPos::new(0, 0, 0),
-            BlockPos::new(1, 0, 0),
-            BlockPos::new(1, 1, 0),
-            BlockPos::new(0, 1, 0),
-        ],
+/// Builds the terrain colliders for every chunk in the block grid. After this, changed chunks
+/// are rebuilt as they change.
+pub fn bake_terrain_colliders(physics_world: &mut PhysicsWorld, blocks: &BlockGrid) {
+    for (position, chunk) in blocks.chunks() {
+        physics_world.set_terrain_chunk(position, Some(chunk));
     }
 }
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Axis {
-    X,
-    Y,
-    Z,
-}
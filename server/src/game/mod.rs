@@ -1,40 +1,47 @@
+mod edit_history;
 mod editor_instance;
 mod game_instance;
+mod model_nodes;
 mod network;
-mod player;
+mod relevance;
+mod storage;
+#[cfg(test)]
+mod test_harness;
 mod world;
 
 use {
     crate::{
         game::network::{ClientMessageReceiver, ServerMessageSender},
-        js::JSContext,
+        js::{JSContext, ScriptWatcher},
     },
-    blocks::BlockPos,
     crossbeam::queue::SegQueue,
+    edit_history::EditHistory,
     editor_instance::EditorInstance,
-    entities::{EntityID, PlayerId},
+    entities::{EntityData, EntityID, PlayerController, PlayerId},
     game_instance::GameInstance,
     network::ClientId,
-    physics::PhysicsWorld,
     serde::{Deserialize, Serialize},
     std::{
         collections::HashMap,
         fmt::Display,
         path::PathBuf,
         sync::{Arc, Mutex},
+        time::{Duration, Instant},
     },
 };
 
-pub use world::World;
-
-const WORLD_SIZE: i32 = 32;
+pub use world::{new_entity_id, EntityEvent, World};
 
 pub struct GameServer {
     state: ServerState,
     incoming_connections: Arc<SegQueue<(ClientMessageReceiver, ServerMessageSender)>>,
     storage_dir: PathBuf,
     js_context: JSContext,
+    script_watcher: ScriptWatcher,
     timer: util::FrameTimer,
+    script_stats_logged: Instant,
+    // Kept here rather than in the editor instance so it lasts between editing sessions
+    edit_history: EditHistory,
 }
 
 impl GameServer {
@@ -43,6 +50,15 @@ impl GameServer {
 
         tokio::spawn(network::start_client_listener(incoming_connections.clone()));
 
+        Self::with_connections(storage_dir, incoming_connections).await
+    }
+
+    // Load the world without listening for connections. Clients are whatever gets pushed onto
+    // `incoming_connections`.
+    async fn with_connections(
+        storage_dir: impl Into<PathBuf>,
+        incoming_connections: Arc<SegQueue<(ClientMessageReceiver, ServerMessageSender)>>,
+    ) -> Self {
         // Load the world
         let storage_dir: PathBuf = storage_dir.into();
         let world = Arc::new(Mutex::new(
@@ -53,6 +69,7 @@ impl GameServer {
 
         tracing::info!("Starting JS context..");
         let script_root = storage_dir.join("dist/");
+        let script_watcher = ScriptWatcher::new(&script_root);
         let mut js_context = JSContext::new(
             &script_root,
             world.clone(),
@@ -63,10 +80,9 @@ impl GameServer {
 
         game_instance.spawn_entities(&mut js_context).await;
 
-        // Init the world after entities are spawned but before players are added
-        game_instance
-            .init(&mut js_context)
-            .expect("Error during world init");
+        // Init the world after entities are spawned but before players are added. A failing init
+        // script is reported like any other script error, the server runs regardless.
+        let _ = game_instance.init(&mut js_context);
 
         // Set the initial state
         let initial_state = ServerState::Paused(game_instance);
@@ -78,13 +94,25 @@ impl GameServer {
             state: initial_state,
             storage_dir,
             js_context,
+            script_watcher,
             timer: Default::default(),
+            script_stats_logged: Instant::now(),
+            edit_history: EditHistory::default(),
         }
     }
 
     pub async fn tick(&mut self) {
         self.timer.start();
 
+        if self.script_stats_logged.elapsed() >= SCRIPT_STATS_LOG_INTERVAL {
+            self.log_script_stats();
+        }
+
+        // Pick up any changes to the scripts
+        if self.script_watcher.poll() {
+            self.reload_scripts().await;
+        }
+
         // Handle new connections
         while let Some(channels) = self.incoming_connections.pop() {
             match &mut self.state {
@@ -99,10 +127,11 @@ impl GameServer {
 
         // Tick
         let next_state = match &mut self.state {
-            ServerState::Playing(instance) | ServerState::Paused(instance) => {
-                instance.tick(&mut self.js_context).await
+            ServerState::Playing(instance) => instance.tick(&mut self.js_context, false).await,
+            ServerState::Paused(instance) => instance.tick(&mut self.js_context, true).await,
+            ServerState::Editing(instance) => {
+                instance.tick(&self.storage_dir, &mut self.edit_history)
             }
-            ServerState::Editing(instance) => instance.tick(&self.storage_dir),
             invalid => panic!("Invalid server state: {invalid}"),
         };
 
@@ -118,10 +147,53 @@ impl GameServer {
 
         self.timer.stop();
     }
+
+    fn log_script_stats(&mut self) {
+        for (script, stats) in self.js_context.script_stats() {
+            tracing::debug!(
+                "{script}: {} calls, mean {:?}, max {:?}, {} terminated, {} skipped",
+                stats.calls,
+                stats.mean_time(),
+                stats.max_time,
+                stats.terminated,
+                stats.skipped,
+            );
+        }
+        self.script_stats_logged = Instant::now();
+    }
+
+    async fn reload_scripts(&mut self) {
+        let reloaded = self.js_context.reload().await.is_ok();
+
+        // Let everyone know how it went
+        let errors = self.js_context.take_errors();
+        match &mut self.state {
+            ServerState::Playing(instance) | ServerState::Paused(instance) => {
+                if reloaded {
+                    instance.scripts_reloaded();
+                }
+                instance.send_script_errors_to_clients(errors);
+            }
+            ServerState::Editing(instance) => {
+                for error in errors {
+                    instance
+                        .editor_client
+                        .send(net_types::ServerPacket::ScriptError(error));
+                }
+            }
+            ServerState::Transitioning => {}
+        }
+        if reloaded {
+            self.state
+                .send_to_clients(net_types::ServerPacket::ScriptsReloaded);
+        }
+    }
 }
 
 pub const TICK_RATE: u32 = 60;
 pub const TICK_DT: f32 = 1. / TICK_RATE as f32;
+// How often the time each script has spent running is logged
+const SCRIPT_STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
 
 enum ServerState {
     Playing(GameInstance),
@@ -144,6 +216,19 @@ impl Display for ServerState {
 }
 
 impl ServerState {
+    // Queue a packet for every connected client
+    fn send_to_clients(&mut self, packet: net_types::ServerPacket) {
+        match self {
+            ServerState::Playing(instance) | ServerState::Paused(instance) => {
+                for client in instance.clients.values_mut() {
+                    client.send(packet.clone());
+                }
+            }
+            ServerState::Editing(instance) => instance.editor_client.send(packet),
+            ServerState::Transitioning => {}
+        }
+    }
+
     // state machines, my beloved
     async fn transition(
         &mut self,
@@ -244,12 +329,40 @@ struct GameState {
     _blue_points: u32,
 }
 
-#[derive(Debug)]
-struct Player {
-    state: PlayerState,
-    body: physics::PhysicsBody,
+// Players' models are drawn at half size
+const PLAYER_SCALE: f32 = 0.5;
+
+/// A new player's entity, before any scripts have seen it
+fn new_player(player_id: PlayerId, position: glam::Vec3) -> EntityData {
+    EntityData {
+        id: world::new_entity_id(),
+        name: format!("Player {}", player_id.inner()),
+        entity_type: None,
+        model_path: Default::default(),
+        state: entities::EntityState {
+            position,
+            scale: glam::Vec3::splat(PLAYER_SCALE),
+            ..Default::default()
+        },
+        controller: Some(PlayerController::new(player_id)),
+        joints: Vec::new(),
+    }
+}
+
+// The part of a player's state that the client predicts
+fn movement_state(player: &EntityData) -> Option<movement::MovementState> {
+    let controller = player.controller.as_ref()?;
+    Some(movement::MovementState {
+        position: player.state.position,
+        velocity: player.state.velocity,
+        is_on_ground: controller.is_on_ground,
+        coyote_time: controller.coyote_time,
+        jump_input_time: controller.jump_input_time,
+        jumped: false,
+    })
 }
 
+/// A player entity as the player script sees it
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerState {
@@ -258,6 +371,11 @@ pub struct PlayerState {
     facing_angle: f32,
     animation_state: String,
     is_on_ground: bool,
+    // Jump leeway, see `movement::step`
+    #[serde(default)]
+    coyote_time: f32,
+    #[serde(default)]
+    jump_input_time: f32,
     #[serde(default)]
     pub custom_state: HashMap<String, serde_json::Value>,
     // Map of anchor name to entity id for attached entities
@@ -266,29 +384,947 @@ pub struct PlayerState {
     pub model_path: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PlayerCollision {
-    // The block that the player collided with
-    pub block: BlockPos,
-    // The normal of the face that the player collided with
-    pub normal: glam::Vec3,
-    // The movement required to resolve the collision
-    pub resolution: glam::Vec3,
+impl PlayerState {
+    /// `None` if the entity isn't a player
+    pub fn new(
+        player: &EntityData,
+        attached_entities: HashMap<String, Vec<EntityID>>,
+    ) -> Option<Self> {
+        let controller = player.controller.as_ref()?;
+        Some(Self {
+            position: player.state.position,
+            velocity: player.state.velocity,
+            facing_angle: player.state.rotation.to_euler(glam::EulerRot::YXZ).0,
+            animation_state: controller.animation_state.clone(),
+            is_on_ground: controller.is_on_ground,
+            coyote_time: controller.coyote_time,
+            jump_input_time: controller.jump_input_time,
+            custom_state: player.state.custom_state.clone(),
+            attached_entities,
+            model_path: player.model_path.clone(),
+        })
+    }
+
+    /// Copy what the player script changed back onto the player's entity
+    pub fn apply(self, player: &mut EntityData) {
+        let Some(controller) = player.controller.as_mut() else {
+            return;
+        };
+        controller.animation_state = self.animation_state;
+        controller.is_on_ground = self.is_on_ground;
+        controller.coyote_time = self.coyote_time;
+        controller.jump_input_time = self.jump_input_time;
+
+        player.state.position = self.position;
+        player.state.velocity = self.velocity;
+        player.state.rotation = glam::Quat::from_rotation_y(self.facing_angle);
+        player.state.custom_state = self.custom_state;
+        player.model_path = self.model_path;
+    }
 }
 
-impl Player {
-    pub fn new(id: PlayerId, physics_world: &mut PhysicsWorld, position: glam::Vec3) -> Self {
-        // obtained by creating rulers in Blender and comparing them against the Player model
-        let player_height = 3.04 / 2.0; // because we scale the model down in the client
-        let player_width = 1.6 / 2.0;
-        let physics_body =
-            physics_world.add_player_body(id.inner(), position, player_width, player_height);
-        Self {
-            state: PlayerState {
-                position,
-                ..Default::default()
-            },
-            body: physics_body,
+#[cfg(test)]
+mod tests {
+    use {
+        super::test_harness::{TestServer, TestWorld},
+        blocks::BlockPos,
+        entities::{test_util, Anchor, EntityData, Joint, JointKind, PlayerId},
+        net_types::{
+            ClientPacket, ClientShouldSwitchMode, Controls, ScriptError, ServerPacket, SetBlock,
+        },
+    };
+
+    fn with_custom_state(mut entity: EntityData, custom_state: serde_json::Value) -> EntityData {
+        let serde_json::Value::Object(custom_state) = custom_state else {
+            panic!("Script state has to be an object, not {custom_state}");
+        };
+        entity.state.custom_state = custom_state.into_iter().collect();
+        entity
+    }
+
+    fn script_errors(packets: Vec<ServerPacket>) -> Vec<ScriptError> {
+        packets
+            .into_iter()
+            .filter_map(|packet| match packet {
+                ServerPacket::ScriptError(error) => Some(error),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn new_clients_are_sent_the_world() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+
+        let packets = client.received();
+        let Some(ServerPacket::Init(init)) = packets.first() else {
+            panic!("Expected Init first, got {packets:?}");
+        };
+        assert_eq!(init.client_player, PlayerId::new(0));
+        assert_eq!(init.world_script_state["ticks"], 0);
+
+        // The player and the chunks around it follow straight away
+        assert!(packets.iter().any(|packet| matches!(
+            packet,
+            ServerPacket::AddEntity(add) if add.entity_data.player_id() == Some(PlayerId::new(0))
+                && add.entity_data.model_path == "player.gltf"
+        )));
+        assert!(packets
+            .iter()
+            .any(|packet| matches!(packet, ServerPacket::LoadChunk(_))));
+    }
+
+    #[tokio::test]
+    async fn world_script_state_is_patched() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick_n(3).await;
+
+        let mut world_state = serde_json::Value::Null;
+        for packet in client.received() {
+            match packet {
+                ServerPacket::Init(init) => world_state = init.world_script_state,
+                ServerPacket::PatchWorldScriptState(patch) => {
+                    patch.0.apply(&mut world_state).unwrap()
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(world_state["ticks"], 3);
+    }
+
+    #[tokio::test]
+    async fn scripts_are_loaded_from_the_world() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            export const init = (worldState) => ({ greeting: "hello" });
+            export const onAddPlayer = (worldState, playerId, playerState) => [
+                worldState,
+                { ...playerState, customState: { team: "red" } },
+            ];
+            export const update = (worldState) => worldState;
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut client = server.connect();
+        server.tick().await;
+
+        let packets = client.received();
+        let Some(ServerPacket::Init(init)) = packets.first() else {
+            panic!("Expected Init first, got {packets:?}");
+        };
+        assert_eq!(init.world_script_state["greeting"], "hello");
+        assert!(packets.iter().any(|packet| matches!(
+            packet,
+            ServerPacket::AddEntity(add) if add.entity_data.state.custom_state["team"] == "red"
+        )));
+    }
+
+    #[tokio::test]
+    async fn clients_can_start_and_pause() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        assert_eq!(server.state(), "Paused");
+
+        let client = server.connect();
+        server.tick().await;
+
+        client.send(ClientPacket::Start);
+        server.tick().await;
+        assert_eq!(server.state(), "Playing");
+
+        client.send(ClientPacket::Pause);
+        server.tick().await;
+        assert_eq!(server.state(), "Paused");
+    }
+
+    #[tokio::test]
+    async fn players_are_entities() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+        let Some(player) = client
+            .received()
+            .into_iter()
+            .find_map(|packet| match packet {
+                ServerPacket::AddEntity(add) if add.entity_data.controller.is_some() => {
+                    Some(add.entity_data)
+                }
+                _ => None,
+            })
+        else {
+            panic!("Expected the client's player to be sent as an entity");
+        };
+        assert_eq!(player.player_id(), Some(PlayerId::new(0)));
+        assert_eq!(player.entity_type, None);
+
+        client.send(ClientPacket::Start);
+        server.tick().await;
+        client.send(ClientPacket::Controls(Controls {
+            sequence: 1,
+            move_direction: glam::Vec2::new(0., 1.),
+            ..Default::default()
+        }));
+        server.tick_n(2).await;
+
+        // The player script moves the player's entity, which is synced like any other
+        let moved = server
+            .entity(&player.id)
+            .expect("The player's entity is gone");
+        assert_ne!(
+            (moved.state.position - player.state.position).with_y(0.),
+            glam::Vec3::ZERO
+        );
+        assert!(client.received().iter().any(|packet| matches!(
+            packet,
+            ServerPacket::UpdateEntity(update) if update.entity_id == player.id && update.position.is_some()
+        )));
+    }
+
+    #[tokio::test]
+    async fn inputs_are_acknowledged() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+        client.send(ClientPacket::Start);
+        server.tick().await;
+        client.received();
+
+        client.send(ClientPacket::Controls(Controls {
+            sequence: 1,
+            move_direction: glam::Vec2::new(0., 1.),
+            ..Default::default()
+        }));
+        // Inputs are processed after updates are sent, so the ack goes out a tick later
+        server.tick_n(2).await;
+
+        let acks = client
+            .received()
+            .into_iter()
+            .filter_map(|packet| match packet {
+                ServerPacket::InputAck(ack) => Some(ack),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let [ack] = acks.as_slice() else {
+            panic!("Expected a single ack, got {acks:?}");
+        };
+        assert_eq!(ack.sequence, 1);
+        assert_ne!(ack.movement.velocity.x + ack.movement.velocity.z, 0.);
+    }
+
+    #[tokio::test]
+    async fn players_keep_moving_when_inputs_are_late() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+        client.send(ClientPacket::Start);
+        server.tick().await;
+        client.send(ClientPacket::Controls(Controls {
+            sequence: 1,
+            move_direction: glam::Vec2::new(0., 1.),
+            ..Default::default()
+        }));
+        server.tick_n(2).await;
+        let acked = server.player(PlayerId::new(0)).unwrap().state.position;
+        client.received();
+
+        // No more inputs arrive. After waiting a moment the player carries on with its last
+        // controls, still falling towards the floor, and the client is told where it ended up.
+        server.tick_n(5).await;
+        let position = server.player(PlayerId::new(0)).unwrap().state.position;
+        assert!(position.y < acked.y, "{position} isn't below {acked}");
+        assert!(
+            position.z != acked.z,
+            "{position} hasn't moved from {acked}"
+        );
+        assert!(client.received().iter().any(|packet| matches!(
+            packet,
+            ServerPacket::InputAck(ack) if ack.sequence == 1
+        )));
+    }
+
+    #[tokio::test]
+    async fn clients_can_edit_and_resume() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+        client.received();
+
+        client.send(ClientPacket::Edit);
+        server.tick().await;
+        assert_eq!(server.state(), "Editing");
+
+        // The editor gets the whole world, not just what's around its player
+        let packets = client.received();
+        let Some(world) = packets.iter().find_map(|packet| match packet {
+            ServerPacket::ClientShouldSwitchMode(ClientShouldSwitchMode::Edit { world }) => {
+                Some(world)
+            }
+            _ => None,
+        }) else {
+            panic!("Expected a switch to edit mode, got {packets:?}");
+        };
+        assert_eq!(world.blocks.get(BlockPos::new(15, 0, 15)), Some(&1));
+
+        client.send(ClientPacket::Start);
+        server.tick_n(2).await;
+        assert_eq!(server.state(), "Playing");
+
+        // The editor gets a fresh player
+        let packets = client.received();
+        assert!(packets.iter().any(|packet| matches!(
+            packet,
+            ServerPacket::ClientShouldSwitchMode(ClientShouldSwitchMode::Play { new_player_id })
+                if *new_player_id == PlayerId::new(1)
+        )));
+    }
+
+    #[tokio::test]
+    async fn disconnected_clients_are_removed() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let leaving = server.connect();
+        let mut staying = server.connect();
+        server.tick().await;
+        let Some(leaving_entity) = staying
+            .received()
+            .into_iter()
+            .find_map(|packet| match packet {
+                ServerPacket::AddEntity(add)
+                    if add.entity_data.player_id() == Some(PlayerId::new(0)) =>
+                {
+                    Some(add.entity_id)
+                }
+                _ => None,
+            })
+        else {
+            panic!("Expected to be told about the other player");
+        };
+
+        drop(leaving);
+        server.tick_n(2).await;
+        assert!(staying.received().iter().any(|packet| matches!(
+            packet,
+            ServerPacket::RemoveEntity(remove) if remove.entity_id == leaving_entity
+        )));
+        assert!(server.entity(&leaving_entity).is_none());
+    }
+
+    #[tokio::test]
+    async fn script_errors_are_sent_to_the_editor() {
+        let world = TestWorld::new();
+        world.write_script(
+            "player.js",
+            r#"
+            export const onSpawn = (playerId, playerState) => playerState;
+            export const update = (playerId, playerState, controls) => {
+                throw new Error("oops");
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut editor = server.connect_editor().await;
+        let mut player = server.connect();
+        server.tick_n(3).await;
+
+        // The server carries on, and the editor hears about the failure once rather than every tick
+        assert!(script_errors(player.received()).is_empty());
+        let errors = script_errors(editor.received());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        let error = &errors[0];
+        assert_eq!(
+            (error.script.as_str(), error.function.as_str()),
+            ("player.js", "update")
+        );
+        assert!(error.message.contains("oops"), "{error:?}");
+        assert!(
+            error.stack.as_ref().unwrap().contains("player.js"),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_players_stop_being_updated() {
+        let world = TestWorld::new();
+        world.write_script(
+            "player.js",
+            r#"
+            export const onSpawn = (playerId, playerState) => playerState;
+            export const update = (playerId, playerState, controls) => {
+                throw new Error("oops");
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut editor = server.connect_editor().await;
+        let mut errors = Vec::new();
+        for _ in 0..3 {
+            server.tick_n(10).await;
+            errors.extend(script_errors(editor.received()));
+        }
+
+        let [oops, stopped] = errors.as_slice() else {
+            panic!("Expected the error and then the player being stopped, got {errors:?}");
+        };
+        assert!(oops.message.contains("oops"), "{errors:?}");
+        assert!(stopped.message.contains("stopped"), "{errors:?}");
+        let stats = server.server.js_context.script_stats();
+        assert!(stats["player.js"].calls < 30, "{stats:?}");
+    }
+
+    #[tokio::test]
+    async fn failing_entities_are_disabled() {
+        let world = TestWorld::with_entities(
+            vec![test_util::entity_type(0, "Broken")],
+            vec![test_util::entity("1", 0, glam::Vec3::new(8., 1., 8.))],
+        );
+        world.write_script(
+            "broken.js",
+            r#"export const update = (entityId, state) => { throw new Error("broken"); };"#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut editor = server.connect_editor().await;
+        // Drain as we go, a client only buffers so many ticks
+        let mut errors = Vec::new();
+        for _ in 0..3 {
+            server.tick_n(10).await;
+            errors.extend(script_errors(editor.received()));
+        }
+
+        let disabled = errors
+            .iter()
+            .position(|error| error.message.contains("disabled"))
+            .expect("Entity should have been disabled");
+        assert_eq!(errors[disabled].entity_id.as_deref(), Some("1"));
+        // The same error is only reported once, and nothing more is heard from it afterwards
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(disabled, 1);
+    }
+
+    #[tokio::test]
+    async fn runaway_scripts_are_terminated() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            export const init = (worldState) => worldState;
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => { while (true) {} };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut editor = server.connect_editor().await;
+        let before = server.server.js_context.script_stats().clone();
+        server.tick_n(3).await;
+
+        // Every tick still finishes, and the player's script still runs after the world's
+        let errors = script_errors(editor.received());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(errors[0].message.contains("time budget"), "{errors:?}");
+
+        let stats = server.server.js_context.script_stats();
+        assert_eq!(
+            stats["world.js"].terminated - before["world.js"].terminated,
+            3
+        );
+        assert_eq!(
+            stats["player.js"].calls - before["player.js"].calls,
+            3,
+            "{stats:?}"
+        );
+        assert_eq!(stats["player.js"].terminated, 0);
+    }
+
+    #[tokio::test]
+    async fn runaway_scripts_are_terminated_while_loading() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut editor = server.connect_editor().await;
+
+        server.world().write_script(
+            "world.js",
+            r#"
+            while (true) {}
+            export const init = (worldState) => worldState;
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => worldState;
+            "#,
+        );
+        let mut packets = Vec::new();
+        for _ in 0..10 {
+            server.tick_n(10).await;
+            packets.extend(editor.received());
+        }
+
+        // The new scripts are given up on, and the old ones carry on
+        assert!(!packets
+            .iter()
+            .any(|packet| matches!(packet, ServerPacket::ScriptsReloaded)));
+        let errors = script_errors(packets);
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.function == "reload" && error.message.contains("time budget")),
+            "{errors:?}"
+        );
+        let ticks = server.world_script_state()["ticks"].as_u64().unwrap();
+        server.tick().await;
+        assert_eq!(server.world_script_state()["ticks"], ticks + 1);
+    }
+
+    #[tokio::test]
+    async fn entity_scripts_get_event_callbacks() {
+        let world = TestWorld::with_entities(
+            vec![test_util::ball_type(0, 1.)],
+            vec![test_util::entity("1", 0, glam::Vec3::new(8., 3., 8.))],
+        );
+        // Interacts with itself once, then waits to land on the floor
+        world.write_script(
+            "ball.js",
+            r#"
+            export const update = (entityId, state) => {
+                if (!state.customState.interacted) {
+                    hy.interactEntity(entityId, 0, state.position, 0, 0);
+                }
+                return state;
+            };
+            export const onInteract = (entityId, state, interaction) => ({
+                ...state,
+                customState: { ...state.customState, interacted: true },
+            });
+            export const onCollisionStart = (entityId, state, collision) => ({
+                ...state,
+                customState: { ...state.customState, hit: collision.collisionTarget },
+            });
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(60).await;
+
+        let ball = server.entity("1").unwrap();
+        assert_eq!(ball.state.custom_state["interacted"], true);
+        assert_eq!(ball.state.custom_state["hit"], "block");
+    }
+
+    #[tokio::test]
+    async fn entities_can_be_anchored_to_entities() {
+        // A cart, a turret on the cart and a flag on the turret. The cart tries to get on the
+        // flag once the others are settled, which would make a loop.
+        let part = |id: &str, parent: &str, after: u32| {
+            with_custom_state(
+                test_util::entity(id, 0, glam::Vec3::new(4., 1., 4.)),
+                serde_json::json!({ "parent": parent, "after": after, "ticks": 0 }),
+            )
+        };
+        let world = TestWorld::with_entities(
+            vec![test_util::entity_type(0, "Part")],
+            vec![part("1", "3", 3), part("2", "1", 0), part("3", "2", 0)],
+        );
+        world.write_script(
+            "part.js",
+            r#"
+            export const update = (entityId, state) => {
+                const { parent, after, ticks } = state.customState;
+                if (state.anchor === null && ticks === after) {
+                    hy.anchorToEntity(entityId, parent, null, [0, 1, 0]);
+                }
+                if (entityId === "1" && ticks === 6) {
+                    hy.despawnEntity(entityId);
+                }
+                return { ...state, customState: { ...state.customState, ticks: ticks + 1 } };
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(5).await;
+
+        let turret = server.entity("2").unwrap();
+        let flag = server.entity("3").unwrap();
+        assert_eq!(turret.state.anchor.unwrap().parent_id, "1");
+        assert_eq!(turret.state.absolute_position, glam::Vec3::new(4., 2., 4.));
+        assert_eq!(flag.state.absolute_position, glam::Vec3::new(4., 3., 4.));
+        assert!(server.entity("1").unwrap().state.anchor.is_none());
+
+        // Despawning the cart takes everything on it along
+        server.tick_n(3).await;
+        for entity_id in ["1", "2", "3"] {
+            assert!(server.entity(entity_id).is_none(), "{entity_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn entities_can_be_anchored_to_nodes() {
+        // A cart whose model has a mast, one up and one along from its origin, and a flag on the
+        // mast
+        let world = TestWorld::with_entities(vec![test_util::entity_type(0, "Part")], Vec::new());
+        let model_path = world.path().join("cart.gltf");
+        std::fs::write(
+            &model_path,
+            r#"{
+                "asset": { "version": "2.0" },
+                "scene": 0,
+                "scenes": [{ "nodes": [0] }],
+                "nodes": [
+                    { "name": "root", "translation": [0, 1, 0], "children": [1] },
+                    { "name": "mast", "translation": [0, 0, 1] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let cart = EntityData {
+            model_path: model_path.to_string_lossy().into(),
+            ..test_util::entity("1", 0, glam::Vec3::new(4., 1., 4.))
+        };
+        let mut flag = test_util::entity("2", 0, glam::Vec3::Y);
+        flag.state.anchor = Some(Anchor {
+            parent_id: "1".into(),
+            parent_node: Some("mast".into()),
+        });
+        world.write_json(
+            "entities.json",
+            &std::collections::HashMap::from([("1", cart), ("2", flag)]),
+        );
+        world.write_script(
+            "part.js",
+            "export const update = (entityId, state) => state;",
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(2).await;
+
+        let flag = server.entity("2").unwrap();
+        assert_eq!(flag.state.absolute_position, glam::Vec3::new(4., 3., 5.));
+    }
+
+    #[tokio::test]
+    async fn joints_go_with_their_entities() {
+        let ball = |id: &str, x: f32| {
+            with_custom_state(
+                test_util::entity(id, 0, glam::Vec3::new(x, 3., 8.)),
+                serde_json::json!({ "ticks": 0 }),
+            )
+        };
+        let world = TestWorld::with_entities(
+            vec![test_util::ball_type(0, 0.5)],
+            vec![ball("1", 8.), ball("2", 10.)],
+        );
+        // Ball 1 ties itself to ball 2 and to the world, then ball 2 despawns
+        world.write_script(
+            "ball.js",
+            r#"
+            export const update = (entityId, state) => {
+                const { ticks } = state.customState;
+                if (entityId === "1" && ticks === 0) {
+                    hy.addJoint(entityId, { name: "chain", kind: "rope", otherEntity: "2", maxDistance: 2 });
+                    hy.addJoint(entityId, { name: "post", kind: "rope", otherAnchor: [8, 5, 8], maxDistance: 2 });
+                }
+                if (entityId === "2" && ticks === 3) {
+                    hy.despawnEntity(entityId);
+                }
+                return { ...state, customState: { ticks: ticks + 1 } };
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(2).await;
+        let joints = server.entity("1").unwrap().joints;
+        assert_eq!(joints.len(), 2, "{joints:?}");
+        assert_eq!(joints[0].other_entity.as_deref(), Some("2"));
+
+        server.tick_n(3).await;
+        assert!(server.entity("2").is_none());
+        let joints = server.entity("1").unwrap().joints;
+        assert_eq!(joints.len(), 1, "{joints:?}");
+        assert_eq!(joints[0].name, "post");
+    }
+
+    #[tokio::test]
+    async fn spawned_entities_get_their_joints() {
+        let world = TestWorld::with_entities(vec![test_util::ball_type(0, 0.5)], Vec::new());
+        world.write_script(
+            "ball.js",
+            "export const update = (entityId, state) => state;",
+        );
+
+        let mut server = TestServer::start(world).await;
+        let post = Joint {
+            name: "post".into(),
+            kind: JointKind::Rope,
+            other_entity: None,
+            local_anchor: glam::Vec3::ZERO,
+            other_anchor: glam::Vec3::new(8., 9., 8.),
+            axis: glam::Vec3::Y,
+            limits: None,
+            max_distance: 1.,
+        };
+        server.spawn_entity(EntityData {
+            joints: vec![post],
+            ..test_util::entity("1", 0, glam::Vec3::new(8., 8.5, 8.))
+        });
+        server.tick_n(60).await;
+
+        // Hanging from the post, rather than lying on the floor
+        let ball = server.entity("1").unwrap();
+        assert!(ball.state.position.y > 7., "{:?}", ball.state.position);
+    }
+
+    #[tokio::test]
+    async fn timers_stop_while_paused() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            let fired = 0;
+            export const init = (worldState) => {
+                hy.setInterval(() => { fired += 1; }, 10);
+                return worldState;
+            };
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => ({ ...worldState, fired });
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut client = server.connect();
+        for _ in 0..2 {
+            server.tick_n(10).await;
+            client.received();
+        }
+        assert_eq!(server.world_script_state()["fired"], 0);
+
+        client.send(ClientPacket::Start);
+        for _ in 0..3 {
+            server.tick_n(10).await;
+            client.received();
+        }
+        // The world script sees the count from the tick before, and the game starts a tick late
+        let fired = server.world_script_state()["fired"].as_u64().unwrap();
+        assert!((2..=3).contains(&fired), "{fired}");
+    }
+
+    #[tokio::test]
+    async fn player_timers_outlive_entities() {
+        let world = TestWorld::with_entities(
+            vec![test_util::entity_type(0, "Bomb")],
+            vec![test_util::entity("1", 0, glam::Vec3::new(8., 1., 8.))],
+        );
+        world.write_script(
+            "world.js",
+            r#"
+            export const init = (worldState) => worldState;
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => ({ ...worldState, fired: globalThis.playerTimerFired ?? false });
+            "#,
+        );
+        // The bomb's timer goes off, then the player sets a timer, then the bomb despawns
+        world.write_script(
+            "bomb.js",
+            r#"
+            let armed = false;
+            export const update = (entityId, state) => {
+                if (!armed) {
+                    armed = true;
+                    hy.setTimeout(() => { globalThis.bombTimerFired = true; }, 1);
+                }
+                if (globalThis.playerTimerSet) {
+                    hy.despawnEntity(entityId);
+                }
+                return state;
+            };
+            "#,
+        );
+        world.write_script(
+            "player.js",
+            r#"
+            export const onSpawn = (playerId, playerState) => playerState;
+            export const update = (playerId, playerState, controls) => {
+                if (globalThis.bombTimerFired && !globalThis.playerTimerSet) {
+                    globalThis.playerTimerSet = true;
+                    hy.setTimeout(() => { globalThis.playerTimerFired = true; }, 10);
+                }
+                return playerState;
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut client = server.connect();
+        server.tick().await;
+        client.send(ClientPacket::Start);
+        for _ in 0..3 {
+            server.tick_n(10).await;
+            client.received();
+        }
+
+        // The player's timer wasn't mistaken for the bomb's and cleared along with it
+        assert!(server.entity("1").is_none());
+        assert_eq!(server.world_script_state()["fired"], true);
+    }
+
+    #[tokio::test]
+    async fn scripts_can_edit_blocks() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            let ticks = 0;
+            export const init = (worldState) => worldState;
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => {
+                ticks += 1;
+                if (ticks === 3) {
+                    hy.setBlock([0, 0, 0], 0);
+                    hy.fillRegion([1, 2, 1], [0, 2, 0], 1);
+                }
+                const found = hy.getBlocksInBox([0, 0, 0], [1, 2, 1]).length;
+                return { ...worldState, found };
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        let mut client = server.connect();
+        server.tick_n(2).await;
+        assert_eq!(server.world_script_state()["found"], 4);
+        client.received();
+
+        server.tick_n(2).await;
+        // One block of the floor has gone, and there's a 2x2 square above it
+        assert_eq!(server.world_script_state()["found"], 3 + 4);
+        let changes = client
+            .received()
+            .into_iter()
+            .filter_map(|packet| match packet {
+                ServerPacket::SetBlock(SetBlock { position, block_id }) => {
+                    Some((position, block_id))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(changes.len(), 5, "{changes:?}");
+        assert!(changes.contains(&(BlockPos::new(0, 0, 0), 0)));
+        assert!(changes.contains(&(BlockPos::new(1, 2, 1), 1)));
+    }
+
+    #[tokio::test]
+    async fn scripts_cant_use_huge_regions() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            export const init = (worldState) => ({ ...worldState, refused: 0 });
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => {
+                let refused = 0;
+                for (const [min, max] of [[[-3e9, 0, 0], [3e9, 0, 0]], [[0, 0, 0], [1e6, 1e6, 1e6]]]) {
+                    try {
+                        hy.fillRegion(min, max, 1);
+                    } catch {
+                        refused += 1;
+                    }
+                    try {
+                        hy.getBlocksInBox(min, max);
+                    } catch {
+                        refused += 1;
+                    }
+                }
+                return { ...worldState, refused };
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(2).await;
+        assert_eq!(server.world_script_state()["refused"], 4);
+        assert_eq!(server.block(BlockPos::new(0, 1, 0)), 0);
+    }
+
+    #[tokio::test]
+    async fn scripts_can_raycast() {
+        let world = TestWorld::new();
+        world.write_script(
+            "world.js",
+            r#"
+            let ticks = 0;
+            export const init = (worldState) => worldState;
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => {
+                ticks += 1;
+                if (ticks === 3) {
+                    hy.setBlock([0, 0, 0], 0);
+                }
+                const hit = hy.raycast([0.5, 5, 0.5], [0, -1, 0], 10);
+                const nearby = hy.overlapSphere([0.5, 1.2, 0.5], 0.5, { targets: ["block"] });
+                return { ...worldState, hit, nearby: nearby.length };
+            };
+            "#,
+        );
+
+        let mut server = TestServer::start(world).await;
+        server.tick_n(2).await;
+        let hit = &server.world_script_state()["hit"];
+        assert_eq!(hit["target"], "block");
+        assert_eq!(
+            hit["blockPosition"],
+            serde_json::json!({ "x": 0, "y": 0, "z": 0 })
+        );
+        assert!(
+            (hit["distance"].as_f64().unwrap() - 4.).abs() < 0.001,
+            "{hit}"
+        );
+        assert_ne!(server.world_script_state()["nearby"], 0);
+
+        // Once the block's gone, the ray goes straight through the hole it left
+        server.tick_n(3).await;
+        assert_eq!(server.world_script_state()["hit"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn changed_scripts_are_reloaded() {
+        let mut server = TestServer::start(TestWorld::new()).await;
+        let mut client = server.connect();
+        server.tick().await;
+
+        server.world().write_script(
+            "world.js",
+            r#"
+            export const init = (worldState) => ({ ticks: 0 });
+            export const onAddPlayer = (worldState, playerId, playerState) => [worldState, playerState];
+            export const update = (worldState) => ({ ...worldState, ticks: worldState.ticks + 100 });
+            "#,
+        );
+
+        let mut packets = Vec::new();
+        for _ in 0..10 {
+            server.tick_n(10).await;
+            packets.extend(client.received());
+        }
+        assert!(packets
+            .iter()
+            .any(|packet| matches!(packet, ServerPacket::ScriptsReloaded)));
+
+        // The world state carried on from where the old script left it
+        let mut world_state = serde_json::Value::Null;
+        for packet in packets {
+            match packet {
+                ServerPacket::Init(init) => world_state = init.world_script_state,
+                ServerPacket::PatchWorldScriptState(patch) => {
+                    patch.0.apply(&mut world_state).unwrap()
+                }
+                _ => {}
+            }
         }
+        let ticks = world_state["ticks"].as_u64().unwrap();
+        assert!(ticks > 100 && !ticks.is_multiple_of(100), "{ticks}");
     }
 }
@@ -0,0 +1,70 @@
+// Where the named nodes in entities' models are, so that entities anchored to a node are placed
+// where the client draws them.
+//
+// Models are read the first time they're needed, from the same path the client fetches them from.
+// Only the model's rest pose is known here: the client also plays its animations, so something
+// held in an animated hand will be a little off on the server.
+
+use {
+    anyhow::Context,
+    glam::{Affine3A, Quat},
+    std::collections::HashMap,
+};
+
+#[derive(Default)]
+pub struct ModelNodes {
+    // Each node's transform relative to the model's origin, by node name, by model path
+    models: HashMap<String, HashMap<String, Affine3A>>,
+}
+
+impl ModelNodes {
+    /// The transform of the node called `node_name` relative to the model's origin, if the model
+    /// can be loaded and has a node by that name
+    pub fn get(&mut self, model_path: &str, node_name: &str) -> Option<Affine3A> {
+        let nodes = self
+            .models
+            .entry(model_path.to_string())
+            .or_insert_with(|| {
+                // Only complain once, entities with missing models stay at their parent's origin
+                load(model_path).unwrap_or_else(|e| {
+                    tracing::warn!("Couldn't load nodes from {model_path}: {e:#}");
+                    HashMap::new()
+                })
+            });
+        nodes.get(node_name).copied()
+    }
+}
+
+fn load(model_path: &str) -> anyhow::Result<HashMap<String, Affine3A>> {
+    let file = std::fs::read(model_path)?;
+    let document = gltf::Gltf::from_slice_without_validation(&file)?.document;
+
+    // The same root as the client uses
+    let root = document
+        .default_scene()
+        .and_then(|scene| scene.nodes().next())
+        .or_else(|| document.nodes().next())
+        .context("No root node found in glTF")?;
+
+    let mut nodes = HashMap::new();
+    add_nodes(root, Affine3A::IDENTITY, &mut nodes);
+    Ok(nodes)
+}
+
+// Add the node and its children, depth first. If two nodes share a name the first one found wins,
+// as it does on the client.
+fn add_nodes(node: gltf::Node, parent: Affine3A, nodes: &mut HashMap<String, Affine3A>) {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    let transform = parent
+        * Affine3A::from_scale_rotation_translation(
+            scale.into(),
+            Quat::from_array(rotation),
+            translation.into(),
+        );
+    if let Some(name) = node.name() {
+        nodes.entry(name.to_string()).or_insert(transform);
+    }
+    for child in node.children() {
+        add_nodes(child, transform, nodes);
+    }
+}
@@ -1,19 +1,31 @@
 use {
-    crate::game::PlayerState,
+    crate::game::TICK_RATE,
     anyhow::Result,
+    blocks::ChunkPos,
     crossbeam::queue::SegQueue,
-    entities::{Anchor, EntityID, PlayerId},
+    entities::{Anchor, EntityData, EntityID, PlayerId},
     futures_util::{SinkExt, StreamExt},
-    net_types::ClientPacket,
-    std::{collections::HashMap, ops::Add, sync::Arc},
+    net_types::{
+        codec,
+        quantize::{QuantizedQuat, QuantizedVec3},
+        ClientPacket,
+    },
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        ops::Add,
+        sync::Arc,
+    },
     tokio::{
         net::TcpListener,
         select,
-        sync::mpsc::{self, Receiver, Sender},
+        sync::mpsc::{self, error::TrySendError, Receiver, Sender},
     },
     tokio_tungstenite::tungstenite::Message,
 };
 
+// Clients that we haven't been able to send anything to for this many ticks are disconnected
+const MAX_BACKED_UP_TICKS: u32 = TICK_RATE * 5;
+
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct ClientId(u64);
 
@@ -26,9 +38,15 @@ impl Add<u64> for ClientId {
 }
 
 pub struct Client {
-    // The last received controls
-    // TODO(ll): Once we have prediction this should be a queue of inputs
+    // Controls received from the client that haven't been processed yet, oldest first. One is
+    // processed per tick.
+    pub input_queue: VecDeque<net_types::Controls>,
+
+    // The last controls processed, with jump and fire cleared once they've been acted on. Its
+    // sequence is the one acknowledged to the client.
     pub last_controls: net_types::Controls,
+    // How many ticks in a row the next input hasn't arrived in time for
+    pub late_ticks: u32,
 
     // This client's player ID
     pub player_id: PlayerId,
@@ -37,7 +55,65 @@ pub struct Client {
 
     // The packet channels for this client
     pub incoming_rx: Receiver<net_types::ClientPacket>,
-    pub outgoing_tx: Sender<net_types::ServerPacket>,
+    outgoing_tx: Sender<net_types::ServerPacket>,
+
+    // Packets waiting to be sent with the rest of this tick's packets
+    outbox: Vec<net_types::ServerPacket>,
+    // How many ticks in a row we haven't been able to send to the client
+    backed_up_ticks: u32,
+}
+
+impl Client {
+    pub fn new(
+        player_id: PlayerId,
+        incoming_rx: ClientMessageReceiver,
+        outgoing_tx: ServerMessageSender,
+    ) -> Self {
+        Self {
+            input_queue: Default::default(),
+            last_controls: Default::default(),
+            late_ticks: 0,
+            player_id,
+            awareness: Default::default(),
+            incoming_rx,
+            outgoing_tx,
+            outbox: Vec::new(),
+            backed_up_ticks: 0,
+        }
+    }
+
+    /// Queue a packet, to be sent to the client along with everything else at the next `flush`
+    pub fn send(&mut self, packet: impl Into<net_types::ServerPacket>) {
+        self.outbox.push(packet.into());
+    }
+
+    /// Send everything queued since the last flush as a single message. This never waits on the
+    /// client: if it isn't keeping up, the packets are held on to and sent along with the next
+    /// tick's instead. Returns `false` if the client has gone away or fallen too far behind, and
+    /// should be dropped.
+    pub fn flush(&mut self) -> bool {
+        if self.outbox.is_empty() {
+            self.backed_up_ticks = 0;
+            return true;
+        }
+
+        let batch = net_types::ServerPacket::Batch(std::mem::take(&mut self.outbox));
+        match self.outgoing_tx.try_send(batch) {
+            Ok(()) => {
+                self.backed_up_ticks = 0;
+                true
+            }
+            Err(TrySendError::Full(batch)) => {
+                let net_types::ServerPacket::Batch(packets) = batch else {
+                    unreachable!()
+                };
+                self.outbox = packets;
+                self.backed_up_ticks += 1;
+                self.backed_up_ticks <= MAX_BACKED_UP_TICKS
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
 }
 
 pub async fn start_client_listener(
@@ -56,7 +132,8 @@ pub async fn start_client_listener(
                 .await
                 .expect("error during the websocket handshake");
 
-            // Create channels for serialized messages
+            // Create channels for serialized messages. The server sends one batch of packets per
+            // tick, so the outgoing channel holds a few ticks' worth.
             let (incoming_tx, incoming_rx) = mpsc::channel(16);
             let (outgoing_tx, mut outgoing_rx) = mpsc::channel(16);
 
@@ -83,19 +160,25 @@ pub async fn start_client_listener(
                                 }
                             };
 
-                            // Bincode is currently broken, fall back to json for now.
-                            // See: https://github.com/leetvr/hy/issues/189
-                            let client_packet: ClientPacket = match serde_json::de::from_slice(
+                            // Pings, pongs and close frames aren't packets
+                            if !message.is_binary() {
+                                continue;
+                            }
+
+                            let client_packet: ClientPacket = match codec::decode(
                                 &message.into_data(),
                             ) {
                                 Ok(v) => v,
                                 Err(e) => {
-                                    tracing::warn!("Error deserializing controls: {}", e);
+                                    tracing::warn!("Error deserializing client packet: {}", e);
                                     break;
                                 }
                             };
 
-                            incoming_tx.send(client_packet).await.unwrap();
+                            if incoming_tx.send(client_packet).await.is_err() {
+                                // The game server has dropped the client, eg. for falling behind
+                                break;
+                            }
                         }
                         // Handle outgoing messages
                         message = outgoing_rx.recv() => {
@@ -104,12 +187,13 @@ pub async fn start_client_listener(
                                 break;
                             };
 
-                            // Bincode is currently broken, fall back to json for now.
-                            // See: https://github.com/leetvr/hy/issues/189
-                            // let message =
-                            //     bincode::serialize(&message).unwrap();
-                            let message =
-                                serde_json::ser::to_vec(&message).unwrap();
+                            let message = match codec::encode(&message) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    tracing::error!("Error serializing server packet: {}", e);
+                                    continue;
+                                }
+                            };
                             if let Err(e) = write.send(Message::Binary(message)).await {
                                 tracing::info!("Error sending message: {}", e);
                                 break;
@@ -131,38 +215,44 @@ pub type ServerMessageSender = Sender<net_types::ServerPacket>;
 // This is used to check what updates the client needs to receive from the server to stay in sync
 #[derive(Clone, Debug, Default)]
 pub struct ClientAwareness {
-    // The players that the client is aware of, and their last known position
-    pub players: HashMap<PlayerId, ClientPlayerState>,
-
-    // The scripted entities that the client is aware of, and their last known position
+    // The entities that the client is aware of, players included, and their last known state
     pub entities: HashMap<EntityID, KnownEntityState>,
 
     // The known world script state
     pub world_state: serde_json::Value,
+
+    // The chunks that have been streamed to the client
+    pub chunks: HashSet<ChunkPos>,
+
+    // The sequence number of the last input the client has been told was processed
+    pub acked_input: Option<u32>,
 }
 
+// The state of an entity as the client knows it. The transform is kept quantized, as it was
+// sent, so that changes too small to be sent don't count as changes.
 #[derive(Clone, Debug)]
 pub struct KnownEntityState {
-    pub position: glam::Vec3,
-    pub rotation: glam::Quat,
-    pub scale: glam::Vec3,
+    pub position: QuantizedVec3,
+    pub rotation: QuantizedQuat,
+    pub scale: QuantizedVec3,
     pub anchor: Option<Anchor>,
-}
-
-// The state of a player as the client knows it
-#[derive(Clone, Debug, Default, PartialEq)]
-pub struct ClientPlayerState {
-    pub position: glam::Vec3,
-    pub animation_state: String,
+    // Players only
+    pub animation_state: Option<String>,
     pub script_state: HashMap<String, serde_json::Value>,
 }
 
-impl ClientPlayerState {
-    pub fn new(state: &PlayerState) -> ClientPlayerState {
-        ClientPlayerState {
-            position: state.position,
-            animation_state: state.animation_state.clone(),
-            script_state: state.custom_state.clone(),
+impl KnownEntityState {
+    pub fn new(entity: &EntityData) -> KnownEntityState {
+        KnownEntityState {
+            position: entity.state.position.into(),
+            rotation: entity.state.rotation.into(),
+            scale: entity.state.scale.into(),
+            anchor: entity.state.anchor.clone(),
+            animation_state: entity
+                .controller
+                .as_ref()
+                .map(|controller| controller.animation_state.clone()),
+            script_state: entity.state.custom_state.clone(),
         }
     }
 }
@@ -0,0 +1,382 @@
+// Interest management: decides which players and entities each client gets told about.
+//
+// Clients are only sent the things that are relevant to their player. This saves bandwidth on
+// bigger maps, and means a hacked client can't see enemies through walls on the other side of the
+// map.
+
+use {
+    super::network::KnownEntityState,
+    entities::{EntityData, EntityID, EntityType, EntityTypeRegistry, PlayerId},
+    serde::{Deserialize, Serialize},
+    std::collections::{HashMap, HashSet},
+};
+
+// Things a client already knows about stay relevant until they're this much further away than the
+// view radius, so that they don't flicker in and out at the edge
+const HYSTERESIS: f32 = 4.0;
+
+/// World-wide relevance settings, loaded from `relevance.json` if it exists
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RelevanceConfig {
+    /// Players and entities further than this from a client's player are not sent to it
+    pub view_radius: f32,
+    /// The key in a player's custom state that holds their team. Teammates are always relevant to
+    /// each other.
+    pub team_key: String,
+}
+
+impl Default for RelevanceConfig {
+    fn default() -> Self {
+        Self {
+            view_radius: 64.0,
+            team_key: "team".into(),
+        }
+    }
+}
+
+/// The client's player, that relevance is computed for
+pub struct Viewer<'a> {
+    pub player_id: PlayerId,
+    pub position: glam::Vec3,
+    pub team: Option<&'a serde_json::Value>,
+}
+
+impl RelevanceConfig {
+    pub fn viewer<'a>(&self, player_id: PlayerId, player: &'a EntityData) -> Viewer<'a> {
+        Viewer {
+            player_id,
+            position: player.state.position,
+            team: self.team(player),
+        }
+    }
+
+    fn team<'a>(&self, player: &'a EntityData) -> Option<&'a serde_json::Value> {
+        player
+            .state
+            .custom_state
+            .get(&self.team_key)
+            .filter(|team| !team.is_null())
+    }
+
+    fn in_range(&self, viewer: &Viewer, position: glam::Vec3, radius: f32, known: bool) -> bool {
+        let radius = if known { radius + HYSTERESIS } else { radius };
+        viewer.position.distance_squared(position) <= radius * radius
+    }
+
+    /// Get the players that are relevant to the viewer. `known` is the entities the client
+    /// already knows about.
+    pub fn relevant_players(
+        &self,
+        viewer: &Viewer,
+        players: &HashMap<PlayerId, EntityID>,
+        entities: &HashMap<EntityID, EntityData>,
+        known: &HashMap<EntityID, KnownEntityState>,
+    ) -> HashSet<PlayerId> {
+        players
+            .iter()
+            .filter(|(player_id, entity_id)| {
+                // You can always see yourself
+                if **player_id == viewer.player_id {
+                    return true;
+                }
+                let Some(player) = entities.get(*entity_id) else {
+                    return false;
+                };
+
+                // And your teammates
+                if viewer.team.is_some() && viewer.team == self.team(player) {
+                    return true;
+                }
+
+                self.in_range(
+                    viewer,
+                    player.state.position,
+                    self.view_radius,
+                    known.contains_key(*entity_id),
+                )
+            })
+            .map(|(player_id, _)| *player_id)
+            .collect()
+    }
+
+    /// Get the entities that are relevant to the viewer, including players. `known` is the
+    /// entities the client already knows about.
+    pub fn relevant_entities(
+        &self,
+        viewer: &Viewer,
+        entities: &HashMap<EntityID, EntityData>,
+        entity_type_registry: &EntityTypeRegistry,
+        relevant_players: &HashSet<PlayerId>,
+        known: &HashMap<EntityID, KnownEntityState>,
+    ) -> HashSet<EntityID> {
+        let is_relevant = |entity: &EntityData, parent_relevant| {
+            self.is_entity_relevant(
+                viewer,
+                entity,
+                entity
+                    .entity_type
+                    .and_then(|entity_type| entity_type_registry.get(entity_type)),
+                relevant_players,
+                parent_relevant,
+                known.contains_key(&entity.id),
+            )
+        };
+
+        let mut relevance = HashMap::new();
+        for entity_id in entities.keys() {
+            resolve_entity_relevance(entity_id, entities, &is_relevant, &mut relevance);
+        }
+
+        relevance
+            .into_iter()
+            .filter(|(_, relevant)| *relevant)
+            .map(|(entity_id, _)| entity_id)
+            .collect()
+    }
+
+    /// Is the entity relevant to the viewer? Players are relevant if they're in
+    /// `relevant_players`. Anchored entities are relevant whenever their parent is, which is
+    /// `parent_relevant`, otherwise the client would get an entity attached to something it
+    /// doesn't know about.
+    fn is_entity_relevant(
+        &self,
+        viewer: &Viewer,
+        entity: &EntityData,
+        entity_type: Option<&EntityType>,
+        relevant_players: &HashSet<PlayerId>,
+        parent_relevant: Option<bool>,
+        known: bool,
+    ) -> bool {
+        if let Some(player_id) = entity.player_id() {
+            return relevant_players.contains(&player_id);
+        }
+
+        let relevance = entity_type.map(EntityType::relevance);
+
+        if let Some(relevance) = relevance {
+            if !relevance.teams.is_empty() {
+                let team = viewer.team.and_then(serde_json::Value::as_str);
+                if !team.is_some_and(|team| relevance.teams.iter().any(|t| t == team)) {
+                    return false;
+                }
+            }
+
+            if relevance.always_relevant {
+                return true;
+            }
+        }
+
+        if let Some(parent_relevant) = parent_relevant {
+            return parent_relevant;
+        }
+
+        let radius = relevance
+            .and_then(|relevance| relevance.view_radius)
+            .unwrap_or(self.view_radius);
+        self.in_range(viewer, entity.state.absolute_position, radius, known)
+    }
+}
+
+// Work out whether the entity is relevant, after its parents if it's anchored. Results are
+// remembered in `relevance`.
+fn resolve_entity_relevance(
+    entity_id: &str,
+    entities: &HashMap<EntityID, EntityData>,
+    is_relevant: &impl Fn(&EntityData, Option<bool>) -> bool,
+    relevance: &mut HashMap<EntityID, bool>,
+) -> bool {
+    if let Some(relevant) = relevance.get(entity_id) {
+        return *relevant;
+    }
+    let Some(entity) = entities.get(entity_id) else {
+        return false;
+    };
+
+    // Mark the entity as irrelevant while its parents are worked out, in case there's a loop
+    relevance.insert(entity_id.to_string(), false);
+    let parent_relevant = entity.state.anchor.as_ref().map(|anchor| {
+        resolve_entity_relevance(&anchor.parent_id, entities, is_relevant, relevance)
+    });
+
+    let relevant = is_relevant(entity, parent_relevant);
+    relevance.insert(entity_id.to_string(), relevant);
+    relevant
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        entities::{test_util, Anchor, EntityRelevance, PlayerController},
+    };
+
+    // The client's player is always player 0
+    fn viewer_id() -> PlayerId {
+        PlayerId::new(0)
+    }
+
+    fn player(player_id: u64, position: glam::Vec3) -> EntityData {
+        EntityData {
+            controller: Some(PlayerController::new(PlayerId::new(player_id))),
+            entity_type: None,
+            ..test_util::entity(&format!("player {player_id}"), 0, position)
+        }
+    }
+
+    // Things have the given relevance, and Props the default
+    fn registry(relevance: EntityRelevance) -> EntityTypeRegistry {
+        let mut registry = EntityTypeRegistry::default();
+        registry.insert(test_util::entity_type(0, "Thing").with_relevance(relevance));
+        registry.insert(test_util::entity_type(1, "Prop"));
+        registry
+    }
+
+    // Everything in `entities`, as if the client had been sent it already
+    fn known(entities: &[&EntityData]) -> HashMap<EntityID, KnownEntityState> {
+        entities
+            .iter()
+            .map(|entity| (entity.id.clone(), KnownEntityState::new(entity)))
+            .collect()
+    }
+
+    fn by_id(entities: Vec<EntityData>) -> HashMap<EntityID, EntityData> {
+        entities
+            .into_iter()
+            .map(|entity| (entity.id.clone(), entity))
+            .collect()
+    }
+
+    #[test]
+    fn players_are_relevant_within_the_view_radius() {
+        let config = RelevanceConfig::default();
+        let viewer = player(0, glam::Vec3::ZERO);
+        let near = player(1, glam::Vec3::X * (config.view_radius - 1.));
+        let edge = player(2, glam::Vec3::X * (config.view_radius + HYSTERESIS / 2.));
+        let far = player(3, glam::Vec3::X * (config.view_radius + HYSTERESIS * 2.));
+        let players = [&viewer, &near, &edge, &far]
+            .iter()
+            .map(|player| (player.player_id().unwrap(), player.id.clone()))
+            .collect::<HashMap<_, _>>();
+        let entities = by_id(vec![viewer.clone(), near, edge.clone(), far.clone()]);
+        let viewer = config.viewer(viewer_id(), &viewer);
+
+        let relevant = config.relevant_players(&viewer, &players, &entities, &HashMap::new());
+        assert_eq!(relevant, HashSet::from([viewer_id(), PlayerId::new(1)]));
+
+        // Players the client already knows about only go once they're well outside the radius
+        let known = known(&[&edge, &far]);
+        let relevant = config.relevant_players(&viewer, &players, &entities, &known);
+        assert_eq!(
+            relevant,
+            HashSet::from([viewer_id(), PlayerId::new(1), PlayerId::new(2)])
+        );
+    }
+
+    #[test]
+    fn teammates_are_always_relevant() {
+        let config = RelevanceConfig::default();
+        let mut viewer = player(0, glam::Vec3::ZERO);
+        let mut teammate = player(1, glam::Vec3::X * config.view_radius * 10.);
+        for player in [&mut viewer, &mut teammate] {
+            player
+                .state
+                .custom_state
+                .insert(config.team_key.clone(), "red".into());
+        }
+        let players = HashMap::from([
+            (viewer_id(), viewer.id.clone()),
+            (PlayerId::new(1), teammate.id.clone()),
+        ]);
+        let entities = by_id(vec![viewer.clone(), teammate]);
+        let viewer = config.viewer(viewer_id(), &viewer);
+
+        let relevant = config.relevant_players(&viewer, &players, &entities, &HashMap::new());
+        assert!(relevant.contains(&PlayerId::new(1)));
+    }
+
+    #[test]
+    fn entities_are_relevant_within_their_view_radius() {
+        let config = RelevanceConfig::default();
+        let viewer_entity = player(0, glam::Vec3::ZERO);
+        let viewer = config.viewer(viewer_id(), &viewer_entity);
+        let registry = registry(EntityRelevance {
+            view_radius: Some(10.),
+            ..Default::default()
+        });
+        let near = test_util::entity("near", 0, glam::Vec3::X * 9.);
+        let edge = test_util::entity("edge", 0, glam::Vec3::X * (10. + HYSTERESIS / 2.));
+        let entities = by_id(vec![near.clone(), edge.clone()]);
+        let players = HashSet::from([viewer_id()]);
+
+        let relevant =
+            config.relevant_entities(&viewer, &entities, &registry, &players, &HashMap::new());
+        assert_eq!(relevant, HashSet::from(["near".to_string()]));
+
+        let relevant =
+            config.relevant_entities(&viewer, &entities, &registry, &players, &known(&[&edge]));
+        assert_eq!(
+            relevant,
+            HashSet::from(["near".to_string(), "edge".to_string()])
+        );
+    }
+
+    #[test]
+    fn always_relevant_entities_are_relevant_anywhere() {
+        let config = RelevanceConfig::default();
+        let viewer_entity = player(0, glam::Vec3::ZERO);
+        let viewer = config.viewer(viewer_id(), &viewer_entity);
+        let registry = registry(EntityRelevance {
+            always_relevant: true,
+            ..Default::default()
+        });
+        let far_away = glam::Vec3::X * config.view_radius * 10.;
+        let flag = test_util::entity("flag", 0, far_away);
+        // Anything anchored to it goes with it
+        let mut pennant = test_util::entity("pennant", 1, glam::Vec3::Y);
+        pennant.state.absolute_position = far_away + glam::Vec3::Y;
+        pennant.state.anchor = Some(Anchor {
+            parent_id: "flag".into(),
+            parent_node: None,
+        });
+        let entities = by_id(vec![flag, pennant]);
+
+        let relevant = config.relevant_entities(
+            &viewer,
+            &entities,
+            &registry,
+            &HashSet::from([viewer_id()]),
+            &HashMap::new(),
+        );
+        assert_eq!(
+            relevant,
+            HashSet::from(["flag".to_string(), "pennant".to_string()])
+        );
+    }
+
+    #[test]
+    fn team_entities_are_only_relevant_to_the_team() {
+        let config = RelevanceConfig::default();
+        let mut viewer_entity = player(0, glam::Vec3::ZERO);
+        viewer_entity
+            .state
+            .custom_state
+            .insert(config.team_key.clone(), "blue".into());
+        let viewer = config.viewer(viewer_id(), &viewer_entity);
+        let registry = registry(EntityRelevance {
+            always_relevant: true,
+            teams: vec!["red".into()],
+            ..Default::default()
+        });
+        let entities = by_id(vec![test_util::entity("base", 0, glam::Vec3::ZERO)]);
+
+        let relevant = config.relevant_entities(
+            &viewer,
+            &entities,
+            &registry,
+            &HashSet::from([viewer_id()]),
+            &HashMap::new(),
+        );
+        assert!(relevant.is_empty());
+    }
+}
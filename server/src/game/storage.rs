@@ -0,0 +1,294 @@
+// How worlds are kept on disk.
+//
+// A world is a directory of files plus `world.json`, a manifest recording which version of the
+// format the rest of the files are in. Worlds saved by older servers are migrated as they're
+// loaded, and written back in the current format the next time they're saved. Worlds from before
+// there was a manifest are version 0.
+//
+// Blocks are kept in `blocks.bin`, in the binary format from `BlockGrid::encode`, compressed with
+// zstd when the `zstd` feature is on. Before version 2 they were a JSON file, which is still read
+// and migrated so worlds can be imported from it. The block and entity type files are written by
+// hand and never saved by the server, so only the blocks and entities go through migrations.
+
+use {
+    anyhow::{bail, Context, Result},
+    blocks::BlockGrid,
+    serde::{Deserialize, Serialize},
+    serde_json::Value,
+    std::{
+        fs::File,
+        io::Write,
+        path::{Path, PathBuf},
+    },
+};
+
+const BLOCKS_PATH: &str = "blocks.bin";
+const JSON_BLOCKS_PATH: &str = "blocks.json";
+const ENTITIES_PATH: &str = "entities.json";
+const MANIFEST_PATH: &str = "world.json";
+
+// zstd frames start with this, which is how compressed blocks are told apart from uncompressed ones
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// The version of the world format this server reads and writes
+pub const WORLD_VERSION: u32 = 2;
+
+// Each migration takes a world from the version at its index to the next one. Saves write the
+// manifest last, so a save that's interrupted part way through can leave files that are already
+// in the new format behind a manifest with the old version. Migrations have to leave those alone.
+const MIGRATIONS: [fn(&mut StoredWorld) -> Result<()>; WORLD_VERSION as usize] =
+    [drop_player_anchors, binary_blocks];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+}
+
+/// A world's blocks and entities as they are on disk, brought up to the current version
+pub struct WorldFiles {
+    pub blocks: BlockGrid,
+    pub entities: Value,
+}
+
+// A world part way through being migrated
+struct StoredWorld {
+    blocks: StoredBlocks,
+    entities: Value,
+}
+
+enum StoredBlocks {
+    // As they were stored before version 2
+    Json(Value),
+    Grid(BlockGrid),
+}
+
+pub fn read_world(storage_dir: &Path) -> Result<WorldFiles> {
+    let version = match std::fs::read(storage_dir.join(MANIFEST_PATH)) {
+        Ok(manifest) => serde_json::from_slice::<Manifest>(&manifest)?.version,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if version > WORLD_VERSION {
+        bail!(
+            "{} was saved in version {version} of the world format, but this server only \
+             understands up to version {WORLD_VERSION}",
+            storage_dir.display()
+        );
+    }
+
+    let mut world = StoredWorld {
+        blocks: read_blocks(storage_dir, version)?,
+        entities: read_json(storage_dir.join(ENTITIES_PATH))?,
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut world)
+            .with_context(|| format!("Couldn't migrate {}", storage_dir.display()))?;
+    }
+    let StoredBlocks::Grid(blocks) = world.blocks else {
+        bail!("{}'s blocks weren't migrated", storage_dir.display());
+    };
+    if version < WORLD_VERSION {
+        tracing::info!(
+            "Migrated {} from version {version} to {WORLD_VERSION}, it'll be written in the new \
+             format when it's next saved",
+            storage_dir.display()
+        );
+    }
+
+    Ok(WorldFiles {
+        blocks,
+        entities: world.entities,
+    })
+}
+
+/// Save a world's blocks and entities in the current version of the format
+pub fn write_world(
+    storage_dir: &Path,
+    blocks: &BlockGrid,
+    entities: &impl Serialize,
+) -> Result<()> {
+    write_atomic(&storage_dir.join(BLOCKS_PATH), &encode_blocks(blocks)?)?;
+    write_atomic(
+        &storage_dir.join(ENTITIES_PATH),
+        &serde_json::to_vec(entities)?,
+    )?;
+    let manifest = Manifest {
+        version: WORLD_VERSION,
+    };
+    write_atomic(
+        &storage_dir.join(MANIFEST_PATH),
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    // Now that the manifest says the blocks are in `blocks.bin`, an old JSON file would only
+    // mislead anyone looking at the world
+    match std::fs::remove_file(storage_dir.join(JSON_BLOCKS_PATH)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Replace a file's contents so that anyone reading it sees either the old contents or the new
+/// ones, never a half written file. The contents are written to a temporary file next to it, which
+/// is then renamed over it.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = temp_path(path);
+    let mut file = File::create(&temp_path)
+        .with_context(|| format!("Couldn't create {}", temp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Couldn't replace {}", path.display()))?;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+fn read_json(path: PathBuf) -> Result<Value> {
+    let contents =
+        std::fs::read(&path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    serde_json::from_slice(&contents).with_context(|| format!("Couldn't parse {}", path.display()))
+}
+
+// Read the blocks from whichever file the world's version keeps them in
+fn read_blocks(storage_dir: &Path, version: u32) -> Result<StoredBlocks> {
+    if version < 2 {
+        let json = read_json(storage_dir.join(JSON_BLOCKS_PATH))?;
+        return Ok(StoredBlocks::Json(json));
+    }
+
+    let path = storage_dir.join(BLOCKS_PATH);
+    let bytes =
+        std::fs::read(&path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let blocks =
+        decode_blocks(&bytes).with_context(|| format!("Couldn't decode {}", path.display()))?;
+    Ok(StoredBlocks::Grid(blocks))
+}
+
+fn encode_blocks(blocks: &BlockGrid) -> Result<Vec<u8>> {
+    let bytes = blocks.encode();
+    #[cfg(feature = "zstd")]
+    let bytes = zstd::encode_all(bytes.as_slice(), 0)?;
+    Ok(bytes)
+}
+
+fn decode_blocks(bytes: &[u8]) -> Result<BlockGrid> {
+    if !bytes.starts_with(&ZSTD_MAGIC) {
+        return Ok(BlockGrid::decode(bytes)?);
+    }
+
+    #[cfg(feature = "zstd")]
+    return Ok(BlockGrid::decode(&zstd::decode_all(bytes)?)?);
+    #[cfg(not(feature = "zstd"))]
+    bail!("The blocks are compressed, but this server was built without the zstd feature");
+}
+
+// 0 -> 1: entities used to be anchored to players by player ID. Players are entities now, and an
+// anchor to one only lasts as long as they're connected, so anchors in the old form are dropped.
+fn drop_player_anchors(world: &mut StoredWorld) -> Result<()> {
+    let Some(entities) = world.entities.as_object_mut() else {
+        return Ok(());
+    };
+    for entity in entities.values_mut() {
+        let Some(anchor) = entity.pointer_mut("/state/anchor") else {
+            continue;
+        };
+        if anchor.get("playerId").is_some() {
+            *anchor = Value::Null;
+        }
+    }
+    Ok(())
+}
+
+// 1 -> 2: blocks moved from a JSON array of every block to `blocks.bin`. They're read from the JSON
+// file for older worlds, and parsed into a grid here to be saved in the binary format.
+fn binary_blocks(world: &mut StoredWorld) -> Result<()> {
+    if let StoredBlocks::Json(json) = &mut world.blocks {
+        let blocks = serde_json::from_value(json.take()).context("Couldn't parse the blocks")?;
+        world.blocks = StoredBlocks::Grid(blocks);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, blocks::BlockPos, serde_json::json};
+
+    fn legacy_world(dir: &Path) {
+        // Block 3 at (1, 0, 0)
+        let blocks = r#"{"blocks":[0,3],"size":[2,1,1]}"#;
+        std::fs::write(dir.join(JSON_BLOCKS_PATH), blocks).unwrap();
+        let entities = json!({
+            "1": {"state": {"anchor": {"playerId": 5, "parentAnchor": "hand"}}},
+            "2": {"state": {"anchor": {"parentId": "1"}}},
+            "3": {"state": {"anchor": null}},
+        });
+        std::fs::write(dir.join(ENTITIES_PATH), entities.to_string()).unwrap();
+    }
+
+    #[test]
+    fn legacy_worlds_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        legacy_world(dir.path());
+
+        let files = read_world(dir.path()).unwrap();
+        assert_eq!(files.entities["1"]["state"]["anchor"], Value::Null);
+        assert_eq!(files.entities["2"]["state"]["anchor"]["parentId"], "1");
+        assert_eq!(files.entities["3"]["state"]["anchor"], Value::Null);
+        assert_eq!(files.blocks[BlockPos::new(1, 0, 0)], 3);
+
+        // Saving records the version, so the world isn't migrated again
+        write_world(dir.path(), &files.blocks, &files.entities).unwrap();
+        assert!(!dir.path().join(JSON_BLOCKS_PATH).exists());
+        let manifest: Manifest =
+            serde_json::from_slice(&std::fs::read(dir.path().join(MANIFEST_PATH)).unwrap())
+                .unwrap();
+        assert_eq!(manifest.version, WORLD_VERSION);
+        let reread = read_world(dir.path()).unwrap();
+        assert_eq!(reread.entities, files.entities);
+        assert_eq!(reread.blocks[BlockPos::new(1, 0, 0)], 3);
+    }
+
+    #[test]
+    fn blocks_are_read_compressed_or_not() {
+        let mut blocks = BlockGrid::new();
+        blocks[BlockPos::new(-5, 10, 200)] = 2;
+
+        let compressed = encode_blocks(&blocks).unwrap();
+        let uncompressed = blocks.encode();
+        for bytes in [compressed, uncompressed] {
+            let decoded = decode_blocks(&bytes).unwrap();
+            assert_eq!(decoded[BlockPos::new(-5, 10, 200)], 2);
+        }
+    }
+
+    #[test]
+    fn newer_worlds_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        legacy_world(dir.path());
+        let manifest = json!({ "version": WORLD_VERSION + 1 });
+        std::fs::write(dir.path().join(MANIFEST_PATH), manifest.to_string()).unwrap();
+
+        assert!(read_world(dir.path()).is_err());
+    }
+
+    #[test]
+    fn atomic_writes_replace_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.json");
+        std::fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+
+        // Nothing is left behind
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 1);
+    }
+}
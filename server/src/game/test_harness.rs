@@ -0,0 +1,272 @@
+// An in-process game server for tests.
+//
+// The world is written to a temporary directory along with the scripts the test wants to run, and
+// clients are channel pairs pushed straight onto the server's connection queue, the same way the
+// websocket listener does it. Nothing touches the network, and the server only ticks when the
+// test tells it to.
+
+use {
+    super::{
+        network::{ClientMessageReceiver, ServerMessageSender},
+        GameServer, ServerState, World,
+    },
+    blocks::{BlockGrid, BlockPos, BlockRegistry, BlockType, BlockTypeID, EMPTY_BLOCK},
+    crossbeam::queue::SegQueue,
+    entities::{EntityData, EntityType, EntityTypeRegistry, PlayerId},
+    net_types::{ClientPacket, ServerPacket},
+    std::{path::Path, sync::Arc},
+    tokio::sync::mpsc::{self, Receiver, Sender},
+};
+
+// Size of the floor in the default world, in blocks
+const FLOOR_SIZE: i32 = 16;
+
+// Keeps a running tick count in the world script state, so there's always something to sync
+pub const DEFAULT_WORLD_SCRIPT: &str = r#"
+export const init = (worldState) => ({ ...worldState, ticks: 0 });
+export const onAddPlayer = (worldState, playerId, playerState) => [
+    worldState,
+    { ...playerState, modelPath: "player.gltf" },
+];
+export const update = (worldState) => ({ ...worldState, ticks: worldState.ticks + 1 });
+"#;
+
+// Moves players with the engine's movement code and nothing else
+pub const DEFAULT_PLAYER_SCRIPT: &str = r#"
+export const onSpawn = (playerId, playerState) => playerState;
+export const update = (playerId, playerState, controls) => {
+    const { position, velocity, isOnGround, coyoteTime, jumpInputTime } = playerState;
+    const movement = hy.stepMovement(
+        { position, velocity, isOnGround, coyoteTime, jumpInputTime },
+        controls,
+    );
+    return { ...playerState, ...movement };
+};
+"#;
+
+/// A world on disk for a `TestServer` to load
+pub struct TestWorld {
+    dir: tempfile::TempDir,
+}
+
+impl TestWorld {
+    /// A flat floor of a single block type, no entities, and the default scripts
+    pub fn new() -> Self {
+        let world = Self {
+            dir: tempfile::tempdir().expect("Couldn't create a temporary directory"),
+        };
+
+        let mut block_registry = BlockRegistry::default();
+        let block_id = block_registry.insert(test_block_type());
+        let mut blocks = BlockGrid::new();
+        for x in 0..FLOOR_SIZE {
+            for z in 0..FLOOR_SIZE {
+                blocks.set(BlockPos::new(x, 0, z), block_id);
+            }
+        }
+
+        world.write_json("blocks.json", &blocks);
+        world.write_json("block_types.json", &block_registry);
+        world.write_json("entities.json", &serde_json::json!({}));
+        world.write_json("entity_types.json", &EntityTypeRegistry::default());
+        world.write_script("world.js", DEFAULT_WORLD_SCRIPT);
+        world.write_script("player.js", DEFAULT_PLAYER_SCRIPT);
+        world
+    }
+
+    /// The default world, with these entity types and entities in it. See `entities::test_util` for
+    /// making them.
+    pub fn with_entities(entity_types: Vec<EntityType>, entities: Vec<EntityData>) -> Self {
+        let world = Self::new();
+        let mut registry = EntityTypeRegistry::default();
+        for entity_type in entity_types {
+            registry.insert(entity_type);
+        }
+        let entities = entities
+            .into_iter()
+            .map(|entity| (entity.id.clone(), entity))
+            .collect::<std::collections::HashMap<_, _>>();
+        world.write_json("entity_types.json", &registry);
+        world.write_json("entities.json", &entities);
+        world
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Write a file into the world directory, replacing anything already there
+    pub fn write_json(&self, path: &str, value: &impl serde::Serialize) {
+        let json = serde_json::to_vec(value).expect("Couldn't serialize world file");
+        std::fs::write(self.path().join(path), json).expect("Couldn't write world file");
+    }
+
+    /// Write a script where the server looks for them, relative to the world's `dist/` directory
+    pub fn write_script(&self, path: &str, source: &str) {
+        let path = self.path().join("dist").join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).expect("Couldn't create script directory");
+        std::fs::write(path, source).expect("Couldn't write script");
+    }
+}
+
+fn test_block_type() -> BlockType {
+    BlockType {
+        name: "Test Block".into(),
+        north_texture: "test.png".into(),
+        south_texture: "test.png".into(),
+        east_texture: "test.png".into(),
+        west_texture: "test.png".into(),
+        top_texture: "test.png".into(),
+        bottom_texture: "test.png".into(),
+        metallic_factor: 0.,
+        roughness_factor: 1.,
+    }
+}
+
+pub struct TestServer {
+    pub server: GameServer,
+    incoming_connections: Arc<SegQueue<(ClientMessageReceiver, ServerMessageSender)>>,
+    world: TestWorld,
+}
+
+impl TestServer {
+    pub async fn start(world: TestWorld) -> Self {
+        let incoming_connections = Arc::new(SegQueue::new());
+        let server = GameServer::with_connections(world.path(), incoming_connections.clone()).await;
+
+        Self {
+            server,
+            incoming_connections,
+            world,
+        }
+    }
+
+    /// The world the server loaded, eg. to change its scripts while it's running
+    pub fn world(&self) -> &TestWorld {
+        &self.world
+    }
+
+    /// Connect a new client. It's picked up by the server on the next tick.
+    pub fn connect(&self) -> TestClient {
+        // Same capacity as the real connections
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(16);
+        self.incoming_connections.push((incoming_rx, outgoing_tx));
+
+        TestClient {
+            tx: incoming_tx,
+            rx: outgoing_rx,
+        }
+    }
+
+    /// Connect a client and start editing the world with it
+    pub async fn connect_editing(&mut self) -> TestClient {
+        let mut client = self.connect();
+        self.tick().await;
+        client.send(ClientPacket::Edit);
+        self.tick().await;
+        client.received();
+        client
+    }
+
+    /// Connect a client and take it through the editor and back into the game, the way someone
+    /// working on the world would. Script errors are only sent to this client.
+    pub async fn connect_editor(&mut self) -> TestClient {
+        let mut client = self.connect_editing().await;
+        client.send(ClientPacket::Start);
+        self.tick().await;
+        client.received();
+        client
+    }
+
+    pub async fn tick(&mut self) {
+        self.server.tick().await;
+    }
+
+    pub async fn tick_n(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick().await;
+        }
+    }
+
+    /// An entity as it is in the running game, or in the editor
+    pub fn entity(&self, entity_id: &str) -> Option<EntityData> {
+        self.with_world(|world| world.entities.get(entity_id).cloned())
+            .flatten()
+    }
+
+    /// The block at a position in the running game, or in the editor
+    pub fn block(&self, position: BlockPos) -> BlockTypeID {
+        self.with_world(|world| world.blocks.get(position).copied())
+            .flatten()
+            .unwrap_or(EMPTY_BLOCK)
+    }
+
+    /// Spawn an entity into the running game, the same way scripts do. It's spawned on the next
+    /// tick.
+    pub fn spawn_entity(&self, entity: EntityData) {
+        let (ServerState::Playing(instance) | ServerState::Paused(instance)) = &self.server.state
+        else {
+            panic!("Entities can only be spawned into a running game");
+        };
+        let mut world = instance.world.lock().expect("Deadlock!");
+        world.spawn_entity(entity.id.clone(), entity);
+    }
+
+    /// A player's entity as it is in the running game
+    pub fn player(&self, player_id: PlayerId) -> Option<EntityData> {
+        self.with_world(|world| world.player_entity(player_id).cloned())
+            .flatten()
+    }
+
+    fn with_world<T>(&self, f: impl FnOnce(&World) -> T) -> Option<T> {
+        let world = match &self.server.state {
+            ServerState::Playing(instance) | ServerState::Paused(instance) => &instance.world,
+            ServerState::Editing(editor) => &editor.world,
+            ServerState::Transitioning => return None,
+        };
+        let world = world.lock().expect("Deadlock!");
+        Some(f(&world))
+    }
+
+    /// The world script's state in the running game
+    pub fn world_script_state(&self) -> serde_json::Value {
+        let (ServerState::Playing(instance) | ServerState::Paused(instance)) = &self.server.state
+        else {
+            return serde_json::Value::Null;
+        };
+        instance.custom_world_state.clone()
+    }
+
+    /// The name of the state the server is in, eg. "Playing"
+    pub fn state(&self) -> String {
+        self.server.state.to_string()
+    }
+}
+
+/// The client's end of a connection to a `TestServer`
+pub struct TestClient {
+    tx: Sender<ClientPacket>,
+    rx: Receiver<ServerPacket>,
+}
+
+impl TestClient {
+    /// Send a packet, to be handled on the server's next tick
+    pub fn send(&self, packet: ClientPacket) {
+        self.tx
+            .try_send(packet)
+            .expect("Test client's channel is full or closed");
+    }
+
+    /// Everything the server has sent since last time, with batches flattened
+    pub fn received(&mut self) -> Vec<ServerPacket> {
+        let mut packets = Vec::new();
+        while let Ok(packet) = self.rx.try_recv() {
+            match packet {
+                ServerPacket::Batch(batch) => packets.extend(batch),
+                packet => packets.push(packet),
+            }
+        }
+        packets
+    }
+}
@@ -1,36 +1,184 @@
 use {
-    super::PlayerState,
+    super::{model_nodes::ModelNodes, relevance::RelevanceConfig, storage, PlayerState},
     crate::js::JSContext,
-    anyhow::Result,
-    blocks::{BlockGrid, BlockRegistry},
-    entities::{Anchor, EntityData, EntityTypeRegistry, Interaction, PlayerId},
+    anyhow::{bail, Result},
+    blocks::{BlockGrid, BlockPos, BlockRegistry, BlockTypeID, ChunkPos},
+    entities::{Anchor, EntityData, EntityID, EntityTypeRegistry, Interaction, Joint, PlayerId},
+    nanorand::Rng,
     net_types::PlaySound,
-    physics::PhysicsWorld,
+    physics::{Collision, PhysicsWorld},
     std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         path::{Path, PathBuf},
         sync::{Arc, Mutex},
     },
 };
 
-const BLOCKS_PATH: &str = "blocks.json";
 const BLOCK_TYPES_PATH: &str = "block_types.json";
-const ENTITIES_PATH: &str = "entities.json";
 const ENTITY_TYPES_PATH: &str = "entity_types.json";
+const RELEVANCE_PATH: &str = "relevance.json";
 
 pub struct World {
     pub blocks: BlockGrid,
     pub block_registry: BlockRegistry,
     pub entities: HashMap<String, EntityData>, // key is EntityID
     pub entity_type_registry: EntityTypeRegistry,
+    pub relevance: RelevanceConfig,
     command_queue: Vec<WorldCommand>,
-    pub player_data: HashMap<PlayerId, PlayerState>,
+    // Each connected player's entity. Players are in `entities` along with everything else, but
+    // aren't saved with the world.
+    pub players: HashMap<PlayerId, EntityID>,
+    // Where entities anchored to a node in their parent's model go
+    model_nodes: ModelNodes,
 }
 
 impl World {
+    pub fn player_entity(&self, player_id: PlayerId) -> Option<&EntityData> {
+        self.entities.get(self.players.get(&player_id)?)
+    }
+
+    pub fn player_entity_mut(&mut self, player_id: PlayerId) -> Option<&mut EntityData> {
+        self.entities.get_mut(self.players.get(&player_id)?)
+    }
+
+    /// The player's entity as the player script sees it, along with what's anchored to it
+    pub fn player_state(&self, player_id: PlayerId) -> Option<PlayerState> {
+        let player = self.player_entity(player_id)?;
+        let mut attached_entities: HashMap<String, Vec<EntityID>> = HashMap::new();
+        for (entity_id, entity) in &self.entities {
+            if let Some(anchor) = &entity.state.anchor {
+                if anchor.parent_id == player.id {
+                    attached_entities
+                        .entry(anchor.parent_node.clone().unwrap_or_default())
+                        .or_default()
+                        .push(entity_id.clone());
+                }
+            }
+        }
+
+        PlayerState::new(player, attached_entities)
+    }
+
+    /// Add a player's entity to the world, and its body to the physics world
+    pub fn add_player(&mut self, player: EntityData, physics_world: &mut PhysicsWorld) {
+        let Some(player_id) = player.player_id() else {
+            tracing::error!("Entity {} isn't a player, not adding it", player.id);
+            return;
+        };
+
+        physics_world.spawn_entity(&player, &self.entity_type_registry);
+        self.players.insert(player_id, player.id.clone());
+        self.entities.insert(player.id.clone(), player);
+    }
+
+    /// Remove a player's entity and body. Anything anchored to the player is despawned along with
+    /// the rest of the tick's queued updates.
+    pub fn remove_player(&mut self, player_id: PlayerId, physics_world: &mut PhysicsWorld) {
+        let Some(entity_id) = self.players.remove(&player_id) else {
+            return;
+        };
+
+        for child_id in self.children(&entity_id) {
+            self.despawn_entity(child_id);
+        }
+        physics_world.despawn_entity(&entity_id);
+        self.entities.remove(&entity_id);
+        self.remove_joints_to(&entity_id);
+    }
+
+    // Joints to an entity go when it does. Its body has already taken them out of the physics
+    // world, so this just keeps the other entities' data in step.
+    fn remove_joints_to(&mut self, entity_id: &str) {
+        for entity in self.entities.values_mut() {
+            entity
+                .joints
+                .retain(|joint| joint.other_entity.as_deref() != Some(entity_id));
+        }
+    }
+
+    // The entities anchored directly to this one
+    fn children(&self, entity_id: &str) -> Vec<EntityID> {
+        self.entities
+            .iter()
+            .filter(|(_, entity)| {
+                entity
+                    .state
+                    .anchor
+                    .as_ref()
+                    .is_some_and(|anchor| anchor.parent_id == entity_id)
+            })
+            .map(|(child_id, _)| child_id.clone())
+            .collect()
+    }
+
+    /// Everything anchored to the entity, directly or through other entities
+    pub fn descendants(&self, entity_id: &str) -> Vec<EntityID> {
+        self.entities
+            .keys()
+            .filter(|descendant_id| self.is_anchored_to(descendant_id, entity_id))
+            .cloned()
+            .collect()
+    }
+
+    // Whether `ancestor_id` is the entity's parent, or its parent's parent, and so on
+    fn is_anchored_to(&self, entity_id: &str, ancestor_id: &str) -> bool {
+        let mut current_id = entity_id;
+        // Bounded, in case a world was saved with a loop in it
+        for _ in 0..self.entities.len() {
+            let Some(anchor) = self
+                .entities
+                .get(current_id)
+                .and_then(|entity| entity.state.anchor.as_ref())
+            else {
+                return false;
+            };
+            if anchor.parent_id == ancestor_id {
+                return true;
+            }
+            current_id = &anchor.parent_id;
+        }
+        false
+    }
+
+    // Anchors can't make loops, and players are only ever moved by their own script
+    fn check_anchor(&self, entity_id: &str, anchor: &Anchor) -> Result<()> {
+        let Some(entity) = self.entities.get(entity_id) else {
+            bail!("it doesn't exist");
+        };
+        if entity.controller.is_some() {
+            bail!("it's a player");
+        }
+        if !self.entities.contains_key(&anchor.parent_id) {
+            bail!("its parent {} doesn't exist", anchor.parent_id);
+        }
+        if anchor.parent_id == entity_id || self.is_anchored_to(&anchor.parent_id, entity_id) {
+            bail!("{} is anchored to it", anchor.parent_id);
+        }
+        Ok(())
+    }
+
+    /// Work out where every entity is in the world, following anchors up to the entity at the top
+    pub fn update_absolute_positions(&mut self) {
+        let mut transforms = HashMap::new();
+        for entity_id in self.entities.keys() {
+            world_transform(
+                &self.entities,
+                entity_id,
+                &mut self.model_nodes,
+                &mut transforms,
+                0,
+            );
+        }
+        for (entity_id, entity) in &mut self.entities {
+            if let Some(transform) = transforms.get(entity_id) {
+                entity.state.absolute_position = transform.translation.into();
+            }
+        }
+    }
+
     pub fn spawn_entity(&mut self, entity_id: String, entity_data: EntityData) {
         self.command_queue
-            .push(WorldCommand::SpawnEntity(entity_id, entity_data));
+            .push(WorldCommand::SpawnEntity(entity_id, Box::new(entity_data)));
     }
 
     pub fn despawn_entity(&mut self, entity_id: String) {
@@ -38,14 +186,47 @@ impl World {
             .push(WorldCommand::DespawnEntity(entity_id));
     }
 
+    /// Attach an entity to a node in a player's model
     pub fn anchor_entity(&mut self, entity_id: String, player_id: u64, anchor_name: String) {
+        let Some(parent_id) = self.players.get(&PlayerId::new(player_id)).cloned() else {
+            tracing::warn!("Not anchoring entity {entity_id}, player {player_id} doesn't exist");
+            return;
+        };
+
+        let anchor = Anchor {
+            parent_id,
+            parent_node: Some(anchor_name),
+        };
+        self.anchor_to_entity(entity_id, anchor, None);
+    }
+
+    /// Attach an entity to another entity. If there's an offset, it's the entity's new position
+    /// relative to the anchor.
+    pub fn anchor_to_entity(
+        &mut self,
+        entity_id: String,
+        anchor: Anchor,
+        offset: Option<glam::Vec3>,
+    ) {
         self.command_queue.push(WorldCommand::AnchorEntity {
             entity_id,
-            player_id,
-            anchor_name,
+            anchor,
+            offset,
         });
     }
 
+    /// Join an entity to another entity, or to the world, replacing any joint it has with the same
+    /// name
+    pub fn add_joint(&mut self, entity_id: String, joint: Joint) {
+        self.command_queue
+            .push(WorldCommand::AddJoint { entity_id, joint });
+    }
+
+    pub fn remove_joint(&mut self, entity_id: String, name: String) {
+        self.command_queue
+            .push(WorldCommand::RemoveJoint { entity_id, name });
+    }
+
     pub fn detach_entity(&mut self, entity_id: String, position: glam::Vec3) {
         self.command_queue.push(WorldCommand::DetachEntity {
             entity_id,
@@ -72,6 +253,17 @@ impl World {
         });
     }
 
+    pub fn set_block(&mut self, position: BlockPos, block_id: BlockTypeID) {
+        self.command_queue
+            .push(WorldCommand::SetBlock { position, block_id });
+    }
+
+    /// Set every block from `min` to `max`, inclusive
+    pub fn fill_region(&mut self, min: BlockPos, max: BlockPos, block_id: BlockTypeID) {
+        self.command_queue
+            .push(WorldCommand::FillRegion { min, max, block_id });
+    }
+
     pub fn play_sound(&mut self, sound_id: String, position: glam::Vec3, volume: f32) {
         self.command_queue.push(WorldCommand::PlaySound {
             sound_id,
@@ -85,8 +277,11 @@ impl World {
         js_context: &mut JSContext,
         physics_world: Arc<Mutex<PhysicsWorld>>,
         queued_sounds: &mut Vec<PlaySound>,
+        entity_events: &mut Vec<EntityEvent>,
+        changed_blocks: &mut Vec<BlockPos>,
     ) {
-        for command in self.command_queue.drain(..) {
+        let first_changed_block = changed_blocks.len();
+        for command in std::mem::take(&mut self.command_queue) {
             match command {
                 WorldCommand::SpawnEntity(entity_id, mut entity_data) => {
                     spawn_entity(
@@ -95,23 +290,73 @@ impl World {
                         physics_world.clone(),
                         &self.entity_type_registry,
                     );
-                    self.entities.insert(entity_id, entity_data);
+                    physics_world
+                        .lock()
+                        .expect("Deadlock!")
+                        .add_entity_joints(&entity_data);
+                    self.entities.insert(entity_id, *entity_data);
                 }
                 WorldCommand::DespawnEntity(entity_id) => {
-                    tracing::debug!("Despawning entity {entity_id}");
-                    despawn_entity(&entity_id, physics_world.clone());
-                    self.entities.remove(&entity_id);
+                    // Players leave when their client does
+                    if self
+                        .entities
+                        .get(&entity_id)
+                        .is_some_and(|e| e.controller.is_some())
+                    {
+                        tracing::warn!("Not despawning entity {entity_id}, it's a player");
+                        continue;
+                    }
+
+                    // Anything anchored to the entity goes with it
+                    let descendants = self.descendants(&entity_id);
+                    for entity_id in std::iter::once(entity_id).chain(descendants) {
+                        tracing::debug!("Despawning entity {entity_id}");
+                        despawn_entity(&entity_id, physics_world.clone());
+                        if let Some(entity_data) = self.entities.remove(&entity_id) {
+                            self.remove_joints_to(&entity_id);
+                            entity_events.push(EntityEvent::Despawn(Box::new(entity_data)));
+                        }
+                    }
+                }
+                WorldCommand::AddJoint { entity_id, joint } => {
+                    let Some(entity) = self.entities.get_mut(&entity_id) else {
+                        continue;
+                    };
+                    let added = physics_world
+                        .lock()
+                        .expect("Deadlock!")
+                        .add_joint(&entity_id, &joint);
+                    if let Err(e) = added {
+                        tracing::warn!("Couldn't add joint {} to {entity_id}: {e}", joint.name);
+                        continue;
+                    }
+                    entity.joints.retain(|j| j.name != joint.name);
+                    entity.joints.push(joint);
+                }
+                WorldCommand::RemoveJoint { entity_id, name } => {
+                    physics_world
+                        .lock()
+                        .expect("Deadlock!")
+                        .remove_joint(&entity_id, &name);
+                    if let Some(entity) = self.entities.get_mut(&entity_id) {
+                        entity.joints.retain(|joint| joint.name != name);
+                    }
                 }
                 WorldCommand::AnchorEntity {
                     entity_id,
-                    player_id,
-                    anchor_name,
+                    anchor,
+                    offset,
                 } => {
+                    if let Err(e) = self.check_anchor(&entity_id, &anchor) {
+                        tracing::warn!("Not anchoring entity {entity_id}: {e}");
+                        continue;
+                    }
                     if let Some(entity) = self.entities.get_mut(&entity_id) {
-                        entity.state.anchor = Some(Anchor {
-                            player_id: PlayerId::new(player_id),
-                            parent_anchor: anchor_name,
-                        });
+                        if let Some(offset) = offset {
+                            entity.state.position = offset;
+                        }
+                        entity.state.anchor = Some(anchor.clone());
+                        entity_events.push(EntityEvent::Anchor(entity_id, anchor));
                     }
                 }
                 WorldCommand::DetachEntity {
@@ -120,7 +365,9 @@ impl World {
                 } => {
                     if let Some(entity) = self.entities.get_mut(&entity_id) {
                         entity.state.position = position;
-                        entity.state.anchor = None;
+                        if entity.state.anchor.take().is_some() {
+                            entity_events.push(EntityEvent::Detach(entity_id));
+                        }
                     }
                 }
                 WorldCommand::InteractEntity {
@@ -132,13 +379,28 @@ impl World {
                     custom_state,
                 } => {
                     if let Some(entity) = self.entities.get_mut(&entity_id) {
-                        entity.state.interactions.push(Interaction {
+                        let interaction = Interaction {
                             player_id,
                             position,
                             yaw,
                             pitch,
                             custom_state,
-                        });
+                        };
+                        entity.state.interactions.push(interaction.clone());
+                        entity_events.push(EntityEvent::Interact(entity_id, interaction));
+                    }
+                }
+                WorldCommand::SetBlock { position, block_id } => {
+                    set_block(&mut self.blocks, position, block_id, changed_blocks);
+                }
+                WorldCommand::FillRegion { min, max, block_id } => {
+                    for x in min.x..=max.x {
+                        for y in min.y..=max.y {
+                            for z in min.z..=max.z {
+                                let position = BlockPos::new(x, y, z);
+                                set_block(&mut self.blocks, position, block_id, changed_blocks);
+                            }
+                        }
                     }
                 }
                 WorldCommand::PlaySound {
@@ -152,45 +414,67 @@ impl World {
                 }),
             }
         }
+
+        // Rebuild the colliders of each chunk that changed, once, however many of its blocks did
+        let changed_chunks = changed_blocks[first_changed_block..]
+            .iter()
+            .map(|&position| ChunkPos::containing(position))
+            .collect::<HashSet<_>>();
+        if !changed_chunks.is_empty() {
+            let mut physics_world = physics_world.lock().expect("Deadlock!");
+            for position in changed_chunks {
+                physics_world.set_terrain_chunk(position, self.blocks.chunk(position));
+            }
+        }
     }
 
     pub fn load(storage_dir: impl AsRef<Path>) -> Result<Self> {
-        let blocks_path = storage_dir.as_ref().join(BLOCKS_PATH);
-        let blocks = serde_json::from_slice(&std::fs::read(blocks_path)?)?;
+        let files = storage::read_world(storage_dir.as_ref())?;
+        let blocks = files.blocks;
+        let entities = serde_json::from_value(files.entities)?;
 
         let block_types_path = storage_dir.as_ref().join(BLOCK_TYPES_PATH);
         let block_registry = serde_json::from_slice(&std::fs::read(&block_types_path)?)?;
 
-        let entities_path = storage_dir.as_ref().join(ENTITIES_PATH);
-        let entities = serde_json::from_slice(&std::fs::read(entities_path)?)?;
-
         let entity_types_path = storage_dir.as_ref().join(ENTITY_TYPES_PATH);
         let entity_type_registry = serde_json::from_slice(&std::fs::read(entity_types_path)?)?;
 
+        // Optional, most worlds are happy with the defaults
+        let relevance_path = storage_dir.as_ref().join(RELEVANCE_PATH);
+        let relevance = match std::fs::read(relevance_path) {
+            Ok(relevance) => serde_json::from_slice(&relevance)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RelevanceConfig::default(),
+            Err(e) => return Err(e.into()),
+        };
+
         Ok(Self {
             blocks,
             block_registry,
             entities,
             entity_type_registry,
+            relevance,
             command_queue: Vec::new(),
-            player_data: HashMap::new(),
+            players: HashMap::new(),
+            model_nodes: ModelNodes::default(),
         })
     }
 
     pub fn save(&mut self, storage_dir: &PathBuf) -> anyhow::Result<()> {
-        // Save blocks
-        let blocks_path = storage_dir.join(BLOCKS_PATH);
-        let blocks = serde_json::to_string(&self.blocks)?;
-        std::fs::write(blocks_path, blocks)?;
-
-        // Save entities
-        let entities_path = storage_dir.join(ENTITIES_PATH);
-        let entities = serde_json::to_string(&self.entities)?;
-        std::fs::write(entities_path, entities)?;
-        Ok(())
+        // Players aren't part of the world
+        let entities = self
+            .entities
+            .iter()
+            .filter(|(_, entity)| entity.controller.is_none())
+            .collect::<HashMap<_, _>>();
+        storage::write_world(storage_dir, &self.blocks, &entities)
     }
 }
 
+/// A fresh, random entity ID
+pub fn new_entity_id() -> EntityID {
+    nanorand::tls_rng().generate::<u64>().to_string()
+}
+
 pub fn spawn_entity(
     entity_data: &mut EntityData,
     js_context: &mut JSContext,
@@ -205,6 +489,95 @@ pub fn spawn_entity(
         .spawn_entity(entity_data, entity_type_registry);
 }
 
+// Set a block, if it's changed
+fn set_block(
+    blocks: &mut BlockGrid,
+    position: BlockPos,
+    block_id: BlockTypeID,
+    changed_blocks: &mut Vec<BlockPos>,
+) {
+    if blocks[position] == block_id {
+        return;
+    }
+
+    blocks.set(position, block_id);
+    changed_blocks.push(position);
+}
+
+// The entity's transform in the world, worked out from its parents' and remembered in
+// `transforms`. Entities anchored to a node in their parent's model are placed relative to the
+// node, or to the parent's origin if the node can't be found.
+fn world_transform(
+    entities: &HashMap<EntityID, EntityData>,
+    entity_id: &str,
+    model_nodes: &mut ModelNodes,
+    transforms: &mut HashMap<EntityID, glam::Affine3A>,
+    depth: usize,
+) -> Option<glam::Affine3A> {
+    if let Some(transform) = transforms.get(entity_id) {
+        return Some(*transform);
+    }
+
+    let entity = entities.get(entity_id)?;
+    let state = &entity.state;
+    let local = glam::Affine3A::from_scale_rotation_translation(
+        state.scale,
+        state.rotation,
+        state.position,
+    );
+    // Entities whose parent is missing, or that are in a loop, stay where they'd be unanchored
+    let transform = match &state.anchor {
+        Some(anchor) if depth < entities.len() => {
+            match world_transform(
+                entities,
+                &anchor.parent_id,
+                model_nodes,
+                transforms,
+                depth + 1,
+            ) {
+                Some(parent) => {
+                    let node = anchor
+                        .parent_node
+                        .as_ref()
+                        .and_then(|node| {
+                            let model_path = &entities.get(&anchor.parent_id)?.model_path;
+                            model_nodes.get(model_path, node)
+                        })
+                        .unwrap_or(glam::Affine3A::IDENTITY);
+                    parent * node * local
+                }
+                None => local,
+            }
+        }
+        _ => local,
+    };
+
+    transforms.insert(entity_id.to_string(), transform);
+    Some(transform)
+}
+
+/// Spawn every entity in the world, then their joints, which need the bodies at both ends
+pub fn spawn_entities(
+    entities: &mut HashMap<EntityID, EntityData>,
+    js_context: &mut JSContext,
+    physics_world: Arc<Mutex<PhysicsWorld>>,
+    entity_type_registry: &EntityTypeRegistry,
+) {
+    for entity_data in entities.values_mut() {
+        spawn_entity(
+            entity_data,
+            js_context,
+            physics_world.clone(),
+            entity_type_registry,
+        );
+    }
+
+    let mut physics_world = physics_world.lock().expect("Deadlock!");
+    for entity_data in entities.values() {
+        physics_world.add_entity_joints(entity_data);
+    }
+}
+
 fn despawn_entity(entity_id: &str, physics_world: Arc<Mutex<PhysicsWorld>>) {
     physics_world
         .lock()
@@ -212,13 +585,73 @@ fn despawn_entity(entity_id: &str, physics_world: Arc<Mutex<PhysicsWorld>>) {
         .despawn_entity(entity_id)
 }
 
+/// Something that happened to an entity, that its script gets a callback for
+#[derive(Debug, Clone)]
+pub enum EntityEvent {
+    CollisionStart(EntityID, Collision),
+    CollisionEnd(EntityID, Collision),
+    Interact(EntityID, Interaction),
+    Anchor(EntityID, Anchor),
+    Detach(EntityID),
+    // The entity has already been removed from the world, so it comes with its last data
+    Despawn(Box<EntityData>),
+}
+
+impl EntityEvent {
+    pub fn entity_id(&self) -> &str {
+        match self {
+            EntityEvent::CollisionStart(entity_id, _)
+            | EntityEvent::CollisionEnd(entity_id, _)
+            | EntityEvent::Interact(entity_id, _)
+            | EntityEvent::Anchor(entity_id, _)
+            | EntityEvent::Detach(entity_id) => entity_id,
+            EntityEvent::Despawn(entity_data) => &entity_data.id,
+        }
+    }
+
+    /// The name of the script export that handles the event
+    pub fn callback_name(&self) -> &'static str {
+        match self {
+            EntityEvent::CollisionStart(..) => "onCollisionStart",
+            EntityEvent::CollisionEnd(..) => "onCollisionEnd",
+            EntityEvent::Interact(..) => "onInteract",
+            EntityEvent::Anchor(..) => "onAnchor",
+            EntityEvent::Detach(..) => "onDetach",
+            EntityEvent::Despawn(..) => "onDespawn",
+        }
+    }
+}
+
+impl From<physics::CollisionEvent> for EntityEvent {
+    fn from(event: physics::CollisionEvent) -> Self {
+        match event {
+            physics::CollisionEvent::Started {
+                entity_id,
+                collision,
+            } => EntityEvent::CollisionStart(entity_id, collision),
+            physics::CollisionEvent::Stopped {
+                entity_id,
+                collision,
+            } => EntityEvent::CollisionEnd(entity_id, collision),
+        }
+    }
+}
+
 enum WorldCommand {
-    SpawnEntity(String, EntityData),
+    SpawnEntity(String, Box<EntityData>),
     DespawnEntity(String),
     AnchorEntity {
         entity_id: String,
-        player_id: u64,
-        anchor_name: String,
+        anchor: Anchor,
+        offset: Option<glam::Vec3>,
+    },
+    AddJoint {
+        entity_id: String,
+        joint: Joint,
+    },
+    RemoveJoint {
+        entity_id: String,
+        name: String,
     },
     DetachEntity {
         entity_id: String,
@@ -237,4 +670,13 @@ enum WorldCommand {
         position: glam::Vec3,
         volume: f32,
     },
+    SetBlock {
+        position: BlockPos,
+        block_id: BlockTypeID,
+    },
+    FillRegion {
+        min: BlockPos,
+        max: BlockPos,
+        block_id: BlockTypeID,
+    },
 }
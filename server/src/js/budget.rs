@@ -0,0 +1,154 @@
+use {
+    super::{call_export, watchdog::Watchdog},
+    deno_core::v8,
+    serde::{de::DeserializeOwned, Serialize},
+    std::{
+        collections::BTreeMap,
+        fmt::Display,
+        time::{Duration, Instant},
+    },
+};
+
+// Longest a single call into a script can run before it's terminated
+const CALL_BUDGET: Duration = Duration::from_millis(5);
+// Longest all of the scripts can run for in a tick, put together. Once it's used up, scripts
+// aren't called again until the next tick, so the tick still finishes on time.
+const TICK_BUDGET: Duration = Duration::from_millis(10);
+// Longest a script's top-level code can run for when it's loaded. More generous than a call, as
+// scripts may well set themselves up there, but still stops one that never finishes.
+pub const LOAD_BUDGET: Duration = Duration::from_millis(500);
+
+/// The script wasn't called, because the scripts had already used up this tick's time budget
+#[derive(Debug)]
+pub struct OutOfTime;
+
+impl Display for OutOfTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Scripts have used up their time budget for this tick")
+    }
+}
+
+impl std::error::Error for OutOfTime {}
+
+/// How long a script's calls have taken since the server started
+#[derive(Debug, Default, Clone)]
+pub struct ScriptStats {
+    pub calls: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+    /// Calls that ran over budget and were terminated
+    pub terminated: u64,
+    /// Calls that weren't made because the tick was already out of time
+    pub skipped: u64,
+}
+
+impl ScriptStats {
+    pub fn mean_time(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => self.total_time / calls as u32,
+        }
+    }
+}
+
+// Times every call into the scripts, and stops them when they run over budget
+pub struct ScriptBudget {
+    watchdog: Watchdog,
+    // Script time left this tick, or None outside of a tick, where only the call budget applies
+    tick_remaining: Option<Duration>,
+    skipped_this_tick: u32,
+    // Whether the last tick ran out of time, so that it's only reported when it starts happening
+    was_out_of_time: bool,
+    // Indexed by script path
+    stats: BTreeMap<String, ScriptStats>,
+}
+
+impl ScriptBudget {
+    pub fn new(watchdog: Watchdog) -> Self {
+        Self {
+            watchdog,
+            tick_remaining: None,
+            skipped_this_tick: 0,
+            was_out_of_time: false,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    /// Watch a different runtime, eg. after the scripts have been reloaded. The old watchdog stops
+    /// straight away.
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = watchdog;
+    }
+
+    pub fn begin_tick(&mut self) {
+        self.tick_remaining = Some(TICK_BUDGET);
+        self.skipped_this_tick = 0;
+    }
+
+    /// Returns how many calls were skipped for lack of time, if this is the first tick in a row
+    /// that it's happened
+    pub fn end_tick(&mut self) -> Option<u32> {
+        self.tick_remaining = None;
+
+        let out_of_time = self.skipped_this_tick > 0;
+        let newly_out_of_time = out_of_time && !self.was_out_of_time;
+        self.was_out_of_time = out_of_time;
+        newly_out_of_time.then_some(self.skipped_this_tick)
+    }
+
+    pub fn stats(&self) -> &BTreeMap<String, ScriptStats> {
+        &self.stats
+    }
+
+    // `call_export`, but terminated if it runs for too long
+    pub fn call<T: DeserializeOwned>(
+        &mut self,
+        runtime: &mut deno_core::JsRuntime,
+        module_namespace: &v8::Global<v8::Object>,
+        script: &str,
+        function_name: &str,
+        args: impl Serialize,
+    ) -> anyhow::Result<T> {
+        let stats = self.stats.entry(script.to_string()).or_default();
+
+        let budget = match self.tick_remaining {
+            Some(remaining) if remaining.is_zero() => {
+                stats.skipped += 1;
+                self.skipped_this_tick += 1;
+                return Err(OutOfTime.into());
+            }
+            Some(remaining) => remaining.min(CALL_BUDGET),
+            None => CALL_BUDGET,
+        };
+
+        let start = Instant::now();
+        self.watchdog.arm(start + budget);
+        let result = call_export(runtime, module_namespace, function_name, args);
+        let terminated = self.watchdog.disarm();
+        let elapsed = start.elapsed();
+
+        stats.calls += 1;
+        stats.total_time += elapsed;
+        stats.max_time = stats.max_time.max(elapsed);
+        if let Some(remaining) = &mut self.tick_remaining {
+            *remaining = remaining.saturating_sub(elapsed);
+        }
+
+        if !terminated {
+            return result;
+        }
+
+        // Otherwise the next script to run would be terminated straight away
+        runtime.v8_isolate().cancel_terminate_execution();
+
+        // The watchdog may have gone off just after the script returned
+        if result.is_ok() {
+            return result;
+        }
+
+        stats.terminated += 1;
+        tracing::debug!("{script} {function_name} was stopped after running for {elapsed:?}");
+        // Kept the same every time, so that the error is only reported now and then
+        anyhow::bail!("{function_name} was stopped for running over its time budget")
+    }
+}
@@ -1,25 +1,46 @@
 use {
-    crate::game::{PlayerState, World},
+    crate::game::{new_entity_id, PlayerState, World},
     anyhow::bail,
-    blocks::{BlockPos, EMPTY_BLOCK},
+    blocks::{BlockPos, BlockTypeID, EMPTY_BLOCK},
     deno_core::{error::AnyError, extension, op2, OpState},
-    entities::{EntityData, EntityID, EntityState, PlayerId},
+    entities::{Anchor, EntityData, EntityID, EntityState, Joint, PlayerId},
     glam::{EulerRot, Vec3},
-    nanorand::Rng,
-    physics::{Collision, CollisionResult, PhysicsWorld},
+    movement::MovementState,
+    physics::{Collision, CollisionResult, HitFilter, Overlap, PhysicsWorld, QueryHit, QueryShape},
+    serde::Serialize,
     std::{
         collections::HashMap,
         sync::{Arc, Mutex},
     },
 };
 
+// The entity whose script is running, if any. Timers belong to the entity that set them, so that
+// they can be cleared when it despawns.
+#[derive(Default)]
+pub struct CurrentEntity(pub Option<EntityID>);
+
+// The player whose script is running, if any, so that scene queries can leave them out
+#[derive(Default)]
+pub struct CurrentPlayer(pub Option<PlayerId>);
+
+#[op2]
+#[serde]
+fn get_current_entity_id(state: &mut OpState) -> Option<EntityID> {
+    state.borrow::<CurrentEntity>().0.clone()
+}
+
+#[op2]
+fn set_current_entity_id(state: &mut OpState, #[serde] entity_id: Option<EntityID>) {
+    state.put(CurrentEntity(entity_id));
+}
+
 #[op2]
 #[serde]
 fn get_player_state(state: &mut OpState, #[bigint] player_id: u64) -> Option<PlayerState> {
     let world = state.borrow::<Arc<Mutex<World>>>();
     let world = world.lock().unwrap();
 
-    world.player_data.get(&PlayerId::new(player_id)).cloned()
+    world.player_state(PlayerId::new(player_id))
 }
 
 #[op2]
@@ -50,10 +71,11 @@ fn check_movement_for_collisions(
     #[serde] current_position: glam::Vec3,
     #[serde] movement: glam::Vec3,
 ) -> CollisionResult {
+    let entity_id = player_entity_id(state, player_id).unwrap_or_default();
     let physics_world = state.borrow::<Arc<Mutex<PhysicsWorld>>>();
     let mut physics_world = physics_world.lock().expect("Deadlock!");
 
-    physics_world.check_movement_for_collisions(player_id, current_position, movement)
+    physics_world.check_movement_for_collisions(&entity_id, current_position, movement)
 }
 
 #[op2]
@@ -68,10 +90,79 @@ fn get_collisions_for_entity(state: &mut OpState, #[string] entity_id: String) -
 #[op2]
 #[serde]
 fn get_collisions_for_player(state: &mut OpState, #[bigint] player_id: u64) -> Vec<Collision> {
+    let entity_id = player_entity_id(state, player_id).unwrap_or_default();
+    let physics_world = state.borrow::<Arc<Mutex<PhysicsWorld>>>();
+    let physics_world = physics_world.lock().expect("Deadlock!");
+
+    physics_world.get_collisions_for_entity(&entity_id)
+}
+
+// Players' bodies are their entities' bodies
+fn player_entity_id(state: &OpState, player_id: u64) -> Option<EntityID> {
+    let world = state.borrow::<Arc<Mutex<World>>>();
+    let world = world.lock().expect("Deadlock!");
+
+    world.players.get(&PlayerId::new(player_id)).cloned()
+}
+
+#[op2]
+#[serde]
+fn raycast(
+    state: &mut OpState,
+    #[serde] origin: Vec3,
+    #[serde] direction: Vec3,
+    max_distance: f32,
+    #[serde] filter: Option<HitFilter>,
+) -> Option<QueryHit> {
+    let filter = excluding_caller(state, filter);
+    let physics_world = state.borrow::<Arc<Mutex<PhysicsWorld>>>();
+    let physics_world = physics_world.lock().expect("Deadlock!");
+
+    physics_world.raycast(origin, direction, max_distance, &filter)
+}
+
+#[op2]
+#[serde]
+fn shape_cast(
+    state: &mut OpState,
+    #[serde] shape: QueryShape,
+    #[serde] origin: Vec3,
+    #[serde] direction: Vec3,
+    max_distance: f32,
+    #[serde] filter: Option<HitFilter>,
+) -> Option<QueryHit> {
+    let filter = excluding_caller(state, filter);
     let physics_world = state.borrow::<Arc<Mutex<PhysicsWorld>>>();
     let physics_world = physics_world.lock().expect("Deadlock!");
 
-    physics_world.get_collisions_for_player(player_id)
+    physics_world.shape_cast(&shape, origin, direction, max_distance, &filter)
+}
+
+#[op2]
+#[serde]
+fn overlap_sphere(
+    state: &mut OpState,
+    #[serde] center: Vec3,
+    radius: f32,
+    #[serde] filter: Option<HitFilter>,
+) -> Vec<Overlap> {
+    let filter = excluding_caller(state, filter);
+    let physics_world = state.borrow::<Arc<Mutex<PhysicsWorld>>>();
+    let physics_world = physics_world.lock().expect("Deadlock!");
+
+    physics_world.overlap_sphere(center, radius, &filter)
+}
+
+// Scripts asking what's around them don't want to hit themselves
+fn excluding_caller(state: &OpState, filter: Option<HitFilter>) -> HitFilter {
+    let mut filter = filter.unwrap_or_default();
+    if let Some(entity_id) = &state.borrow::<CurrentEntity>().0 {
+        filter.exclude_entities.push(entity_id.clone());
+    }
+    if let Some(player_id) = state.borrow::<CurrentPlayer>().0 {
+        filter.exclude_players.push(player_id.inner());
+    }
+    filter
 }
 
 #[op2]
@@ -91,7 +182,7 @@ fn spawn_entity(
         bail!("Entity type not found");
     };
 
-    let entity_id = nanorand::tls_rng().generate::<u64>().to_string();
+    let entity_id = new_entity_id();
     let mut state = EntityState::default();
     state.position = position.into();
     state.scale = glam::Vec3::new(1.0, 1.0, 1.0);
@@ -101,10 +192,12 @@ fn spawn_entity(
 
     let entity_data = EntityData {
         id: entity_id.clone(),
-        name: "We should let you set entity names in the editor".into(),
-        entity_type: entity_type.id,
+        name: entity_type.name(),
+        entity_type: Some(entity_type.id),
         model_path: entity_type.default_model_path().into(),
         state,
+        controller: None,
+        joints: Vec::new(),
     };
 
     world.spawn_entity(entity_id.clone(), entity_data);
@@ -133,6 +226,35 @@ fn anchor_entity(
     world.anchor_entity(entity_id, player_id, anchor_name);
 }
 
+#[op2]
+fn anchor_to_entity(
+    state: &mut OpState,
+    #[string] entity_id: String,
+    #[serde] anchor: Anchor,
+    #[serde] offset: Option<Vec3>,
+) {
+    let shared_state = state.borrow::<Arc<Mutex<World>>>();
+    let mut world = shared_state.lock().unwrap();
+
+    world.anchor_to_entity(entity_id, anchor, offset);
+}
+
+#[op2]
+fn add_joint(state: &mut OpState, #[string] entity_id: String, #[serde] joint: Joint) {
+    let shared_state = state.borrow::<Arc<Mutex<World>>>();
+    let mut world = shared_state.lock().unwrap();
+
+    world.add_joint(entity_id, joint);
+}
+
+#[op2(fast)]
+fn remove_joint(state: &mut OpState, #[string] entity_id: String, #[string] name: String) {
+    let shared_state = state.borrow::<Arc<Mutex<World>>>();
+    let mut world = shared_state.lock().unwrap();
+
+    world.remove_joint(entity_id, name);
+}
+
 #[op2]
 fn detach_entity(state: &mut OpState, #[string] entity_id: String, #[serde] position: Vec3) {
     let shared_state = state.borrow::<Arc<Mutex<World>>>();
@@ -177,14 +299,128 @@ fn play_sound(
     world.play_sound(sound_id, position, volume)
 }
 
+#[op2]
+#[serde]
+fn step_movement(
+    state: &mut OpState,
+    #[serde] movement_state: MovementState,
+    #[serde] controls: net_types::Controls,
+) -> MovementState {
+    let player_id = state.borrow::<CurrentPlayer>().0;
+    let world = state.borrow::<Arc<Mutex<World>>>();
+    let world = world.lock().unwrap();
+
+    let player_entity_id = player_id
+        .and_then(|player_id| world.players.get(&player_id))
+        .map(String::as_str)
+        .unwrap_or_default();
+    let obstacles = movement::obstacles(
+        world.entities.values(),
+        &world.entity_type_registry,
+        player_entity_id,
+        movement_state.position,
+    );
+    movement::step(
+        &movement_state,
+        &controls.movement_input(),
+        &world.blocks,
+        &obstacles,
+    )
+}
+
 #[op2]
 fn get_block(state: &mut OpState, #[serde] position: Vec3) -> u8 {
     let world = state.borrow::<Arc<Mutex<World>>>();
     let world = world.lock().unwrap();
 
-    BlockPos::from_float(position)
-        .and_then(|pos| world.blocks.get(pos).copied())
-        .unwrap_or(EMPTY_BLOCK)
+    world.blocks[BlockPos::from_float(position)]
+}
+
+// Biggest box of blocks that scripts can fill or read at once, so that a typo can't hang the server
+const MAX_SCRIPT_REGION_VOLUME: i64 = 64 * 64 * 64;
+
+#[op2]
+fn set_block(state: &mut OpState, #[serde] position: Vec3, block_id: u8) -> Result<(), AnyError> {
+    let world = state.borrow::<Arc<Mutex<World>>>();
+    let mut world = world.lock().unwrap();
+
+    check_block_id(&world, block_id)?;
+    world.set_block(BlockPos::from_float(position), block_id);
+    Ok(())
+}
+
+#[op2]
+fn fill_region(
+    state: &mut OpState,
+    #[serde] min: Vec3,
+    #[serde] max: Vec3,
+    block_id: u8,
+) -> Result<(), AnyError> {
+    let world = state.borrow::<Arc<Mutex<World>>>();
+    let mut world = world.lock().unwrap();
+
+    check_block_id(&world, block_id)?;
+    let (min, max) = block_region(min, max)?;
+    world.fill_region(min, max, block_id);
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockInBox {
+    position: BlockPos,
+    block_id: BlockTypeID,
+}
+
+#[op2]
+#[serde]
+fn get_blocks_in_box(
+    state: &mut OpState,
+    #[serde] min: Vec3,
+    #[serde] max: Vec3,
+) -> Result<Vec<BlockInBox>, AnyError> {
+    let world = state.borrow::<Arc<Mutex<World>>>();
+    let world = world.lock().unwrap();
+
+    let (min, max) = block_region(min, max)?;
+    let mut blocks = Vec::new();
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                let position = BlockPos::new(x, y, z);
+                let block_id = world.blocks[position];
+                if block_id != EMPTY_BLOCK {
+                    blocks.push(BlockInBox { position, block_id });
+                }
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+fn check_block_id(world: &World, block_id: BlockTypeID) -> Result<(), AnyError> {
+    if block_id != EMPTY_BLOCK && world.block_registry.get(block_id).is_none() {
+        bail!("Block type {block_id} doesn't exist");
+    }
+    Ok(())
+}
+
+// The blocks containing two corners, as an inclusive (min, max). The corners can be either way
+// round.
+fn block_region(a: Vec3, b: Vec3) -> Result<(BlockPos, BlockPos), AnyError> {
+    let (a, b) = (BlockPos::from_float(a), BlockPos::from_float(b));
+    let min = BlockPos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+    let max = BlockPos::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+
+    // Far apart corners don't fit in an i32, let alone their volume
+    let size = max.as_ivec3().as_i64vec3() - min.as_ivec3().as_i64vec3() + glam::I64Vec3::ONE;
+    let volume = size.x.saturating_mul(size.y).saturating_mul(size.z);
+    if volume > MAX_SCRIPT_REGION_VOLUME {
+        bail!("Region of {volume} blocks is bigger than the limit of {MAX_SCRIPT_REGION_VOLUME}");
+    }
+
+    Ok((min, max))
 }
 
 // Exports the extensions as a variable named `hy`
@@ -195,15 +431,27 @@ extension!(
         get_entities,
         get_entity_data,
         check_movement_for_collisions,
+        step_movement,
         spawn_entity,
         despawn_entity,
         anchor_entity,
+        anchor_to_entity,
+        add_joint,
+        remove_joint,
         detach_entity,
         interact_entity,
         get_collisions_for_entity,
         get_collisions_for_player,
+        raycast,
+        shape_cast,
+        overlap_sphere,
         play_sound,
         get_block,
+        set_block,
+        fill_region,
+        get_blocks_in_box,
+        get_current_entity_id,
+        set_current_entity_id,
     ],
     esm_entry_point = "ext:hy/runtime.js",
     esm = [dir "src/js", "runtime.js"],
@@ -214,5 +462,7 @@ extension!(
     state = |state, options| {
         state.put(options.world.clone());
         state.put(options.physics_world.clone());
+        state.put(CurrentEntity::default());
+        state.put(CurrentPlayer::default());
     }
 );
@@ -0,0 +1,44 @@
+use deno_core::{
+    FsModuleLoader, ModuleLoadResponse, ModuleLoader, ModuleSpecifier, RequestedModuleType,
+    ResolutionKind,
+};
+
+// Loads scripts from disk like `FsModuleLoader`, and also picks up the source maps that tsc writes
+// next to them (with `sourceMap` on), so that stack traces point at the TypeScript.
+pub struct ScriptLoader;
+
+impl ModuleLoader for ScriptLoader {
+    fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        kind: ResolutionKind,
+    ) -> Result<ModuleSpecifier, anyhow::Error> {
+        FsModuleLoader.resolve(specifier, referrer, kind)
+    }
+
+    fn load(
+        &self,
+        module_specifier: &ModuleSpecifier,
+        maybe_referrer: Option<&ModuleSpecifier>,
+        is_dyn_import: bool,
+        requested_module_type: RequestedModuleType,
+    ) -> ModuleLoadResponse {
+        FsModuleLoader.load(
+            module_specifier,
+            maybe_referrer,
+            is_dyn_import,
+            requested_module_type,
+        )
+    }
+
+    fn get_source_map(&self, file_name: &str) -> Option<Vec<u8>> {
+        let path = ModuleSpecifier::parse(file_name)
+            .ok()?
+            .to_file_path()
+            .ok()?;
+        let mut map_path = path.into_os_string();
+        map_path.push(".map");
+        std::fs::read(map_path).ok()
+    }
+}
@@ -1,24 +1,38 @@
+mod budget;
 mod extensions;
+mod loader;
+mod watchdog;
+mod watcher;
 
 use entities::{EntityData, EntityTypeID};
-use extensions::hy;
+use extensions::{hy, CurrentEntity, CurrentPlayer};
 use physics::PhysicsWorld;
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{Arc, Mutex},
 };
+use watchdog::Watchdog;
 use {
-    crate::game::{PlayerState, World},
+    crate::game::{EntityEvent, PlayerState, World},
     deno_core::{
+        error::JsError,
         op2, serde_v8,
         v8::{self},
         OpState,
     },
     entities::{EntityID, PlayerId},
+    serde::{de::DeserializeOwned, Serialize},
     std::collections::HashMap,
 };
-use {entities::EntityState, net_types::Controls};
+pub use {
+    budget::{OutOfTime, ScriptStats},
+    watcher::ScriptWatcher,
+};
+use {
+    entities::EntityState,
+    net_types::{Controls, ScriptError},
+};
 
 #[op2]
 #[serde]
@@ -32,13 +46,21 @@ fn get_entities(state: &mut OpState) -> HashMap<EntityID, EntityData> {
 }
 
 pub struct JSContext {
+    // Declared first so that its watchdog is stopped before the runtime is dropped
+    budget: budget::ScriptBudget,
     runtime: deno_core::JsRuntime,
     // Safe to hold onto as long as the runtime is alive (probably)
     world_module_namespace: v8::Global<v8::Object>,
     player_module_namespace: v8::Global<v8::Object>,
     entity_module_namespaces: HashMap<String, v8::Global<v8::Object>>, // indexed by path
     entity_module_paths: Vec<String>,                                  // indexed by entity type ID
+    // Functions that runtime.js provides for the server, rather than for scripts
+    runtime_internals: v8::Global<v8::Object>,
     world: Arc<Mutex<World>>,
+    physics_world: Arc<Mutex<PhysicsWorld>>,
+    script_root: PathBuf,
+    // Errors from scripts since the last `take_errors`, to be forwarded to the editor
+    errors: Vec<ScriptError>,
 }
 
 impl JSContext {
@@ -47,90 +69,118 @@ impl JSContext {
         world: Arc<Mutex<World>>,
         physics_world: Arc<Mutex<PhysicsWorld>>,
     ) -> anyhow::Result<Self> {
-        // Get a clone the entity type registry before we pass it over to the runtime
-        let entity_type_registry = {
-            let world = world.lock().expect("Deadlock!");
-            world.entity_type_registry.clone()
-        };
-
         // Load the runtime
-        let mut runtime = deno_core::JsRuntime::new(deno_core::RuntimeOptions {
-            module_loader: Some(Rc::new(deno_core::FsModuleLoader)),
-            extensions: vec![hy::init_ops_and_esm(world.clone(), physics_world)],
-            ..Default::default()
-        });
+        let mut runtime = new_runtime(&world, &physics_world);
+        let watchdog = Watchdog::new(runtime.v8_isolate().thread_safe_handle());
+        let runtime_internals = get_global_object(&mut runtime, "hyInternal")?;
 
         let script_root: PathBuf = script_root.into();
-
-        // Load the world module
-        let world_module_namespace =
-            get_module_namespace(script_root.join("world.js"), &mut runtime).await?;
-
-        // Load the player module
-        let player_script = script_root.join("player.js");
-        let player_module_namespace = get_module_namespace(player_script, &mut runtime).await?;
-
-        let mut entity_module_namespaces = HashMap::new();
-        let mut entity_module_paths = Vec::new();
-
-        // Load entity scripts
-        // PARANOIA: Ensure we load the entity types in the correct order
-        let mut entity_types = entity_type_registry.entity_types();
-        entity_types.sort_by_key(|et| et.id());
-
-        for entity_type in entity_type_registry.entity_types().iter() {
-            let path = entity_type.script_path();
-
-            // IMPORTANT: Deno will get very mad if we load the same module twice.
-            if !entity_module_namespaces.contains_key(path) {
-                let module_namespace =
-                    get_module_namespace(script_root.join(path), &mut runtime).await?;
-                entity_module_namespaces.insert(path.to_string(), module_namespace);
-            }
-
-            entity_module_paths.push(path.to_string());
-        }
+        let ScriptModules {
+            world_module_namespace,
+            player_module_namespace,
+            entity_module_namespaces,
+            entity_module_paths,
+        } = load_modules(&mut runtime, &watchdog, &script_root, &world).await?;
+        let budget = budget::ScriptBudget::new(watchdog);
 
         Ok(Self {
+            budget,
             runtime,
             world_module_namespace,
             player_module_namespace,
             entity_module_namespaces,
             entity_module_paths,
+            runtime_internals,
             world,
+            physics_world,
+            script_root,
+            errors: Vec::new(),
         })
     }
 
-    pub async fn get_player_next_state(
-        &mut self,
-        player_id: PlayerId,
-        current_state: &PlayerState,
-        controls: &Controls,
-    ) -> anyhow::Result<PlayerState> {
-        let scope = &mut self.runtime.handle_scope();
-        let module_namespace = self.player_module_namespace.open(scope);
+    /// Load all of the scripts again, eg. after they've been rebuilt. All script state is kept on
+    /// our side, so nothing is lost apart from timers and anything scripts keep in module-level
+    /// variables.
+    /// If the new scripts fail to load the error is reported, and the old scripts stay in use.
+    pub async fn reload(&mut self) -> anyhow::Result<()> {
+        // Modules can't be unloaded from a runtime, so the new scripts get a runtime of their own
+        // and the old one goes, modules, timers and all
+        let mut runtime = new_runtime(&self.world, &self.physics_world);
+        let watchdog = Watchdog::new(runtime.v8_isolate().thread_safe_handle());
+        let result = async {
+            let runtime_internals = get_global_object(&mut runtime, "hyInternal")?;
+            let modules =
+                load_modules(&mut runtime, &watchdog, &self.script_root, &self.world).await?;
+            anyhow::Ok((runtime_internals, modules))
+        }
+        .await;
+        let (
+            runtime_internals,
+            ScriptModules {
+                world_module_namespace,
+                player_module_namespace,
+                entity_module_namespaces,
+                entity_module_paths,
+            },
+        ) = self.report("scripts", "reload", None, result)?;
+
+        self.world_module_namespace = world_module_namespace;
+        self.player_module_namespace = player_module_namespace;
+        self.entity_module_namespaces = entity_module_namespaces;
+        self.entity_module_paths = entity_module_paths;
+        self.runtime_internals = runtime_internals;
+
+        // The watchdog has to stop watching the old runtime before it's dropped
+        self.budget.set_watchdog(watchdog);
+        let old_runtime = std::mem::replace(&mut self.runtime, runtime);
+        drop_older_runtime(old_runtime, &mut self.runtime);
+
+        tracing::info!("Reloaded scripts from {:?}", self.script_root);
+        Ok(())
+    }
 
-        let function_name = v8::String::new(scope, "update").unwrap();
-        let Some(update_fn) = module_namespace.get(scope, function_name.into()) else {
-            anyhow::bail!("ERROR: Module has no function named update!");
-        };
+    /// Start limiting the scripts to a tick's worth of time. Until `end_tick`, scripts called
+    /// after the budget has been used up fail with `OutOfTime`.
+    pub fn begin_tick(&mut self) {
+        self.budget.begin_tick();
+    }
 
-        if !update_fn.is_function() {
-            anyhow::bail!("ERROR: Module has a member named update, but it's not a function!");
-        }
+    pub fn end_tick(&mut self) {
+        let Some(skipped) = self.budget.end_tick() else {
+            return;
+        };
 
-        let player_update = v8::Local::<v8::Function>::try_from(update_fn).unwrap(); // we know it's a function
+        tracing::warn!("Scripts ran out of time this tick, skipped {skipped} calls");
+        self.errors.push(ScriptError {
+            script: "scripts".into(),
+            function: "tick".into(),
+            entity_id: None,
+            message: format!(
+                "Scripts used up their time budget for the tick, {skipped} calls were skipped"
+            ),
+            stack: None,
+        });
+    }
 
-        let undefined = deno_core::v8::undefined(scope).into();
-        let player_id = serde_v8::to_v8(scope, player_id).unwrap();
-        let current_state = serde_v8::to_v8(scope, current_state).unwrap();
-        let controls = serde_v8::to_v8(scope, controls).unwrap();
-        let args = [player_id.into(), current_state.into(), controls.into()];
+    /// How long each script has spent running, indexed by script path
+    pub fn script_stats(&self) -> &std::collections::BTreeMap<String, ScriptStats> {
+        self.budget.stats()
+    }
 
-        let result = player_update.call(scope, undefined, &args).unwrap();
-        let next_state: PlayerState = serde_v8::from_v8(scope, result)?;
+    /// Errors thrown by scripts since this was last called
+    pub fn take_errors(&mut self) -> Vec<ScriptError> {
+        std::mem::take(&mut self.errors)
+    }
 
-        Ok(next_state)
+    pub async fn get_player_next_state(
+        &mut self,
+        player_id: PlayerId,
+        current_state: &PlayerState,
+        controls: &Controls,
+    ) -> anyhow::Result<PlayerState> {
+        let result =
+            self.call_player_export(player_id, "update", (player_id, current_state, controls));
+        self.report("player.js", "update", None, result)
     }
 
     pub(crate) fn get_player_spawn_state(
@@ -138,32 +188,8 @@ impl JSContext {
         player_id: PlayerId,
         current_state: &PlayerState,
     ) -> anyhow::Result<PlayerState> {
-        // This is the same as get_player_next_state, but with a different function name, and no
-        // controls parameter
-        // Laziness made this happen
-        let scope = &mut self.runtime.handle_scope();
-        let module_namespace = self.player_module_namespace.open(scope);
-
-        let function_name = v8::String::new(scope, "onSpawn").unwrap();
-        let Some(update_fn) = module_namespace.get(scope, function_name.into()) else {
-            anyhow::bail!("ERROR: Module has no function named onSpawn!");
-        };
-
-        if !update_fn.is_function() {
-            anyhow::bail!("ERROR: Module has a member named onSpawn, but it's not a function!");
-        }
-
-        let player_update = v8::Local::<v8::Function>::try_from(update_fn).unwrap(); // we know it's a function
-
-        let undefined = deno_core::v8::undefined(scope).into();
-        let player_id = serde_v8::to_v8(scope, player_id).unwrap();
-        let current_state = serde_v8::to_v8(scope, current_state).unwrap();
-        let args = [player_id.into(), current_state.into()];
-
-        let result = player_update.call(scope, undefined, &args).unwrap();
-        let next_state: PlayerState = serde_v8::from_v8(scope, result)?;
-
-        Ok(next_state)
+        let result = self.call_player_export(player_id, "onSpawn", (player_id, current_state));
+        self.report("player.js", "onSpawn", None, result)
     }
 
     pub async fn run_script_for_entity(
@@ -171,25 +197,6 @@ impl JSContext {
         entity_id: &str,
         entity_type_id: EntityTypeID,
     ) -> anyhow::Result<()> {
-        let scope = &mut self.runtime.handle_scope();
-        let module_path = &self.entity_module_paths[entity_type_id as usize];
-        let module_namespace = &self.entity_module_namespaces[module_path];
-        let module_namespace = module_namespace.open(scope);
-
-        // Get the update function
-        let function_name = v8::String::new(scope, "update").unwrap();
-        let Some(update_fn) = module_namespace.get(scope, function_name.into()) else {
-            anyhow::bail!("ERROR: Module has no function named update!");
-        };
-
-        if !update_fn.is_function() {
-            anyhow::bail!("ERROR: Module has a member named update, but it's not a function!");
-        }
-
-        let entity_update = v8::Local::<v8::Function>::try_from(update_fn).unwrap(); // we know it's a function
-
-        let undefined = deno_core::v8::undefined(scope).into();
-
         let current_state = {
             let mut world = self.world.lock().expect("Deadlock!");
             let Some(entity_data) = world.entities.get_mut(entity_id) else {
@@ -197,86 +204,213 @@ impl JSContext {
                 return Ok(());
             };
 
-            let state = serde_v8::to_v8(scope, &entity_data.state).unwrap();
+            let state = entity_data.state.clone();
             entity_data.state.interactions.clear();
 
             state
         };
 
-        let entity_id_arg = serde_v8::to_v8(scope, entity_id).unwrap();
-        let args = [entity_id_arg.into(), current_state.into()];
-
         // Call the function
-        let result = entity_update.call(scope, undefined, &args).unwrap();
+        let module_path = self.entity_module_paths[entity_type_id as usize].clone();
+        let result = self.call_entity_export(
+            entity_id,
+            &module_path,
+            "update",
+            (entity_id, current_state),
+        );
 
         // Get the entity's next state
-        let next_state = serde_v8::from_v8(scope, result)?;
+        let next_state: EntityState =
+            self.report(&module_path, "update", Some(entity_id), result)?;
 
-        // Update the entity
-        {
-            let mut world = self.world.lock().expect("Deadlock!");
-            let entity = world.entities.get_mut(entity_id).unwrap();
-
-            let EntityState {
-                position,
-                rotation,
-                velocity,
-                scale,
-                custom_state,
-
-                // Not mutable from JS
-                anchor: _,
-                interactions: _,
-                absolute_position: _,
-            } = next_state;
-
-            entity.state.position = position;
-            entity.state.rotation = rotation;
-            entity.state.velocity = velocity;
-            entity.state.scale = scale;
-            entity.state.custom_state = custom_state;
+        self.set_entity_state(entity_id, next_state);
+        Ok(())
+    }
+
+    /// Run the entity script's callback for `event`, if it has one
+    pub fn run_entity_event(&mut self, event: &EntityEvent) -> anyhow::Result<()> {
+        let function_name = event.callback_name();
+        match event {
+            EntityEvent::CollisionStart(entity_id, collision)
+            | EntityEvent::CollisionEnd(entity_id, collision) => {
+                self.run_entity_callback(entity_id, function_name, collision)
+            }
+            EntityEvent::Interact(entity_id, interaction) => {
+                self.run_entity_callback(entity_id, function_name, interaction)
+            }
+            EntityEvent::Anchor(entity_id, anchor) => {
+                self.run_entity_callback(entity_id, function_name, anchor)
+            }
+            EntityEvent::Detach(entity_id) => {
+                self.run_entity_callback(entity_id, function_name, ())
+            }
+            EntityEvent::Despawn(entity_data) => self.run_despawn_callback(entity_data),
+        }
+    }
+
+    // Call an optional entity callback as `(entityId, state, argument) => state`
+    fn run_entity_callback(
+        &mut self,
+        entity_id: &str,
+        function_name: &str,
+        argument: impl Serialize,
+    ) -> anyhow::Result<()> {
+        let (entity_type_id, current_state) = {
+            let world = self.world.lock().expect("Deadlock!");
+            let Some(entity_data) = world.entities.get(entity_id) else {
+                return Ok(());
+            };
+            // Players don't have entity scripts
+            let Some(entity_type_id) = entity_data.entity_type else {
+                return Ok(());
+            };
+            (entity_type_id, entity_data.state.clone())
+        };
+
+        let module_path = self.entity_module_paths[entity_type_id as usize].clone();
+        let module_namespace = &self.entity_module_namespaces[&module_path];
+        if !has_export(&mut self.runtime, module_namespace, function_name) {
+            return Ok(());
         }
 
+        let result = self.call_entity_export(
+            entity_id,
+            &module_path,
+            function_name,
+            (entity_id, current_state, argument),
+        );
+        let next_state = self.report(&module_path, function_name, Some(entity_id), result)?;
+
+        self.set_entity_state(entity_id, next_state);
         Ok(())
     }
 
+    // The entity has already left the world, so there's no state to update afterwards
+    fn run_despawn_callback(&mut self, entity_data: &EntityData) -> anyhow::Result<()> {
+        let Some(entity_type_id) = entity_data.entity_type else {
+            return Ok(());
+        };
+        let module_path = self.entity_module_paths[entity_type_id as usize].clone();
+        let module_namespace = &self.entity_module_namespaces[&module_path];
+        if !has_export(&mut self.runtime, module_namespace, "onDespawn") {
+            return Ok(());
+        }
+
+        let result = self.call_entity_export::<serde::de::IgnoredAny>(
+            &entity_data.id,
+            &module_path,
+            "onDespawn",
+            (&entity_data.id, &entity_data.state),
+        );
+        self.report(&module_path, "onDespawn", Some(&entity_data.id), result)?;
+        Ok(())
+    }
+
+    // Call a function exported by an entity's script. Any timers the script sets belong to the
+    // entity.
+    fn call_entity_export<T: DeserializeOwned>(
+        &mut self,
+        entity_id: &str,
+        module_path: &str,
+        function_name: &str,
+        args: impl Serialize,
+    ) -> anyhow::Result<T> {
+        self.set_current_entity(Some(entity_id));
+        let result = self.budget.call(
+            &mut self.runtime,
+            &self.entity_module_namespaces[module_path],
+            module_path,
+            function_name,
+            args,
+        );
+        self.set_current_entity(None);
+        result
+    }
+
+    // Call a function exported by the player script, on behalf of one player
+    fn call_player_export<T: DeserializeOwned>(
+        &mut self,
+        player_id: PlayerId,
+        function_name: &str,
+        args: impl Serialize,
+    ) -> anyhow::Result<T> {
+        self.set_current_player(Some(player_id));
+        let result = self.budget.call(
+            &mut self.runtime,
+            &self.player_module_namespace,
+            "player.js",
+            function_name,
+            args,
+        );
+        self.set_current_player(None);
+        result
+    }
+
+    fn set_current_player(&mut self, player_id: Option<PlayerId>) {
+        let op_state = self.runtime.op_state();
+        let mut op_state = op_state.borrow_mut();
+        op_state.put(CurrentPlayer(player_id));
+    }
+
+    fn set_current_entity(&mut self, entity_id: Option<&str>) {
+        let op_state = self.runtime.op_state();
+        let mut op_state = op_state.borrow_mut();
+        op_state.put(CurrentEntity(entity_id.map(Into::into)));
+    }
+
+    // Copy the parts of an entity's state that scripts are allowed to change back into the world
+    fn set_entity_state(&mut self, entity_id: &str, next_state: EntityState) {
+        let mut world = self.world.lock().expect("Deadlock!");
+        let Some(entity) = world.entities.get_mut(entity_id) else {
+            return;
+        };
+
+        let EntityState {
+            position,
+            rotation,
+            velocity,
+            scale,
+            custom_state,
+
+            // Not mutable from JS
+            anchor: _,
+            interactions: _,
+            absolute_position: _,
+        } = next_state;
+
+        entity.state.position = position;
+        entity.state.rotation = rotation;
+        entity.state.velocity = velocity;
+        entity.state.scale = scale;
+        entity.state.custom_state = custom_state;
+    }
+
+    /// Run the entity's `onSpawn`, if it has one. If it fails, the entity is left as it was.
     pub(crate) fn spawn_entity(&mut self, entity_data: &mut EntityData) {
-        // Load the module for this entity type
-        let scope = &mut self.runtime.handle_scope();
-        let module_path = &self.entity_module_paths[entity_data.entity_type as usize];
-        let module_namespace = &self.entity_module_namespaces[module_path];
-        let module_namespace = module_namespace.open(scope);
-
-        // Check whether this entity type has an onSpawn function
-        let function_name = v8::String::new(scope, "onSpawn").unwrap();
-        let Some(on_spawn) = module_namespace.get(scope, function_name.into()) else {
+        let Some(entity_type_id) = entity_data.entity_type else {
             return;
         };
+        let module_path = self.entity_module_paths[entity_type_id as usize].clone();
+        let module_namespace = &self.entity_module_namespaces[&module_path];
 
         // Since onSpawn is optional, if there's no function then just return
-        if !on_spawn.is_function() {
+        if !has_export(&mut self.runtime, module_namespace, "onSpawn") {
             return;
         }
 
-        // Get the actual function
-        let on_spawn = v8::Local::<v8::Function>::try_from(on_spawn).unwrap(); // we know it's a function
-
-        // Put the args together
-        let undefined = deno_core::v8::undefined(scope).into();
-        let initial_state = serde_v8::to_v8(scope, &entity_data).unwrap();
-
-        let args = [initial_state.into()];
-
-        // Call the function
-        let result = on_spawn.call(scope, undefined, &args).unwrap();
+        let result =
+            self.call_entity_export(&entity_data.id, &module_path, "onSpawn", (&*entity_data,));
 
         // Get the entity's initial state
-        let EntityData {
+        let Ok(EntityData {
             name,
             model_path,
             state,
             ..
-        } = serde_v8::from_v8(scope, result).unwrap();
+        }) = self.report(&module_path, "onSpawn", Some(&entity_data.id), result)
+        else {
+            return;
+        };
 
         // Update the entity
         entity_data.name = name;
@@ -284,6 +418,42 @@ impl JSContext {
         entity_data.state = state;
     }
 
+    /// Run any script timers that are due. Call once per tick of the game clock.
+    pub fn run_timers(&mut self) -> anyhow::Result<()> {
+        let result = self.budget.call::<serde::de::IgnoredAny>(
+            &mut self.runtime,
+            &self.runtime_internals,
+            "timers",
+            "runTimers",
+            NO_ARGS,
+        );
+        // A timer that was terminated never got as far as putting the current entity back
+        self.set_current_entity(None);
+        self.report("timers", "runTimers", None, result)?;
+        Ok(())
+    }
+
+    /// Clear the timers set by an entity's script, or every timer if there's no entity
+    pub fn clear_timers(&mut self, entity_id: Option<&str>) {
+        let result = match entity_id {
+            Some(entity_id) => self.budget.call::<serde::de::IgnoredAny>(
+                &mut self.runtime,
+                &self.runtime_internals,
+                "timers",
+                "clearTimers",
+                (entity_id,),
+            ),
+            None => self.budget.call(
+                &mut self.runtime,
+                &self.runtime_internals,
+                "timers",
+                "clearTimers",
+                NO_ARGS,
+            ),
+        };
+        let _ = self.report("timers", "clearTimers", entity_id, result);
+    }
+
     pub fn run_world_init(
         &mut self,
         custom_world_state: &mut serde_json::Value,
@@ -304,29 +474,15 @@ impl JSContext {
         player_id: PlayerId,
         current_state: &PlayerState,
     ) -> anyhow::Result<PlayerState> {
-        let scope = &mut self.runtime.handle_scope();
-        let module_namespace = self.world_module_namespace.open(scope);
-
-        let function_name = v8::String::new(scope, "onAddPlayer").unwrap();
-        let Some(update_fn) = module_namespace.get(scope, function_name.into()) else {
-            anyhow::bail!("ERROR: Module has no function named onAddPlayer!");
-        };
-
-        if !update_fn.is_function() {
-            anyhow::bail!("ERROR: Module has a member named onAddPlayer, but it's not a function!");
-        }
-
-        let player_update = v8::Local::<v8::Function>::try_from(update_fn).unwrap(); // we know it's a function
-
-        let undefined = deno_core::v8::undefined(scope).into();
-        let world_state = serde_v8::to_v8(scope, &*custom_world_state).unwrap();
-        let player_id = serde_v8::to_v8(scope, player_id).unwrap();
-        let current_state = serde_v8::to_v8(scope, current_state).unwrap();
-        let args = [world_state.into(), player_id.into(), current_state.into()];
-
-        let result = player_update.call(scope, undefined, &args).unwrap();
-        let (next_world_state, next_player_state): (serde_json::Value, PlayerState) =
-            serde_v8::from_v8(scope, result)?;
+        let result = self.budget.call(
+            &mut self.runtime,
+            &self.world_module_namespace,
+            "world.js",
+            "onAddPlayer",
+            (&*custom_world_state, player_id, current_state),
+        );
+        let (next_world_state, next_player_state) =
+            self.report("world.js", "onAddPlayer", None, result)?;
 
         *custom_world_state = next_world_state;
 
@@ -338,44 +494,231 @@ impl JSContext {
         custom_world_state: &mut serde_json::Value,
         function_name: &str,
     ) -> anyhow::Result<()> {
-        let scope = &mut self.runtime.handle_scope();
-        let module_namespace = self.world_module_namespace.open(scope);
+        let result = self.budget.call(
+            &mut self.runtime,
+            &self.world_module_namespace,
+            "world.js",
+            function_name,
+            (&*custom_world_state,),
+        );
+        *custom_world_state = self.report("world.js", function_name, None, result)?;
+
+        Ok(())
+    }
 
-        let fn_name = v8::String::new(scope, function_name).unwrap();
-        let Some(init_fn) = module_namespace.get(scope, fn_name.into()) else {
-            anyhow::bail!("ERROR: Module has no function named {function_name}!");
+    // Log a script's error and keep it to be sent on to the editor
+    fn report<T>(
+        &mut self,
+        script: &str,
+        function: &str,
+        entity_id: Option<&str>,
+        result: anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let Err(error) = &result else {
+            return result;
         };
+        // Reported once for the whole tick instead
+        if error.is::<OutOfTime>() {
+            return result;
+        }
 
-        if !init_fn.is_function() {
-            anyhow::bail!(
-                "ERROR: Module has a member named {function_name}, but it's not a function!"
-            );
+        let (message, stack) = match error.downcast_ref::<JsError>() {
+            Some(js_error) => (js_error.exception_message.clone(), js_error.stack.clone()),
+            None => (format!("{error:#}"), None),
+        };
+        match &entity_id {
+            Some(entity_id) => tracing::error!(
+                "Error in {script} {function} for entity {entity_id}: {}",
+                stack.as_deref().unwrap_or(&message)
+            ),
+            None => tracing::error!(
+                "Error in {script} {function}: {}",
+                stack.as_deref().unwrap_or(&message)
+            ),
         }
 
-        let world_init = v8::Local::<v8::Function>::try_from(init_fn).unwrap(); // we know it's a function
+        self.errors.push(ScriptError {
+            script: script.into(),
+            function: function.into(),
+            entity_id: entity_id.map(Into::into),
+            message,
+            stack,
+        });
+        result
+    }
+}
 
-        let undefined = deno_core::v8::undefined(scope).into();
-        let world_state = serde_v8::to_v8(scope, &*custom_world_state).unwrap();
-        let args = [world_state.into()];
+// `call_export` takes its arguments as a tuple, and `()` isn't serialized as one
+const NO_ARGS: [(); 0] = [];
 
-        let result = world_init.call(scope, undefined, &args).unwrap();
-        let next_world_state: serde_json::Value = serde_v8::from_v8(scope, result)?;
+fn get_global_object(
+    runtime: &mut deno_core::JsRuntime,
+    name: &str,
+) -> anyhow::Result<v8::Global<v8::Object>> {
+    let scope = &mut runtime.handle_scope();
+    let global = scope.get_current_context().global(scope);
+    let key = v8::String::new(scope, name).unwrap();
+    let Some(value) = global.get(scope, key.into()) else {
+        anyhow::bail!("ERROR: There's no global named {name}!");
+    };
+    let object = v8::Local::<v8::Object>::try_from(value)?;
+    Ok(v8::Global::new(scope, object))
+}
 
-        *custom_world_state = next_world_state;
+fn has_export(
+    runtime: &mut deno_core::JsRuntime,
+    module_namespace: &v8::Global<v8::Object>,
+    function_name: &str,
+) -> bool {
+    let scope = &mut runtime.handle_scope();
+    let module_namespace = module_namespace.open(scope);
+    let function_name = v8::String::new(scope, function_name).unwrap();
+    module_namespace
+        .get(scope, function_name.into())
+        .is_some_and(|export| export.is_function())
+}
 
-        Ok(())
+// Call a function exported by a module. `args` is a tuple of the arguments, which are serialized
+// to JS. If the function throws, the error is a `JsError` with the (source mapped) stack trace.
+fn call_export<T: DeserializeOwned>(
+    runtime: &mut deno_core::JsRuntime,
+    module_namespace: &v8::Global<v8::Object>,
+    function_name: &str,
+    args: impl Serialize,
+) -> anyhow::Result<T> {
+    let scope = &mut runtime.handle_scope();
+    let module_namespace = module_namespace.open(scope);
+
+    let name = v8::String::new(scope, function_name).unwrap();
+    let Some(function) = module_namespace.get(scope, name.into()) else {
+        anyhow::bail!("ERROR: Module has no function named {function_name}!");
+    };
+
+    let Ok(function) = v8::Local::<v8::Function>::try_from(function) else {
+        anyhow::bail!("ERROR: Module has a member named {function_name}, but it's not a function!");
+    };
+
+    // Tuples are serialized as arrays
+    let args = v8::Local::<v8::Array>::try_from(serde_v8::to_v8(scope, args)?)?;
+    let args = (0..args.length())
+        .map(|index| args.get_index(scope, index).unwrap())
+        .collect::<Vec<_>>();
+
+    let scope = &mut v8::TryCatch::new(scope);
+    let undefined = v8::undefined(scope).into();
+    let Some(result) = function.call(scope, undefined, &args) else {
+        let Some(exception) = scope.exception() else {
+            // Execution was terminated rather than anything being thrown
+            anyhow::bail!("{function_name} did not return");
+        };
+        return Err(JsError::from_v8_exception(scope, exception).into());
+    };
+
+    Ok(serde_v8::from_v8(scope, result)?)
+}
+
+struct ScriptModules {
+    world_module_namespace: v8::Global<v8::Object>,
+    player_module_namespace: v8::Global<v8::Object>,
+    entity_module_namespaces: HashMap<String, v8::Global<v8::Object>>,
+    entity_module_paths: Vec<String>,
+}
+
+fn new_runtime(
+    world: &Arc<Mutex<World>>,
+    physics_world: &Arc<Mutex<PhysicsWorld>>,
+) -> deno_core::JsRuntime {
+    deno_core::JsRuntime::new(deno_core::RuntimeOptions {
+        module_loader: Some(Rc::new(loader::ScriptLoader)),
+        extensions: vec![hy::init_ops_and_esm(world.clone(), physics_world.clone())],
+        ..Default::default()
+    })
+}
+
+// V8 enters each runtime's isolate when it's created and exits it when it's dropped, so runtimes
+// have to be dropped in the reverse order they were created in. To drop an older runtime, the
+// newer one is stepped out of while it goes.
+fn drop_older_runtime(old: deno_core::JsRuntime, new: &mut deno_core::JsRuntime) {
+    // SAFETY: `new` was entered after `old`, so exiting it leaves `old` as the current isolate,
+    // which is what dropping `old` expects. Nothing else runs before `new` is entered again.
+    unsafe { new.v8_isolate().exit() };
+    drop(old);
+    unsafe { new.v8_isolate().enter() };
+}
+
+async fn load_modules(
+    runtime: &mut deno_core::JsRuntime,
+    watchdog: &Watchdog,
+    script_root: &Path,
+    world: &Arc<Mutex<World>>,
+) -> anyhow::Result<ScriptModules> {
+    // Get a clone the entity type registry before we pass it over to the runtime
+    let entity_type_registry = {
+        let world = world.lock().expect("Deadlock!");
+        world.entity_type_registry.clone()
+    };
+
+    // Load the world module
+    let world_module_namespace =
+        get_module_namespace(script_root.join("world.js"), runtime, watchdog).await?;
+
+    // Load the player module
+    let player_script = script_root.join("player.js");
+    let player_module_namespace = get_module_namespace(player_script, runtime, watchdog).await?;
+
+    let mut entity_module_namespaces = HashMap::new();
+    let mut entity_module_paths = Vec::new();
+
+    // Load entity scripts
+    // PARANOIA: Ensure we load the entity types in the correct order
+    let mut entity_types = entity_type_registry.entity_types();
+    entity_types.sort_by_key(|et| et.id());
+
+    for entity_type in entity_types.iter() {
+        let path = entity_type.script_path();
+
+        // IMPORTANT: Deno will get very mad if we load the same module twice.
+        if !entity_module_namespaces.contains_key(path) {
+            let module_namespace =
+                get_module_namespace(script_root.join(path), runtime, watchdog).await?;
+            entity_module_namespaces.insert(path.to_string(), module_namespace);
+        }
+
+        entity_module_paths.push(path.to_string());
     }
+
+    Ok(ScriptModules {
+        world_module_namespace,
+        player_module_namespace,
+        entity_module_namespaces,
+        entity_module_paths,
+    })
 }
 
 async fn get_module_namespace(
     script_path: PathBuf,
     runtime: &mut deno_core::JsRuntime,
+    watchdog: &Watchdog,
 ) -> Result<v8::Global<v8::Object>, anyhow::Error> {
     tracing::debug!("Loading script at {script_path:?}");
-    let module = deno_core::resolve_path(script_path, &std::env::current_dir()?)?;
+    let module = deno_core::resolve_path(&script_path, &std::env::current_dir()?)?;
     let module_id = runtime.load_side_es_module(&module).await?;
-    runtime.mod_evaluate(module_id).await?;
-    runtime.run_event_loop(Default::default()).await?;
+
+    // Running the module's top-level code is stopped if it takes too long, like any other call
+    watchdog.arm(std::time::Instant::now() + budget::LOAD_BUDGET);
+    let result = async {
+        runtime.mod_evaluate(module_id).await?;
+        runtime.run_event_loop(Default::default()).await
+    }
+    .await;
+    if watchdog.disarm() {
+        runtime.v8_isolate().cancel_terminate_execution();
+        anyhow::bail!(
+            "{} was stopped for running over its time budget while loading",
+            script_path.display()
+        );
+    }
+    result?;
     let module_namespace = runtime.get_module_namespace(module_id)?;
     Ok(module_namespace)
 }
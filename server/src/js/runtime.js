@@ -14,6 +14,84 @@ globalThis.console = {
   },
 };
 
+// Timers count ticks of the game clock, which stands still while the game is paused. Each timer
+// belongs to the entity whose script set it, if any, and is cleared when that entity despawns.
+const timers = new Map();
+let currentTick = 0;
+let nextTimerId = 1;
+
+function addTimer(callback, ticks, repeat) {
+  if (typeof callback !== "function") {
+    throw new TypeError("Timer callback must be a function");
+  }
+
+  // A timer can't go off in the tick it was set
+  const delay = Math.max(1, Math.floor(ticks) || 0);
+  const id = nextTimerId++;
+  timers.set(id, {
+    id,
+    callback,
+    dueTick: currentTick + delay,
+    interval: repeat ? delay : null,
+    entityId: core.ops.get_current_entity_id(),
+  });
+  return id;
+}
+
+// Called by the server once per tick, while the game is being played
+function runTimers() {
+  currentTick += 1;
+
+  const due = [...timers.values()]
+    .filter((timer) => timer.dueTick <= currentTick)
+    .sort((a, b) => a.dueTick - b.dueTick || a.id - b.id);
+
+  let firstError;
+  const currentEntityId = core.ops.get_current_entity_id();
+  try {
+    for (const timer of due) {
+      // Cleared by an earlier callback
+      if (!timers.has(timer.id)) {
+        continue;
+      }
+
+      // Rescheduled before it runs, so that a timer that's terminated doesn't run again straight away
+      if (timer.interval === null) {
+        timers.delete(timer.id);
+      } else {
+        timer.dueTick += timer.interval;
+      }
+
+      // Timers set from a timer belong to the same entity
+      core.ops.set_current_entity_id(timer.entityId);
+      try {
+        timer.callback();
+      } catch (error) {
+        firstError ??= error;
+      }
+    }
+  } finally {
+    // Otherwise whatever runs next would set its timers on behalf of the last timer's entity
+    core.ops.set_current_entity_id(currentEntityId);
+  }
+
+  // One timer failing doesn't stop the others, but it's still reported
+  if (firstError !== undefined) {
+    throw firstError;
+  }
+}
+
+// Called by the server when an entity despawns, or with no ID to clear every timer
+function clearTimers(entityId) {
+  for (const [id, timer] of timers) {
+    if (entityId === undefined || timer.entityId === entityId) {
+      timers.delete(id);
+    }
+  }
+}
+
+globalThis.hyInternal = { runTimers, clearTimers };
+
 globalThis.hy = {
   getPlayerState: core.ops.get_player_state,
   getEntities: () => {
@@ -29,9 +107,22 @@ globalThis.hy = {
   checkMovementForCollisions: (playerID, currentPosition, movement) => {
     return core.ops.check_movement_for_collisions(playerID, currentPosition, movement);
   },
+  stepMovement: (movementState, controls) => {
+    return core.ops.step_movement(movementState, controls);
+  },
   anchorEntity: (entityId, anchorId, anchorName) => {
     return core.ops.anchor_entity(entityId, anchorId, anchorName);
   },
+  anchorToEntity: (entityId, parentId, parentNode, offset) => {
+    const anchor = { parentId, parentNode: parentNode ?? null };
+    return core.ops.anchor_to_entity(entityId, anchor, offset ?? null);
+  },
+  addJoint: (entityId, joint) => {
+    return core.ops.add_joint(entityId, joint);
+  },
+  removeJoint: (entityId, name) => {
+    return core.ops.remove_joint(entityId, name);
+  },
   detachEntity: (entityId, position) => {
     return core.ops.detach_entity(entityId, position);
   },
@@ -42,8 +133,28 @@ globalThis.hy = {
   getCollisionsForPlayer: (playerId) => {
     return core.ops.get_collisions_for_player(playerId);
   },
+  raycast: (origin, direction, maxDistance, filter) => {
+    return core.ops.raycast(origin, direction, maxDistance, filter ?? null);
+  },
+  shapeCast: (shape, origin, direction, maxDistance, filter) => {
+    return core.ops.shape_cast(shape, origin, direction, maxDistance, filter ?? null);
+  },
+  overlapSphere: (center, radius, filter) => {
+    return core.ops.overlap_sphere(center, radius, filter ?? null);
+  },
   playSound: (soundId, position, volume) => {
     return core.ops.play_sound(soundId, position, volume);
   },
   getBlock: core.ops.get_block,
+  setBlock: core.ops.set_block,
+  fillRegion: core.ops.fill_region,
+  clearRegion: (min, max) => {
+    return core.ops.fill_region(min, max, 0);
+  },
+  getBlocksInBox: core.ops.get_blocks_in_box,
+  setTimeout: (callback, ticks) => addTimer(callback, ticks, false),
+  setInterval: (callback, ticks) => addTimer(callback, ticks, true),
+  clearTimer: (timerId) => {
+    timers.delete(timerId);
+  },
 };
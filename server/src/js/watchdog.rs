@@ -0,0 +1,98 @@
+use {
+    deno_core::v8,
+    std::{
+        sync::{Arc, Condvar, Mutex},
+        thread::JoinHandle,
+        time::Instant,
+    },
+};
+
+// Stops scripts that run for too long. Scripts are called synchronously on the game loop, so a
+// script that never returns can't be stopped from that thread; instead a deadline is set before
+// each call, and if it passes before the call returns this thread terminates V8's execution.
+pub struct Watchdog {
+    shared: Arc<(Mutex<WatchdogState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    deadline: Option<Instant>,
+    // Execution was terminated since the watchdog was last armed
+    fired: bool,
+    stop: bool,
+}
+
+impl Watchdog {
+    pub fn new(isolate: v8::IsolateHandle) -> Self {
+        let shared = Arc::new((Mutex::new(WatchdogState::default()), Condvar::new()));
+        let thread = std::thread::Builder::new()
+            .name("script watchdog".into())
+            .spawn({
+                let shared = shared.clone();
+                move || watch(&shared, isolate)
+            })
+            .expect("Couldn't start the script watchdog");
+
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Terminate the running script if it's still running at `deadline`
+    pub fn arm(&self, deadline: Instant) {
+        let (state, wake) = &*self.shared;
+        let mut state = state.lock().expect("Deadlock!");
+        state.deadline = Some(deadline);
+        state.fired = false;
+        wake.notify_one();
+    }
+
+    /// Call once the script has returned. Returns true if it was terminated, in which case the
+    /// isolate's termination needs cancelling before it can run anything else.
+    pub fn disarm(&self) -> bool {
+        let (state, _) = &*self.shared;
+        let mut state = state.lock().expect("Deadlock!");
+        state.deadline = None;
+        std::mem::take(&mut state.fired)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        {
+            let (state, wake) = &*self.shared;
+            state.lock().expect("Deadlock!").stop = true;
+            wake.notify_one();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(shared: &(Mutex<WatchdogState>, Condvar), isolate: v8::IsolateHandle) {
+    let (state, wake) = shared;
+    let mut state = state.lock().expect("Deadlock!");
+    while !state.stop {
+        let Some(deadline) = state.deadline else {
+            state = wake.wait(state).expect("Deadlock!");
+            continue;
+        };
+
+        let now = Instant::now();
+        if now < deadline {
+            state = wake
+                .wait_timeout(state, deadline - now)
+                .expect("Deadlock!")
+                .0;
+            continue;
+        }
+
+        // Still holding the lock, so this can't land after the script has been disarmed
+        isolate.terminate_execution();
+        state.deadline = None;
+        state.fired = true;
+    }
+}
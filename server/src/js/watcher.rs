@@ -0,0 +1,80 @@
+use {
+    crate::game::TICK_RATE,
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    },
+};
+
+// How often the scripts directory is checked for changes
+const POLL_INTERVAL_TICKS: u32 = TICK_RATE / 2;
+
+// Notices when the scripts in a directory change, by polling their modification times. tsc
+// writes files one at a time, so a change is only reported once a poll finds nothing new, rather
+// than reloading halfway through a build.
+pub struct ScriptWatcher {
+    root: PathBuf,
+    // Modification time and size of every script, as of the last poll
+    scripts: HashMap<PathBuf, (SystemTime, u64)>,
+    // Something has changed, and we're waiting for it to settle
+    pending: bool,
+    ticks_until_poll: u32,
+}
+
+impl ScriptWatcher {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Self {
+            scripts: scan(&root),
+            root,
+            pending: false,
+            ticks_until_poll: POLL_INTERVAL_TICKS,
+        }
+    }
+
+    /// Call once per tick. Returns true when the scripts have changed and should be reloaded.
+    pub fn poll(&mut self) -> bool {
+        if self.ticks_until_poll > 0 {
+            self.ticks_until_poll -= 1;
+            return false;
+        }
+        self.ticks_until_poll = POLL_INTERVAL_TICKS;
+
+        let scripts = scan(&self.root);
+        if scripts != self.scripts {
+            tracing::debug!("Scripts in {:?} have changed", self.root);
+            self.scripts = scripts;
+            self.pending = true;
+            return false;
+        }
+
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// Finds all the scripts under `dir`
+fn scan(dir: &Path) -> HashMap<PathBuf, (SystemTime, u64)> {
+    let mut scripts = HashMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "js") {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                scripts.insert(path, (modified, metadata.len()));
+            }
+        }
+    }
+    scripts
+}